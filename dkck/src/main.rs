@@ -0,0 +1,49 @@
+extern crate clap;
+extern crate dkfs;
+
+use dkfs::check::check;
+use dkfs::*;
+use std::process::exit;
+
+fn main() -> DkResult<()> {
+    use clap::*;
+
+    let matches = App::new("dkck")
+        .version("0.1.2")
+        .author("Yilin Chen <sticnarf@gmail.com>")
+        .about("Check a donkey file system")
+        .arg(
+            Arg::with_name("device")
+                .help("Path to the device to be checked")
+                .required(true),
+        ).arg(
+            Arg::with_name("repair")
+                .help("Repair the problems found")
+                .short("r"),
+        ).get_matches();
+
+    let dev_path = matches.value_of("device").unwrap();
+    let repair = matches.is_present("repair");
+
+    let report = check(dev(dev_path)?, repair)?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if report.is_clean() {
+        println!("The file system is clean.");
+    } else if !repair {
+        println!("{} problems were found.", report.problems.len());
+        exit(1);
+    } else {
+        let repaired = report.problems.len() - report.unrepaired.len();
+        println!("{} problems were repaired.", repaired);
+        if !report.is_consistent() {
+            println!("These problems cannot be repaired:");
+            for problem in &report.unrepaired {
+                println!("{}", problem);
+            }
+            exit(1);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,394 @@
+//! POSIX access control lists, kept in xattrs in the format used by Linux.
+//!
+//! The access ACL of a file refines the permission bits of its mode,
+//! and the default ACL of a directory is inherited by new files in it.
+
+use byteorder::{ByteOrder, LE};
+use file::DkFile;
+use std::ffi::OsStr;
+use *;
+
+/// Name of the xattr holding the access ACL
+pub const ACCESS_XATTR: &str = "system.posix_acl_access";
+/// Name of the xattr holding the default ACL of a directory
+pub const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+/// Id of the entries which do not name a user or group
+const UNDEFINED_ID: u32 = !0;
+
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_USER: u16 = 0x02;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_GROUP: u16 = 0x08;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+
+/// The entries of an ACL sort in the order of the variants
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AclTag {
+    /// The owner of the file
+    UserObj,
+    User(u32),
+    /// The group of the file
+    GroupObj,
+    Group(u32),
+    /// The most permissions granted by `User`, `GroupObj` and `Group`
+    Mask,
+    Other,
+}
+
+/// `perm` holds the read (4), write (2) and execute (1) bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perm: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /// The ACL equivalent to the permission bits of `mode`
+    pub fn from_mode(mode: FileMode) -> Self {
+        let bits = mode.bits();
+        Acl {
+            entries: vec![
+                AclEntry {
+                    tag: AclTag::UserObj,
+                    perm: (bits >> 6) & 7,
+                },
+                AclEntry {
+                    tag: AclTag::GroupObj,
+                    perm: (bits >> 3) & 7,
+                },
+                AclEntry {
+                    tag: AclTag::Other,
+                    perm: bits & 7,
+                },
+            ],
+        }
+    }
+
+    /// Parses an xattr value, which is a version number (u32) followed by
+    /// entries of a tag (u16), permissions (u16) and an id (u32).
+    /// All integers are little-endian.
+    pub fn decode(bytes: &[u8]) -> DkResult<Self> {
+        if bytes.len() < 4 || bytes.len() % 8 != 4 {
+            return Err(Invalid(format!(
+                "An ACL cannot be {} bytes long",
+                bytes.len()
+            )));
+        }
+        let version = LE::read_u32(&bytes[0..4]);
+        if version != ACL_VERSION {
+            return Err(Invalid(format!("Unknown ACL version {}", version)));
+        }
+        let mut entries = Vec::new();
+        for e in bytes[4..].chunks(8) {
+            let id = LE::read_u32(&e[4..8]);
+            let tag = match LE::read_u16(&e[0..2]) {
+                TAG_USER_OBJ => AclTag::UserObj,
+                TAG_USER => AclTag::User(id),
+                TAG_GROUP_OBJ => AclTag::GroupObj,
+                TAG_GROUP => AclTag::Group(id),
+                TAG_MASK => AclTag::Mask,
+                TAG_OTHER => AclTag::Other,
+                tag => return Err(Invalid(format!("Unknown ACL tag {:#x}", tag))),
+            };
+            let perm = LE::read_u16(&e[2..4]);
+            if perm & !7 != 0 {
+                return Err(Invalid(format!("Invalid ACL permissions {:#o}", perm)));
+            }
+            entries.push(AclEntry { tag, perm });
+        }
+        let acl = Acl { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; 4 + 8 * self.entries.len()];
+        LE::write_u32(&mut bytes[0..4], ACL_VERSION);
+        for (e, buf) in self.entries.iter().zip(bytes[4..].chunks_mut(8)) {
+            let (tag, id) = match e.tag {
+                AclTag::UserObj => (TAG_USER_OBJ, UNDEFINED_ID),
+                AclTag::User(id) => (TAG_USER, id),
+                AclTag::GroupObj => (TAG_GROUP_OBJ, UNDEFINED_ID),
+                AclTag::Group(id) => (TAG_GROUP, id),
+                AclTag::Mask => (TAG_MASK, UNDEFINED_ID),
+                AclTag::Other => (TAG_OTHER, UNDEFINED_ID),
+            };
+            LE::write_u16(&mut buf[0..2], tag);
+            LE::write_u16(&mut buf[2..4], e.perm);
+            LE::write_u32(&mut buf[4..8], id);
+        }
+        bytes
+    }
+
+    /// An ACL has one entry of each of `UserObj`, `GroupObj` and `Other`,
+    /// and a `Mask` entry if it names any user or group.
+    /// Entries are sorted, and no user or group is named twice.
+    fn validate(&self) -> DkResult<()> {
+        let sorted = self.entries.windows(2).all(|w| w[0].tag < w[1].tag);
+        if !sorted {
+            return Err(Invalid(
+                "ACL entries are not sorted or not unique".to_string(),
+            ));
+        }
+        let has = |tag| self.perm(tag).is_some();
+        // Other tags appear at most once each
+        let named = self.entries.len() > 3 + has(AclTag::Mask) as usize;
+        if !has(AclTag::UserObj) || !has(AclTag::GroupObj) || !has(AclTag::Other) {
+            return Err(Invalid(
+                "An ACL entry for the owner, group or others is missing".to_string(),
+            ));
+        }
+        if named && !has(AclTag::Mask) {
+            return Err(Invalid(
+                "An ACL naming users or groups needs a mask".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn perm(&self, tag: AclTag) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+
+    fn set_perm(&mut self, tag: AclTag, perm: u16) {
+        if let Some(e) = self.entries.iter_mut().find(|e| e.tag == tag) {
+            e.perm = perm;
+        }
+    }
+
+    /// The entry limiting the permissions of the group class
+    fn group_class(&self) -> AclTag {
+        match self.perm(AclTag::Mask) {
+            Some(_) => AclTag::Mask,
+            None => AclTag::GroupObj,
+        }
+    }
+
+    /// Whether the ACL says no more than the permission bits
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// The permission bits equivalent to the ACL.
+    /// The group bits are those of the mask if any.
+    pub fn mode(&self) -> FileMode {
+        let perm = |tag| self.perm(tag).unwrap_or(0);
+        let bits = perm(AclTag::UserObj) << 6 | perm(self.group_class()) << 3 | perm(AclTag::Other);
+        FileMode::from_bits_truncate(bits)
+    }
+
+    /// Updates the ACL after the permission bits change to those of `mode`.
+    pub fn chmod(&mut self, mode: FileMode) {
+        let bits = mode.bits();
+        let group_class = self.group_class();
+        self.set_perm(AclTag::UserObj, (bits >> 6) & 7);
+        self.set_perm(group_class, (bits >> 3) & 7);
+        self.set_perm(AclTag::Other, bits & 7);
+    }
+
+    /// Limits an inherited ACL by the permission bits of `mode`,
+    /// with which a file is made. Returns the mode of the new file.
+    pub fn create(&mut self, mode: FileMode) -> FileMode {
+        let bits = mode.bits();
+        let group_class = self.group_class();
+        for e in &mut self.entries {
+            if e.tag == AclTag::UserObj {
+                e.perm &= (bits >> 6) & 7;
+            } else if e.tag == group_class {
+                e.perm &= (bits >> 3) & 7;
+            } else if e.tag == AclTag::Other {
+                e.perm &= bits & 7;
+            }
+        }
+        (mode - FileMode::USER_RWX - FileMode::GROUP_RWX - FileMode::OTHERS_RWX) | self.mode()
+    }
+
+    /// Whether `cred` is granted all permissions in `want`
+    /// on a file owned by `owner` and `group`
+    pub fn permits(&self, owner: u32, group: u32, cred: &Credentials, want: u16) -> bool {
+        let mask = self.perm(AclTag::Mask).unwrap_or(7);
+        let grants = |perm: u16| perm & want == want;
+        let mut in_group = false;
+        for e in &self.entries {
+            match e.tag {
+                AclTag::UserObj if cred.uid == owner => return grants(e.perm),
+                AclTag::User(id) if id == cred.uid => return grants(e.perm & mask),
+                AclTag::GroupObj if cred.in_group(group) => {
+                    in_group = true;
+                    if grants(e.perm & mask) {
+                        return true;
+                    }
+                }
+                AclTag::Group(id) if cred.in_group(id) => {
+                    in_group = true;
+                    if grants(e.perm & mask) {
+                        return true;
+                    }
+                }
+                // Groups matched but granted too little
+                AclTag::Other => return !in_group && grants(e.perm),
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// Checks an ACL about to be set through the xattr `name`, and updates the
+/// mode of the file after an access ACL. Returns the value to keep in the
+/// xattr, or `None` if the xattr is not needed.
+pub(crate) fn set(f: &mut DkFile, name: &OsStr, value: &[u8]) -> DkResult<Option<Vec<u8>>> {
+    if name == ACCESS_XATTR {
+        let acl = Acl::decode(value)?;
+        let mode = f.inode.mode - FileMode::USER_RWX - FileMode::GROUP_RWX - FileMode::OTHERS_RWX;
+        f.inode.mode = mode | acl.mode();
+        f.inode.ctime = SystemTime::now().into();
+        Ok(if acl.is_minimal() {
+            None
+        } else {
+            Some(acl.encode())
+        })
+    } else if name == DEFAULT_XATTR {
+        if !f.inode.mode.is_directory() {
+            return Err(Invalid("Only directories have default ACLs".to_string()));
+        }
+        Ok(Some(Acl::decode(value)?.encode()))
+    } else {
+        Ok(Some(value.to_vec()))
+    }
+}
+
+/// Brings the access ACL of the file in line with its mode after `chmod`.
+pub(crate) fn chmod(f: &mut DkFile) -> DkResult<()> {
+    let name = OsStr::new(ACCESS_XATTR);
+    let acl = f.xattr.get(name).map(|value| Acl::decode(value));
+    if let Some(acl) = acl {
+        let mut acl = acl?;
+        acl.chmod(f.inode.mode);
+        f.xattr.insert(name.to_owned(), acl.encode());
+        f.dirty = true;
+    }
+    Ok(())
+}
+
+/// Gives a new file the ACLs inherited from `default`,
+/// the default ACL of the directory it is made in.
+pub(crate) fn inherit(f: &mut DkFile, default: &[u8]) -> DkResult<()> {
+    if f.inode.mode.is_symbolic_link() {
+        return Ok(());
+    }
+    let mut acl = Acl::decode(default)?;
+    if f.inode.mode.is_directory() {
+        f.xattr.insert(DEFAULT_XATTR.into(), default.to_vec());
+    }
+    f.inode.mode = acl.create(f.inode.mode);
+    if !acl.is_minimal() {
+        f.xattr.insert(ACCESS_XATTR.into(), acl.encode());
+    }
+    f.dirty = true;
+    Ok(())
+}
+
+/// Whether `cred` is granted `want` on the file
+/// by its access ACL, or its permission bits without one
+pub(crate) fn permits(f: &DkFile, cred: &Credentials, want: AccessMode) -> DkResult<bool> {
+    let acl = match f.xattr.get(OsStr::new(ACCESS_XATTR)) {
+        Some(value) => Acl::decode(value)?,
+        None => Acl::from_mode(f.inode.mode),
+    };
+    let want = want.bits() as u16;
+    Ok(acl.permits(f.inode.uid, f.inode.gid, cred, want))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(tag: AclTag, perm: u16) -> AclEntry {
+        AclEntry { tag, perm }
+    }
+
+    fn shared() -> Acl {
+        Acl {
+            entries: vec![
+                entry(AclTag::UserObj, 7),
+                entry(AclTag::User(1000), 6),
+                entry(AclTag::GroupObj, 5),
+                entry(AclTag::Group(2000), 7),
+                entry(AclTag::Mask, 6),
+                entry(AclTag::Other, 0),
+            ],
+        }
+    }
+
+    #[test]
+    fn encode_decode() -> DkResult<()> {
+        let acl = shared();
+        let bytes = acl.encode();
+        assert_eq!(bytes.len(), 4 + 6 * 8);
+        assert_eq!(&bytes[12..20], &[2, 0, 6, 0, 0xe8, 3, 0, 0]);
+        assert_eq!(Acl::decode(&bytes)?, acl);
+
+        // Without a mask
+        let mut bad = shared();
+        bad.entries.remove(4);
+        assert!(Acl::decode(&bad.encode()).is_err());
+        // Unsorted
+        let mut bad = shared();
+        bad.entries.swap(1, 2);
+        assert!(Acl::decode(&bad.encode()).is_err());
+        assert!(Acl::decode(&bytes[..10]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn masks() {
+        let mut acl = shared();
+        assert_eq!(acl.mode(), FileMode::from_bits_truncate(0o760));
+        acl.chmod(FileMode::from_bits_truncate(0o750));
+        assert_eq!(acl.perm(AclTag::Mask), Some(5));
+        assert_eq!(acl.perm(AclTag::GroupObj), Some(5));
+
+        let mut acl = shared();
+        let mode = acl.create(FileMode::REGULAR_FILE | FileMode::from_bits_truncate(0o644));
+        assert_eq!(
+            mode,
+            FileMode::REGULAR_FILE | FileMode::from_bits_truncate(0o640)
+        );
+        assert_eq!(acl.perm(AclTag::User(1000)), Some(6));
+        assert_eq!(acl.perm(AclTag::Mask), Some(4));
+
+        let mut acl = Acl::from_mode(FileMode::from_bits_truncate(0o755));
+        assert!(acl.is_minimal());
+        assert_eq!(
+            acl.create(FileMode::from_bits_truncate(0o666)).bits(),
+            0o644
+        );
+    }
+
+    #[test]
+    fn permissions() {
+        let acl = shared();
+        let user = |uid, gid, groups: &[u32]| Credentials::new(uid, gid).groups(groups.to_vec());
+        // The owner is not limited by the mask
+        assert!(acl.permits(1, 10, &user(1, 10, &[]), 7));
+        assert!(acl.permits(1, 10, &user(1000, 100, &[]), 6));
+        assert!(!acl.permits(1, 10, &user(1000, 100, &[]), 1));
+        // Group 2000 has rwx, but the mask takes execution away
+        assert!(acl.permits(1, 10, &user(3000, 2000, &[]), 6));
+        assert!(!acl.permits(1, 10, &user(3000, 100, &[2000]), 1));
+        // Any matching group may grant the permissions
+        assert!(acl.permits(1, 10, &user(3000, 10, &[2000]), 2));
+        assert!(!acl.permits(1, 10, &user(3000, 100, &[10]), 2));
+        assert!(!acl.permits(1, 10, &user(3000, 100, &[]), 4));
+        assert!(acl.permits(1, 10, &user(3000, 100, &[]), 0));
+    }
+}
@@ -0,0 +1,526 @@
+//! Asynchronous counterparts of `Device` and `Handle`.
+//!
+//! The futures here do not depend on any particular runtime, so they
+//! can be awaited on tokio or any other executor. Blocking work is done
+//! on a shared pool of worker threads and never stalls the thread polling
+//! the future.
+
+use device::{BlockDevice, DEFAULT_BLOCK_SIZE};
+use failure::Fail;
+use nix::libc;
+use pool::{self, Pool};
+use replies::*;
+use std::cmp::{max, min};
+use std::ffi::OsString;
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::future::{self, Future};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use *;
+
+pub type DkFuture<T> = Pin<Box<dyn Future<Output = DkResult<T>> + Send>>;
+
+/// A device accessed by positional reads and writes which complete
+/// asynchronously. Operations may be issued concurrently.
+pub trait AsyncDevice: Debug + Send + Sync {
+    fn block_count(&self) -> u64;
+
+    fn block_size(&self) -> u64;
+
+    fn size(&self) -> u64 {
+        self.block_size() * self.block_count()
+    }
+
+    /// Reads exactly `len` bytes at `ptr`.
+    fn read_at(&self, ptr: u64, len: u64) -> DkFuture<Vec<u8>>;
+
+    fn write_at(&self, ptr: u64, data: Vec<u8>) -> DkFuture<()>;
+
+    /// Makes sure all written data reaches the storage.
+    fn sync(&self) -> DkFuture<()>;
+}
+
+/// The workers running blocking work, one per CPU.
+/// They are started on first use and live as long as the process.
+fn workers() -> &'static Mutex<Pool> {
+    static START: Once = Once::new();
+    static WORKERS: AtomicPtr<Mutex<Pool>> = AtomicPtr::new(ptr::null_mut());
+    START.call_once(|| {
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        let pool = Box::new(Mutex::new(Pool::new(max(cpus, 1) as usize)));
+        WORKERS.store(Box::into_raw(pool), Ordering::Release);
+    });
+    // Set once by `START` and never freed
+    unsafe { &*WORKERS.load(Ordering::Acquire) }
+}
+
+/// Runs `f` on a worker and resolves to its result. On a worker,
+/// such as when `AsyncHandle` accesses an `AsyncFile`, `f` runs in place,
+/// so that a busy pool never waits on itself.
+fn blocking<T, F>(f: F) -> DkFuture<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> DkResult<T> + Send + 'static,
+{
+    if pool::is_worker() {
+        return Box::pin(future::ready(f()));
+    }
+    let state = Arc::new(Mutex::new(BlockingState {
+        res: None,
+        waker: None,
+    }));
+    let shared = state.clone();
+    workers().lock().unwrap().spawn(move || {
+        let res = f();
+        let mut state = shared.lock().unwrap();
+        state.res = Some(res);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+    Box::pin(Blocking { state })
+}
+
+struct BlockingState<T> {
+    res: Option<DkResult<T>>,
+    waker: Option<Waker>,
+}
+
+struct Blocking<T> {
+    state: Arc<Mutex<BlockingState<T>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = DkResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.res.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs a future to completion on the current thread.
+/// Useful where no runtime is available.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// An image file or a block device accessed asynchronously.
+#[derive(Debug)]
+pub struct AsyncFile {
+    file: Arc<File>,
+    block_count: u64,
+}
+
+impl AsyncFile {
+    pub fn open<P: AsRef<Path>>(dev_path: P) -> DkResult<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(dev_path)?;
+        let file_type = file.metadata()?.file_type();
+        let size = if file_type.is_file() {
+            file.metadata()?.len()
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            BlockDevice::dev_size(&file)?
+        } else {
+            return Err(NotSupported);
+        };
+        Ok(AsyncFile {
+            file: Arc::new(file),
+            block_count: size / DEFAULT_BLOCK_SIZE,
+        })
+    }
+
+    fn check(&self, ptr: u64, len: u64) -> DkResult<()> {
+        let size = self.size();
+        if ptr + len > size {
+            Err(Corrupted(format!(
+                "Access {} bytes at {}, but device size is {}",
+                len, ptr, size
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl AsyncDevice for AsyncFile {
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn block_size(&self) -> u64 {
+        DEFAULT_BLOCK_SIZE
+    }
+
+    fn read_at(&self, ptr: u64, len: u64) -> DkFuture<Vec<u8>> {
+        let file = self.file.clone();
+        let checked = self.check(ptr, len);
+        blocking(move || {
+            checked?;
+            let mut buf = vec![0; len as usize];
+            file.read_exact_at(&mut buf, ptr)?;
+            Ok(buf)
+        })
+    }
+
+    fn write_at(&self, ptr: u64, data: Vec<u8>) -> DkFuture<()> {
+        let file = self.file.clone();
+        let checked = self.check(ptr, data.len() as u64);
+        blocking(move || {
+            checked?;
+            Ok(file.write_all_at(&data, ptr)?)
+        })
+    }
+
+    fn sync(&self) -> DkFuture<()> {
+        let file = self.file.clone();
+        blocking(move || Ok(file.sync_data()?))
+    }
+}
+
+/// Lets a file system be stored on an `AsyncDevice`.
+///
+/// Every access blocks the calling thread until the operation completes,
+/// so the device must not need the calling thread to make progress.
+/// `AsyncHandle` never calls into the file system on runtime threads.
+pub struct SyncDevice<D: AsyncDevice> {
+    dev: D,
+    pos: u64,
+}
+
+impl<D: AsyncDevice> SyncDevice<D> {
+    pub fn new(dev: D) -> Self {
+        SyncDevice { dev, pos: 0 }
+    }
+}
+
+impl<D: AsyncDevice> Debug for SyncDevice<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SyncDevice")
+            .field("dev", &self.dev)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl<D: AsyncDevice> Device for SyncDevice<D> {
+    fn block_count(&self) -> u64 {
+        self.dev.block_count()
+    }
+
+    fn block_size(&self) -> u64 {
+        self.dev.block_size()
+    }
+
+    fn sync(&mut self) -> DkResult<()> {
+        block_on(self.dev.sync())
+    }
+}
+
+impl<D: AsyncDevice> Read for SyncDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len() as u64, self.dev.size().saturating_sub(self.pos));
+        if len == 0 {
+            return Ok(0);
+        }
+        match block_on(self.dev.read_at(self.pos, len)) {
+            Ok(data) => {
+                buf[..data.len()].copy_from_slice(&data);
+                self.pos += len;
+                Ok(len as usize)
+            }
+            Err(e) => Err(io::Error::new(ErrorKind::Other, e.compat())),
+        }
+    }
+}
+
+impl<D: AsyncDevice> Write for SyncDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match block_on(self.dev.write_at(self.pos, buf.to_vec())) {
+            Ok(_) => {
+                self.pos += buf.len() as u64;
+                Ok(buf.len())
+            }
+            Err(e) => Err(io::Error::new(ErrorKind::Other, e.compat())),
+        }
+    }
+
+    /// Writes are done once `write_at` completes
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: AsyncDevice> Seek for SyncDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => offset(self.dev.size(), delta),
+            SeekFrom::Current(delta) => offset(self.pos, delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Seek to a negative position",
+            )),
+        }
+    }
+}
+
+fn offset(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    }
+}
+
+/// Asynchronous facade of `Handle`.
+///
+/// Each call runs the operation on a worker thread, so the futures
+/// can be awaited on any runtime without blocking it.
+#[derive(Debug, Clone)]
+pub struct AsyncHandle {
+    inner: Handle<'static>,
+}
+
+impl From<Handle<'static>> for AsyncHandle {
+    fn from(handle: Handle<'static>) -> Self {
+        AsyncHandle { inner: handle }
+    }
+}
+
+impl AsyncHandle {
+    /// Runs `f` with the blocking handle.
+    fn run<T, F>(&self, f: F) -> DkFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Handle<'static>) -> DkResult<T> + Send + 'static,
+    {
+        let handle = self.inner.clone();
+        blocking(move || f(&handle))
+    }
+
+    /// The blocking handle the operations are run on
+    pub fn handle(&self) -> &Handle<'static> {
+        &self.inner
+    }
+
+    pub fn statfs(&self) -> DkFuture<Statvfs> {
+        self.run(|h| h.statfs())
+    }
+
+    pub fn getattr(&self, ino: u64) -> DkFuture<Stat> {
+        self.run(move |h| h.getattr(ino))
+    }
+
+    pub fn lookup(&self, cred: Credentials, parent: u64, name: OsString) -> DkFuture<Stat> {
+        self.run(move |h| h.lookup(&cred, parent, &name))
+    }
+
+    pub fn access(&self, cred: Credentials, ino: u64, mode: AccessMode) -> DkFuture<()> {
+        self.run(move |h| h.access(&cred, ino, mode))
+    }
+
+    pub fn opendir(&self, cred: Credentials, ino: u64) -> DkFuture<DkDirHandle> {
+        self.run(move |h| h.opendir(&cred, ino))
+    }
+
+    /// Reads at most `count` entries after `cookie`.
+    /// Pass the cookie of the last entry to continue.
+    pub fn readdir(&self, dir: DkDirHandle, cookie: u64, count: usize) -> DkFuture<Vec<DirEntry>> {
+        self.run(move |h| h.readdir(dir, cookie).take(count).collect())
+    }
+
+    /// Like `readdir`, but also yields the attributes of each entry.
+    pub fn readdir_plus(
+        &self,
+        dir: DkDirHandle,
+        cookie: u64,
+        count: usize,
+    ) -> DkFuture<Vec<(DirEntry, Stat)>> {
+        self.run(move |h| h.readdir_plus(dir, cookie).take(count).collect())
+    }
+
+    pub fn mknod(
+        &self,
+        cred: Credentials,
+        parent: u64,
+        name: OsString,
+        mode: FileMode,
+        rdev: Option<u64>,
+    ) -> DkFuture<Stat> {
+        self.run(move |h| h.mknod(&cred, parent, &name, mode, rdev))
+    }
+
+    pub fn create(
+        &self,
+        cred: Credentials,
+        parent: u64,
+        name: OsString,
+        mode: FileMode,
+        flags: Flags,
+    ) -> DkFuture<(Stat, DkFileHandle)> {
+        self.run(move |h| h.create(&cred, parent, &name, mode, flags))
+    }
+
+    pub fn link(&self, cred: Credentials, ino: u64, parent: u64, name: OsString) -> DkFuture<Stat> {
+        self.run(move |h| h.link(&cred, ino, parent, &name))
+    }
+
+    pub fn open(&self, cred: Credentials, ino: u64, flags: Flags) -> DkFuture<DkFileHandle> {
+        self.run(move |h| h.open(&cred, ino, flags))
+    }
+
+    pub fn flush(&self, fh: DkFileHandle) -> DkFuture<()> {
+        self.run(move |h| h.flush(fh))
+    }
+
+    pub fn setattr(
+        &self,
+        cred: Credentials,
+        ino: u64,
+        fh: Option<DkFileHandle>,
+        attr: SetAttr,
+    ) -> DkFuture<Stat> {
+        self.run(move |h| h.setattr(&cred, ino, fh, attr))
+    }
+
+    pub fn read(&self, fh: DkFileHandle, offset: u64, size: u64) -> DkFuture<Vec<u8>> {
+        self.run(move |h| h.read(fh, offset, size))
+    }
+
+    pub fn write(&self, fh: DkFileHandle, offset: u64, data: Vec<u8>) -> DkFuture<usize> {
+        self.run(move |h| h.write(fh, offset, &data))
+    }
+
+    pub fn punch_hole(&self, fh: DkFileHandle, offset: u64, len: u64) -> DkFuture<()> {
+        self.run(move |h| h.punch_hole(fh, offset, len))
+    }
+
+    pub fn fallocate(
+        &self,
+        fh: DkFileHandle,
+        offset: u64,
+        len: u64,
+        keep_size: bool,
+    ) -> DkFuture<()> {
+        self.run(move |h| h.fallocate(fh, offset, len, keep_size))
+    }
+
+    pub fn seek_data(&self, fh: DkFileHandle, offset: u64) -> DkFuture<Option<u64>> {
+        self.run(move |h| h.seek_data(fh, offset))
+    }
+
+    pub fn seek_hole(&self, fh: DkFileHandle, offset: u64) -> DkFuture<Option<u64>> {
+        self.run(move |h| h.seek_hole(fh, offset))
+    }
+
+    pub fn mkdir(
+        &self,
+        cred: Credentials,
+        parent: u64,
+        name: OsString,
+        mode: FileMode,
+    ) -> DkFuture<Stat> {
+        self.run(move |h| h.mkdir(&cred, parent, &name, mode))
+    }
+
+    pub fn getxattr(
+        &self,
+        cred: Credentials,
+        ino: u64,
+        name: OsString,
+    ) -> DkFuture<Option<Vec<u8>>> {
+        self.run(move |h| h.getxattr(&cred, ino, &name))
+    }
+
+    pub fn listxattr(&self, cred: Credentials, ino: u64) -> DkFuture<Vec<OsString>> {
+        self.run(move |h| h.listxattr(&cred, ino))
+    }
+
+    pub fn setxattr(
+        &self,
+        cred: Credentials,
+        ino: u64,
+        name: OsString,
+        value: Vec<u8>,
+    ) -> DkFuture<()> {
+        self.run(move |h| h.setxattr(&cred, ino, &name, &value))
+    }
+
+    pub fn removexattr(&self, cred: Credentials, ino: u64, name: OsString) -> DkFuture<()> {
+        self.run(move |h| h.removexattr(&cred, ino, &name))
+    }
+
+    pub fn fsync(&self, fh: DkFileHandle, datasync: bool) -> DkFuture<()> {
+        self.run(move |h| h.fsync(fh, datasync))
+    }
+
+    pub fn fsyncdir(&self, dh: DkDirHandle, datasync: bool) -> DkFuture<()> {
+        self.run(move |h| h.fsyncdir(dh, datasync))
+    }
+
+    pub fn unlink(&self, cred: Credentials, parent: u64, name: OsString) -> DkFuture<()> {
+        self.run(move |h| h.unlink(&cred, parent, &name))
+    }
+
+    pub fn rename(
+        &self,
+        cred: Credentials,
+        old_parent: u64,
+        name: OsString,
+        new_parent: u64,
+        new_name: OsString,
+        flags: RenameFlags,
+    ) -> DkFuture<()> {
+        self.run(move |h| h.rename(&cred, old_parent, &name, new_parent, &new_name, flags))
+    }
+
+    pub fn rmdir(&self, cred: Credentials, parent: u64, name: OsString) -> DkFuture<()> {
+        self.run(move |h| h.rmdir(&cred, parent, &name))
+    }
+
+    pub fn symlink(
+        &self,
+        cred: Credentials,
+        parent: u64,
+        name: OsString,
+        link: PathBuf,
+    ) -> DkFuture<Stat> {
+        self.run(move |h| h.symlink(&cred, parent, &name, &link))
+    }
+}
@@ -0,0 +1,247 @@
+use block::RefData;
+use device::Device;
+use journal::Journal;
+use std::cmp::min;
+use std::collections::HashSet;
+use std::io::Read;
+use *;
+
+/// An allocation bitmap. Bit `i` is set if the `i`th object
+/// (inode or data block) is in use.
+///
+/// The whole bitmap is cached in memory. Modified bytes are written
+/// back by `flush`.
+#[derive(Debug)]
+pub(crate) struct Bitmap {
+    /// Pointer to the on-disk bitmap
+    pub(crate) ptr: u64,
+    /// Number of valid bits
+    pub(crate) len: u64,
+    bits: Vec<u8>,
+    /// Searching for free bits starts here
+    hint: u64,
+    /// Bits freed in the current transaction. They cannot be reused
+    /// until the transaction is committed.
+    reserved: HashSet<u64>,
+}
+
+impl Bitmap {
+    /// Bytes needed to store a bitmap of `len` bits
+    pub(crate) fn bytes_len(len: u64) -> u64 {
+        (len + 7) / 8
+    }
+
+    /// Creates an empty bitmap without touching the device.
+    pub(crate) fn new(ptr: u64, len: u64) -> Self {
+        Bitmap {
+            ptr,
+            len,
+            bits: vec![0; Self::bytes_len(len) as usize],
+            hint: 0,
+            reserved: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn load(dev: &mut dyn Device, ptr: u64, len: u64) -> DkResult<Self> {
+        let mut bm = Self::new(ptr, len);
+        let mut reader = dev.read_len_at(ptr, Self::bytes_len(len))?;
+        reader.read_exact(&mut bm.bits)?;
+        Ok(bm)
+    }
+
+    /// Reads the bits from the device again, dropping changes made in memory only.
+    pub(crate) fn reload(&mut self, dev: &mut dyn Device) -> DkResult<()> {
+        let mut reader = dev.read_len_at(self.ptr, Self::bytes_len(self.len))?;
+        reader.read_exact(&mut self.bits)?;
+        Ok(())
+    }
+
+    /// Writes the bytes containing bits `start..start + count` back to the device.
+    pub(crate) fn flush(&self, dev: &mut dyn Device, start: u64, count: u64) -> DkResult<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let from = start / 8;
+        let to = Self::bytes_len(start + count);
+        let bytes = RefData(&self.bits[from as usize..to as usize]);
+        dev.write_at(&bytes, self.ptr + from)
+    }
+
+    /// Writes the whole bitmap back to the device.
+    pub(crate) fn flush_all(&self, dev: &mut dyn Device) -> DkResult<()> {
+        self.flush(dev, 0, self.len)
+    }
+
+    /// Writes the bytes from `from` to the end to the device, bypassing
+    /// the journal. Only for bytes nothing refers to yet.
+    pub(crate) fn write_unjournaled(&self, dev: &mut Journal, from: u64) -> DkResult<()> {
+        if from >= self.bits.len() as u64 {
+            return Ok(());
+        }
+        let bytes = RefData(&self.bits[from as usize..]);
+        dev.write_data(self.ptr + from, &bytes)
+    }
+
+    /// Adds free bits up to `len`.
+    pub(crate) fn grow(&mut self, len: u64) {
+        self.bits.resize(Self::bytes_len(len) as usize, 0);
+        self.len = len;
+    }
+
+    /// Drops the bits from `len` on.
+    pub(crate) fn truncate(&mut self, len: u64) {
+        for i in len..min(self.len, Self::bytes_len(len) * 8) {
+            self.clear(i);
+        }
+        self.bits.truncate(Self::bytes_len(len) as usize);
+        self.len = len;
+    }
+
+    pub(crate) fn get(&self, i: u64) -> bool {
+        self.bits[(i / 8) as usize] & (1 << (i % 8)) != 0
+    }
+
+    pub(crate) fn set(&mut self, i: u64) {
+        self.bits[(i / 8) as usize] |= 1 << (i % 8);
+    }
+
+    pub(crate) fn clear(&mut self, i: u64) {
+        self.bits[(i / 8) as usize] &= !(1 << (i % 8));
+    }
+
+    /// Clears bit `i` but keeps it from being allocated
+    /// before `release_reserved` is called.
+    pub(crate) fn clear_reserved(&mut self, i: u64) {
+        self.clear(i);
+        self.reserved.insert(i);
+    }
+
+    pub(crate) fn has_reserved(&self) -> bool {
+        !self.reserved.is_empty()
+    }
+
+    pub(crate) fn release_reserved(&mut self) {
+        self.reserved.clear();
+    }
+
+    fn is_free(&self, i: u64) -> bool {
+        !self.get(i) && !self.reserved.contains(&i)
+    }
+
+    /// Finds the first free bit in `from..to`.
+    fn find_free_in(&self, from: u64, to: u64) -> Option<u64> {
+        let mut i = from;
+        while i < to {
+            if i % 8 == 0 && i + 8 <= to && self.bits[(i / 8) as usize] == 0xff {
+                // Skip a full byte at once
+                i += 8;
+                continue;
+            }
+            if self.is_free(i) {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Finds a free bit, starting from the hint and wrapping around.
+    pub(crate) fn find_free(&self) -> Option<u64> {
+        let hint = if self.hint < self.len { self.hint } else { 0 };
+        self.find_free_in(hint, self.len)
+            .or_else(|| self.find_free_in(0, hint))
+    }
+
+    /// Finds a run of free bits of length up to `max`.
+    /// Returns the start of the run and its length.
+    pub(crate) fn find_free_run(&self, max: u64) -> Option<(u64, u64)> {
+        let start = self.find_free()?;
+        let mut len = 1;
+        while len < max && start + len < self.len && self.is_free(start + len) {
+            len += 1;
+        }
+        Some((start, len))
+    }
+
+    /// Finds the first run of `len` free bits ending before `to`.
+    pub(crate) fn find_run_before(&self, len: u64, to: u64) -> Option<u64> {
+        let mut start = 0;
+        while start + len <= to {
+            match (start..start + len).rev().find(|&i| !self.is_free(i)) {
+                Some(used) => start = used + 1,
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// Number of bits set
+    pub(crate) fn count(&self) -> u64 {
+        (0..self.len).filter(|&i| self.get(i)).count() as u64
+    }
+
+    /// Marks bits `start..start + count` as used.
+    pub(crate) fn set_range(&mut self, start: u64, count: u64) {
+        for i in start..start + count {
+            self.set(i);
+        }
+        self.hint = start + count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_and_set() {
+        let mut bm = Bitmap::new(0, 20);
+        assert_eq!(bm.find_free(), Some(0));
+        bm.set_range(0, 9);
+        assert_eq!(bm.find_free(), Some(9));
+        bm.clear(3);
+        // Next-fit: searching continues after the last allocation
+        assert_eq!(bm.find_free(), Some(9));
+        bm.set_range(9, 11);
+        assert_eq!(bm.find_free(), Some(3));
+        bm.set(3);
+        assert_eq!(bm.find_free(), None);
+    }
+
+    #[test]
+    fn free_runs() {
+        let mut bm = Bitmap::new(0, 64);
+        bm.set_range(0, 4);
+        bm.set(10);
+        assert_eq!(bm.find_free_run(100), Some((4, 6)));
+        assert_eq!(bm.find_free_run(3), Some((4, 3)));
+        bm.set_range(4, 6);
+        assert_eq!(bm.find_free_run(100), Some((11, 53)));
+    }
+
+    #[test]
+    fn runs_before() {
+        let mut bm = Bitmap::new(0, 32);
+        bm.set(2);
+        bm.set(6);
+        assert_eq!(bm.find_run_before(3, 32), Some(3));
+        assert_eq!(bm.find_run_before(4, 32), Some(7));
+        assert_eq!(bm.find_run_before(4, 10), None);
+        bm.set(20);
+        bm.truncate(12);
+        assert_eq!(bm.len, 12);
+        bm.grow(32);
+        assert!(!bm.get(20));
+    }
+
+    #[test]
+    fn reserved() {
+        let mut bm = Bitmap::new(0, 8);
+        bm.set_range(0, 8);
+        bm.clear_reserved(5);
+        assert!(!bm.get(5));
+        assert_eq!(bm.find_free(), None);
+        bm.release_reserved();
+        assert_eq!(bm.find_free(), Some(5));
+    }
+}
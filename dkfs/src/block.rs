@@ -1,7 +1,7 @@
-use bincode::{deserialize_from, serialize};
 use byteorder::{ByteOrder, LE};
+use checksum::crc32c;
 use std::fmt::Debug;
-use std::io::{self, BufReader, Read};
+use std::io::Read;
 use std::ops::{Deref, DerefMut, Index, IndexMut};
 use *;
 
@@ -28,17 +28,182 @@ impl Writable for Box<Writable> {
 }
 
 pub(crate) const MAGIC_NUMBER: u64 = 0x1BAD_FACE_DEAD_C0DE;
+pub(crate) const INODE_CHUNK_MAGIC: u64 = 0x1BAD_FACE_C4A2_C0DE;
+/// Bumped when images cannot be read by older versions in any way
+pub(crate) const MAJOR_VERSION: u32 = 1;
+/// Bumped for changes described by feature flags
+pub(crate) const MINOR_VERSION: u32 = 4;
+
+bitflags! {
+    /// Features that older versions may safely ignore
+    pub struct FeatureCompat: u64 {
+        const HAS_JOURNAL      = 0b0000_0000_0000_0001;
+        /// Copies of the super block are kept in the data area
+        const BACKUP_SUPER     = 0b0000_0000_0000_0010;
+    }
+}
+
+bitflags! {
+    /// Features that older versions may read but must not write
+    pub struct FeatureRoCompat: u64 {
+        /// Metadata blocks carry checksums
+        const METADATA_CSUM    = 0b0000_0000_0000_0001;
+    }
+}
+
+bitflags! {
+    /// Features that older versions cannot handle at all
+    pub struct FeatureIncompat: u64 {
+        /// Some files map their blocks with extents
+        const EXTENTS          = 0b0000_0000_0000_0001;
+        /// Inodes beyond the inode table are kept in chunks in the data area
+        const INODE_CHUNKS     = 0b0000_0000_0000_0010;
+        /// Small files and symbolic links keep their data in the inode
+        const INLINE_DATA      = 0b0000_0000_0000_0100;
+        /// Extended attributes may take up a list of blocks
+        const LARGE_XATTRS     = 0b0000_0000_0000_1000;
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+/// Leading fields of the super block. They keep their place
+/// in every version, so they are checked before the rest is read.
+#[derive(Debug)]
+pub(crate) struct SuperBlockHeader {
+    pub(crate) magic_number: u64,
+    pub(crate) major_version: u32,
+    pub(crate) minor_version: u32,
+    pub(crate) feature_compat: FeatureCompat,
+    pub(crate) feature_ro_compat: FeatureRoCompat,
+    pub(crate) feature_incompat: FeatureIncompat,
+}
+
+impl SuperBlockHeader {
+    /// Returns whether the file system may only be opened read-only.
+    pub(crate) fn check<R: Read>(mut bytes: R) -> DkResult<bool> {
+        let mut buf = [0; SB_HEADER_LEN];
+        bytes.read_exact(&mut buf)?;
+        let h = SuperBlockHeader::decode(&buf);
+        if h.magic_number != MAGIC_NUMBER {
+            // Reported by the validation of the super block
+            return Ok(false);
+        }
+        if h.major_version > MAJOR_VERSION {
+            return Err(Incompatible(format!(
+                "Format version {}.{} is newer than {}.{}",
+                h.major_version, h.minor_version, MAJOR_VERSION, MINOR_VERSION
+            )));
+        }
+        let unknown = h.feature_incompat.bits() & !FeatureIncompat::all().bits();
+        if unknown != 0 {
+            return Err(Incompatible(format!(
+                "Unknown incompatible features {:#x}",
+                unknown
+            )));
+        }
+        Ok(h.feature_ro_compat.bits() & !FeatureRoCompat::all().bits() != 0)
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        SuperBlockHeader {
+            magic_number: LE::read_u64(&buf[0..8]),
+            major_version: LE::read_u32(&buf[8..12]),
+            minor_version: LE::read_u32(&buf[12..16]),
+            // Unknown bits are kept
+            feature_compat: FeatureCompat {
+                bits: LE::read_u64(&buf[16..24]),
+            },
+            feature_ro_compat: FeatureRoCompat {
+                bits: LE::read_u64(&buf[24..32]),
+            },
+            feature_incompat: FeatureIncompat {
+                bits: LE::read_u64(&buf[32..40]),
+            },
+        }
+    }
+}
+
+/// The super block is laid out as follows. All integers are little-endian.
+///
+/// | Offset | Size | Field                                |
+/// |--------|------|--------------------------------------|
+/// | 0      | 40   | `SuperBlockHeader`                   |
+/// | 40     | 8    | `block_size`                         |
+/// | 48     | 8    | `inode_count`                        |
+/// | 56     | 8    | `used_inode_count`                   |
+/// | 64     | 8    | `db_count`                           |
+/// | 72     | 8    | `used_db_count`                      |
+/// | 80     | 8    | `inode_bitmap_ptr`                   |
+/// | 88     | 8    | `db_bitmap_ptr`                      |
+/// | 96     | 8    | `first_db_ptr`                       |
+/// | 104    | 8    | `journal_ptr`                        |
+/// | 112    | 8    | `journal_blocks`                     |
+/// | 120    | 8    | `reserved_db_count`                  |
+/// | 128    | 16   | `label`                              |
+/// | 144    | 16   | `uuid`                               |
+/// | 160    | 8    | `generation`                         |
+/// | 168    | 8    | `max_inode_count`                    |
+/// | 176    | 8    | `inode_chunk_ptr`                    |
+/// | 184    | 8    | `free_inode_chunk_ptr`               |
+/// | 192    | 8    | `inode_chunk_count`                  |
+/// | 200    | 8    | `used_chunk_inode_count`             |
+/// | 208    | 812  | Reserved, zeros                      |
+/// | 1020   | 4    | CRC-32C of the bytes before          |
+///
+/// New fields of compatible features take the reserved space,
+/// so the checksum never moves.
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct SuperBlock {
     pub(crate) magic_number: u64,
+    pub(crate) major_version: u32,
+    pub(crate) minor_version: u32,
+    pub(crate) feature_compat: FeatureCompat,
+    pub(crate) feature_ro_compat: FeatureRoCompat,
+    pub(crate) feature_incompat: FeatureIncompat,
     pub(crate) block_size: u64,
     pub(crate) inode_count: u64,
+    /// Inodes in use in the inode table
     pub(crate) used_inode_count: u64,
     pub(crate) db_count: u64,
     pub(crate) used_db_count: u64,
-    pub(crate) inode_fl_ptr: u64,
-    pub(crate) db_fl_ptr: u64,
+    pub(crate) inode_bitmap_ptr: u64,
+    pub(crate) db_bitmap_ptr: u64,
+    pub(crate) first_db_ptr: u64,
+    pub(crate) journal_ptr: u64,
+    pub(crate) journal_blocks: u64,
+    /// Data blocks kept free for the super user
+    pub(crate) reserved_db_count: u64,
+    /// Padded with zeros
+    pub(crate) label: [u8; MAX_LABEL_LEN],
+    pub(crate) uuid: [u8; 16],
+    /// Increased each time the backups are written,
+    /// so the newest backup can be told apart
+    pub(crate) generation: u64,
+    /// Inodes the inode table and its bitmap have room for.
+    /// Zero in file systems made before it was added.
+    pub(crate) max_inode_count: u64,
+    /// The first of all inode chunks, or zero if there are none
+    pub(crate) inode_chunk_ptr: u64,
+    /// The first of the inode chunks with free slots
+    pub(crate) free_inode_chunk_ptr: u64,
+    pub(crate) inode_chunk_count: u64,
+    /// Inodes in use in the inode chunks
+    pub(crate) used_chunk_inode_count: u64,
+}
+
+impl SuperBlock {
+    /// Pointer to the end of the data area
+    pub(crate) fn db_end(&self) -> u64 {
+        self.first_db_ptr + self.db_count * self.block_size
+    }
+
+    pub(crate) fn max_inode_count(&self) -> u64 {
+        max(self.max_inode_count, self.inode_count)
+    }
+
+    /// Inodes in use in the inode table and the inode chunks
+    pub(crate) fn used_inodes(&self) -> u64 {
+        self.used_inode_count + self.used_chunk_inode_count
+    }
 }
 
 /// super block validation
@@ -47,19 +212,37 @@ fn sbv(sb: &SuperBlock) -> DkResult<()> {
         Err(Corrupted(format!(
             "Magic number validation failed! It is probably not using Donkey filesystem."
         )))
+    } else if !sb.block_size.is_power_of_two() || sb.block_size < 1024 {
+        Err(Corrupted(format!("Invalid block size {}", sb.block_size)))
     } else {
         Ok(())
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct FreeList {
-    pub next_ptr: u64,
-    /// Size of this free node
-    pub size: u64,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+/// An inode is laid out as follows. All integers are little-endian.
+/// A timestamp is the seconds (i64) followed by the nanoseconds (u32).
+///
+/// | Offset | Size | Field                                |
+/// |--------|------|--------------------------------------|
+/// | 0      | 8    | `ino`                                |
+/// | 8      | 2    | `mode`                               |
+/// | 10     | 2    | Reserved, zeros                      |
+/// | 12     | 4    | `uid`                                |
+/// | 16     | 4    | `gid`                                |
+/// | 20     | 4    | `flags`                              |
+/// | 24     | 8    | `nlink`                              |
+/// | 32     | 12   | `atime`                              |
+/// | 44     | 12   | `mtime`                              |
+/// | 56     | 12   | `ctime`                              |
+/// | 68     | 12   | `crtime`                             |
+/// | 80     | 8    | `size`                               |
+/// | 88     | 8    | `blocks`                             |
+/// | 96     | 8    | `device`                             |
+/// | 104    | 8    | `xattr_ptr`                          |
+/// | 112    | 128  | `ptrs`, 16 words, or inline data     |
+/// | 240    | 12   | Reserved, zeros                      |
+/// | 252    | 4    | CRC-32C of the bytes before          |
+#[derive(Debug, Clone)]
 pub struct Inode {
     pub ino: u64,
     pub mode: FileMode,
@@ -76,6 +259,10 @@ pub struct Inode {
     /// valid for device special files
     pub device: u64,
     pub xattr_ptr: u64,
+    pub flags: InodeFlags,
+    /// Block pointers, the root of the extent tree
+    /// if `flags` contains `EXTENTS`, or the data of the file
+    /// if `flags` contains `INLINE_DATA`
     pub ptrs: InodePtrs,
 }
 
@@ -86,6 +273,11 @@ fn inv(inode: &Inode) -> DkResult<()> {
             "Inode number {} is smaller than the root inode number {}",
             inode.ino, ROOT_INODE
         )))
+    } else if inode.flags.contains(InodeFlags::INLINE_DATA) && inode.size > MAX_INLINE_LEN {
+        Err(Corrupted(format!(
+            "Inode {} keeps {} bytes inline",
+            inode.ino, inode.size
+        )))
     } else {
         Ok(())
     }
@@ -102,9 +294,46 @@ impl Inode {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct InodePtrs([u64; 12], [u64; 1], [u64; 1], [u64; 1], [u64; 1]);
 
+impl InodePtrs {
+    /// All pointers as a flat array
+    pub fn words(&self) -> [u64; 16] {
+        let mut words = [0; 16];
+        words[..12].copy_from_slice(&self.0);
+        words[12] = self.1[0];
+        words[13] = self.2[0];
+        words[14] = self.3[0];
+        words[15] = self.4[0];
+        words
+    }
+
+    pub fn from_words(words: &[u64; 16]) -> Self {
+        let mut ptrs = InodePtrs::default();
+        ptrs.0.copy_from_slice(&words[..12]);
+        ptrs.1[0] = words[12];
+        ptrs.2[0] = words[13];
+        ptrs.3[0] = words[14];
+        ptrs.4[0] = words[15];
+        ptrs
+    }
+
+    /// All pointers as bytes in their on-disk order,
+    /// which are the data of an inline file
+    pub fn bytes(&self) -> [u8; MAX_INLINE_LEN as usize] {
+        let mut bytes = [0; MAX_INLINE_LEN as usize];
+        LE::write_u64_into(&self.words(), &mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; MAX_INLINE_LEN as usize]) -> Self {
+        let mut words = [0; 16];
+        LE::read_u64_into(bytes, &mut words);
+        Self::from_words(&words)
+    }
+}
+
 impl Index<usize> for InodePtrs {
     type Output = [u64];
 
@@ -133,7 +362,78 @@ impl IndexMut<usize> for InodePtrs {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// The head of an inode chunk, a data block whose other
+/// `INODE_SIZE` slots hold inodes. Chunks are linked into the list
+/// of all chunks and the list of chunks with free slots,
+/// which start in the super block.
+///
+/// | Offset | Size | Field                                |
+/// |--------|------|--------------------------------------|
+/// | 0      | 8    | `magic_number`                       |
+/// | 8      | 8    | `next`                               |
+/// | 16     | 8    | `next_free`                          |
+/// | 24     | 32   | `slots`, a bit for each slot in use  |
+/// | 56     | 196  | Reserved, zeros                      |
+/// | 252    | 4    | CRC-32C of the bytes before          |
+#[derive(Debug, Clone)]
+pub(crate) struct InodeChunk {
+    pub(crate) magic_number: u64,
+    pub(crate) next: u64,
+    /// Meaningless unless the chunk has free slots
+    pub(crate) next_free: u64,
+    pub(crate) slots: [u8; 32],
+}
+
+/// Number of inodes in a chunk of block size `bs`.
+/// Slot 0 is taken by the head.
+pub(crate) fn inodes_per_chunk(bs: u64) -> u64 {
+    bs / INODE_SIZE - 1
+}
+
+impl InodeChunk {
+    pub(crate) fn new(next: u64, next_free: u64) -> Self {
+        InodeChunk {
+            magic_number: INODE_CHUNK_MAGIC,
+            next,
+            next_free,
+            slots: [0; 32],
+        }
+    }
+
+    pub(crate) fn is_used(&self, slot: u64) -> bool {
+        self.slots[slot as usize / 8] & (1 << (slot % 8)) != 0
+    }
+
+    pub(crate) fn set_used(&mut self, slot: u64, used: bool) {
+        let (byte, bit) = (slot as usize / 8, 1 << (slot % 8));
+        if used {
+            self.slots[byte] |= bit;
+        } else {
+            self.slots[byte] &= !bit;
+        }
+    }
+
+    /// Number of slots in use
+    pub(crate) fn used(&self) -> u64 {
+        self.slots.iter().map(|b| u64::from(b.count_ones())).sum()
+    }
+
+    /// The first free one of slots 1 to `count`
+    pub(crate) fn find_free(&self, count: u64) -> Option<u64> {
+        (1..=count).find(|&slot| !self.is_used(slot))
+    }
+}
+
+/// inode chunk validation
+fn icv(chunk: &InodeChunk) -> DkResult<()> {
+    if chunk.magic_number != INODE_CHUNK_MAGIC {
+        Err(Corrupted("Not an inode chunk".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Data<T>(pub Vec<T>);
 
 impl<T> Deref for Data<T> {
@@ -156,15 +456,56 @@ pub type PtrBlock = Data<u64>;
 #[derive(Debug)]
 pub struct RefData<'a>(pub &'a [u8]);
 
+/// Number of pointers in a `PtrBlock` of block size `bs`.
+/// The last word of the block holds the checksum.
+pub(crate) fn ptrs_per_block(bs: u64) -> u64 {
+    bs / 8 - 1
+}
+
+/// Length of the encoded `SuperBlockHeader`
+const SB_HEADER_LEN: usize = 40;
+
+/// Fails to compile unless `$cond` holds.
+macro_rules! const_assert {
+    ($name:ident: $cond:expr) => {
+        #[allow(dead_code)]
+        const $name: [(); 0 - !($cond) as usize] = [];
+    };
+}
+
+const_assert!(SB_FITS: SuperBlock::LEN as u64 == SUPER_BLOCK_SIZE);
+const_assert!(INODE_FITS: Inode::LEN as u64 == INODE_SIZE);
+const_assert!(CHUNK_HEAD_FITS: InodeChunk::LEN as u64 == INODE_SIZE);
+const_assert!(CHUNK_SLOTS_FIT: MAX_BLOCK_SIZE / INODE_SIZE <= 32 * 8);
+const_assert!(SB_HEADER_FITS: SB_HEADER_LEN <= 128);
+
+/// A structure with a fixed-size encoding
+/// whose last 4 bytes are the CRC-32C of the others.
+trait Encoded: Sized {
+    const LEN: usize;
+    const NAME: &'static str;
+
+    /// Writes the fields into `buf`, which is zeroed and `LEN` bytes long.
+    fn encode(&self, buf: &mut [u8]);
+
+    fn decode(buf: &[u8]) -> Self;
+}
+
 macro_rules! impl_block {
     ($b:ty$(; validation: $f:ident)*) => {
         impl Readable for $b {
-            fn from_bytes<R: Read>(bytes: R) -> DkResult<Self>
+            fn from_bytes<R: Read>(mut bytes: R) -> DkResult<Self>
             where
                 Self: Sized,
             {
-                let b: Self = deserialize_from(bytes)?;
+                let mut buf = vec![0; <$b>::LEN];
+                bytes.read_exact(&mut buf)?;
+                let b = <$b>::decode(&buf);
                 b.validate()?;
+                let (body, sum) = buf.split_at(<$b>::LEN - 4);
+                if crc32c(body) != LE::read_u32(sum) {
+                    return Err(Corrupted(format!("Checksum mismatch in {}", <$b>::NAME)));
+                }
                 Ok(b)
             }
 
@@ -177,23 +518,188 @@ macro_rules! impl_block {
 
         impl Writable for $b {
             fn as_bytes(&self) -> DkResult<Box<Deref<Target = [u8]>>> {
-                Ok(Box::new(serialize(&self)?))
+                let mut buf = vec![0; <$b>::LEN];
+                self.encode(&mut buf);
+                let sum = crc32c(&buf[..<$b>::LEN - 4]);
+                LE::write_u32(&mut buf[<$b>::LEN - 4..], sum);
+                Ok(Box::new(buf))
             }
         }
     };
 }
 
+impl Encoded for SuperBlock {
+    const LEN: usize = 1024;
+    const NAME: &'static str = "super block";
+
+    fn encode(&self, buf: &mut [u8]) {
+        LE::write_u64(&mut buf[0..8], self.magic_number);
+        LE::write_u32(&mut buf[8..12], self.major_version);
+        LE::write_u32(&mut buf[12..16], self.minor_version);
+        LE::write_u64(&mut buf[16..24], self.feature_compat.bits());
+        LE::write_u64(&mut buf[24..32], self.feature_ro_compat.bits());
+        LE::write_u64(&mut buf[32..40], self.feature_incompat.bits());
+        LE::write_u64_into(
+            &[
+                self.block_size,
+                self.inode_count,
+                self.used_inode_count,
+                self.db_count,
+                self.used_db_count,
+                self.inode_bitmap_ptr,
+                self.db_bitmap_ptr,
+                self.first_db_ptr,
+                self.journal_ptr,
+                self.journal_blocks,
+                self.reserved_db_count,
+            ],
+            &mut buf[40..128],
+        );
+        buf[128..144].copy_from_slice(&self.label);
+        buf[144..160].copy_from_slice(&self.uuid);
+        LE::write_u64(&mut buf[160..168], self.generation);
+        LE::write_u64_into(
+            &[
+                self.max_inode_count,
+                self.inode_chunk_ptr,
+                self.free_inode_chunk_ptr,
+                self.inode_chunk_count,
+                self.used_chunk_inode_count,
+            ],
+            &mut buf[168..208],
+        );
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let h = SuperBlockHeader::decode(buf);
+        let mut words = [0; 11];
+        LE::read_u64_into(&buf[40..128], &mut words);
+        let mut sb = SuperBlock {
+            magic_number: h.magic_number,
+            major_version: h.major_version,
+            minor_version: h.minor_version,
+            feature_compat: h.feature_compat,
+            feature_ro_compat: h.feature_ro_compat,
+            feature_incompat: h.feature_incompat,
+            block_size: words[0],
+            inode_count: words[1],
+            used_inode_count: words[2],
+            db_count: words[3],
+            used_db_count: words[4],
+            inode_bitmap_ptr: words[5],
+            db_bitmap_ptr: words[6],
+            first_db_ptr: words[7],
+            journal_ptr: words[8],
+            journal_blocks: words[9],
+            reserved_db_count: words[10],
+            label: [0; MAX_LABEL_LEN],
+            uuid: [0; 16],
+            generation: LE::read_u64(&buf[160..168]),
+            max_inode_count: LE::read_u64(&buf[168..176]),
+            inode_chunk_ptr: LE::read_u64(&buf[176..184]),
+            free_inode_chunk_ptr: LE::read_u64(&buf[184..192]),
+            inode_chunk_count: LE::read_u64(&buf[192..200]),
+            used_chunk_inode_count: LE::read_u64(&buf[200..208]),
+        };
+        sb.label.copy_from_slice(&buf[128..144]);
+        sb.uuid.copy_from_slice(&buf[144..160]);
+        sb
+    }
+}
+
+fn encode_timespec(t: &DkTimespec, buf: &mut [u8]) {
+    LE::write_i64(&mut buf[0..8], t.sec);
+    LE::write_u32(&mut buf[8..12], t.nsec);
+}
+
+fn decode_timespec(buf: &[u8]) -> DkTimespec {
+    DkTimespec {
+        sec: LE::read_i64(&buf[0..8]),
+        nsec: LE::read_u32(&buf[8..12]),
+    }
+}
+
+impl Encoded for Inode {
+    const LEN: usize = 256;
+    const NAME: &'static str = "inode";
+
+    fn encode(&self, buf: &mut [u8]) {
+        LE::write_u64(&mut buf[0..8], self.ino);
+        LE::write_u16(&mut buf[8..10], self.mode.bits());
+        LE::write_u32(&mut buf[12..16], self.uid);
+        LE::write_u32(&mut buf[16..20], self.gid);
+        LE::write_u32(&mut buf[20..24], self.flags.bits());
+        LE::write_u64(&mut buf[24..32], self.nlink);
+        encode_timespec(&self.atime, &mut buf[32..44]);
+        encode_timespec(&self.mtime, &mut buf[44..56]);
+        encode_timespec(&self.ctime, &mut buf[56..68]);
+        encode_timespec(&self.crtime, &mut buf[68..80]);
+        LE::write_u64_into(
+            &[self.size, self.blocks, self.device, self.xattr_ptr],
+            &mut buf[80..112],
+        );
+        LE::write_u64_into(&self.ptrs.words(), &mut buf[112..240]);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let mut words = [0; 16];
+        LE::read_u64_into(&buf[112..240], &mut words);
+        Inode {
+            ino: LE::read_u64(&buf[0..8]),
+            mode: FileMode::from_bits_truncate(LE::read_u16(&buf[8..10])),
+            uid: LE::read_u32(&buf[12..16]),
+            gid: LE::read_u32(&buf[16..20]),
+            flags: InodeFlags::from_bits_truncate(LE::read_u32(&buf[20..24])),
+            nlink: LE::read_u64(&buf[24..32]),
+            atime: decode_timespec(&buf[32..44]),
+            mtime: decode_timespec(&buf[44..56]),
+            ctime: decode_timespec(&buf[56..68]),
+            crtime: decode_timespec(&buf[68..80]),
+            size: LE::read_u64(&buf[80..88]),
+            blocks: LE::read_u64(&buf[88..96]),
+            device: LE::read_u64(&buf[96..104]),
+            xattr_ptr: LE::read_u64(&buf[104..112]),
+            ptrs: InodePtrs::from_words(&words),
+        }
+    }
+}
+
+impl Encoded for InodeChunk {
+    const LEN: usize = 256;
+    const NAME: &'static str = "inode chunk";
+
+    fn encode(&self, buf: &mut [u8]) {
+        LE::write_u64_into(
+            &[self.magic_number, self.next, self.next_free],
+            &mut buf[0..24],
+        );
+        buf[24..56].copy_from_slice(&self.slots);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let mut chunk = InodeChunk {
+            magic_number: LE::read_u64(&buf[0..8]),
+            next: LE::read_u64(&buf[8..16]),
+            next_free: LE::read_u64(&buf[16..24]),
+            slots: [0; 32],
+        };
+        chunk.slots.copy_from_slice(&buf[24..56]);
+        chunk
+    }
+}
+
 impl_block!(SuperBlock; validation: sbv);
-impl_block!(FreeList);
 impl_block!(Inode; validation: inv);
+impl_block!(InodeChunk; validation: icv);
 
 impl Readable for ByteData {
-    fn from_bytes<R: Read>(bytes: R) -> DkResult<Self>
+    fn from_bytes<R: Read>(mut bytes: R) -> DkResult<Self>
     where
         Self: Sized,
     {
-        let v: Result<Vec<u8>, io::Error> = bytes.bytes().collect();
-        Ok(Data(v?))
+        let mut v = Vec::new();
+        bytes.read_to_end(&mut v)?;
+        Ok(Data(v))
     }
 }
 
@@ -204,22 +710,28 @@ impl Writable for ByteData {
 }
 
 impl Readable for PtrBlock {
-    fn from_bytes<R: Read>(bytes: R) -> DkResult<Self>
+    fn from_bytes<R: Read>(mut read: R) -> DkResult<Self>
     where
         Self: Sized,
     {
-        let read = BufReader::new(bytes);
-        let bytes = read.bytes().collect::<Result<Vec<u8>, io::Error>>()?;
-        let mut v = vec![0; bytes.len() / 8];
-        LE::read_u64_into(&bytes[..], &mut v[..]);
+        let mut bytes = Vec::new();
+        read.read_to_end(&mut bytes)?;
+        let len = bytes.len() / 8 * 8;
+        if len == 0 || u64::from(crc32c(&bytes[..len - 8])) != LE::read_u64(&bytes[len - 8..len]) {
+            return Err(Corrupted("Checksum mismatch in pointer block".to_string()));
+        }
+        let mut v = vec![0; len / 8 - 1];
+        LE::read_u64_into(&bytes[..len - 8], &mut v[..]);
         Ok(Data(v))
     }
 }
 
 impl Writable for PtrBlock {
     fn as_bytes<'a>(&'a self) -> DkResult<Box<Deref<Target = [u8]> + 'a>> {
-        let mut v = vec![0; self.len() * 8];
-        LE::write_u64_into(&self[..], &mut v[..]);
+        let mut v = vec![0; self.len() * 8 + 8];
+        LE::write_u64_into(&self[..], &mut v[..self.len() * 8]);
+        let sum = crc32c(&v[..self.len() * 8]);
+        LE::write_u64(&mut v[self.len() * 8..], u64::from(sum));
         Ok(Box::new(v))
     }
 }
@@ -242,4 +754,14 @@ mod tests {
         assert_eq!(pb, pb2);
         Ok(())
     }
+
+    #[test]
+    fn detect_corruption() -> DkResult<()> {
+        let pb: PtrBlock = Data((0..511).collect());
+        let mut bytes: Vec<u8> = pb.as_bytes()?.to_vec();
+        assert_eq!(bytes.len(), 4096);
+        bytes[100] ^= 0x10;
+        assert!(PtrBlock::from_bytes(&bytes[..]).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,223 @@
+use device::Device;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use *;
+
+/// The default number of cached blocks, that is 4 MiB for 4 KiB blocks
+pub const DEFAULT_CACHE_BLOCKS: usize = 1024;
+
+#[derive(Debug)]
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    /// Time of the last access, used to find the least recently used block
+    tick: u64,
+}
+
+/// A write-back LRU cache of blocks in front of any `Device`.
+///
+/// Writes stay in memory until the block is evicted or the cache
+/// is flushed. `sync` writes back all dirty blocks before syncing the
+/// underlying device, so that it keeps its meaning for the journal.
+#[derive(Debug)]
+pub struct Cache<'a> {
+    dev: Box<Device + 'a>,
+    bs: u64,
+    /// Maximum number of cached blocks
+    capacity: usize,
+    blocks: HashMap<u64, CachedBlock>,
+    /// Cached blocks by the time of their last access
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    pos: u64,
+}
+
+impl<'a> Cache<'a> {
+    /// Caches at most `capacity` blocks of `dev`.
+    pub fn new(dev: Box<Device + 'a>, capacity: usize) -> Self {
+        let bs = dev.block_size();
+        Cache {
+            dev,
+            bs,
+            capacity: capacity.max(1),
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            pos: 0,
+        }
+    }
+
+    /// Returns the cached copy of `block`, reading it from the device
+    /// unless it is going to be overwritten as a `whole`.
+    fn block(&mut self, block: u64, whole: bool) -> io::Result<&mut CachedBlock> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(b) = self.blocks.get_mut(&block) {
+            self.lru.remove(&b.tick);
+            self.lru.insert(tick, block);
+            b.tick = tick;
+            return Ok(self.blocks.get_mut(&block).unwrap());
+        }
+
+        if self.blocks.len() >= self.capacity {
+            self.evict()?;
+        }
+        let mut data = vec![0; self.bs as usize];
+        if !whole {
+            self.dev.seek(SeekFrom::Start(block * self.bs))?;
+            self.dev.read_exact(&mut data)?;
+        }
+        self.lru.insert(tick, block);
+        self.blocks.insert(
+            block,
+            CachedBlock {
+                data,
+                dirty: false,
+                tick,
+            },
+        );
+        Ok(self.blocks.get_mut(&block).unwrap())
+    }
+
+    /// Drops the least recently used block, writing it back if dirty.
+    fn evict(&mut self) -> io::Result<()> {
+        let (&tick, &block) = match self.lru.iter().next() {
+            Some(oldest) => oldest,
+            None => return Ok(()),
+        };
+        self.write_back(block)?;
+        self.lru.remove(&tick);
+        self.blocks.remove(&block);
+        Ok(())
+    }
+
+    fn write_back(&mut self, block: u64) -> io::Result<()> {
+        if let Some(b) = self.blocks.get_mut(&block) {
+            if b.dirty {
+                self.dev.seek(SeekFrom::Start(block * self.bs))?;
+                self.dev.write_all(&b.data)?;
+                b.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back all dirty blocks in the order of their position.
+    fn write_back_all(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<u64> = self
+            .blocks
+            .iter()
+            .filter(|(_, b)| b.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+        dirty.sort();
+        for block in dirty {
+            self.write_back(block)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Device for Cache<'a> {
+    fn block_count(&self) -> u64 {
+        self.dev.block_count()
+    }
+
+    fn block_size(&self) -> u64 {
+        self.bs
+    }
+
+    fn sync(&mut self) -> DkResult<()> {
+        self.write_back_all()?;
+        self.dev.sync()
+    }
+
+    fn refresh_size(&mut self) -> DkResult<u64> {
+        self.dev.refresh_size()?;
+        Ok(self.size())
+    }
+}
+
+impl<'a> Read for Cache<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size() {
+            return Ok(0);
+        }
+        let (block, off) = (self.pos / self.bs, (self.pos % self.bs) as usize);
+        let len = min(buf.len(), self.bs as usize - off);
+        {
+            let b = self.block(block, false)?;
+            buf[..len].copy_from_slice(&b.data[off..off + len]);
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a> Write for Cache<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos >= self.size() {
+            return Ok(0);
+        }
+        let bs = self.bs as usize;
+        let (block, off) = (self.pos / self.bs, (self.pos % self.bs) as usize);
+        let len = min(buf.len(), bs - off);
+        {
+            let b = self.block(block, off == 0 && len == bs)?;
+            b.data[off..off + len].copy_from_slice(&buf[..len]);
+            b.dirty = true;
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_back_all()?;
+        self.dev.flush()
+    }
+}
+
+impl<'a> Seek for Cache<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(diff) => (self.pos as i64 + diff) as u64,
+            SeekFrom::End(diff) => (self.size() as i64 + diff) as u64,
+        };
+        Ok(self.pos)
+    }
+}
+
+impl<'a> Drop for Cache<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Failed to write back cached blocks: {}. Data may be lost!", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::{ByteData, Readable, RefData};
+    use device::Memory;
+
+    #[test]
+    fn write_back() -> DkResult<()> {
+        let mut mem = vec![0; 1 << 20];
+        {
+            let mut cache = Cache::new(Box::new(Memory::new(&mut mem[..])), 4);
+            cache.write_at(&RefData(&[42; 5000]), 1000)?;
+            // Evicts the first block
+            for i in 10..13 {
+                cache.write_at(&RefData(&[7; 10]), i * 4096)?;
+            }
+            let data = ByteData::from_bytes(cache.read_len_at(1000, 5000)?)?;
+            assert!(data.iter().all(|&b| b == 42));
+        }
+        assert!(mem[1000..6000].iter().all(|&b| b == 42));
+        assert!(mem[10 * 4096..10 * 4096 + 10].iter().all(|&b| b == 7));
+        Ok(())
+    }
+}
@@ -0,0 +1,781 @@
+//! Offline consistency checking of a donkey file system.
+
+use alloc::Bitmap;
+use file::{DkDir, DkFile};
+use im::ordmap::OrdMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use *;
+
+pub const LOST_FOUND: &str = "lost+found";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// An inode in use cannot be read. It is freed when repairing.
+    BadInode { ino: u64, error: String },
+    /// A directory entry refers to an inode which is not in use
+    DanglingEntry {
+        parent: u64,
+        name: OsString,
+        ino: u64,
+    },
+    /// The file type in a directory entry differs from that of the inode
+    WrongFileType {
+        parent: u64,
+        name: OsString,
+        ino: u64,
+    },
+    /// An inode in use which is unreachable from the root.
+    /// It is moved to `lost+found` when repairing, or freed
+    /// if it is a file without links.
+    Orphan { ino: u64, nlink: u64 },
+    WrongNlink { ino: u64, stored: u64, actual: u64 },
+    WrongBlockCount { ino: u64, stored: u64, actual: u64 },
+    /// A file refers to a block outside the data area
+    InvalidBlock { ino: u64, ptr: u64 },
+    /// A file refers to a block already used by another file
+    DuplicateBlock { ino: u64, ptr: u64 },
+    /// A block in use is marked as free in the bitmap
+    UnmarkedBlock { ptr: u64 },
+    /// A block marked as used in the bitmap is not used by any file
+    LeakedBlock { ptr: u64 },
+    WrongUsedDbCount { stored: u64, actual: u64 },
+    WrongUsedInodeCount { stored: u64, actual: u64 },
+    /// The primary super block cannot be read. It is rebuilt from
+    /// the backup at `backup` when repairing.
+    BadSuperBlock { error: String, backup: u64 },
+    /// A backup super block cannot be read. It is rewritten when repairing.
+    BadBackupSuperBlock { ptr: u64, error: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Problem::*;
+        match self {
+            BadInode { ino, error } => write!(f, "Inode {} is unreadable: {}", ino, error),
+            DanglingEntry { parent, name, ino } => write!(
+                f,
+                "Entry {:?} in directory {} refers to unused inode {}",
+                name, parent, ino
+            ),
+            WrongFileType { parent, name, ino } => write!(
+                f,
+                "Entry {:?} in directory {} has a wrong file type for inode {}",
+                name, parent, ino
+            ),
+            Orphan { ino, nlink } => {
+                write!(f, "Inode {} (nlink {}) is unreachable from the root", ino, nlink)
+            }
+            WrongNlink { ino, stored, actual } => write!(
+                f,
+                "Inode {} has nlink {}, but {} entries refer to it",
+                ino, stored, actual
+            ),
+            WrongBlockCount { ino, stored, actual } => write!(
+                f,
+                "Inode {} has block count {}, but uses {} blocks",
+                ino, stored, actual
+            ),
+            InvalidBlock { ino, ptr } => {
+                write!(f, "Inode {} refers to invalid block {}", ino, ptr)
+            }
+            DuplicateBlock { ino, ptr } => write!(
+                f,
+                "Inode {} refers to block {} which is used by another file",
+                ino, ptr
+            ),
+            UnmarkedBlock { ptr } => write!(f, "Block {} is used but marked as free", ptr),
+            LeakedBlock { ptr } => write!(f, "Block {} is marked as used but unused", ptr),
+            WrongUsedDbCount { stored, actual } => write!(
+                f,
+                "Used data block count is {}, but {} blocks are marked as used",
+                stored, actual
+            ),
+            WrongUsedInodeCount { stored, actual } => write!(
+                f,
+                "Used inode count is {}, but {} inodes are marked as used",
+                stored, actual
+            ),
+            BadSuperBlock { error, backup } => write!(
+                f,
+                "Backup at {} is used instead of the unreadable super block: {}",
+                backup, error
+            ),
+            BadBackupSuperBlock { ptr, error } => {
+                write!(f, "Backup super block at {} is unreadable: {}", ptr, error)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Those of `problems` left on the file system: all of them
+    /// if not repairing, otherwise those which cannot be repaired
+    pub unrepaired: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Whether no problem is left on the file system
+    pub fn is_consistent(&self) -> bool {
+        self.unrepaired.is_empty()
+    }
+}
+
+/// All inodes in use and the entries of all directories
+type Scan = (
+    BTreeMap<u64, DkFileHandle>,
+    BTreeMap<u64, OrdMap<OsString, u64>>,
+);
+
+/// Checks the file system on `dev`. Found problems are repaired
+/// if `repair` is true, otherwise the file system is not modified
+/// except that the journal is replayed.
+pub fn check<'a>(mut dev: Box<Device + 'a>, repair: bool) -> DkResult<Report> {
+    let mut problems = Vec::new();
+    let mut backup = None;
+    let primary = SuperBlock::from_bytes(dev.read_at(SUPER_BLOCK_PTR)?);
+    if let Err(e) = primary {
+        let ptr = if repair {
+            recover_super_block(&mut *dev)?
+        } else {
+            // The file system is checked as the backup describes it
+            let (ptr, _) = newest_backup(&mut *dev)?;
+            backup = Some(ptr);
+            ptr
+        };
+        problems.push(Problem::BadSuperBlock {
+            error: e.to_string(),
+            backup: ptr,
+        });
+    }
+
+    // The counters in the super block are checked, so they are not
+    // reconciled when opening.
+    // Nothing is written when only checking
+    let dk = load(dev, backup, !repair)?;
+    if repair && dk.read_only {
+        return Err(ReadOnly);
+    }
+    let handle = Handle::new(dk);
+    let mut checker = Checker {
+        dk: &handle.inner,
+        repair,
+        report: Report {
+            unrepaired: if repair { Vec::new() } else { problems.clone() },
+            problems,
+        },
+        file_types: BTreeMap::new(),
+    };
+    checker.run()?;
+    Ok(checker.report)
+}
+
+struct Checker<'a, 'b: 'a> {
+    dk: &'a Donkey<'b>,
+    repair: bool,
+    report: Report,
+    /// File types stored in directory entries, by parent and name
+    file_types: BTreeMap<(u64, OsString), FileMode>,
+}
+
+impl<'a, 'b: 'a> Checker<'a, 'b> {
+    /// Records a problem, which is repaired if repairing and `repairable`.
+    fn found(&mut self, problem: Problem, repairable: bool) {
+        if !(self.repair && repairable) {
+            self.report.unrepaired.push(problem.clone());
+        }
+        self.report.problems.push(problem);
+    }
+
+    fn run(&mut self) -> DkResult<()> {
+        let (mut files, mut dirs) = self.scan()?;
+        self.check_entries(&files, &mut dirs)?;
+        self.check_orphans(&mut files, &mut dirs)?;
+        self.apply_releases()?;
+
+        // Repairing may have changed any inode, so scan again.
+        let (files, dirs) = if self.repair {
+            self.scan()?
+        } else {
+            (files, dirs)
+        };
+        let used = self.check_files(&files, &dirs)?;
+        self.apply_releases()?;
+        self.check_bitmaps(&used)?;
+        self.check_backups()
+    }
+
+    fn apply_releases(&mut self) -> DkResult<()> {
+        self.dk.close_dirs_in_list()?;
+        self.dk.close_files_in_list()
+    }
+
+    /// Loads a file without registering it in `Donkey`,
+    /// so that checking never writes to it.
+    fn load(&mut self, ino: u64) -> DkResult<DkFileHandle> {
+        let inode = self.dk.read_inode(ino)?;
+        let mut f = DkFile::new(inode);
+        f.read_xattr(self.dk)?;
+        f.read_extents(self.dk)?;
+        Ok(DkFileHandle {
+            inner: Arc::new(Mutex::new(f)),
+            ino,
+            close_file_list: Arc::new(Mutex::new(Vec::new())),
+            flags: Flags::READ_ONLY,
+            privileged: true,
+        })
+    }
+
+    fn read_entries(&mut self, fh: DkFileHandle) -> DkResult<OrdMap<OsString, u64>> {
+        let parent = fh.ino;
+        let mut dir = DkDir::from_file(fh)?;
+        let mut entries = OrdMap::new();
+        for e in dir.entries(self.dk)? {
+            self.file_types.insert((parent, e.name.clone()), e.file_type);
+            entries.insert(e.name, e.ino);
+        }
+        Ok(entries)
+    }
+
+    fn bad_inode(&mut self, ino: u64, e: DkError) -> DkResult<()> {
+        match e {
+            Corrupted(error) => {
+                self.found(Problem::BadInode { ino, error }, true);
+                if self.repair {
+                    self.dk.free_inode(ino)?;
+                }
+                Ok(())
+            }
+            e => Err(e),
+        }
+    }
+
+    /// Loads all inodes in use and the entries of all directories.
+    fn scan(&mut self) -> DkResult<Scan> {
+        let mut files = BTreeMap::new();
+        let mut dirs = BTreeMap::new();
+        self.file_types.clear();
+        for ino in self.dk.inodes_in_use()? {
+            let fh = match self.load(ino) {
+                Ok(fh) => fh,
+                Err(e) => {
+                    self.bad_inode(ino, e)?;
+                    continue;
+                }
+            };
+            if fh.lock().inode.mode.is_directory() {
+                match self.read_entries(fh.clone()) {
+                    Ok(entries) => {
+                        dirs.insert(ino, entries);
+                    }
+                    Err(e) => {
+                        self.bad_inode(ino, e)?;
+                        continue;
+                    }
+                }
+            }
+            files.insert(ino, fh);
+        }
+        Ok((files, dirs))
+    }
+
+    fn check_entries(
+        &mut self,
+        files: &BTreeMap<u64, DkFileHandle>,
+        dirs: &mut BTreeMap<u64, OrdMap<OsString, u64>>,
+    ) -> DkResult<()> {
+        for (&parent, entries) in dirs.iter_mut() {
+            for (name, ino) in entries.clone() {
+                if let Some(fh) = files.get(&ino) {
+                    let mode = fh.lock().inode.mode;
+                    let stored = self.file_types[&(parent, name.clone())];
+                    if stored != mode & FileMode::FILE_TYPE_MASK {
+                        self.found(
+                            Problem::WrongFileType {
+                                parent,
+                                name: name.clone(),
+                                ino,
+                            },
+                            true,
+                        );
+                        if self.repair {
+                            let dh = self.dk.open_dir(parent)?;
+                            dh.remove_entry(self.dk, &name)?;
+                            dh.add_entry(self.dk, &name, ino, mode)?;
+                        }
+                    }
+                    continue;
+                }
+                self.found(
+                    Problem::DanglingEntry {
+                        parent,
+                        name: name.clone(),
+                        ino,
+                    },
+                    true,
+                );
+                if self.repair {
+                    self.dk.open_dir(parent)?.remove_entry(self.dk, &name)?;
+                    entries.remove(&name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Inodes reachable from the root
+    fn reachable(dirs: &BTreeMap<u64, OrdMap<OsString, u64>>) -> BTreeSet<u64> {
+        let mut reached = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        reached.insert(ROOT_INODE);
+        queue.push_back(ROOT_INODE);
+        while let Some(ino) = queue.pop_front() {
+            if let Some(entries) = dirs.get(&ino) {
+                for (name, child) in entries {
+                    if name != "." && name != ".." && reached.insert(*child) {
+                        queue.push_back(*child);
+                    }
+                }
+            }
+        }
+        reached
+    }
+
+    fn check_orphans(
+        &mut self,
+        files: &mut BTreeMap<u64, DkFileHandle>,
+        dirs: &mut BTreeMap<u64, OrdMap<OsString, u64>>,
+    ) -> DkResult<()> {
+        loop {
+            let reached = Self::reachable(dirs);
+            let unreachable: BTreeSet<u64> = files
+                .keys()
+                .filter(|ino| !reached.contains(ino))
+                .cloned()
+                .collect();
+            if unreachable.is_empty() {
+                return Ok(());
+            }
+            // Only report the tops of unreachable trees. If they form
+            // a cycle, start from the smallest inode number.
+            let referenced: BTreeSet<u64> = unreachable
+                .iter()
+                .filter_map(|ino| dirs.get(ino))
+                .flat_map(|entries| {
+                    entries
+                        .iter()
+                        .filter(|(name, _)| name != "." && name != "..")
+                        .map(|(_, child)| *child)
+                        .collect::<Vec<_>>()
+                }).collect();
+            let mut tops: Vec<u64> = unreachable.difference(&referenced).cloned().collect();
+            if tops.is_empty() {
+                tops.extend(unreachable.iter().next());
+            }
+            for &ino in &tops {
+                let nlink = files[&ino].lock().inode.nlink;
+                self.found(Problem::Orphan { ino, nlink }, true);
+            }
+            if !self.repair {
+                return Ok(());
+            }
+            for ino in tops {
+                let f = files.remove(&ino).unwrap();
+                let (nlink, is_dir) = {
+                    let f = f.lock();
+                    (f.inode.nlink, f.inode.mode.is_directory())
+                };
+                if nlink == 0 && !is_dir {
+                    // An unlinked file which was still open. It is destroyed
+                    // when it is closed.
+                    drop(self.dk.open(ino, Flags::READ_ONLY)?);
+                    self.dk.close_files_in_list()?;
+                } else {
+                    self.reattach(ino, is_dir, files, dirs)?;
+                    files.insert(ino, f);
+                }
+            }
+        }
+    }
+
+    /// Moves an orphan into `lost+found`.
+    fn reattach(
+        &mut self,
+        ino: u64,
+        is_dir: bool,
+        files: &BTreeMap<u64, DkFileHandle>,
+        dirs: &mut BTreeMap<u64, OrdMap<OsString, u64>>,
+    ) -> DkResult<()> {
+        let name = OsString::from(format!("#{}", ino));
+        let lost_found = self.lost_found(dirs)?;
+        let fh = self.dk.open(ino, Flags::READ_ONLY)?;
+        let dh = self.dk.open_dir(lost_found)?;
+        let mode = fh.lock().inode.mode;
+        dh.add_entry(self.dk, &name, ino, mode)?;
+        dirs.get_mut(&lost_found).unwrap().insert(name, ino);
+
+        {
+            let mut f = fh.lock();
+            if f.inode.nlink == 0 {
+                // Links are counted again later. This keeps the file
+                // from being destroyed when it is closed.
+                f.inode.nlink = 1;
+                f.dirty = true;
+            }
+        }
+        if is_dir {
+            let dotdot = OsStr::new("..");
+            let dh = self.dk.open_dir(ino)?;
+            match dh.lookup(self.dk, dotdot)? {
+                Some(parent) if files.contains_key(&parent) => {
+                    self.dk.unlink(dh.clone(), dotdot)?;
+                }
+                _ => {
+                    dh.remove_entry(self.dk, dotdot)?;
+                }
+            }
+            self.dk.link(lost_found, dh, dotdot)?;
+            dirs.get_mut(&ino)
+                .unwrap()
+                .insert(OsString::from(".."), lost_found);
+        }
+        Ok(())
+    }
+
+    /// Returns the inode number of `lost+found`, creating it if needed.
+    fn lost_found(&mut self, dirs: &mut BTreeMap<u64, OrdMap<OsString, u64>>) -> DkResult<u64> {
+        let name = OsStr::new(LOST_FOUND);
+        if let Some(&ino) = dirs.get(&ROOT_INODE).and_then(|root| root.get(name)) {
+            if dirs.contains_key(&ino) {
+                return Ok(ino);
+            }
+            return Err(NotDirectory);
+        }
+        let ino = self.dk.mkdir(ROOT_INODE, FileMode::USER_RWX, 0, 0, None)?;
+        let root = self.dk.open_dir(ROOT_INODE)?;
+        self.dk.link(ino, root, name)?;
+        let mut entries = OrdMap::new();
+        entries.insert(OsString::from("."), ino);
+        entries.insert(OsString::from(".."), ROOT_INODE);
+        dirs.insert(ino, entries);
+        if let Some(root) = dirs.get_mut(&ROOT_INODE) {
+            root.insert(name.to_os_string(), ino);
+        }
+        Ok(ino)
+    }
+
+    /// Checks link and block counts of every file.
+    /// Returns the data blocks in use.
+    fn check_files(
+        &mut self,
+        files: &BTreeMap<u64, DkFileHandle>,
+        dirs: &BTreeMap<u64, OrdMap<OsString, u64>>,
+    ) -> DkResult<Bitmap> {
+        let reached = Self::reachable(dirs);
+        let mut refs: HashMap<u64, u64> = HashMap::new();
+        for entries in dirs.values() {
+            for (_, ino) in entries {
+                *refs.entry(*ino).or_insert(0) += 1;
+            }
+        }
+
+        let (first_db_ptr, db_count, bs) = {
+            let alloc = self.dk.alloc();
+            (alloc.sb.first_db_ptr, alloc.sb.db_count, self.dk.block_size())
+        };
+        let mut used = Bitmap::new(0, db_count);
+        // Metadata may be in the data area too
+        for ptr in metadata_in_data_area(&self.dk.alloc().sb) {
+            used.set((ptr - first_db_ptr) / bs);
+        }
+        for (ptr, _) in self.dk.inode_chunks()? {
+            used.set((ptr - first_db_ptr) / bs);
+        }
+        for (&ino, fh) in files {
+            let (nlink, blocks) = {
+                let f = fh.lock();
+                (f.inode.nlink, f.inode.blocks)
+            };
+            let actual = refs.get(&ino).cloned().unwrap_or(0);
+            // Orphans are reported already
+            if reached.contains(&ino) && actual != nlink {
+                self.found(
+                    Problem::WrongNlink {
+                        ino,
+                        stored: nlink,
+                        actual,
+                    },
+                    true,
+                );
+                if self.repair {
+                    let fh = self.dk.open(ino, Flags::READ_ONLY)?;
+                    let mut f = fh.lock();
+                    f.inode.nlink = actual;
+                    f.dirty = true;
+                }
+            }
+
+            let runs = match fh.lock().used_blocks(self.dk) {
+                Ok(runs) => runs,
+                Err(Corrupted(error)) => {
+                    self.found(Problem::BadInode { ino, error }, false);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let count = runs.iter().map(|(_, len)| len).sum();
+            if count != blocks {
+                self.found(
+                    Problem::WrongBlockCount {
+                        ino,
+                        stored: blocks,
+                        actual: count,
+                    },
+                    true,
+                );
+                if self.repair {
+                    let fh = self.dk.open(ino, Flags::READ_ONLY)?;
+                    let mut f = fh.lock();
+                    f.inode.blocks = count;
+                    f.dirty = true;
+                }
+            }
+            for (start, len) in runs {
+                for ptr in (0..len).map(|i| start + i * bs) {
+                    if ptr < first_db_ptr
+                        || (ptr - first_db_ptr) % bs != 0
+                        || (ptr - first_db_ptr) / bs >= db_count
+                    {
+                        self.found(Problem::InvalidBlock { ino, ptr }, false);
+                        continue;
+                    }
+                    let i = (ptr - first_db_ptr) / bs;
+                    if used.get(i) {
+                        self.found(Problem::DuplicateBlock { ino, ptr }, false);
+                    }
+                    used.set(i);
+                }
+            }
+        }
+        Ok(used)
+    }
+
+    fn check_bitmaps(&mut self, used: &Bitmap) -> DkResult<()> {
+        let dk = self.dk;
+        let chunks = dk.inode_chunks()?;
+        let mut alloc = dk.alloc();
+        let marked = alloc.db_bitmap.count();
+        if marked != alloc.sb.used_db_count {
+            self.found(
+                Problem::WrongUsedDbCount {
+                    stored: alloc.sb.used_db_count,
+                    actual: marked,
+                },
+                true,
+            );
+        }
+        let marked = alloc.inode_bitmap.count();
+        let marked_in_chunks = chunks.iter().map(|(_, chunk)| chunk.used()).sum();
+        if marked + marked_in_chunks != alloc.sb.used_inodes() {
+            self.found(
+                Problem::WrongUsedInodeCount {
+                    stored: alloc.sb.used_inodes(),
+                    actual: marked + marked_in_chunks,
+                },
+                true,
+            );
+        }
+
+        let (first_db_ptr, bs) = (alloc.sb.first_db_ptr, dk.block_size());
+        for i in 0..alloc.sb.db_count {
+            let ptr = first_db_ptr + i * bs;
+            match (used.get(i), alloc.db_bitmap.get(i)) {
+                (true, false) => {
+                    self.found(Problem::UnmarkedBlock { ptr }, true);
+                    if self.repair {
+                        alloc.db_bitmap.set(i);
+                        alloc.db_bitmap.flush(&mut *dk.dev(), i, 1)?;
+                    }
+                }
+                (false, true) => {
+                    self.found(Problem::LeakedBlock { ptr }, true);
+                    if self.repair {
+                        alloc.db_bitmap.clear(i);
+                        alloc.db_bitmap.flush(&mut *dk.dev(), i, 1)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if self.repair {
+            alloc.sb.used_db_count = alloc.db_bitmap.count();
+            alloc.sb.used_inode_count = marked;
+            alloc.sb.used_chunk_inode_count = marked_in_chunks;
+            alloc.flush_sb(&mut dk.dev())?;
+        }
+        Ok(())
+    }
+
+    fn check_backups(&mut self) -> DkResult<()> {
+        let dk = self.dk;
+        let alloc = dk.alloc();
+        let mut dev = dk.dev();
+        for ptr in backup_ptrs(&alloc.sb) {
+            let backup = SuperBlock::from_bytes(dev.read_at(ptr)?);
+            if let Err(e) = backup {
+                self.found(
+                    Problem::BadBackupSuperBlock {
+                        ptr,
+                        error: e.to_string(),
+                    },
+                    true,
+                );
+                if self.repair {
+                    dev.write_at(&alloc.sb, ptr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device::Memory;
+
+    #[test]
+    fn clean() -> DkResult<()> {
+        let cred = Credentials::root();
+        prepare!(mem, handle);
+        let dir = handle.mkdir(&cred, ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+        let homura = OsStr::new("Homura");
+        let f = handle.mknod(&cred, dir.ino, homura, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&cred, f.ino, Flags::WRITE_ONLY)?;
+        handle.write(fh, 0, &[42; 100_000])?;
+        drop(handle);
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        assert!(report.is_clean(), "{:?}", report);
+        Ok(())
+    }
+
+    #[test]
+    fn repair() -> DkResult<()> {
+        let cred = Credentials::root();
+        prepare!(mem, handle);
+        let (dir, file) = {
+            let dir = handle.mkdir(&cred, ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+            let homura = OsStr::new("Homura");
+            let file = handle.mknod(&cred, dir.ino, homura, FileMode::REGULAR_FILE, None)?;
+            // Detach the directory from the root without updating any counts
+            let root = handle.opendir(&cred, ROOT_INODE)?;
+            let dk = &handle.inner;
+            root.remove_entry(dk, OsStr::new("Madoka"))?;
+            let mut alloc = dk.alloc();
+            alloc.sb.used_db_count += 1;
+            alloc.flush_sb(&mut dk.dev())?;
+            (dir.ino, file.ino)
+        };
+        drop(handle);
+        let image = mem.clone();
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        // Only checking writes nothing
+        assert!(mem == image);
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::Orphan { ino: dir, nlink: 2 },
+                Problem::WrongUsedDbCount {
+                    stored: 7,
+                    actual: 6
+                },
+            ]
+        );
+
+        let report = check(Box::new(Memory::new(&mut mem[..])), true)?;
+        assert_eq!(report.problems.len(), 2, "{:?}", report);
+        assert!(report.is_consistent(), "{:?}", report);
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        assert!(report.is_clean(), "{:?}", report);
+
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        let lost_found = handle.lookup(&cred, ROOT_INODE, OsStr::new(LOST_FOUND))?.ino;
+        let name = format!("#{}", dir);
+        assert_eq!(handle.lookup(&cred, lost_found, OsStr::new(&name))?.ino, dir);
+        assert_eq!(handle.lookup(&cred, dir, OsStr::new(".."))?.ino, lost_found);
+        assert_eq!(handle.lookup(&cred, dir, OsStr::new("Homura"))?.ino, file);
+        Ok(())
+    }
+
+    #[test]
+    fn repair_super_blocks() -> DkResult<()> {
+        prepare!(mem, handle);
+        drop(handle);
+        for b in &mut mem[SUPER_BLOCK_PTR as usize..FIRST_INODE_PTR as usize] {
+            *b = 0;
+        }
+        // The first backup in the data area is damaged too
+        mem[1 << 20] ^= 1;
+
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        assert_eq!(report.problems.len(), 2, "{:?}", report);
+        match report.problems[0] {
+            Problem::BadSuperBlock { backup, .. } => assert_eq!(backup, 4 << 20),
+            ref p => panic!("Unexpected problem {:?}", p),
+        }
+        match report.problems[1] {
+            Problem::BadBackupSuperBlock { ptr, .. } => assert_eq!(ptr, 1 << 20),
+            ref p => panic!("Unexpected problem {:?}", p),
+        }
+
+        let report = check(Box::new(Memory::new(&mut mem[..])), true)?;
+        assert_eq!(report.problems.len(), 2, "{:?}", report);
+        assert!(report.is_consistent(), "{:?}", report);
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        assert!(report.is_clean(), "{:?}", report);
+        Ok(())
+    }
+
+    #[test]
+    fn unrepairable() -> DkResult<()> {
+        let cred = Credentials::root();
+        let mut mem = vec![0; 1 << 25];
+        let opts = FormatOptions::default().extents(false).inline_data(false);
+        let (ptr, file) = {
+            let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+            let madoka = OsStr::new("Madoka");
+            let a = handle.mknod(&cred, ROOT_INODE, madoka, FileMode::REGULAR_FILE, None)?;
+            let fh = handle.open(&cred, a.ino, Flags::WRITE_ONLY)?;
+            handle.write(fh.clone(), 0, b"Madoka")?;
+            handle.flush(fh.clone())?;
+            let homura = OsStr::new("Homura");
+            let b = handle.mknod(&cred, ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+            // Both files refer to the same block
+            let inode = fh.lock().inode.clone();
+            let fh = handle.open(&cred, b.ino, Flags::WRITE_ONLY)?;
+            let mut f = fh.lock();
+            f.inode.ptrs = inode.ptrs.clone();
+            f.inode.size = inode.size;
+            f.inode.blocks = inode.blocks;
+            f.dirty = true;
+            (inode.ptrs[0][0], b.ino)
+        };
+        let duplicate = Problem::DuplicateBlock { ino: file, ptr };
+
+        let report = check(Box::new(Memory::new(&mut mem[..])), true)?;
+        assert_eq!(report.problems, vec![duplicate.clone()]);
+        assert_eq!(report.unrepaired, vec![duplicate.clone()]);
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        assert_eq!(report.unrepaired, vec![duplicate]);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+/// CRC-32C (Castagnoli) lookup table for four bits at a time
+const TABLE: [u32; 16] = [
+    0x0000_0000, 0x105E_C76F, 0x20BD_8EDE, 0x30E3_49B1, 0x417B_1DBC, 0x5125_DAD3, 0x61C6_9362,
+    0x7198_540D, 0x82F6_3B78, 0x92A8_FC17, 0xA24B_B5A6, 0xB215_72C9, 0xC38D_26C4, 0xD3D3_E1AB,
+    0xE330_A81A, 0xF36E_6F75,
+];
+
+/// A running CRC-32C.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Crc32c(u32);
+
+impl Crc32c {
+    pub(crate) fn new() -> Self {
+        Crc32c(!0)
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        let mut crc = self.0;
+        for &b in bytes {
+            crc ^= u32::from(b);
+            crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+            crc = (crc >> 4) ^ TABLE[(crc & 0xf) as usize];
+        }
+        self.0 = crc;
+    }
+
+    pub(crate) fn sum(&self) -> u32 {
+        !self.0
+    }
+}
+
+pub(crate) fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        let mut crc = Crc32c::new();
+        crc.update(b"12345");
+        crc.update(b"6789");
+        assert_eq!(crc.sum(), 0xE306_9283);
+    }
+}
@@ -6,7 +6,7 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
 use *;
 
-pub trait Device: Read + Write + Seek + Debug {
+pub trait Device: Read + Write + Seek + Debug + Send {
     fn block_count(&self) -> u64;
 
     fn block_size(&self) -> u64;
@@ -15,6 +15,17 @@ pub trait Device: Read + Write + Seek + Debug {
         self.block_size() * self.block_count()
     }
 
+    /// Makes sure all written data reaches the storage.
+    fn sync(&mut self) -> DkResult<()> {
+        Ok(self.flush()?)
+    }
+
+    /// Looks up the size of the storage again, which may have been
+    /// enlarged since the device was opened. Returns the new size.
+    fn refresh_size(&mut self) -> DkResult<u64> {
+        Ok(self.size())
+    }
+
     /// No length limit
     fn read_at<'a>(&'a mut self, ptr: u64) -> DkResult<Box<dyn Read + 'a>> {
         let size = self.size();
@@ -78,7 +89,7 @@ pub fn dev<P: AsRef<Path>>(dev_path: P) -> DkResult<Box<dyn Device>> {
 }
 
 // The default block size is 4 KiB
-const DEFAULT_BLOCK_SIZE: u64 = 4096;
+pub(crate) const DEFAULT_BLOCK_SIZE: u64 = 4096;
 
 #[derive(Debug)]
 struct ImageFile {
@@ -108,6 +119,15 @@ impl Device for ImageFile {
         self.block_count
     }
 
+    fn refresh_size(&mut self) -> DkResult<u64> {
+        self.block_count = self.file.metadata()?.len() / DEFAULT_BLOCK_SIZE;
+        Ok(self.size())
+    }
+
+    fn sync(&mut self) -> DkResult<()> {
+        Ok(self.file.sync_data()?)
+    }
+
     fn block_size(&self) -> u64 {
         DEFAULT_BLOCK_SIZE
     }
@@ -136,7 +156,7 @@ impl Seek for ImageFile {
 }
 
 #[derive(Debug)]
-struct BlockDevice {
+pub(crate) struct BlockDevice {
     file: File,
     block_count: u64,
     block_size: u64,
@@ -161,7 +181,7 @@ impl BlockDevice {
         Ok(dev)
     }
 
-    fn dev_size(dev: &File) -> DkResult<u64> {
+    pub(crate) fn dev_size(dev: &File) -> DkResult<u64> {
         let fd = dev.as_raw_fd();
         #[cfg(target_os = "linux")]
         fn getsize(fd: RawFd) -> DkResult<u64> {
@@ -208,6 +228,15 @@ impl Device for BlockDevice {
         self.block_count
     }
 
+    fn refresh_size(&mut self) -> DkResult<u64> {
+        self.block_count = Self::dev_size(&self.file)? / self.block_size;
+        Ok(self.size())
+    }
+
+    fn sync(&mut self) -> DkResult<()> {
+        Ok(self.file.sync_data()?)
+    }
+
     fn block_size(&self) -> u64 {
         self.block_size
     }
@@ -0,0 +1,590 @@
+//! On-disk format of directories.
+//!
+//! A directory is a B+ tree keyed by the hash of entry names. Every node
+//! occupies one block of the directory file and the root is always
+//! the first block. Leaves are chained in hash order so that they can
+//! be read one after another.
+//!
+//! Entries with equal hashes are always kept in the same leaf,
+//! so a lookup reads exactly one leaf.
+
+use byteorder::{ByteOrder, LE};
+use checksum::crc32c;
+use file::{DkDir, DkFileIO};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use *;
+
+const NODE_MAGIC: u32 = 0x5845_4944;
+const LEAF: u8 = 0;
+const INDEX: u8 = 1;
+/// magic: u32, kind: u8, padding: u8, count: u16,
+/// next: u64, total: u64, checksum: u32, padding: u32
+const HEADER_SIZE: usize = 32;
+const CHECKSUM_OFFSET: usize = 24;
+/// hash: u64, ino: u64, name length: u16, file type: u8
+const LEAF_ENTRY_SIZE: usize = 19;
+/// hash: u64, child: u64
+const INDEX_ENTRY_SIZE: usize = 16;
+
+/// FNV-1a hash of an entry name, truncated to 62 bits so that
+/// readdir cookies derived from it fit in a positive `i64`.
+pub(crate) fn hash(name: &OsStr) -> u64 {
+    let h = name
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |h: u64, &b| {
+            (h ^ u64::from(b)).wrapping_mul(0x100_0000_01b3)
+        });
+    h >> 2
+}
+
+/// Readdir cookies of entries in a leaf. Reading again from the cookie
+/// of an entry continues right after it. Entries with equal hashes
+/// cannot be told apart by a cookie, so all but the last of them
+/// point back at the first one. Those may be read twice, but no entry
+/// is ever skipped.
+pub(crate) fn cookies(entries: &[LeafEntry]) -> Vec<u64> {
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| match entries.get(i + 1) {
+            Some(next) if next.hash == e.hash => e.hash,
+            _ => e.hash + 1,
+        }).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LeafEntry {
+    pub(crate) hash: u64,
+    pub(crate) ino: u64,
+    pub(crate) name: OsString,
+    /// Only the file type bits are kept
+    pub(crate) file_type: FileMode,
+}
+
+impl LeafEntry {
+    fn cmp_key(&self, hash: u64, name: &OsStr) -> Ordering {
+        (self.hash, self.name.as_bytes()).cmp(&(hash, name.as_bytes()))
+    }
+}
+
+#[derive(Debug)]
+enum Entries {
+    Leaf(Vec<LeafEntry>),
+    /// `(first hash, child)`. A child covers hashes from its first hash
+    /// to the first hash of the next child.
+    Index(Vec<(u64, u64)>),
+}
+
+#[derive(Debug)]
+pub(crate) struct Node {
+    entries: Entries,
+    /// The next leaf, or 0 if this is the last one
+    next: u64,
+    /// Number of entries in the directory. Only valid in the root.
+    total: u64,
+}
+
+impl Node {
+    fn empty_leaf() -> Self {
+        Node {
+            entries: Entries::Leaf(Vec::new()),
+            next: 0,
+            total: 0,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        HEADER_SIZE + match &self.entries {
+            Entries::Leaf(entries) => entries
+                .iter()
+                .map(|e| LEAF_ENTRY_SIZE + e.name.len())
+                .sum(),
+            Entries::Index(entries) => entries.len() * INDEX_ENTRY_SIZE,
+        }
+    }
+
+    fn encode(&self, bs: usize) -> Vec<u8> {
+        let mut buf = vec![0; bs];
+        LE::write_u32(&mut buf[0..4], NODE_MAGIC);
+        let (kind, count) = match &self.entries {
+            Entries::Leaf(entries) => (LEAF, entries.len()),
+            Entries::Index(entries) => (INDEX, entries.len()),
+        };
+        buf[4] = kind;
+        LE::write_u16(&mut buf[6..8], count as u16);
+        LE::write_u64(&mut buf[8..16], self.next);
+        LE::write_u64(&mut buf[16..24], self.total);
+        let mut off = HEADER_SIZE;
+        match &self.entries {
+            Entries::Leaf(entries) => {
+                for e in entries {
+                    let name = e.name.as_bytes();
+                    LE::write_u64(&mut buf[off..off + 8], e.hash);
+                    LE::write_u64(&mut buf[off + 8..off + 16], e.ino);
+                    LE::write_u16(&mut buf[off + 16..off + 18], name.len() as u16);
+                    buf[off + 18] = (e.file_type.bits() >> 12) as u8;
+                    off += LEAF_ENTRY_SIZE;
+                    buf[off..off + name.len()].copy_from_slice(name);
+                    off += name.len();
+                }
+            }
+            Entries::Index(entries) => {
+                for (hash, child) in entries {
+                    LE::write_u64(&mut buf[off..off + 8], *hash);
+                    LE::write_u64(&mut buf[off + 8..off + 16], *child);
+                    off += INDEX_ENTRY_SIZE;
+                }
+            }
+        }
+        let sum = crc32c(&buf);
+        LE::write_u32(&mut buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4], sum);
+        buf
+    }
+
+    /// `location` is used in error messages.
+    fn decode(mut buf: Vec<u8>, location: &str) -> DkResult<Self> {
+        let invalid = || Corrupted(format!("Invalid directory node in {}", location));
+        if LE::read_u32(&buf[0..4]) != NODE_MAGIC {
+            return Err(invalid());
+        }
+        let sum = LE::read_u32(&buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4]);
+        LE::write_u32(&mut buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4], 0);
+        if crc32c(&buf) != sum {
+            return Err(Corrupted(format!(
+                "Checksum mismatch in directory node in {}",
+                location
+            )));
+        }
+        let count = LE::read_u16(&buf[6..8]) as usize;
+        let mut off = HEADER_SIZE;
+        let entries = match buf[4] {
+            LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    if off + LEAF_ENTRY_SIZE > buf.len() {
+                        return Err(invalid());
+                    }
+                    let hash = LE::read_u64(&buf[off..off + 8]);
+                    let ino = LE::read_u64(&buf[off + 8..off + 16]);
+                    let len = LE::read_u16(&buf[off + 16..off + 18]) as usize;
+                    let file_type = FileMode::from_bits_truncate(u16::from(buf[off + 18]) << 12);
+                    off += LEAF_ENTRY_SIZE;
+                    if off + len > buf.len() {
+                        return Err(invalid());
+                    }
+                    let name = OsString::from_vec(buf[off..off + len].to_vec());
+                    off += len;
+                    entries.push(LeafEntry {
+                        hash,
+                        ino,
+                        name,
+                        file_type,
+                    });
+                }
+                Entries::Leaf(entries)
+            }
+            INDEX => {
+                if HEADER_SIZE + count * INDEX_ENTRY_SIZE > buf.len() || count == 0 {
+                    return Err(invalid());
+                }
+                Entries::Index(
+                    (0..count)
+                        .map(|i| HEADER_SIZE + i * INDEX_ENTRY_SIZE)
+                        .map(|off| {
+                            (
+                                LE::read_u64(&buf[off..off + 8]),
+                                LE::read_u64(&buf[off + 8..off + 16]),
+                            )
+                        }).collect(),
+                )
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(Node {
+            entries,
+            next: LE::read_u64(&buf[8..16]),
+            total: LE::read_u64(&buf[16..24]),
+        })
+    }
+
+    /// Moves the upper half of the entries to a new node.
+    /// Returns the first hash of the new node and the node.
+    fn split(&mut self) -> DkResult<(u64, Node)> {
+        let half = self.encoded_len() / 2;
+        let (key, entries) = match &mut self.entries {
+            Entries::Leaf(entries) => {
+                // Split by size, but never between equal hashes
+                let mut size = HEADER_SIZE;
+                let mut mid = 0;
+                while mid < entries.len() && size < half {
+                    size += LEAF_ENTRY_SIZE + entries[mid].name.len();
+                    mid += 1;
+                }
+                let boundary = |i: &usize| *i > 0 && entries[*i - 1].hash != entries[*i].hash;
+                let at = (mid..entries.len())
+                    .find(&boundary)
+                    .or_else(|| (1..mid).rev().find(&boundary))
+                    .ok_or(Exhausted)?;
+                let right = entries.split_off(at);
+                (right[0].hash, Entries::Leaf(right))
+            }
+            Entries::Index(entries) => {
+                let at = entries.len() / 2;
+                let right = entries.split_off(at);
+                (right[0].0, Entries::Index(right))
+            }
+        };
+        Ok((
+            key,
+            Node {
+                entries,
+                next: 0,
+                total: 0,
+            },
+        ))
+    }
+
+    fn is_leaf(&self) -> bool {
+        match self.entries {
+            Entries::Leaf(_) => true,
+            Entries::Index(_) => false,
+        }
+    }
+
+    /// Index of the child covering `hash`
+    fn child_index(entries: &[(u64, u64)], hash: u64) -> usize {
+        match entries.binary_search_by_key(&hash, |e| e.0) {
+            Ok(i) => i,
+            Err(0) => 0,
+            Err(i) => i - 1,
+        }
+    }
+}
+
+impl DkDir {
+    fn location(&self, lblk: u64) -> String {
+        format!("directory {} block {}", self.fh.lock().inode.ino, lblk)
+    }
+
+    fn read_node(&mut self, dk: &Donkey, lblk: u64) -> DkResult<Node> {
+        let bs = dk.block_size();
+        if (lblk + 1) * bs > self.fh.lock().inode.size {
+            return Err(Corrupted(format!(
+                "Directory node out of range in {}",
+                self.location(lblk)
+            )));
+        }
+        let mut buf = vec![0; bs as usize];
+        {
+            let mut file = self.fh.lock();
+            file.seek(SeekFrom::Start(lblk * bs))?;
+            let file = &mut *file;
+            DkFileIO { dk, file }.read_exact(&mut buf)?;
+        }
+        Node::decode(buf, &self.location(lblk))
+    }
+
+    fn write_node(&mut self, dk: &Donkey, lblk: u64, node: &Node) -> DkResult<()> {
+        let bs = dk.block_size();
+        let buf = node.encode(bs as usize);
+        let mut file = self.fh.lock();
+        file.seek(SeekFrom::Start(lblk * bs))?;
+        let file = &mut *file;
+        DkFileIO { dk, file }.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn is_empty_file(&self) -> bool {
+        self.fh.lock().inode.size == 0
+    }
+
+    /// Reads the nodes from the root to the leaf covering `hash`.
+    fn path(&mut self, dk: &Donkey, hash: u64) -> DkResult<Vec<(u64, Node)>> {
+        let mut path = Vec::new();
+        let mut lblk = 0;
+        loop {
+            let node = self.read_node(dk, lblk)?;
+            let child = match &node.entries {
+                Entries::Leaf(_) => None,
+                Entries::Index(entries) => Some(entries[Node::child_index(entries, hash)].1),
+            };
+            path.push((lblk, node));
+            match child {
+                Some(child) if path.len() < 64 => lblk = child,
+                Some(_) => {
+                    return Err(Corrupted(format!(
+                        "Directory tree is too deep in {}",
+                        self.location(lblk)
+                    )))
+                }
+                None => return Ok(path),
+            }
+        }
+    }
+
+    pub(crate) fn lookup(&mut self, dk: &Donkey, name: &OsStr) -> DkResult<Option<u64>> {
+        if self.is_empty_file() {
+            return Ok(None);
+        }
+        let hash = hash(name);
+        let path = self.path(dk, hash)?;
+        match &path.last().unwrap().1.entries {
+            Entries::Leaf(entries) => Ok(entries
+                .binary_search_by(|e| e.cmp_key(hash, name))
+                .ok()
+                .map(|i| entries[i].ino)),
+            Entries::Index(_) => unreachable!(),
+        }
+    }
+
+    /// Number of entries
+    pub(crate) fn len(&mut self, dk: &Donkey) -> DkResult<u64> {
+        if self.is_empty_file() {
+            Ok(0)
+        } else {
+            Ok(self.read_node(dk, 0)?.total)
+        }
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        dk: &Donkey,
+        name: &OsStr,
+        ino: u64,
+        mode: FileMode,
+    ) -> DkResult<()> {
+        if self.is_empty_file() {
+            self.write_node(dk, 0, &Node::empty_leaf())?;
+        }
+        let bs = dk.block_size();
+        let hash = hash(name);
+        let mut path = self.path(dk, hash)?;
+        if let Entries::Leaf(entries) = &mut path.last_mut().unwrap().1.entries {
+            match entries.binary_search_by(|e| e.cmp_key(hash, name)) {
+                Ok(_) => return Err(AlreadyExists),
+                Err(i) => entries.insert(
+                    i,
+                    LeafEntry {
+                        hash,
+                        ino,
+                        name: name.to_os_string(),
+                        file_type: mode & FileMode::FILE_TYPE_MASK,
+                    },
+                ),
+            }
+        }
+        path[0].1.total += 1;
+
+        // Split overflowing nodes from the leaf upwards
+        let mut next_lblk = self.fh.lock().inode.size / bs;
+        let mut level = path.len() - 1;
+        while path[level].1.encoded_len() > bs as usize {
+            let (key, mut right) = path[level].1.split()?;
+            if path[level].1.encoded_len() > bs as usize || right.encoded_len() > bs as usize {
+                // Too many names with equal hashes
+                return Err(Exhausted);
+            }
+            if level == 0 {
+                // The root stays in the first block, so both halves move out.
+                let (left_lblk, right_lblk) = (next_lblk, next_lblk + 1);
+                let root = &mut path[0].1;
+                let left_entries = std::mem::replace(
+                    &mut root.entries,
+                    Entries::Index(vec![(0, left_lblk), (key, right_lblk)]),
+                );
+                let left = Node {
+                    entries: left_entries,
+                    next: if right.is_leaf() { right_lblk } else { 0 },
+                    total: 0,
+                };
+                right.next = root.next;
+                root.next = 0;
+                self.write_node(dk, left_lblk, &left)?;
+                self.write_node(dk, right_lblk, &right)?;
+                break;
+            }
+            let right_lblk = next_lblk;
+            next_lblk += 1;
+            if right.is_leaf() {
+                right.next = path[level].1.next;
+                path[level].1.next = right_lblk;
+            }
+            self.write_node(dk, right_lblk, &right)?;
+            level -= 1;
+            if let Entries::Index(entries) = &mut path[level].1.entries {
+                let i = Node::child_index(entries, key) + 1;
+                entries.insert(i, (key, right_lblk));
+            }
+        }
+
+        for (lblk, node) in &path[level..] {
+            self.write_node(dk, *lblk, node)?;
+        }
+        if level > 0 {
+            self.write_node(dk, 0, &path[0].1)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, dk: &Donkey, name: &OsStr) -> DkResult<Option<u64>> {
+        if self.is_empty_file() {
+            return Ok(None);
+        }
+        let hash = hash(name);
+        let mut path = self.path(dk, hash)?;
+        let ino = match &mut path.last_mut().unwrap().1.entries {
+            Entries::Leaf(entries) => match entries.binary_search_by(|e| e.cmp_key(hash, name)) {
+                Ok(i) => entries.remove(i).ino,
+                Err(_) => return Ok(None),
+            },
+            Entries::Index(_) => unreachable!(),
+        };
+        path[0].1.total -= 1;
+        let (lblk, leaf) = path.last().unwrap();
+        self.write_node(dk, *lblk, leaf)?;
+        if path.len() > 1 {
+            self.write_node(dk, 0, &path[0].1)?;
+        }
+        Ok(Some(ino))
+    }
+
+    /// The leaf covering `hash`, or `None` if the directory is empty.
+    pub(crate) fn find_leaf(&mut self, dk: &Donkey, hash: u64) -> DkResult<Option<u64>> {
+        if self.is_empty_file() {
+            return Ok(None);
+        }
+        Ok(self.path(dk, hash)?.pop().map(|(lblk, _)| lblk))
+    }
+
+    /// Returns the entries in leaf `lblk` and the next leaf.
+    pub(crate) fn read_leaf(
+        &mut self,
+        dk: &Donkey,
+        lblk: u64,
+    ) -> DkResult<(Vec<LeafEntry>, Option<u64>)> {
+        let node = self.read_node(dk, lblk)?;
+        match node.entries {
+            Entries::Leaf(entries) => Ok((entries, Some(node.next).filter(|&n| n != 0))),
+            Entries::Index(_) => Err(Corrupted(format!(
+                "Expected a leaf in {}",
+                self.location(lblk)
+            ))),
+        }
+    }
+
+    /// Makes the entries referring to the inodes in `moved`
+    /// refer to their new numbers instead.
+    pub(crate) fn renumber(&mut self, dk: &Donkey, moved: &HashMap<u64, u64>) -> DkResult<()> {
+        let mut leaf = self.find_leaf(dk, 0)?;
+        while let Some(lblk) = leaf {
+            let mut node = self.read_node(dk, lblk)?;
+            let mut changed = false;
+            match &mut node.entries {
+                Entries::Leaf(entries) => {
+                    for e in entries.iter_mut() {
+                        if let Some(&ino) = moved.get(&e.ino) {
+                            e.ino = ino;
+                            changed = true;
+                        }
+                    }
+                }
+                Entries::Index(_) => {
+                    return Err(Corrupted(format!(
+                        "Expected a leaf in {}",
+                        self.location(lblk)
+                    )))
+                }
+            }
+            if changed {
+                self.write_node(dk, lblk, &node)?;
+            }
+            leaf = Some(node.next).filter(|&n| n != 0);
+        }
+        Ok(())
+    }
+
+    /// Reads all entries. Only used when the whole directory is needed.
+    pub(crate) fn entries(&mut self, dk: &Donkey) -> DkResult<Vec<LeafEntry>> {
+        let mut all = Vec::new();
+        let mut leaf = self.find_leaf(dk, 0)?;
+        while let Some(lblk) = leaf {
+            let (entries, next) = self.read_leaf(dk, lblk)?;
+            all.extend(entries);
+            leaf = next;
+        }
+        Ok(all)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(names: &[&str]) -> Node {
+        let mut entries: Vec<LeafEntry> = names
+            .iter()
+            .map(|name| LeafEntry {
+                hash: hash(OsStr::new(name)),
+                ino: ROOT_INODE,
+                name: OsString::from(name),
+                file_type: FileMode::DIRECTORY,
+            }).collect();
+        entries.sort_by(|a, b| a.cmp_key(b.hash, &b.name));
+        Node {
+            entries: Entries::Leaf(entries),
+            next: 3,
+            total: 2,
+        }
+    }
+
+    #[test]
+    fn encode_decode() -> DkResult<()> {
+        let node = leaf(&[".", "..", "Homura"]);
+        let decoded = Node::decode(node.encode(4096), "test")?;
+        assert_eq!(decoded.next, 3);
+        assert_eq!(decoded.total, 2);
+        match (node.entries, decoded.entries) {
+            (Entries::Leaf(a), Entries::Leaf(b)) => assert_eq!(a, b),
+            _ => unreachable!(),
+        }
+
+        let mut buf = leaf(&["Madoka"]).encode(4096);
+        buf[40] ^= 1;
+        assert!(Node::decode(buf, "test").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cookies_of_equal_hashes() {
+        let entry = |hash| LeafEntry {
+            hash,
+            ino: ROOT_INODE,
+            name: OsString::new(),
+            file_type: FileMode::REGULAR_FILE,
+        };
+        let entries = [entry(1), entry(5), entry(5), entry(5), entry(9)];
+        assert_eq!(cookies(&entries), vec![2, 5, 5, 6, 10]);
+    }
+
+    #[test]
+    fn split_between_hashes() -> DkResult<()> {
+        let names: Vec<String> = (0..100).map(|i| format!("{}", i)).collect();
+        let names: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+        let mut node = leaf(&names);
+        let (key, right) = node.split()?;
+        match (&node.entries, &right.entries) {
+            (Entries::Leaf(l), Entries::Leaf(r)) => {
+                assert_eq!(l.len() + r.len(), 100);
+                assert!(l.iter().all(|e| e.hash < key));
+                assert!(r.iter().all(|e| e.hash >= key));
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+}
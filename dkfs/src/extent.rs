@@ -0,0 +1,378 @@
+use block::*;
+use std::cmp::{max, min};
+use *;
+
+/// Magic number in the header of every extent node
+const EXTENT_MAGIC: u64 = 0xE87E;
+/// Number of entries that fit in the root node stored in `InodePtrs`
+const ROOT_CAPACITY: usize = 7;
+/// The length of an extent is stored in 16 bits.
+pub(crate) const MAX_EXTENT_LEN: u64 = 0xffff;
+const LBLK_MASK: u64 = (1 << 48) - 1;
+
+/// A run of `len` blocks starting at logical block `lblk`
+/// and physically located at `ptr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Extent {
+    pub(crate) lblk: u64,
+    pub(crate) ptr: u64,
+    pub(crate) len: u64,
+}
+
+/// An extent node is a header word followed by pairs of words.
+///
+/// The header contains the magic number, the depth of the node
+/// and the number of entries.
+/// In a leaf (depth 0), each entry is `(lblk | len << 48, ptr)`.
+/// In an index node, each entry is `(first lblk, child ptr)`.
+/// Child nodes occupy a whole block each.
+fn encode_node(depth: u64, entries: &[(u64, u64)], words: &mut [u64]) {
+    for w in words.iter_mut() {
+        *w = 0;
+    }
+    words[0] = EXTENT_MAGIC | depth << 16 | (entries.len() as u64) << 32;
+    for (i, (a, b)) in entries.iter().enumerate() {
+        words[1 + 2 * i] = *a;
+        words[2 + 2 * i] = *b;
+    }
+}
+
+fn decode_node(words: &[u64], ptr: u64) -> DkResult<(u64, Vec<(u64, u64)>)> {
+    let header = words[0];
+    let depth = (header >> 16) & 0xffff;
+    let count = (header >> 32) as usize;
+    if header & 0xffff != EXTENT_MAGIC || 1 + 2 * count > words.len() {
+        return Err(Corrupted(format!("Invalid extent node at {}", ptr)));
+    }
+    let entries = (0..count)
+        .map(|i| (words[1 + 2 * i], words[2 + 2 * i]))
+        .collect();
+    Ok((depth, entries))
+}
+
+/// The extents of a file. All extents are loaded into memory
+/// when the file is opened and the tree is rebuilt by `store`.
+#[derive(Debug, Clone)]
+pub(crate) struct ExtentMap {
+    /// Sorted by `lblk` and never overlapping
+    extents: Vec<Extent>,
+    /// Blocks occupied by non-root nodes of the tree
+    tree: Vec<u64>,
+    bs: u64,
+    pub(crate) dirty: bool,
+}
+
+impl ExtentMap {
+    /// Returns the root of an empty extent tree.
+    pub(crate) fn empty_root() -> InodePtrs {
+        let mut words = [0; 16];
+        encode_node(0, &[], &mut words);
+        InodePtrs::from_words(&words)
+    }
+
+    pub(crate) fn load(dk: &Donkey, root: &InodePtrs) -> DkResult<Self> {
+        let mut map = ExtentMap {
+            extents: Vec::new(),
+            tree: Vec::new(),
+            bs: dk.block_size(),
+            dirty: false,
+        };
+        map.load_node(dk, &root.words(), 0)?;
+        Ok(map)
+    }
+
+    fn load_node(&mut self, dk: &Donkey, words: &[u64], ptr: u64) -> DkResult<()> {
+        let (depth, entries) = decode_node(words, ptr)?;
+        for (a, b) in entries {
+            if depth == 0 {
+                self.extents.push(Extent {
+                    lblk: a & LBLK_MASK,
+                    ptr: b,
+                    len: a >> 48,
+                });
+            } else {
+                let child: PtrBlock = dk.read_block(b)?;
+                self.tree.push(b);
+                self.load_node(dk, &child, b)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the tree back, reusing blocks of the old tree where possible.
+    /// Returns the number of blocks allocated and the number of blocks freed.
+    pub(crate) fn store(&mut self, dk: &Donkey, root: &mut InodePtrs) -> DkResult<(u64, u64)> {
+        let cap = (ptrs_per_block(self.bs) as usize - 1) / 2;
+        let mut old_tree = std::mem::replace(&mut self.tree, Vec::new());
+        let (mut allocated, mut freed) = (0, 0);
+        let mut entries: Vec<(u64, u64)> = self
+            .extents
+            .iter()
+            .map(|e| (e.lblk | e.len << 48, e.ptr))
+            .collect();
+        let mut depth = 0;
+        while entries.len() > ROOT_CAPACITY {
+            let mut parents = Vec::new();
+            for chunk in entries.chunks(cap) {
+                let ptr = match old_tree.pop() {
+                    Some(ptr) => ptr,
+                    None => {
+                        allocated += 1;
+                        dk.allocate_db()?
+                    }
+                };
+                let mut node = Data(vec![0; ptrs_per_block(self.bs) as usize]);
+                encode_node(depth, chunk, &mut node);
+                dk.write(ptr, &node)?;
+                self.tree.push(ptr);
+                parents.push((chunk[0].0 & LBLK_MASK, ptr));
+            }
+            entries = parents;
+            depth += 1;
+        }
+        for ptr in old_tree {
+            dk.free_db(ptr)?;
+            freed += 1;
+        }
+        let mut words = [0; 16];
+        encode_node(depth, &entries, &mut words);
+        *root = InodePtrs::from_words(&words);
+        self.dirty = false;
+        Ok((allocated, freed))
+    }
+
+    /// Index of the last extent starting at or before `lblk`
+    fn find(&self, lblk: u64) -> Option<usize> {
+        match self.extents.binary_search_by_key(&lblk, |e| e.lblk) {
+            Ok(i) => Some(i),
+            Err(0) => None,
+            Err(i) => Some(i - 1),
+        }
+    }
+
+    /// Returns the pointer of logical block `lblk` and the number of
+    /// blocks that follow it contiguously in the same extent.
+    pub(crate) fn lookup(&self, lblk: u64) -> Option<(u64, u64)> {
+        self.find(lblk).and_then(|i| {
+            let e = &self.extents[i];
+            if lblk < e.lblk + e.len {
+                let off = lblk - e.lblk;
+                Some((e.ptr + off * self.bs, e.len - off))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Number of unmapped blocks starting at `lblk`, if bounded
+    pub(crate) fn hole_len(&self, lblk: u64) -> Option<u64> {
+        let next = match self.find(lblk) {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        self.extents.get(next).map(|e| e.lblk - lblk)
+    }
+
+    /// Maps `len` blocks at `lblk` to `ptr`. The range must be unmapped.
+    pub(crate) fn insert(&mut self, lblk: u64, ptr: u64, len: u64) {
+        self.dirty = true;
+        let bs = self.bs;
+        let adjacent = |a: &Extent, b: &Extent| {
+            a.lblk + a.len == b.lblk && a.ptr + a.len * bs == b.ptr && a.len + b.len <= MAX_EXTENT_LEN
+        };
+        let new = Extent { lblk, ptr, len };
+        let i = self.find(lblk).map(|i| i + 1).unwrap_or(0);
+        if i > 0 && adjacent(&self.extents[i - 1], &new) {
+            self.extents[i - 1].len += len;
+            let i = i - 1;
+            if i + 1 < self.extents.len() && adjacent(&self.extents[i], &self.extents[i + 1]) {
+                self.extents[i].len += self.extents[i + 1].len;
+                self.extents.remove(i + 1);
+            }
+        } else if i < self.extents.len() && adjacent(&new, &self.extents[i]) {
+            self.extents[i].lblk = lblk;
+            self.extents[i].ptr = ptr;
+            self.extents[i].len += len;
+        } else {
+            self.extents.insert(i, new);
+        }
+    }
+
+    /// Unmaps all blocks from logical block `from`.
+    /// Returns the physical runs to be freed.
+    pub(crate) fn truncate(&mut self, from: u64) -> Vec<(u64, u64)> {
+        let mut freed = Vec::new();
+        let bs = self.bs;
+        self.extents.retain(|e| {
+            if e.lblk >= from {
+                freed.push((e.ptr, e.len));
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(e) = self.extents.last_mut() {
+            if e.lblk + e.len > from {
+                let keep = from - e.lblk;
+                freed.push((e.ptr + keep * bs, e.len - keep));
+                e.len = keep;
+            }
+        }
+        if !freed.is_empty() {
+            self.dirty = true;
+        }
+        freed
+    }
+
+    /// Unmaps the blocks from logical block `from` to `to` (exclusive).
+    /// Extents across the ends are split. Returns the physical runs to be freed.
+    pub(crate) fn punch(&mut self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let mut freed = Vec::new();
+        let bs = self.bs;
+        let mut kept = Vec::with_capacity(self.extents.len() + 1);
+        for e in std::mem::take(&mut self.extents) {
+            let end = e.lblk + e.len;
+            if end <= from || e.lblk >= to {
+                kept.push(e);
+                continue;
+            }
+            if e.lblk < from {
+                kept.push(Extent {
+                    len: from - e.lblk,
+                    ..e
+                });
+            }
+            let (start, stop) = (max(e.lblk, from), min(end, to));
+            freed.push((e.ptr + (start - e.lblk) * bs, stop - start));
+            if end > to {
+                kept.push(Extent {
+                    lblk: to,
+                    ptr: e.ptr + (to - e.lblk) * bs,
+                    len: end - to,
+                });
+            }
+        }
+        self.extents = kept;
+        if !freed.is_empty() {
+            self.dirty = true;
+        }
+        freed
+    }
+
+    /// All blocks used by the file as runs of `(ptr, len)`,
+    /// including the nodes of the tree.
+    pub(crate) fn runs(&self) -> Vec<(u64, u64)> {
+        self.extents
+            .iter()
+            .map(|e| (e.ptr, e.len))
+            .chain(self.tree.iter().map(|&ptr| (ptr, 1)))
+            .collect()
+    }
+
+    /// Moves the blocks at or after pointer `limit` below it. Extent tree
+    /// nodes there are freed, so `store` allocates new ones.
+    /// Returns whether anything was moved and the number of nodes freed.
+    pub(crate) fn relocate(
+        &mut self,
+        dk: &Donkey,
+        limit: u64,
+        journaled: bool,
+    ) -> DkResult<(bool, u64)> {
+        let bs = self.bs;
+        let dirty = self.dirty;
+        let mut moved = false;
+        for e in std::mem::replace(&mut self.extents, Vec::new()) {
+            let mut i = 0;
+            while i < e.len {
+                let ptr = e.ptr + i * bs;
+                if ptr < limit {
+                    let len = min(e.len - i, (limit - ptr) / bs);
+                    self.insert(e.lblk + i, ptr, len);
+                    i += len;
+                } else {
+                    let (new_ptr, len) = dk.allocate_dbs(e.len - i)?;
+                    for j in 0..len {
+                        dk.copy_db(ptr + j * bs, new_ptr + j * bs, journaled)?;
+                    }
+                    dk.free_dbs(ptr, len)?;
+                    self.insert(e.lblk + i, new_ptr, len);
+                    i += len;
+                    moved = true;
+                }
+            }
+        }
+        let mut freed = 0;
+        for &ptr in self.tree.iter().filter(|&&ptr| ptr >= limit) {
+            dk.free_db(ptr)?;
+            freed += 1;
+        }
+        self.tree.retain(|&ptr| ptr < limit);
+        self.dirty = dirty || moved || freed > 0;
+        Ok((moved, freed))
+    }
+
+    /// Number of blocks a new extent at `lblk` may span
+    /// in order to cover `want` blocks without overlapping others.
+    pub(crate) fn alloc_len(&self, lblk: u64, want: u64) -> u64 {
+        let len = min(want, MAX_EXTENT_LEN);
+        match self.hole_len(lblk) {
+            Some(hole) => min(len, hole),
+            None => len,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> ExtentMap {
+        ExtentMap {
+            extents: Vec::new(),
+            tree: Vec::new(),
+            bs: 4096,
+            dirty: false,
+        }
+    }
+
+    #[test]
+    fn insert_and_merge() {
+        let mut m = map();
+        m.insert(0, 40960, 2);
+        m.insert(2, 40960 + 2 * 4096, 3);
+        assert_eq!(m.extents.len(), 1);
+        assert_eq!(m.lookup(3), Some((40960 + 3 * 4096, 2)));
+        m.insert(10, 8192, 1);
+        m.insert(9, 4096, 1);
+        assert_eq!(m.extents.len(), 2);
+        assert_eq!(m.lookup(5), None);
+        assert_eq!(m.hole_len(5), Some(4));
+        assert_eq!(m.alloc_len(5, 100), 4);
+        assert_eq!(m.hole_len(11), None);
+    }
+
+    #[test]
+    fn truncate() {
+        let mut m = map();
+        m.insert(0, 40960, 4);
+        m.insert(8, 4096, 2);
+        assert_eq!(m.truncate(2), vec![(4096, 2), (40960 + 2 * 4096, 2)]);
+        assert_eq!(m.lookup(1), Some((40960 + 4096, 1)));
+        assert_eq!(m.lookup(2), None);
+    }
+
+    #[test]
+    fn punch() {
+        let mut m = map();
+        m.insert(0, 40960, 10);
+        m.insert(20, 4096, 2);
+        assert_eq!(m.punch(3, 5), vec![(40960 + 3 * 4096, 2)]);
+        assert_eq!(m.lookup(2), Some((40960 + 2 * 4096, 1)));
+        assert_eq!(m.lookup(3), None);
+        assert_eq!(m.lookup(5), Some((40960 + 5 * 4096, 5)));
+        assert_eq!(m.punch(8, 21), vec![(40960 + 8 * 4096, 2), (4096, 1)]);
+        assert_eq!(m.hole_len(8), Some(13));
+        assert_eq!(m.lookup(21), Some((8192, 1)));
+        assert!(m.punch(10, 20).is_empty());
+    }
+}
@@ -1,28 +1,36 @@
-use bincode::{deserialize_from, serialize_into};
 use block::*;
+use byteorder::{ByteOrder, LE};
+use checksum::crc32c;
+use extent::ExtentMap;
 use failure::Fail;
-use im::ordmap::{self, OrdMap};
-use std::cell::RefCell;
-use std::cmp::min;
-use std::ffi::OsString;
-use std::io::{BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use im::ordmap::OrdMap;
+use std::cmp::{max, min};
+use std::ffi::{OsStr, OsString};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::ops::Drop;
-use std::rc::Rc;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex, MutexGuard};
 use *;
 
-#[derive(Debug)]
+const XATTR_MAGIC: u32 = 0x5854_4B44;
+const XATTR_LIST_MAGIC: u32 = 0x5854_4C44;
+
+#[derive(Debug, Clone)]
 pub struct DkFile {
     pub(crate) inode: Inode,
     pub(crate) pos: u64,
     pub(crate) xattr: OrdMap<OsString, Vec<u8>>,
+    /// The blocks holding `xattr` in order, the first of which is `xattr_ptr`
+    pub(crate) xattr_blocks: Vec<u64>,
     pub(crate) dirty: bool,
-    pub(crate) close_file_list: Rc<RefCell<Vec<u64>>>,
     pub(crate) ptr_cache: [Option<(u64, PtrBlock)>; 4],
+    /// Only used by files with `InodeFlags::EXTENTS`
+    pub(crate) extents: Option<ExtentMap>,
 }
 
 #[derive(Debug)]
 pub struct DkFileIO<'a, 'b: 'a> {
-    pub(crate) dk: &'a mut Donkey<'b>,
+    pub(crate) dk: &'a Donkey<'b>,
     pub(crate) file: &'a mut DkFile,
 }
 
@@ -48,6 +56,7 @@ impl<'a, 'b: 'a> Write for DkFileIO<'a, 'b> {
             let res = self
                 .file
                 .write_ptr_cache(self.dk)
+                .and_then(|_| self.file.write_extents(self.dk))
                 .and_then(|_| self.file.write_xattr(self.dk))
                 .and_then(|_| self.dk.write_inode(&self.file.inode));
             if let Err(e) = res {
@@ -79,52 +88,227 @@ impl Seek for DkFile {
 }
 
 impl DkFile {
-    pub(crate) fn new(inode: Inode, close_file_list: Rc<RefCell<Vec<u64>>>) -> Self {
+    pub(crate) fn new(inode: Inode) -> Self {
         DkFile {
             inode,
             pos: 0,
             xattr: OrdMap::new(),
+            xattr_blocks: Vec::new(),
             dirty: false,
-            close_file_list,
             ptr_cache: Default::default(),
+            extents: None,
         }
     }
 
-    pub(crate) fn read_xattr(&mut self, dk: &mut Donkey) -> DkResult<()> {
-        if self.inode.xattr_ptr != 0 {
-            let data: ByteData = dk.read_block(self.inode.xattr_ptr)?;
-            let mut reader = Cursor::new(data.0.as_slice());
-            loop {
-                let key: OsString = deserialize_from(&mut reader)?;
-                if key.len() == 0 {
-                    break;
-                }
-                let value: Vec<u8> = deserialize_from(&mut reader)?;
-                self.xattr.insert(key, value);
+    fn is_inline(&self) -> bool {
+        self.inode.flags.contains(InodeFlags::INLINE_DATA)
+    }
+
+    /// Moves the inline data into a data block, so that the file can grow
+    /// beyond `MAX_INLINE_LEN` bytes.
+    fn move_inline_data(&mut self, dk: &Donkey) -> DkResult<()> {
+        let data = self.inode.ptrs.bytes();
+        let len = self.inode.size as usize;
+        self.inode.flags.remove(InodeFlags::INLINE_DATA);
+        let features = dk.alloc().sb.feature_incompat;
+        if features.contains(FeatureIncompat::EXTENTS) {
+            self.inode.flags.insert(InodeFlags::EXTENTS);
+            self.inode.ptrs = ExtentMap::empty_root();
+            self.extents = Some(ExtentMap::load(dk, &self.inode.ptrs)?);
+        } else {
+            self.inode.ptrs = Default::default();
+        }
+        self.dirty = true;
+        if len > 0 {
+            let (ptr, _) = self.map_alloc(dk, 0, 1)?;
+            dk.write_data(ptr, &RefData(&data[..len]))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_extents(&mut self, dk: &Donkey) -> DkResult<()> {
+        if self.inode.flags.contains(InodeFlags::EXTENTS) {
+            self.extents = Some(ExtentMap::load(dk, &self.inode.ptrs)?);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_extents(&mut self, dk: &Donkey) -> DkResult<()> {
+        let DkFile { extents, inode, .. } = self;
+        if let Some(extents) = extents {
+            if extents.dirty {
+                let (allocated, freed) = extents.store(dk, &mut inode.ptrs)?;
+                inode.blocks = inode.blocks + allocated - freed;
             }
         }
         Ok(())
     }
 
-    pub(crate) fn write_xattr(&mut self, dk: &mut Donkey) -> DkResult<()> {
-        if self.xattr.len() == 0 {
-            if self.inode.xattr_ptr != 0 {
-                dk.free_db(self.inode.xattr_ptr)?;
-                self.inode.xattr_ptr = 0;
-                self.dirty = true;
+    /// Xattrs which fit in one block are laid out as follows.
+    /// All integers are little-endian.
+    ///
+    /// | Offset | Size | Field                                |
+    /// |--------|------|--------------------------------------|
+    /// | 0      | 4    | `XATTR_MAGIC`                        |
+    /// | 4      | 4    | Number of attributes                 |
+    /// | 8      |      | Attributes, each of which is the     |
+    /// |        |      | length of the name (u16), the length |
+    /// |        |      | of the value (u32), the name and the |
+    /// |        |      | value                                |
+    /// | bs - 4 | 4    | CRC-32C of the bytes before          |
+    ///
+    /// Larger ones are split into a list of blocks, whose parts
+    /// from offset 4 of the layout above are joined in order.
+    ///
+    /// | Offset | Size | Field                                |
+    /// |--------|------|--------------------------------------|
+    /// | 0      | 4    | `XATTR_LIST_MAGIC`                   |
+    /// | 4      | 4    | Length of the part in this block     |
+    /// | 8      | 8    | The next block, 0 in the last one    |
+    /// | 16     |      | The part                             |
+    /// | bs - 4 | 4    | CRC-32C of the bytes before          |
+    pub(crate) fn read_xattr(&mut self, dk: &Donkey) -> DkResult<()> {
+        let mut ptr = self.inode.xattr_ptr;
+        if ptr == 0 {
+            return Ok(());
+        }
+        let corrupted = |ptr| Corrupted(format!("Invalid xattr block at {}", ptr));
+        let mut bytes = Vec::new();
+        while ptr != 0 {
+            let data: ByteData = dk.read_block(ptr)?;
+            let bs = data.len();
+            if bs < 20 {
+                return Err(corrupted(ptr));
             }
-        } else {
-            if self.inode.xattr_ptr == 0 {
-                self.inode.xattr_ptr = dk.allocate_db()?;
-                self.inode.blocks += 1;
+            if crc32c(&data[..bs - 4]) != LE::read_u32(&data[bs - 4..]) {
+                return Err(Corrupted(format!(
+                    "Checksum mismatch in xattr block at {}",
+                    ptr
+                )));
+            }
+            self.xattr_blocks.push(ptr);
+            match LE::read_u32(&data[0..4]) {
+                XATTR_MAGIC if bytes.is_empty() => {
+                    bytes.extend_from_slice(&data[4..bs - 4]);
+                    ptr = 0;
+                }
+                XATTR_LIST_MAGIC => {
+                    let len = LE::read_u32(&data[4..8]) as usize;
+                    // Parts are never empty, so a loop in the list cannot go unnoticed
+                    if len == 0 || len > bs - 20 || bytes.len() + len > MAX_XATTRS_LEN {
+                        return Err(corrupted(ptr));
+                    }
+                    bytes.extend_from_slice(&data[16..16 + len]);
+                    ptr = LE::read_u64(&data[8..16]);
+                }
+                _ => return Err(corrupted(ptr)),
+            }
+        }
+
+        let ptr = self.inode.xattr_ptr;
+        if bytes.len() < 4 {
+            return Err(corrupted(ptr));
+        }
+        let count = LE::read_u32(&bytes[0..4]);
+        let mut off = 4;
+        for _ in 0..count {
+            if off + 6 > bytes.len() {
+                return Err(corrupted(ptr));
             }
-            let mut data = Vec::new();
-            for (key, value) in &self.xattr {
-                serialize_into(&mut data, key)?;
-                serialize_into(&mut data, value)?;
+            let name_len = LE::read_u16(&bytes[off..off + 2]) as usize;
+            let value_len = LE::read_u32(&bytes[off + 2..off + 6]) as usize;
+            let name_off = off + 6;
+            let value_off = name_off + name_len;
+            off = value_off + value_len;
+            if off > bytes.len() {
+                return Err(corrupted(ptr));
             }
-            serialize_into(&mut data, &OsString::new())?;
-            dk.write(self.inode.xattr_ptr, &RefData(data.as_slice()))?;
+            let name = OsStr::from_bytes(&bytes[name_off..value_off]).to_os_string();
+            self.xattr.insert(name, bytes[value_off..off].to_vec());
+        }
+        Ok(())
+    }
+
+    /// Length of the encoded xattrs, excluding the magic number and the checksum
+    pub(crate) fn xattr_len(&self) -> usize {
+        4 + self
+            .xattr
+            .iter()
+            .map(|(name, value)| 6 + name.len() + value.len())
+            .sum::<usize>()
+    }
+
+    /// The most bytes the encoded xattrs of the file may take up.
+    /// A list is rewritten as a whole in one transaction, so it takes
+    /// no more than half of the journal.
+    pub(crate) fn xattr_capacity(dk: &Donkey) -> usize {
+        let features = dk.alloc().sb.feature_incompat;
+        if features.contains(FeatureIncompat::LARGE_XATTRS) {
+            let blocks = dk.dev().capacity() as usize / 2;
+            min(MAX_XATTRS_LEN, blocks * (dk.block_size() as usize - 20))
+        } else {
+            dk.block_size() as usize - 8
+        }
+    }
+
+    pub(crate) fn write_xattr(&mut self, dk: &Donkey) -> DkResult<()> {
+        let bs = dk.block_size() as usize;
+        let len = self.xattr_len();
+        let (magic, part_len) = if len + 8 <= bs {
+            (XATTR_MAGIC, bs - 8)
+        } else if len <= Self::xattr_capacity(dk) {
+            (XATTR_LIST_MAGIC, bs - 20)
+        } else {
+            return Err(XattrsFull);
+        };
+
+        let mut bytes = Vec::with_capacity(len);
+        let mut head = [0; 4];
+        LE::write_u32(&mut head, self.xattr.len() as u32);
+        bytes.extend_from_slice(&head);
+        for (name, value) in &self.xattr {
+            let mut head = [0; 6];
+            LE::write_u16(&mut head[0..2], name.len() as u16);
+            LE::write_u32(&mut head[2..6], value.len() as u32);
+            bytes.extend_from_slice(&head);
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(value);
+        }
+
+        // Reuse the blocks of the old list. No block is needed without xattrs.
+        let count = if self.xattr.is_empty() {
+            0
+        } else {
+            (bytes.len() - 1) / part_len + 1
+        };
+        while self.xattr_blocks.len() > count {
+            dk.free_db(self.xattr_blocks.pop().unwrap())?;
+            self.inode.blocks -= 1;
+        }
+        while self.xattr_blocks.len() < count {
+            self.xattr_blocks.push(dk.allocate_db()?);
+            self.inode.blocks += 1;
+        }
+        let first = self.xattr_blocks.first().cloned().unwrap_or(0);
+        if self.inode.xattr_ptr != first {
+            self.inode.xattr_ptr = first;
+            self.dirty = true;
+        }
+
+        for (i, part) in bytes.chunks(part_len).take(count).enumerate() {
+            let mut data = vec![0; bs];
+            LE::write_u32(&mut data[0..4], magic);
+            if magic == XATTR_MAGIC {
+                data[4..4 + part.len()].copy_from_slice(part);
+            } else {
+                let next = self.xattr_blocks.get(i + 1).cloned().unwrap_or(0);
+                LE::write_u32(&mut data[4..8], part.len() as u32);
+                LE::write_u64(&mut data[8..16], next);
+                data[16..16 + part.len()].copy_from_slice(part);
+            }
+            let sum = crc32c(&data[..bs - 4]);
+            LE::write_u32(&mut data[bs - 4..], sum);
+            dk.write(self.xattr_blocks[i], &RefData(data.as_slice()))?;
         }
         Ok(())
     }
@@ -141,7 +325,7 @@ impl DkFile {
 
     fn level_off(&self, dk: &Donkey, bi: u64) -> (usize, usize) {
         let mut bi = bi as usize;
-        let pc = dk.block_size() as usize / 8;
+        let pc = ptrs_per_block(dk.block_size()) as usize;
         let mut multi = 1;
         for level in 0..=4 {
             let len = self.inode.ptrs[level].len() * multi;
@@ -154,7 +338,7 @@ impl DkFile {
         unreachable!()
     }
 
-    fn write_ptr_cache(&mut self, dk: &mut Donkey) -> DkResult<()> {
+    fn write_ptr_cache(&mut self, dk: &Donkey) -> DkResult<()> {
         for cache in &self.ptr_cache {
             if let Some((ptr, cache)) = cache {
                 dk.write(*ptr, cache)?;
@@ -164,7 +348,7 @@ impl DkFile {
     }
 
     /// Returns cache ptr
-    fn load_ptrs_alloc(&mut self, dk: &mut Donkey, level: usize, ptr: u64) -> DkResult<u64> {
+    fn load_ptrs_alloc(&mut self, dk: &Donkey, level: usize, ptr: u64) -> DkResult<u64> {
         assert!(level > 0);
         if let Some((p, pb)) = &self.ptr_cache[level - 1] {
             if *p == ptr {
@@ -186,7 +370,7 @@ impl DkFile {
 
     fn load_ptrs_in_cache_alloc(
         &mut self,
-        dk: &mut Donkey,
+        dk: &Donkey,
         level: usize,
         index: usize,
     ) -> DkResult<()> {
@@ -217,10 +401,10 @@ impl DkFile {
     }
 
     fn empty_ptr_block(dk: &Donkey) -> PtrBlock {
-        Data(vec![0; dk.block_size() as usize / 8])
+        Data(vec![0; ptrs_per_block(dk.block_size()) as usize])
     }
 
-    fn locate_alloc(&mut self, dk: &mut Donkey, bi: u64) -> DkResult<u64> {
+    fn locate_alloc(&mut self, dk: &Donkey, bi: u64) -> DkResult<u64> {
         let (mut level, mut off) = self.level_off(dk, bi); // 512
         if level == 0 {
             if self.inode.ptrs[level][off] == 0 {
@@ -231,7 +415,7 @@ impl DkFile {
         } else {
             let indir_ptr = self.inode.ptrs[level][0];
             self.inode.ptrs[level][0] = self.load_ptrs_alloc(dk, level, indir_ptr)?;
-            let pc = dk.block_size() as usize / 8;
+            let pc = ptrs_per_block(dk.block_size()) as usize;
             let mut ipc = pc.pow(level as u32);
             while level > 1 {
                 ipc /= pc;
@@ -249,7 +433,7 @@ impl DkFile {
     }
 
     /// Returns cache ptr
-    fn load_ptrs(&mut self, dk: &mut Donkey, level: usize, ptr: u64) -> DkResult<Option<u64>> {
+    fn load_ptrs(&mut self, dk: &Donkey, level: usize, ptr: u64) -> DkResult<Option<u64>> {
         assert!(level > 0);
         if ptr == 0 {
             Ok(None)
@@ -269,7 +453,7 @@ impl DkFile {
     /// Returns whether trying to load an empty pointer.
     fn load_ptrs_in_cache(
         &mut self,
-        dk: &mut Donkey,
+        dk: &Donkey,
         level: usize,
         index: usize,
     ) -> DkResult<bool> {
@@ -295,7 +479,7 @@ impl DkFile {
     }
 
     /// Without allocation
-    fn locate(&mut self, dk: &mut Donkey, bi: u64) -> DkResult<Option<u64>> {
+    fn locate(&mut self, dk: &Donkey, bi: u64) -> DkResult<Option<u64>> {
         let (mut level, mut off) = self.level_off(dk, bi);
         if level == 0 {
             if self.inode.ptrs[level][off] == 0 {
@@ -310,7 +494,7 @@ impl DkFile {
             } else {
                 return Ok(None);
             }
-            let pc = dk.block_size() as usize / 8;
+            let pc = ptrs_per_block(dk.block_size()) as usize;
             let mut ipc = pc.pow(level as u32);
             while level > 1 {
                 ipc /= pc;
@@ -329,31 +513,102 @@ impl DkFile {
         }
     }
 
-    fn dk_read(&mut self, dk: &mut Donkey, buf: &mut [u8]) -> DkResult<usize> {
+    /// Returns the pointer of block `bi` and the number of blocks
+    /// that are contiguous with it on the device.
+    fn map(&mut self, dk: &Donkey, bi: u64) -> DkResult<Option<(u64, u64)>> {
+        match &self.extents {
+            Some(extents) => Ok(extents.lookup(bi)),
+            None => Ok(self.locate(dk, bi)?.map(|ptr| (ptr, 1))),
+        }
+    }
+
+    /// Like `map`, but allocates blocks if `bi` is not mapped.
+    /// `want` is the number of blocks the caller is going to use.
+    fn map_alloc(&mut self, dk: &Donkey, bi: u64, want: u64) -> DkResult<(u64, u64)> {
+        if let Some(found) = self.map(dk, bi)? {
+            return Ok(found);
+        }
+        match &mut self.extents {
+            Some(extents) => {
+                let (ptr, len) = dk.allocate_dbs(extents.alloc_len(bi, want))?;
+                extents.insert(bi, ptr, len);
+                self.inode.blocks += len;
+                Ok((ptr, len))
+            }
+            None => Ok((self.locate_alloc(dk, bi)?, 1)),
+        }
+    }
+
+    /// Number of unmapped blocks starting at `bi`, if bounded
+    fn hole_len(&self, bi: u64) -> Option<u64> {
+        match &self.extents {
+            Some(extents) => extents.hole_len(bi),
+            None => Some(1),
+        }
+    }
+
+    fn dk_read(&mut self, dk: &Donkey, buf: &mut [u8]) -> DkResult<usize> {
         if self.pos >= self.inode.size {
             return Ok(0);
         }
+        if self.is_inline() {
+            let (pos, size) = (self.pos as usize, self.inode.size as usize);
+            let len = min(size - pos, buf.len());
+            buf[..len].copy_from_slice(&self.inode.ptrs.bytes()[pos..pos + len]);
+            self.pos += len as u64;
+            return Ok(len);
+        }
         let bs = dk.block_size();
         let (bi, bo) = Self::block_of_pos(self.pos, bs);
-        let block_ptr = self.locate(dk, bi)?;
-        let ptr = match block_ptr {
-            Some(ptr) => ptr + bo,
-            None => return Ok(0), // Nothing to read
+        let (ptr, run) = match self.map(dk, bi)? {
+            Some(found) => found,
+            None => {
+                // A hole reads as zeros
+                let mut len = min(self.inode.size - self.pos, buf.len() as u64);
+                if let Some(hole) = self.hole_len(bi) {
+                    len = min(len, hole * bs - bo);
+                }
+                for b in &mut buf[..len as usize] {
+                    *b = 0;
+                }
+                self.pos += len;
+                return Ok(len as usize);
+            }
         };
-        let len = min(bs - bo, self.inode.size - self.pos); // Cannot read beyond EOF
+        let len = min(run * bs - bo, self.inode.size - self.pos); // Cannot read beyond EOF
         let len = min(len as usize, buf.len());
-        let read_len = dk.read_into(ptr, &mut buf[..len])?;
+        let read_len = dk.read_into(ptr + bo, &mut buf[..len])?;
         self.pos += read_len;
         Ok(read_len as usize)
     }
 
-    fn dk_write(&mut self, dk: &mut Donkey, buf: &[u8]) -> DkResult<usize> {
+    fn dk_write(&mut self, dk: &Donkey, buf: &[u8]) -> DkResult<usize> {
         self.dirty = true;
+        if self.is_inline() {
+            let end = self.pos + buf.len() as u64;
+            if end <= MAX_INLINE_LEN {
+                let mut data = self.inode.ptrs.bytes();
+                data[self.pos as usize..end as usize].copy_from_slice(buf);
+                self.inode.ptrs = InodePtrs::from_bytes(&data);
+                self.pos = end;
+                if end > self.inode.size {
+                    self.update_size(dk, end)?;
+                }
+                return Ok(buf.len());
+            }
+            self.move_inline_data(dk)?;
+        }
         let bs = dk.block_size();
         let (bi, bo) = Self::block_of_pos(self.pos, bs);
-        let ptr = self.locate_alloc(dk, bi)? + bo;
-        let len = min((bs - bo) as usize, buf.len());
-        dk.write(ptr, &RefData(&buf[..len]))?;
+        let want = Self::next_block_of_pos(bo + buf.len() as u64, bs);
+        let (ptr, run) = self.map_alloc(dk, bi, want)?;
+        let len = min(run * bs - bo, buf.len() as u64) as usize;
+        if self.inode.mode.is_directory() {
+            // Directory contents are metadata
+            dk.write(ptr + bo, &RefData(&buf[..len]))?;
+        } else {
+            dk.write_data(ptr + bo, &RefData(&buf[..len]))?;
+        }
         self.pos += len as u64;
         let pos = self.pos;
         if pos > self.inode.size {
@@ -362,14 +617,24 @@ impl DkFile {
         Ok(len)
     }
 
-    pub(crate) fn flush(&mut self, dk: &mut Donkey) -> DkResult<()> {
+    pub(crate) fn flush(&mut self, dk: &Donkey) -> DkResult<()> {
         let mut io = DkFileIO { dk, file: self };
         Ok(io.flush()?)
     }
 
-    pub(crate) fn update_size(&mut self, dk: &mut Donkey, new_size: u64) -> DkResult<()> {
+    pub(crate) fn update_size(&mut self, dk: &Donkey, new_size: u64) -> DkResult<()> {
         self.dirty = true;
-        if self.inode.size > new_size {
+        if self.is_inline() && new_size > MAX_INLINE_LEN {
+            self.move_inline_data(dk)?;
+        }
+        if self.is_inline() {
+            // Bytes beyond the size are kept zero
+            let mut data = self.inode.ptrs.bytes();
+            for b in &mut data[new_size as usize..] {
+                *b = 0;
+            }
+            self.inode.ptrs = InodePtrs::from_bytes(&data);
+        } else if self.inode.size > new_size {
             let bs = dk.block_size();
             let free_from = Self::next_block_of_pos(new_size, bs);
             self.free_file_db(dk, free_from)?;
@@ -384,214 +649,497 @@ impl DkFile {
     }
 
     /// `from` is inclusive
-    fn free_file_db(&mut self, dk: &mut Donkey, from: u64) -> DkResult<()> {
-        // Clear direct pointers
-        if from < 12 {
-            for bi in 0..12 {
-                if self.inode.ptrs[0][bi as usize] > 0 {
-                    dk.free_db(self.inode.ptrs[0][bi as usize])?;
-                    self.inode.blocks -= 1;
-                    self.inode.ptrs[0][bi as usize] = 0;
-                }
+    fn free_file_db(&mut self, dk: &Donkey, from: u64) -> DkResult<()> {
+        if let Some(extents) = &mut self.extents {
+            for (ptr, len) in extents.truncate(from) {
+                dk.free_dbs(ptr, len)?;
+                self.inode.blocks -= len;
             }
+            return Ok(());
         }
-        let pc = dk.block_size() / 8;
-        let mut start = 12;
-        for i in 1..=4 {
-            let indir_ptr = self.inode.ptrs[i][0];
-            if self.clear_pointers_rec(dk, from, start, indir_ptr, i as u32)? {
-                dk.free_db(self.inode.ptrs[i][0])?;
+        self.free_ptrs(dk, from, u64::MAX)
+    }
+
+    /// Frees the blocks from `from` to `to` (exclusive) mapped by
+    /// `InodePtrs`, and the pointer blocks left empty.
+    fn free_ptrs(&mut self, dk: &Donkey, from: u64, to: u64) -> DkResult<()> {
+        // Cached pointer blocks may change below
+        self.write_ptr_cache(dk)?;
+        self.ptr_cache = Default::default();
+        for bi in from..min(to, 12) {
+            let ptr = self.inode.ptrs[0][bi as usize];
+            if ptr != 0 {
+                dk.free_db(ptr)?;
                 self.inode.blocks -= 1;
-                self.inode.ptrs[i][0] = 0;
+                self.inode.ptrs[0][bi as usize] = 0;
+            }
+        }
+        let pc = ptrs_per_block(dk.block_size());
+        let mut start = 12;
+        for level in 1..=4 {
+            let ptr = self.inode.ptrs[level][0];
+            if self.clear_ptrs_rec(dk, from, to, start, ptr, level as u32)? {
+                self.inode.ptrs[level][0] = 0;
             }
-            start += pc.pow(i as u32);
+            start += pc.pow(level as u32);
         }
         Ok(())
     }
 
-    // returns whether to clear `ptr`
-    fn clear_pointers_rec(
+    /// Frees the blocks from `from` to `to` under `ptr`, a block of `level`
+    /// mapping the blocks from `start`. Returns whether `ptr` is freed.
+    fn clear_ptrs_rec(
         &mut self,
-        dk: &mut Donkey,
+        dk: &Donkey,
         from: u64,
+        to: u64,
         start: u64,
         ptr: u64,
         level: u32,
     ) -> DkResult<bool> {
-        if ptr == 0 {
+        let pc = ptrs_per_block(dk.block_size());
+        let len = pc.pow(level);
+        if ptr == 0 || start >= to || start + len <= from {
             return Ok(false);
         }
-        let pc = dk.block_size() / 8;
-        let len = pc.pow(level);
-        if level >= 1 {
-            // clear recursively
-            let mut start = start;
-            let sublen = len / pc;
+        if level > 0 && (start < from || start + len > to) {
+            // Partly in the range
             let mut pb: PtrBlock = dk.read_block(ptr)?;
-            for ptr in &mut pb.0 {
-                if self.clear_pointers_rec(dk, from, start, *ptr, level - 1)? {
-                    dk.free_db(*ptr)?;
-                    self.inode.blocks -= 1;
-                    *ptr = 0;
+            let mut changed = false;
+            for (i, p) in pb.iter_mut().enumerate() {
+                let sub_start = start + i as u64 * len / pc;
+                if self.clear_ptrs_rec(dk, from, to, sub_start, *p, level - 1)? {
+                    *p = 0;
+                    changed = true;
+                }
+            }
+            if pb.iter().any(|&p| p != 0) {
+                if changed {
+                    dk.write(ptr, &pb)?;
+                }
+                return Ok(false);
+            }
+        } else if level > 0 {
+            let pb: PtrBlock = dk.read_block(ptr)?;
+            for (i, &p) in pb.iter().enumerate() {
+                let sub_start = start + i as u64 * len / pc;
+                self.clear_ptrs_rec(dk, from, to, sub_start, p, level - 1)?;
+            }
+        }
+        dk.free_db(ptr)?;
+        self.inode.blocks -= 1;
+        Ok(true)
+    }
+
+    /// Frees the blocks within `len` bytes at `offset`, which read as zeros
+    /// afterwards. Partial blocks at both ends are zeroed. The size is kept.
+    pub(crate) fn punch_hole(&mut self, dk: &Donkey, offset: u64, len: u64) -> DkResult<()> {
+        let bs = dk.block_size();
+        let end = offset.saturating_add(len);
+        let (first, last) = (Self::next_block_of_pos(offset, bs), end / bs);
+        if self.is_inline() {
+            // No blocks to free
+            let mut data = self.inode.ptrs.bytes();
+            let (from, to) = (min(offset, MAX_INLINE_LEN), min(end, MAX_INLINE_LEN));
+            for b in &mut data[from as usize..to as usize] {
+                *b = 0;
+            }
+            self.inode.ptrs = InodePtrs::from_bytes(&data);
+        } else if first > last {
+            // Within one block
+            self.zero_range(dk, offset, end)?;
+        } else {
+            self.zero_range(dk, offset, first * bs)?;
+            self.zero_range(dk, last * bs, end)?;
+            match &mut self.extents {
+                Some(extents) => {
+                    for (ptr, len) in extents.punch(first, last) {
+                        dk.free_dbs(ptr, len)?;
+                        self.inode.blocks -= len;
+                    }
+                }
+                None => self.free_ptrs(dk, first, last)?,
+            }
+        }
+        self.touch_data();
+        Ok(())
+    }
+
+    /// Zeroes the bytes from `from` to `to` in one block if it is mapped.
+    fn zero_range(&mut self, dk: &Donkey, from: u64, to: u64) -> DkResult<()> {
+        if from >= to {
+            return Ok(());
+        }
+        let bs = dk.block_size();
+        if let Some((ptr, _)) = self.map(dk, from / bs)? {
+            let zeros = vec![0; (to - from) as usize];
+            dk.write_data(ptr + from % bs, &RefData(&zeros))?;
+        }
+        Ok(())
+    }
+
+    /// Allocates the blocks within `len` bytes at `offset` which are not
+    /// mapped yet. They are zeroed, so no stale data can be read from them.
+    /// The size grows to cover the range unless `keep_size`.
+    pub(crate) fn allocate(
+        &mut self,
+        dk: &Donkey,
+        offset: u64,
+        len: u64,
+        keep_size: bool,
+    ) -> DkResult<()> {
+        let bs = dk.block_size();
+        let end = offset.saturating_add(len);
+        if self.is_inline() {
+            if end <= MAX_INLINE_LEN {
+                // The inode holds the whole range already
+                if !keep_size && end > self.inode.size {
+                    self.update_size(dk, end)?;
+                }
+                return Ok(());
+            }
+            self.move_inline_data(dk)?;
+        }
+        let last = Self::next_block_of_pos(end, bs);
+        let zeros = vec![0; bs as usize];
+        let mut bi = offset / bs;
+        while bi < last {
+            if let Some((_, run)) = self.map(dk, bi)? {
+                bi += run;
+                continue;
+            }
+            let (ptr, run) = self.map_alloc(dk, bi, last - bi)?;
+            self.dirty = true;
+            for i in 0..run {
+                dk.write_data(ptr + i * bs, &RefData(&zeros))?;
+            }
+            bi += run;
+        }
+        if !keep_size && end > self.inode.size {
+            self.update_size(dk, end)?;
+        }
+        Ok(())
+    }
+
+    /// The first mapped block from `bi` on, if any
+    pub(crate) fn next_data(&mut self, dk: &Donkey, bi: u64) -> DkResult<Option<u64>> {
+        if self.is_inline() {
+            // All data is in block 0
+            return Ok(if bi == 0 { Some(0) } else { None });
+        }
+        if let Some(extents) = &self.extents {
+            return Ok(match extents.lookup(bi) {
+                Some(_) => Some(bi),
+                None => extents.hole_len(bi).map(|hole| bi + hole),
+            });
+        }
+        if bi < 12 {
+            if let Some(i) = (bi..12).find(|&i| self.inode.ptrs[0][i as usize] != 0) {
+                return Ok(Some(i));
+            }
+        }
+        // The cached pointer blocks may be newer than those on the device
+        self.write_ptr_cache(dk)?;
+        let pc = ptrs_per_block(dk.block_size());
+        let mut start = 12;
+        for level in 1..=4 {
+            let ptr = self.inode.ptrs[level][0];
+            if let Some(found) = Self::first_mapped_rec(dk, bi, start, ptr, level as u32)? {
+                return Ok(Some(found));
+            }
+            start += pc.pow(level as u32);
+        }
+        Ok(None)
+    }
+
+    /// The first block from `from` on mapped under `ptr`, a block of `level`
+    /// mapping the blocks from `start`
+    fn first_mapped_rec(
+        dk: &Donkey,
+        from: u64,
+        start: u64,
+        ptr: u64,
+        level: u32,
+    ) -> DkResult<Option<u64>> {
+        let pc = ptrs_per_block(dk.block_size());
+        let len = pc.pow(level);
+        if ptr == 0 || start + len <= from {
+            return Ok(None);
+        }
+        if level == 0 {
+            return Ok(Some(start));
+        }
+        let pb: PtrBlock = dk.read_block(ptr)?;
+        for (i, &p) in pb.iter().enumerate() {
+            let sub_start = start + i as u64 * len / pc;
+            if let Some(found) = Self::first_mapped_rec(dk, from, sub_start, p, level - 1)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The first unmapped block from `bi` on
+    pub(crate) fn next_hole(&mut self, dk: &Donkey, mut bi: u64) -> DkResult<u64> {
+        if self.is_inline() {
+            return Ok(max(bi, 1));
+        }
+        while let Some((_, run)) = self.map(dk, bi)? {
+            bi += run;
+        }
+        Ok(bi)
+    }
+
+    /// Updates the times after the contents change.
+    fn touch_data(&mut self) {
+        self.dirty = true;
+        self.inode.mtime = SystemTime::now().into();
+        self.inode.ctime = self.inode.mtime;
+    }
+
+    /// All blocks used by the file as runs of `(ptr, len)`, including
+    /// pointer blocks, extent tree nodes and the xattr blocks.
+    pub(crate) fn used_blocks(&mut self, dk: &Donkey) -> DkResult<Vec<(u64, u64)>> {
+        let mut runs = match &self.extents {
+            Some(extents) => extents.runs(),
+            None if self.is_inline() => Vec::new(),
+            None => {
+                let mut runs = Vec::new();
+                for &ptr in self.inode.ptrs[0].iter().filter(|&&ptr| ptr != 0) {
+                    runs.push((ptr, 1));
+                }
+                for level in 1..=4 {
+                    let ptr = self.inode.ptrs[level][0];
+                    Self::used_ptr_blocks(dk, ptr, level, &mut runs)?;
+                }
+                runs
+            }
+        };
+        runs.extend(self.xattr_blocks.iter().map(|&ptr| (ptr, 1)));
+        Ok(runs)
+    }
+
+    fn used_ptr_blocks(
+        dk: &Donkey,
+        ptr: u64,
+        level: usize,
+        runs: &mut Vec<(u64, u64)>,
+    ) -> DkResult<()> {
+        if ptr == 0 {
+            return Ok(());
+        }
+        runs.push((ptr, 1));
+        if level > 0 {
+            let pb: PtrBlock = dk.read_block(ptr)?;
+            for &ptr in pb.iter() {
+                Self::used_ptr_blocks(dk, ptr, level - 1, runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves all blocks of the file at or after pointer `limit` below it,
+    /// including pointer blocks, extent tree nodes and the xattr blocks.
+    pub(crate) fn relocate(&mut self, dk: &Donkey, limit: u64) -> DkResult<()> {
+        // Directory contents are metadata
+        let journaled = self.inode.mode.is_directory();
+        let mut moved = false;
+        let inline = self.is_inline();
+        match &mut self.extents {
+            Some(extents) => {
+                let (moved_blocks, freed) = extents.relocate(dk, limit, journaled)?;
+                self.inode.blocks -= freed;
+                moved = moved_blocks || freed > 0;
+            }
+            None if inline => {}
+            None => {
+                for ptr in self.inode.ptrs[0].iter_mut().filter(|ptr| **ptr >= limit) {
+                    *ptr = dk.move_db(*ptr, journaled)?;
+                    moved = true;
+                }
+                for level in 1..=4 {
+                    let ptr = self.inode.ptrs[level][0];
+                    if ptr != 0 {
+                        let new_ptr = Self::relocate_ptr_block(dk, ptr, level, limit, journaled)?;
+                        self.inode.ptrs[level][0] = new_ptr;
+                        moved |= new_ptr != ptr;
+                    }
                 }
-                start += sublen;
             }
         }
-        Ok(from <= start)
+        // The links between the xattr blocks are fixed when they are written back
+        for ptr in self.xattr_blocks.iter_mut().filter(|ptr| **ptr >= limit) {
+            *ptr = dk.move_db(*ptr, true)?;
+            moved = true;
+        }
+        self.dirty |= moved;
+        Ok(())
     }
 
-    pub(crate) fn destroy(&mut self, dk: &mut Donkey) -> DkResult<()> {
+    /// Returns the new pointer of the pointer block at `ptr`.
+    fn relocate_ptr_block(
+        dk: &Donkey,
+        ptr: u64,
+        level: usize,
+        limit: u64,
+        journaled: bool,
+    ) -> DkResult<u64> {
+        let mut pb: PtrBlock = dk.read_block(ptr)?;
+        let mut changed = false;
+        for p in pb.iter_mut().filter(|p| **p != 0) {
+            let new_ptr = if level > 1 {
+                Self::relocate_ptr_block(dk, *p, level - 1, limit, journaled)?
+            } else if *p >= limit {
+                dk.move_db(*p, journaled)?
+            } else {
+                *p
+            };
+            changed |= new_ptr != *p;
+            *p = new_ptr;
+        }
+        if changed {
+            dk.write(ptr, &pb)?;
+        }
+        if ptr >= limit {
+            dk.move_db(ptr, true)
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    pub(crate) fn destroy(&mut self, dk: &Donkey) -> DkResult<()> {
         assert_eq!(self.inode.nlink, 0);
         self.update_size(dk, 0)?; // Release used blocks
-        if self.inode.xattr_ptr != 0 {
-            dk.free_db(self.inode.xattr_ptr)?;
+        self.write_extents(dk)?; // Release the extent tree
+        for &ptr in &self.xattr_blocks {
+            dk.free_db(ptr)?;
         }
         dk.free_inode(self.inode.ino)
     }
 }
 
+/// A reference to an open file. The file is locked
+/// while it is read or modified.
 #[derive(Debug, Clone)]
 pub struct DkFileHandle {
-    pub(crate) inner: Rc<RefCell<DkFile>>,
+    pub(crate) inner: Arc<Mutex<DkFile>>,
+    pub(crate) ino: u64,
+    pub(crate) close_file_list: Arc<Mutex<Vec<u64>>>,
     pub flags: Flags,
+    /// Whether writes through it may take the data blocks
+    /// reserved for the super user
+    pub(crate) privileged: bool,
 }
 
-impl Deref for DkFileHandle {
-    type Target = RefCell<DkFile>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+impl DkFileHandle {
+    pub(crate) fn lock(&self) -> MutexGuard<'_, DkFile> {
+        self.inner.lock().unwrap()
     }
 }
 
 impl Drop for DkFileHandle {
     fn drop(&mut self) {
-        let fh = self.borrow();
-        let ino = fh.inode.ino;
-        fh.close_file_list.borrow_mut().push(ino);
+        // The file may be locked by the dropping thread
+        self.close_file_list.lock().unwrap().push(self.ino);
     }
 }
 
 #[derive(Debug)]
 pub struct DkDir {
-    /// Only used in `drop`
     pub(crate) fh: DkFileHandle,
-    pub(crate) entries: OrdMap<OsString, u64>,
-    pub(crate) dirty: bool,
-    pub(crate) close_dir_list: Rc<RefCell<Vec<u64>>>,
+    /// Set by `rmdir`. No entry can be added afterwards.
+    pub(crate) removed: bool,
 }
 
 impl DkDir {
-    pub(crate) fn from_file(
-        fh: DkFileHandle,
-        close_dir_list: Rc<RefCell<Vec<u64>>>,
-    ) -> DkResult<Self> {
-        if !fh.borrow().inode.mode.is_directory() {
+    pub(crate) fn from_file(fh: DkFileHandle) -> DkResult<Self> {
+        if !fh.lock().inode.mode.is_directory() {
             Err(NotDirectory)
         } else {
-            let dir = DkDir {
-                fh,
-                entries: OrdMap::new(),
-                dirty: false,
-                close_dir_list,
-            };
-            Ok(dir)
+            Ok(DkDir { fh, removed: false })
         }
     }
 
-    pub(crate) fn flush(&mut self, dk: &mut Donkey) -> DkResult<()> {
-        if self.dirty {
-            self.fh.borrow_mut().seek(SeekFrom::Start(0))?;
-            let mut io = DkFileIO {
-                dk,
-                file: &mut *self.fh.borrow_mut(),
-            };
-            let mut writer = BufWriter::new(&mut io);
-            for (name, ino) in &self.entries {
-                serialize_into(&mut writer, ino)?;
-                serialize_into(&mut writer, name)?;
-            }
-            // Indicates the end of the directory
-            serialize_into(&mut writer, &(ROOT_INODE - 1))?;
-
-            self.dirty = false;
-        }
-        Ok(())
+    /// Entries are written as soon as they are changed,
+    /// so only the inode needs flushing.
+    pub(crate) fn flush(&mut self, dk: &Donkey) -> DkResult<()> {
+        self.fh.lock().flush(dk)
     }
 
-    pub(crate) fn read_fully(&mut self, dk: &mut Donkey) -> DkResult<()> {
-        if self.fh.borrow().inode.size == 0 {
-            // Directory is just created
-            return Ok(());
-        }
-
-        let file = &mut *self.fh.borrow_mut();
-        let io = DkFileIO { file, dk };
-        let mut reader = BufReader::new(io);
-        loop {
-            // `name` and `ino` are deserialized separately so that
-            // redundant copies are avoided when serializing
-            let ino = deserialize_from(&mut reader)?;
-
-            if ino >= ROOT_INODE {
-                let name: OsString = deserialize_from(&mut reader)?;
-                self.entries.insert(name, ino);
-            } else if ino == ROOT_INODE - 1 {
-                // `ino == ROOT_INODE - 1` indicates the end
-                // of the directory.
-                return Ok(());
-            } else {
-                return Err(Corrupted(format!("Invalid directory entry ino: {}", ino)));
-            }
-        }
+    fn touch(&mut self) {
+        let mut fh = self.fh.lock();
+        fh.inode.ctime = SystemTime::now().into();
+        fh.inode.mtime = SystemTime::now().into();
+        fh.dirty = true;
     }
 }
 
+/// A reference to an open directory. The directory is locked
+/// while its entries are read or modified.
 #[derive(Debug, Clone)]
 pub struct DkDirHandle {
-    pub(crate) inner: Rc<RefCell<DkDir>>,
-    pub(crate) entries: OrdMap<OsString, u64>,
+    pub(crate) inner: Arc<Mutex<DkDir>>,
+    pub(crate) ino: u64,
+    pub(crate) close_dir_list: Arc<Mutex<Vec<u64>>>,
 }
 
-impl Deref for DkDirHandle {
-    type Target = RefCell<DkDir>;
+impl DkDirHandle {
+    pub(crate) fn lock(&self) -> MutexGuard<'_, DkDir> {
+        self.inner.lock().unwrap()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    pub(crate) fn lookup(&self, dk: &Donkey, name: &OsStr) -> DkResult<Option<u64>> {
+        self.lock().lookup(dk, name)
     }
-}
 
-impl DkDirHandle {
-    pub(crate) fn add_entry(&self, name: &OsStr, ino: u64) -> DkResult<()> {
-        let res = match self.borrow_mut().entries.entry(name.to_os_string()) {
-            ordmap::Entry::Vacant(e) => {
-                e.insert(ino);
-                Ok(())
-            }
-            ordmap::Entry::Occupied(_) => Err(AlreadyExists),
-        };
-        self.borrow_mut().dirty = true;
-        let dh = self.borrow();
-        dh.fh.borrow_mut().inode.ctime = SystemTime::now().into();
-        dh.fh.borrow_mut().inode.mtime = SystemTime::now().into();
-        res
-    }
-
-    pub(crate) fn remove_entry(&self, name: &OsStr) -> DkResult<Option<u64>> {
-        let mut dir = self.borrow_mut();
-        Ok(dir.entries.remove(name).map(|ino| {
-            dir.fh.borrow_mut().inode.ctime = SystemTime::now().into();
-            dir.fh.borrow_mut().inode.mtime = SystemTime::now().into();
-            dir.dirty = true;
-            ino
-        }))
+    pub(crate) fn add_entry(
+        &self,
+        dk: &Donkey,
+        name: &OsStr,
+        ino: u64,
+        mode: FileMode,
+    ) -> DkResult<()> {
+        let mut dir = self.lock();
+        if dir.removed {
+            return Err(NotFound);
+        }
+        dir.insert(dk, name, ino, mode)?;
+        dir.touch();
+        Ok(())
+    }
+
+    pub(crate) fn remove_entry(&self, dk: &Donkey, name: &OsStr) -> DkResult<Option<u64>> {
+        let mut dir = self.lock();
+        let res = dir.remove(dk, name)?;
+        if res.is_some() {
+            dir.touch();
+        }
+        Ok(res)
+    }
+
+    /// Removes `.` and `..` if they are the only entries, and returns
+    /// the inodes they refer to. The check and the removal are done
+    /// under one lock, so that no entry sneaks in between.
+    pub(crate) fn remove_dots(&self, dk: &Donkey) -> DkResult<Vec<u64>> {
+        let mut dir = self.lock();
+        if dir.removed {
+            return Err(NotFound);
+        }
+        if dir.len(dk)? != 2 {
+            return Err(NotEmpty);
+        }
+        let mut inos = Vec::new();
+        for name in &[".", ".."] {
+            inos.extend(dir.remove(dk, OsStr::new(name))?);
+        }
+        dir.removed = true;
+        dir.touch();
+        Ok(inos)
     }
 }
 
 impl Drop for DkDirHandle {
     fn drop(&mut self) {
-        let dh = self.inner.borrow();
-        let ino = dh.fh.borrow().inode.ino;
-        dh.close_dir_list.borrow_mut().push(ino);
+        self.close_dir_list.lock().unwrap().push(self.ino);
     }
 }
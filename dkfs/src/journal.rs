@@ -0,0 +1,465 @@
+use block::*;
+use device::Device;
+use failure::Fail;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::SystemTime;
+use *;
+
+const JOURNAL_MAGIC: u64 = 0x4C41_4E52_554F_4A44;
+const HEADER: u64 = 0;
+const DESCRIPTOR: u64 = 1;
+const COMMIT: u64 = 2;
+/// Words at the start of a descriptor block before the block numbers
+const DESC_WORDS: usize = 4;
+pub(crate) const MIN_JOURNAL_BLOCKS: u64 = 16;
+
+/// A write-ahead journal placed between `Donkey` and the device.
+///
+/// Everything written through `Write` is kept in memory until `commit`,
+/// which first writes the modified blocks into the journal region and
+/// then writes them in place. Reads see the uncommitted writes.
+///
+/// The journal region starts with a header block holding the sequence
+/// number of the first transaction in the log. A transaction is a series
+/// of descriptor blocks, each followed by the blocks it describes, and
+/// ends with a commit block. Transactions are appended until the region
+/// is full. Then the device is synced and the log starts over (a checkpoint).
+/// A transaction is never split, so no more blocks than `capacity`
+/// may be pending at once.
+///
+/// File contents are written directly by `write_data` unless the block
+/// is also known to the journal.
+#[derive(Debug)]
+pub(crate) struct Journal<'a> {
+    dev: Box<Device + 'a>,
+    /// Pointer to the journal region
+    ptr: u64,
+    /// Number of blocks in the journal region
+    blocks: u64,
+    bs: u64,
+    /// Next free block in the journal region
+    head: u64,
+    /// Sequence number of the next transaction
+    seq: u64,
+    /// Blocks modified since the last commit
+    pending: BTreeMap<u64, Vec<u8>>,
+    /// Blocks logged since the last checkpoint
+    logged: HashSet<u64>,
+    /// What the blocks modified since the savepoint were before,
+    /// `None` for those which were not pending
+    undo: Option<HashMap<u64, Option<Vec<u8>>>>,
+    pos: u64,
+}
+
+impl<'a> Journal<'a> {
+    /// Initializes an empty journal region.
+    pub(crate) fn format(dev: &mut Device, ptr: u64, bs: u64) -> DkResult<()> {
+        // Start with an arbitrary sequence number, so that transactions left
+        // by a previous file system on the device are never replayed.
+        let seq = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() << 20 ^ d.subsec_nanos() as u64)
+            .unwrap_or(1);
+        let mut header = Data(vec![0; ptrs_per_block(bs) as usize]);
+        header[0] = JOURNAL_MAGIC;
+        header[1] = HEADER;
+        header[2] = seq;
+        dev.write_at(&header, ptr)?;
+        dev.write_at(&Data(vec![0u64; ptrs_per_block(bs) as usize]), ptr + bs)
+    }
+
+    /// Opens the journal described by `sb` and replays
+    /// committed transactions. When `read_only`, they are replayed
+    /// into the pending blocks only, so that nothing is written.
+    pub(crate) fn open(dev: Box<Device + 'a>, sb: &SuperBlock, read_only: bool) -> DkResult<Self> {
+        let mut journal = Journal {
+            dev,
+            ptr: sb.journal_ptr,
+            blocks: sb.journal_blocks,
+            bs: sb.block_size,
+            head: 1,
+            seq: 0,
+            pending: BTreeMap::new(),
+            logged: HashSet::new(),
+            undo: None,
+            pos: 0,
+        };
+        let header: PtrBlock = journal.read_raw(0)?;
+        if header[0] != JOURNAL_MAGIC || header[1] != HEADER {
+            return Err(Corrupted(format!(
+                "Invalid journal header at {}",
+                journal.ptr
+            )));
+        }
+        journal.seq = header[2];
+        journal.replay(read_only)?;
+        Ok(journal)
+    }
+
+    fn read_raw<T: Readable>(&mut self, index: u64) -> DkResult<T> {
+        let ptr = self.ptr + index * self.bs;
+        let bs = self.bs;
+        T::from_bytes(self.dev.read_len_at(ptr, bs)?)
+    }
+
+    fn write_raw(&mut self, index: u64, writable: &Writable) -> DkResult<()> {
+        self.dev.write_at(writable, self.ptr + index * self.bs)
+    }
+
+    fn write_in_place(&mut self, block: u64, data: &[u8]) -> DkResult<()> {
+        self.dev.write_at(&RefData(data), block * self.bs)
+    }
+
+    /// Applies all committed transactions in the log.
+    fn replay(&mut self, read_only: bool) -> DkResult<()> {
+        let per_desc = ptrs_per_block(self.bs) as usize - DESC_WORDS;
+        let mut index = 1;
+        let mut replayed = false;
+        loop {
+            let mut records = Vec::new();
+            let committed = loop {
+                if index >= self.blocks {
+                    break false;
+                }
+                let words: PtrBlock = match self.read_raw(index) {
+                    Ok(words) => words,
+                    // A torn or never written block ends the log
+                    Err(Corrupted(_)) => break false,
+                    Err(e) => return Err(e),
+                };
+                if words[0] != JOURNAL_MAGIC || words[2] != self.seq {
+                    break false;
+                }
+                match words[1] {
+                    DESCRIPTOR if words[3] as usize <= per_desc => {
+                        for i in 0..words[3] {
+                            let data: ByteData = self.read_raw(index + 1 + i)?;
+                            records.push((words[DESC_WORDS + i as usize], data));
+                        }
+                        index += 1 + words[3];
+                    }
+                    COMMIT => {
+                        index += 1;
+                        break words[3] == records.len() as u64;
+                    }
+                    _ => break false,
+                }
+            };
+            if !committed {
+                break;
+            }
+            for (block, data) in records {
+                if read_only {
+                    self.pending.insert(block, data.to_vec());
+                } else {
+                    self.write_in_place(block, &data)?;
+                }
+            }
+            self.seq += 1;
+            replayed = true;
+        }
+        if replayed && !read_only {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Number of blocks modified since the last commit
+    pub(crate) fn pending_len(&self) -> u64 {
+        self.pending.len() as u64
+    }
+
+    /// Number of blocks a transaction may contain
+    pub(crate) fn capacity(&self) -> u64 {
+        let per_desc = ptrs_per_block(self.bs) - DESC_WORDS as u64;
+        // Every `per_desc` blocks need a descriptor and there is a commit block.
+        (self.blocks - 3) * per_desc / (per_desc + 1)
+    }
+
+    /// Starts recording the pending writes, so that they can be undone
+    /// by `rollback` until the next commit.
+    pub(crate) fn savepoint(&mut self) {
+        self.undo = Some(HashMap::new());
+    }
+
+    /// Drops the pending writes made since the savepoint. Returns false
+    /// if there is no savepoint, because a commit came in between.
+    pub(crate) fn rollback(&mut self) -> bool {
+        let undo = match self.undo.take() {
+            Some(undo) => undo,
+            None => return false,
+        };
+        for (block, data) in undo {
+            match data {
+                Some(data) => self.pending.insert(block, data),
+                None => self.pending.remove(&block),
+            };
+        }
+        true
+    }
+
+    /// Writes all pending blocks to the journal and then in place.
+    pub(crate) fn commit(&mut self) -> DkResult<()> {
+        self.undo = None;
+        let pending = std::mem::replace(&mut self.pending, BTreeMap::new());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<(u64, Vec<u8>)> = pending.into_iter().collect();
+        let per_desc = ptrs_per_block(self.bs) as usize - DESC_WORDS;
+        let descs = (records.len() + per_desc - 1) / per_desc;
+        if self.head + (descs + records.len() + 1) as u64 > self.blocks {
+            self.checkpoint()?;
+        }
+        let mut head = self.head;
+        for group in records.chunks(per_desc) {
+            let mut desc = Data(vec![0; ptrs_per_block(self.bs) as usize]);
+            desc[0] = JOURNAL_MAGIC;
+            desc[1] = DESCRIPTOR;
+            desc[2] = self.seq;
+            desc[3] = group.len() as u64;
+            for (i, (block, _)) in group.iter().enumerate() {
+                desc[DESC_WORDS + i] = *block;
+            }
+            self.write_raw(head, &desc)?;
+            head += 1;
+            for (_, data) in group {
+                self.write_raw(head, &RefData(data))?;
+                head += 1;
+            }
+        }
+        self.dev.sync()?;
+
+        let mut commit = Data(vec![0; ptrs_per_block(self.bs) as usize]);
+        commit[0] = JOURNAL_MAGIC;
+        commit[1] = COMMIT;
+        commit[2] = self.seq;
+        commit[3] = records.len() as u64;
+        self.write_raw(head, &commit)?;
+        self.dev.sync()?;
+
+        for (block, data) in records {
+            self.write_in_place(block, &data)?;
+            self.logged.insert(block);
+        }
+        self.head = head + 1;
+        self.seq += 1;
+        Ok(())
+    }
+
+    /// Makes sure everything in the log is written in place and empties the log.
+    pub(crate) fn checkpoint(&mut self) -> DkResult<()> {
+        self.dev.sync()?;
+        let mut header = Data(vec![0; ptrs_per_block(self.bs) as usize]);
+        header[0] = JOURNAL_MAGIC;
+        header[1] = HEADER;
+        header[2] = self.seq;
+        self.write_raw(0, &header)?;
+        self.dev.sync()?;
+        self.head = 1;
+        self.logged.clear();
+        Ok(())
+    }
+
+    /// Writes file contents. They bypass the journal unless a block
+    /// involved is modified in the current transaction or is still in the log,
+    /// in which case a replay would overwrite the direct write.
+    pub(crate) fn write_data(&mut self, ptr: u64, writable: &Writable) -> DkResult<()> {
+        let bytes = writable.as_bytes()?;
+        let first = ptr / self.bs;
+        let last = (ptr + bytes.len() as u64 + self.bs - 1) / self.bs;
+        let journaled = (first..last)
+            .any(|block| self.pending.contains_key(&block) || self.logged.contains(&block));
+        if journaled {
+            self.write_at(&RefData(&bytes), ptr)
+        } else {
+            self.dev.write_at(&RefData(&bytes), ptr)
+        }
+    }
+
+    /// Returns the pending copy of `block`, reading it from the device if needed.
+    /// Fails with `JournalFull` if the transaction would outgrow the journal.
+    fn pending_block(&mut self, block: u64, whole: bool) -> io::Result<&mut Vec<u8>> {
+        if let Some(undo) = &mut self.undo {
+            let pending = &self.pending;
+            undo.entry(block).or_insert_with(|| pending.get(&block).cloned());
+        }
+        if !self.pending.contains_key(&block) {
+            if self.pending.len() as u64 >= self.capacity() {
+                return Err(io::Error::new(ErrorKind::Other, JournalFull.compat()));
+            }
+            let mut data = vec![0; self.bs as usize];
+            if !whole {
+                self.dev.seek(SeekFrom::Start(block * self.bs))?;
+                self.dev.read_exact(&mut data)?;
+            }
+            self.pending.insert(block, data);
+        }
+        Ok(self.pending.get_mut(&block).unwrap())
+    }
+}
+
+impl<'a> Device for Journal<'a> {
+    /// In blocks of the file system, which may differ from those of the device
+    fn block_count(&self) -> u64 {
+        self.dev.size() / self.bs
+    }
+
+    fn block_size(&self) -> u64 {
+        self.bs
+    }
+
+    fn sync(&mut self) -> DkResult<()> {
+        self.dev.sync()
+    }
+
+    fn refresh_size(&mut self) -> DkResult<u64> {
+        self.dev.refresh_size()?;
+        Ok(self.size())
+    }
+}
+
+impl<'a> Read for Journal<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (block, off) = (self.pos / self.bs, self.pos % self.bs);
+        let len = match self.pending.get(&block) {
+            Some(data) => {
+                let len = min(buf.len(), (self.bs - off) as usize);
+                buf[..len].copy_from_slice(&data[off as usize..off as usize + len]);
+                len
+            }
+            None => {
+                // Read up to the next pending block at once
+                let limit = match self.pending.range(block..).next() {
+                    Some((next, _)) => min(buf.len() as u64, next * self.bs - self.pos),
+                    None => buf.len() as u64,
+                };
+                self.dev.seek(SeekFrom::Start(self.pos))?;
+                self.dev.read(&mut buf[..limit as usize])?
+            }
+        };
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a> Write for Journal<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bs = self.bs;
+        let (block, off) = (self.pos / bs, self.pos % bs);
+        let len = min(buf.len(), (bs - off) as usize);
+        {
+            let data = self.pending_block(block, off == 0 && len as u64 == bs)?;
+            data[off as usize..off as usize + len].copy_from_slice(&buf[..len]);
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Seek for Journal<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(diff) => (self.pos as i64 + diff) as u64,
+            SeekFrom::End(diff) => (self.size() as i64 + diff) as u64,
+        };
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device::Memory;
+
+    fn sb() -> SuperBlock {
+        SuperBlock {
+            magic_number: MAGIC_NUMBER,
+            major_version: MAJOR_VERSION,
+            minor_version: MINOR_VERSION,
+            feature_compat: FeatureCompat::all(),
+            feature_ro_compat: FeatureRoCompat::all(),
+            feature_incompat: FeatureIncompat::all(),
+            block_size: 4096,
+            inode_count: 0,
+            used_inode_count: 0,
+            db_count: 0,
+            used_db_count: 0,
+            inode_bitmap_ptr: 0,
+            db_bitmap_ptr: 0,
+            first_db_ptr: 0,
+            journal_ptr: 4096,
+            journal_blocks: MIN_JOURNAL_BLOCKS,
+            reserved_db_count: 0,
+            label: [0; MAX_LABEL_LEN],
+            uuid: [0; 16],
+            generation: 0,
+            max_inode_count: 0,
+            inode_chunk_ptr: 0,
+            free_inode_chunk_ptr: 0,
+            inode_chunk_count: 0,
+            used_chunk_inode_count: 0,
+        }
+    }
+
+    #[test]
+    fn replay_committed() -> DkResult<()> {
+        let mut mem = vec![0; 1 << 20];
+        let target = 100 * 4096;
+        {
+            let mut dev = Box::new(Memory::new(&mut mem[..]));
+            Journal::format(&mut *dev, 4096, 4096)?;
+            let mut journal = Journal::open(dev, &sb(), false)?;
+            journal.write_at(&RefData(&[42; 5000]), target)?;
+            journal.commit()?;
+            // Crash after logging: the in-place writes are lost
+            journal.dev.write_at(&RefData(&[0; 5000]), target)?;
+            // This transaction is never committed
+            journal.write_at(&RefData(&[7; 10]), target)?;
+        }
+        let range = target as usize..target as usize + 5000;
+        for &read_only in &[true, false] {
+            {
+                let dev = Box::new(Memory::new(&mut mem[..]));
+                let mut journal = Journal::open(dev, &sb(), read_only)?;
+                let data: ByteData = ByteData::from_bytes(journal.read_len_at(target, 5000)?)?;
+                assert!(data.iter().all(|&b| b == 42));
+            }
+            // A read-only replay writes nothing
+            let expected = if read_only { 0 } else { 42 };
+            assert!(mem[range.clone()].iter().all(|&b| b == expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_and_rolled_back() -> DkResult<()> {
+        let mut mem = vec![0; 1 << 20];
+        let target = 100 * 4096;
+        let mut dev = Box::new(Memory::new(&mut mem[..]));
+        Journal::format(&mut *dev, 4096, 4096)?;
+        let mut journal = Journal::open(dev, &sb(), false)?;
+        journal.write_at(&RefData(&[42; 10]), target)?;
+        journal.savepoint();
+        journal.write_at(&RefData(&[7; 10]), target)?;
+        let capacity = journal.capacity();
+        let res = journal.write_at(&RefData(&vec![7; capacity as usize * 4096]), target + 4096);
+        assert!(res.is_err());
+        assert!(journal.rollback());
+        assert_eq!(journal.pending_len(), 1);
+        let data: ByteData = ByteData::from_bytes(journal.read_len_at(target, 10)?)?;
+        assert!(data.iter().all(|&b| b == 42));
+        // Nothing to roll back to after a commit
+        journal.savepoint();
+        journal.commit()?;
+        assert!(!journal.rollback());
+        Ok(())
+    }
+}
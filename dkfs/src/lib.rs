@@ -1,30 +1,27 @@
-//! Attention! This filesystem does not work
-//! in a multi-threaded environment!
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
 #[macro_use]
 extern crate bitflags;
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
-extern crate bincode;
 #[macro_use]
 extern crate nix;
 extern crate byteorder;
 extern crate im;
 
+use alloc::Bitmap;
 use block::*;
 use device::Device;
+use extent::ExtentMap;
+use journal::{Journal, MIN_JOURNAL_BLOCKS};
 use failure::Compat;
 use file::{DkDir, DkFile};
-use std::cell::RefCell;
+use std::cell::Cell;
+use std::cmp::{max, min};
 use std::collections::hash_map::HashMap;
 use std::ffi::OsStr;
-use std::io;
-use std::ops::Deref;
-use std::rc::Rc;
-use std::time::SystemTime;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 const BOOT_BLOCK_SIZE: u64 = 1024;
 const SUPER_BLOCK_SIZE: u64 = 1024;
@@ -33,14 +30,40 @@ const BOOT_BLOCK_PTR: u64 = 0;
 const SUPER_BLOCK_PTR: u64 = BOOT_BLOCK_PTR + BOOT_BLOCK_SIZE;
 const FIRST_INODE_PTR: u64 = SUPER_BLOCK_PTR + SUPER_BLOCK_SIZE;
 pub const DEFAULT_BYTES_PER_INODE: u64 = 16384;
+/// The most inodes in the table by default if inode chunks are used,
+/// since more are allocated in chunks on demand
+pub const DEFAULT_CHUNKED_INODE_COUNT: u64 = 1024;
 /// This cannot be a very small integer. Inode numbers of
 /// small integers are reserved for special use.
 pub const ROOT_INODE: u64 = 114_514;
 const MAX_NAMELEN: u32 = 256;
-
+/// Regular files and symbolic links up to this many bytes
+/// are kept in the inode instead of data blocks
+pub const MAX_INLINE_LEN: u64 = 128;
+/// Values of extended attributes are no longer than this, as in Linux
+pub const MAX_XATTR_VALUE_LEN: usize = 65536;
+/// The encoded extended attributes of an inode are no longer than this
+pub const MAX_XATTRS_LEN: usize = 1 << 20;
+/// Pending journal writes are committed at least this often
+const COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+/// Default journals are no larger than this many bytes
+const DEFAULT_MAX_JOURNAL_SIZE: u64 = 32 << 20;
+const MIN_BLOCK_SIZE: u64 = 1024;
+const MAX_BLOCK_SIZE: u64 = 65536;
+pub const MAX_LABEL_LEN: usize = 16;
+/// The first backup of the super block. Others follow at every power of 4 times it.
+const FIRST_BACKUP_PTR: u64 = 1 << 20;
+/// Inodes from this number on are in inode chunks. The rest of the number
+/// is the index of the data block of the chunk times `CHUNK_INODE_STRIDE`
+/// plus the slot of the inode in it.
+const FIRST_CHUNK_INODE: u64 = 1 << 40;
+/// Inode numbers taken by a chunk of any block size
+const CHUNK_INODE_STRIDE: u64 = MAX_BLOCK_SIZE / INODE_SIZE;
+
+pub use cache::{Cache, DEFAULT_CACHE_BLOCKS};
 pub use device::dev;
 pub use file::{DkDirHandle, DkFileHandle};
-pub use ops::Handle;
+pub use ops::{Handle, ReadDir, ReadDirPlus};
 
 #[derive(Fail, Debug)]
 pub enum DkError {
@@ -64,6 +87,24 @@ pub enum DkError {
     Invalid(String),
     #[fail(display = "Name is too long")]
     NameTooLong,
+    #[fail(display = "Value is too large")]
+    TooLarge,
+    #[fail(display = "No room is left for extended attributes")]
+    XattrsFull,
+    #[fail(display = "Operation is too large for the journal")]
+    JournalFull,
+    #[fail(display = "Is a directory")]
+    IsDirectory,
+    #[fail(display = "Cannot move a directory into itself")]
+    RenameLoop,
+    #[fail(display = "Incompatible file system: {}", _0)]
+    Incompatible(String),
+    #[fail(display = "Read-only file system")]
+    ReadOnly,
+    #[fail(display = "Permission denied")]
+    PermissionDenied,
+    #[fail(display = "Operation not permitted")]
+    NotPermitted,
     #[fail(display = "{}", _0)]
     Other(failure::Error),
 }
@@ -72,6 +113,16 @@ pub type DkResult<T> = std::result::Result<T, DkError>;
 
 use DkError::*;
 
+impl DkError {
+    /// Adds the location of a corrupted structure to the error message.
+    fn at(self, ptr: u64) -> Self {
+        match self {
+            Corrupted(msg) => Corrupted(format!("{} at {}", msg, ptr)),
+            e => e,
+        }
+    }
+}
+
 impl From<io::Error> for DkError {
     fn from(error: io::Error) -> DkError {
         // Look forward to NLL
@@ -83,98 +134,470 @@ impl From<io::Error> for DkError {
     }
 }
 
-impl From<bincode::Error> for DkError {
-    fn from(error: bincode::Error) -> DkError {
-        use bincode::ErrorKind::*;
-        match *error {
-            Io(e) => IoError(e),
-            e @ InvalidUtf8Encoding(_)
-            | e @ InvalidBoolEncoding(_)
-            | e @ InvalidCharEncoding
-            | e @ InvalidTagEncoding(_)
-            | e @ DeserializeAnyNotSupported
-            | e @ SequenceMustHaveLength => Corrupted(format!("{}", e)),
-            e => Other(e.into()),
+/// Opens a file system. It is opened read-only if it uses
+/// read-only compatible features unknown to this version.
+pub fn open<'a>(dev: Box<Device + 'a>) -> DkResult<Handle<'a>> {
+    open_with(dev, OpenOptions::default())
+}
+
+pub fn open_with<'a>(dev: Box<Device + 'a>, opts: OpenOptions) -> DkResult<Handle<'a>> {
+    let dk = load(dev, opts.backup, false)?;
+    if !dk.read_only {
+        if opts.backup.is_some() {
+            // The primary super block is rebuilt from the backup
+            dk.alloc().sb_dirty = true;
         }
+        dk.reconcile_counts();
     }
+    Ok(Handle::new(dk))
 }
 
-pub fn open<'a>(mut dev: Box<Device + 'a>) -> DkResult<Handle<'a>> {
-    let sb = SuperBlock::from_bytes(dev.read_at(SUPER_BLOCK_PTR)?)?;
-    Ok(Handle::new(Donkey::new(dev, sb)))
+/// Opens the file system without fixing anything but replaying the journal.
+/// The super block is read from the backup at the given pointer if any.
+/// Nothing is written if opened `read_only`, even by the replay.
+fn load<'a>(
+    mut dev: Box<Device + 'a>,
+    backup: Option<u64>,
+    read_only: bool,
+) -> DkResult<Donkey<'a>> {
+    let sb_ptr = match backup {
+        Some(ptr) if backup_super_block_ptrs(dev.size()).contains(&ptr) => ptr,
+        Some(ptr) => {
+            return Err(Invalid(format!(
+                "No backup super block can be at {}",
+                ptr
+            )))
+        }
+        None => SUPER_BLOCK_PTR,
+    };
+    let read_only = SuperBlockHeader::check(dev.read_at(sb_ptr)?)? || read_only;
+    let sb = SuperBlock::from_bytes(dev.read_at(sb_ptr)?).map_err(|e| e.at(sb_ptr))?;
+    let mut journal = Journal::open(dev, &sb, read_only)?;
+    // Replaying the journal may have changed the super block
+    let read_only = SuperBlockHeader::check(journal.read_at(sb_ptr)?)? || read_only;
+    let sb = SuperBlock::from_bytes(journal.read_at(sb_ptr)?).map_err(|e| e.at(sb_ptr))?;
+    Donkey::new(journal, sb, read_only)
+}
+
+/// Every place where a backup of the super block may be on a device
+/// of `size` bytes, whatever the block size is. They depend on nothing
+/// in the super block, so they can be found without it.
+pub fn backup_super_block_ptrs(size: u64) -> Vec<u64> {
+    let mut ptrs = backups_in_data_area(FIRST_INODE_PTR, size);
+    ptrs.pop();
+    let mut bs = MIN_BLOCK_SIZE;
+    while bs <= MAX_BLOCK_SIZE {
+        // The end of a file system of block size `bs` filling the device
+        let end = size / bs * bs;
+        if end >= FIRST_INODE_PTR + SUPER_BLOCK_SIZE {
+            ptrs.push(end - SUPER_BLOCK_SIZE);
+        }
+        bs *= 2;
+    }
+    ptrs.sort();
+    ptrs.dedup();
+    ptrs
+}
+
+/// The backups of the super block kept in the data area from `first_db_ptr`
+/// to `db_end`: those at `FIRST_BACKUP_PTR` times the powers of 4,
+/// and the last 1 KiB of the data area. Growing the data area only adds
+/// to them besides moving the last one.
+fn backups_in_data_area(first_db_ptr: u64, db_end: u64) -> Vec<u64> {
+    let mut ptrs = Vec::new();
+    if db_end < first_db_ptr + SUPER_BLOCK_SIZE {
+        return ptrs;
+    }
+    let last = db_end - SUPER_BLOCK_SIZE;
+    let mut ptr = FIRST_BACKUP_PTR;
+    while ptr < last {
+        if ptr >= first_db_ptr {
+            ptrs.push(ptr);
+        }
+        ptr = match ptr.checked_mul(4) {
+            Some(ptr) => ptr,
+            None => break,
+        };
+    }
+    ptrs.push(last);
+    ptrs
+}
+
+/// The backups of the super block kept by the file system described by `sb`
+fn backup_ptrs(sb: &SuperBlock) -> Vec<u64> {
+    if sb.feature_compat.contains(FeatureCompat::BACKUP_SUPER) {
+        backups_in_data_area(sb.first_db_ptr, sb.db_end())
+    } else {
+        Vec::new()
+    }
+}
+
+/// Pointers to the metadata in the data area: the backups of the super block,
+/// and the data block bitmap once growing the file system has moved it there.
+fn metadata_in_data_area(sb: &SuperBlock) -> Vec<u64> {
+    let mut ptrs = backup_ptrs(sb);
+    if sb.db_bitmap_ptr >= sb.first_db_ptr {
+        let bs = sb.block_size;
+        let blocks = (Bitmap::bytes_len(sb.db_count) + bs - 1) / bs;
+        ptrs.extend((0..blocks).map(|i| sb.db_bitmap_ptr + i * bs));
+    }
+    ptrs
+}
+
+/// Bytes the data block bitmap can take up where it is
+fn db_bitmap_room(sb: &SuperBlock) -> u64 {
+    let bs = sb.block_size;
+    if sb.db_bitmap_ptr >= sb.first_db_ptr {
+        // Its own blocks in the data area
+        (Bitmap::bytes_len(sb.db_count) + bs - 1) / bs * bs
+    } else {
+        room_before_data_area(sb, sb.db_bitmap_ptr)
+    }
+}
+
+/// Bytes from `ptr` up to the structure after it, which is the journal
+/// in file systems made by `format`
+fn room_before_data_area(sb: &SuperBlock, ptr: u64) -> u64 {
+    let next = [sb.journal_ptr, sb.first_db_ptr]
+        .iter()
+        .cloned()
+        .filter(|&next| next > ptr)
+        .min()
+        .unwrap_or(sb.first_db_ptr);
+    next - ptr
+}
+
+/// Where `format` puts the data block bitmap, right after the inode bitmap
+fn front_db_bitmap_ptr(sb: &SuperBlock) -> u64 {
+    sb.inode_bitmap_ptr + Bitmap::bytes_len(sb.max_inode_count())
+}
+
+/// Rebuilds the primary super block from the valid backup written last.
+/// Returns the pointer to the backup used.
+pub fn recover_super_block(dev: &mut Device) -> DkResult<u64> {
+    let (ptr, sb) = newest_backup(dev)?;
+    dev.write_at(&sb, SUPER_BLOCK_PTR)?;
+    dev.sync()?;
+    Ok(ptr)
+}
+
+/// Finds the valid backup super block written last.
+fn newest_backup(dev: &mut Device) -> DkResult<(u64, SuperBlock)> {
+    let mut newest: Option<(u64, SuperBlock)> = None;
+    for ptr in backup_super_block_ptrs(dev.size()) {
+        // Unreadable backups are skipped
+        let sb = match SuperBlock::from_bytes(dev.read_at(ptr)?) {
+            Ok(sb) => sb,
+            Err(_) => continue,
+        };
+        let newer = match newest {
+            Some((_, ref n)) => sb.generation > n.generation,
+            None => true,
+        };
+        if newer {
+            newest = Some((ptr, sb));
+        }
+    }
+    newest.ok_or_else(|| Corrupted("No valid backup super block".to_string()))
+}
+
+/// Computes where the structures of a new file system go on `dev`
+/// without writing anything.
+pub fn layout(dev: &Device, opts: &FormatOptions) -> DkResult<Layout> {
+    let block_size = opts.block_size.unwrap_or_else(|| dev.block_size());
+    if !block_size.is_power_of_two()
+        || block_size < MIN_BLOCK_SIZE
+        || block_size > MAX_BLOCK_SIZE
+    {
+        return Err(Invalid(format!(
+            "Block size must be a power of 2 from {} to {}",
+            MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+        )));
+    }
+    if opts.label.len() > MAX_LABEL_LEN {
+        return Err(Invalid(format!(
+            "Volume label is longer than {} bytes",
+            MAX_LABEL_LEN
+        )));
+    }
+    if !(opts.reserved_percent >= 0.0 && opts.reserved_percent <= 50.0) {
+        return Err(Invalid(
+            "Reserved blocks must be from 0% to 50%".to_string(),
+        ));
+    }
+    let block_count = dev.size() / block_size;
+    let inode_count = match (opts.inode_count, opts.bytes_per_inode) {
+        (Some(count), _) => count,
+        (None, Some(0)) => 0,
+        (None, Some(bytes_per_inode)) => dev.size() / bytes_per_inode,
+        (None, None) if opts.inode_chunks => min(
+            dev.size() / DEFAULT_BYTES_PER_INODE,
+            DEFAULT_CHUNKED_INODE_COUNT,
+        ),
+        (None, None) => dev.size() / DEFAULT_BYTES_PER_INODE,
+    };
+    if inode_count == 0 {
+        return Err(Invalid("At least one inode is needed".to_string()));
+    }
+    let max_inode_count = opts.max_inode_count.unwrap_or(inode_count);
+    if max_inode_count < inode_count {
+        return Err(Invalid(format!(
+            "Room for {} inodes is less than the {} inodes",
+            max_inode_count, inode_count
+        )));
+    }
+    if max_inode_count > FIRST_CHUNK_INODE - ROOT_INODE {
+        return Err(Invalid(format!(
+            "The inode table cannot hold more than {} inodes",
+            FIRST_CHUNK_INODE - ROOT_INODE
+        )));
+    }
+    let inode_bitmap_ptr = FIRST_INODE_PTR + INODE_SIZE * max_inode_count;
+    let db_bitmap_ptr = inode_bitmap_ptr + Bitmap::bytes_len(max_inode_count);
+    let journal_blocks = opts.journal_blocks.unwrap_or_else(|| {
+        max(
+            MIN_JOURNAL_BLOCKS,
+            min(block_count / 64, DEFAULT_MAX_JOURNAL_SIZE / block_size),
+        )
+    });
+    if journal_blocks < MIN_JOURNAL_BLOCKS {
+        return Err(Invalid(format!(
+            "The journal needs at least {} blocks",
+            MIN_JOURNAL_BLOCKS
+        )));
+    }
+
+    // The data block bitmap shrinks as the data area shrinks,
+    // so find the largest data area that leaves room for its bitmap.
+    // The journal is placed between the bitmaps and the data area.
+    let mut db_count = block_count;
+    let journal_ptr = loop {
+        let used_bytes = db_bitmap_ptr + Bitmap::bytes_len(db_count);
+        let used_blocks = (used_bytes + block_size - 1) / block_size + journal_blocks;
+        if used_blocks + db_count <= block_count {
+            break (used_blocks - journal_blocks) * block_size;
+        }
+        db_count = block_count.saturating_sub(used_blocks);
+    };
+    if db_count == 0 {
+        return Err(Exhausted);
+    }
+    let first_db_ptr = journal_ptr + journal_blocks * block_size;
+    let reserved_db_count = (db_count as f64 * opts.reserved_percent / 100.0) as u64;
+    let backup_ptrs = backups_in_data_area(first_db_ptr, first_db_ptr + db_count * block_size);
+
+    Ok(Layout {
+        block_size,
+        block_count,
+        inode_count,
+        max_inode_count,
+        inode_bitmap_ptr,
+        db_bitmap_ptr,
+        journal_ptr,
+        journal_blocks,
+        first_db_ptr,
+        db_count,
+        reserved_db_count,
+        backup_ptrs,
+    })
 }
 
 pub fn format<'a>(mut dev: Box<Device + 'a>, opts: FormatOptions) -> DkResult<Handle<'a>> {
-    let block_size = dev.block_size();
-    let inode_count = dev.size() / opts.bytes_per_inode;
-    let used_blocks = (FIRST_INODE_PTR + INODE_SIZE * inode_count + block_size - 1) / block_size;
-    let first_db_ptr = used_blocks * block_size;
+    let layout = layout(&*dev, &opts)?;
+    let block_size = layout.block_size;
+    let mut label = [0; MAX_LABEL_LEN];
+    label[..opts.label.len()].copy_from_slice(opts.label.as_bytes());
+    let uuid = match opts.uuid {
+        Some(uuid) => uuid,
+        None => random_uuid()?,
+    };
 
     // No plan to implement a real boot block here.
 
+    // The blocks of the backup super blocks are never freed
+    let mut db_bitmap = Bitmap::new(layout.db_bitmap_ptr, layout.db_count);
+    for ptr in &layout.backup_ptrs {
+        db_bitmap.set((ptr - layout.first_db_ptr) / block_size);
+    }
+
     // Make the initial super block
     let sb = SuperBlock {
         magic_number: block::MAGIC_NUMBER,
+        major_version: block::MAJOR_VERSION,
+        minor_version: block::MINOR_VERSION,
+        feature_compat: FeatureCompat::HAS_JOURNAL | FeatureCompat::BACKUP_SUPER,
+        feature_ro_compat: FeatureRoCompat::METADATA_CSUM,
+        feature_incompat: {
+            let mut features = FeatureIncompat::empty();
+            features.set(FeatureIncompat::EXTENTS, opts.extents);
+            features.set(FeatureIncompat::INODE_CHUNKS, opts.inode_chunks);
+            features.set(FeatureIncompat::INLINE_DATA, opts.inline_data);
+            features.set(FeatureIncompat::LARGE_XATTRS, opts.large_xattrs);
+            features
+        },
         block_size,
-        inode_count,
+        inode_count: layout.inode_count,
         used_inode_count: 0,
-        db_count: dev.block_count() - first_db_ptr / block_size,
-        used_db_count: 0,
-        inode_fl_ptr: FIRST_INODE_PTR,
-        db_fl_ptr: first_db_ptr,
+        db_count: layout.db_count,
+        used_db_count: db_bitmap.count(),
+        inode_bitmap_ptr: layout.inode_bitmap_ptr,
+        db_bitmap_ptr: layout.db_bitmap_ptr,
+        first_db_ptr: layout.first_db_ptr,
+        journal_ptr: layout.journal_ptr,
+        journal_blocks: layout.journal_blocks,
+        reserved_db_count: layout.reserved_db_count,
+        label,
+        uuid,
+        generation: 0,
+        max_inode_count: layout.max_inode_count,
+        inode_chunk_ptr: 0,
+        free_inode_chunk_ptr: 0,
+        inode_chunk_count: 0,
+        used_chunk_inode_count: 0,
     };
     dev.write_at(&sb, SUPER_BLOCK_PTR)?;
+    for &ptr in &layout.backup_ptrs {
+        dev.write_at(&sb, ptr)?;
+    }
 
-    // Make the initial free inode
-    let fi = FreeList {
-        next_ptr: 0,
-        size: inode_count * INODE_SIZE,
-    };
-    dev.write_at(&fi, FIRST_INODE_PTR)?;
-
-    // Make the initial free data block
-    let fb = FreeList {
-        next_ptr: 0,
-        size: dev.size() - first_db_ptr,
-    };
-    dev.write_at(&fb, first_db_ptr)?;
+    Bitmap::new(layout.inode_bitmap_ptr, layout.inode_count).flush_all(&mut *dev)?;
+    db_bitmap.flush_all(&mut *dev)?;
+    Journal::format(&mut *dev, layout.journal_ptr, block_size)?;
 
-    let mut dk = Donkey::new(dev, sb);
+    let journal = Journal::open(dev, &sb, false)?;
+    let dk = Donkey::new(journal, sb, false)?;
     dk.create_root()?;
+    dk.commit()?;
     Ok(Handle::new(dk))
 }
 
+/// A random (version 4) UUID
+fn random_uuid() -> DkResult<[u8; 16]> {
+    let mut uuid = [0; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = uuid[6] & 0x0f | 0x40;
+    uuid[8] = uuid[8] & 0x3f | 0x80;
+    Ok(uuid)
+}
+
+/// State guarded by the allocator lock
 #[derive(Debug)]
-pub struct Donkey<'a> {
-    dev: Box<Device + 'a>,
+struct Allocator {
+    sb: SuperBlock,
+    /// Whether `sb` has changes not written to the device yet
+    sb_dirty: bool,
+    inode_bitmap: Bitmap,
+    db_bitmap: Bitmap,
+}
+
+impl Allocator {
+    fn flush_sb(&mut self, dev: &mut Journal) -> DkResult<()> {
+        self.sb_dirty = false;
+        dev.write_at(&self.sb, SUPER_BLOCK_PTR)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Txn {
+    /// Number of operations in progress
+    active: usize,
+    /// Number of threads waiting to sync
+    waiting: usize,
+    /// Number of operations begun since `saved` was taken
+    ops: usize,
+    saved: Option<Savepoint>,
+}
+
+/// What is kept in memory only when no operation is in progress,
+/// so that a failed operation can be undone.
+#[derive(Debug)]
+struct Savepoint {
     sb: SuperBlock,
-    opened_files: HashMap<u64, Rc<RefCell<DkFile>>>,
-    opened_dirs: HashMap<u64, Rc<RefCell<DkDir>>>,
-    close_file_list: Rc<RefCell<Vec<u64>>>,
-    close_dir_list: Rc<RefCell<Vec<u64>>>,
+    sb_dirty: bool,
+    /// Open files with changes not written yet
+    files: HashMap<u64, DkFile>,
+}
+
+thread_local! {
+    /// Nesting level of operations in progress on the current thread
+    static OP_DEPTH: Cell<usize> = Cell::new(0);
+    /// Whether the operation in progress on the current thread may take
+    /// the data blocks reserved for the super user
+    static PRIVILEGED: Cell<bool> = Cell::new(true);
+}
+
+/// The file system shared by all handles.
+///
+/// Locks are taken in this order: `txn`, `rename_lock`, `opened_dirs`,
+/// a directory, `opened_files`, a file, `alloc` and at last `dev`.
+/// Only a rename may hold more than one directory at a time.
+#[derive(Debug)]
+pub struct Donkey<'a> {
+    /// The device I/O lock
+    dev: Mutex<Journal<'a>>,
+    /// The allocator lock
+    alloc: Mutex<Allocator>,
+    bs: u64,
+    /// Never changes, even when the file system is resized
+    first_db_ptr: u64,
+    /// Set when the file system uses unknown read-only compatible features
+    read_only: bool,
+    opened_files: Mutex<HashMap<u64, Arc<Mutex<DkFile>>>>,
+    opened_dirs: Mutex<HashMap<u64, Arc<Mutex<DkDir>>>>,
+    close_file_list: Arc<Mutex<Vec<u64>>>,
+    close_dir_list: Arc<Mutex<Vec<u64>>>,
+    /// Held exclusively by renames, which may change the ancestors
+    /// of a directory, and shared by other changes to directories.
+    rename_lock: RwLock<()>,
+    txn: Mutex<Txn>,
+    /// Notified when no operation is in progress
+    idle: Condvar,
+    last_commit: Mutex<Instant>,
 }
 
 impl<'a> Donkey<'a> {
-    fn new(dev: Box<Device + 'a>, sb: SuperBlock) -> Donkey<'a> {
-        Donkey {
-            dev,
-            sb,
-            opened_files: HashMap::new(),
-            opened_dirs: HashMap::new(),
-            close_file_list: Rc::new(RefCell::new(Vec::new())),
-            close_dir_list: Rc::new(RefCell::new(Vec::new())),
-        }
+    fn new(mut dev: Journal<'a>, sb: SuperBlock, read_only: bool) -> DkResult<Donkey<'a>> {
+        let inode_bitmap = Bitmap::load(&mut dev, sb.inode_bitmap_ptr, sb.inode_count)?;
+        let db_bitmap = Bitmap::load(&mut dev, sb.db_bitmap_ptr, sb.db_count)?;
+        Ok(Donkey {
+            dev: Mutex::new(dev),
+            bs: sb.block_size,
+            first_db_ptr: sb.first_db_ptr,
+            read_only,
+            alloc: Mutex::new(Allocator {
+                sb,
+                sb_dirty: false,
+                inode_bitmap,
+                db_bitmap,
+            }),
+            opened_files: Mutex::new(HashMap::new()),
+            opened_dirs: Mutex::new(HashMap::new()),
+            close_file_list: Arc::new(Mutex::new(Vec::new())),
+            close_dir_list: Arc::new(Mutex::new(Vec::new())),
+            rename_lock: RwLock::new(()),
+            txn: Mutex::new(Txn::default()),
+            idle: Condvar::new(),
+            last_commit: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn dev(&self) -> MutexGuard<'_, Journal<'a>> {
+        self.dev.lock().unwrap()
+    }
+
+    fn alloc(&self) -> MutexGuard<'_, Allocator> {
+        self.alloc.lock().unwrap()
     }
 
     /// This function is only called in `format`
     /// because we assume root inode is not allocated yet.
-    fn create_root(&mut self) -> DkResult<()> {
+    fn create_root(&self) -> DkResult<()> {
         let perm = FileMode::USER_RWX
             | FileMode::GROUP_READ
             | FileMode::GROUP_EXECUTE
             | FileMode::OTHERS_READ
             | FileMode::OTHERS_EXECUTE;
-        let root_inode = self.mkdir(ROOT_INODE, perm, 0, 0)?;
+        let root_inode = self.mkdir(ROOT_INODE, perm, 0, 0, None)?;
 
         if root_inode == ROOT_INODE {
             self.close_dirs_in_list()?;
@@ -188,52 +611,358 @@ impl<'a> Donkey<'a> {
         }
     }
 
-    fn read_into(&mut self, ptr: u64, mut dst: &mut [u8]) -> DkResult<u64> {
-        Ok(io::copy(
-            &mut self.dev.read_len_at(ptr, dst.len() as u64)?,
-            &mut dst,
-        )?)
+    fn read_into(&self, ptr: u64, mut dst: &mut [u8]) -> DkResult<u64> {
+        let mut dev = self.dev();
+        let len = io::copy(&mut dev.read_len_at(ptr, dst.len() as u64)?, &mut dst)?;
+        Ok(len)
+    }
+
+    fn read<T: Readable>(&self, ptr: u64) -> DkResult<T> {
+        let mut dev = self.dev();
+        let res = <T as Readable>::from_bytes(dev.read_at(ptr)?).map_err(|e| e.at(ptr));
+        res
     }
 
-    fn read<T: Readable>(&mut self, ptr: u64) -> DkResult<T> {
-        <T as Readable>::from_bytes(self.dev.read_at(ptr)?)
+    fn read_block<T: Readable>(&self, ptr: u64) -> DkResult<T> {
+        let mut dev = self.dev();
+        let res = <T as Readable>::from_bytes(dev.read_block_at(ptr)?).map_err(|e| e.at(ptr));
+        res
     }
 
-    fn read_block<T: Readable>(&mut self, ptr: u64) -> DkResult<T> {
-        <T as Readable>::from_bytes(self.dev.read_block_at(ptr)?)
+    fn write(&self, ptr: u64, writable: &Writable) -> DkResult<()> {
+        self.dev().write_at(writable, ptr)
     }
 
-    fn write(&mut self, ptr: u64, writable: &Writable) -> DkResult<()> {
-        self.dev.write_at(writable, ptr)
+    /// Writes file contents, which are not journaled.
+    fn write_data(&self, ptr: u64, writable: &Writable) -> DkResult<()> {
+        self.dev().write_data(ptr, writable)
     }
 
     fn block_size(&self) -> u64 {
-        self.sb.block_size
+        self.bs
+    }
+
+    /// Starts an operation. Operations may be nested on one thread.
+    fn begin_op(&self) {
+        let depth = OP_DEPTH.with(|d| {
+            d.set(d.get() + 1);
+            d.get()
+        });
+        if depth > 1 {
+            return;
+        }
+        let mut txn = self.txn.lock().unwrap();
+        // Otherwise a busy file system may never get to commit
+        while txn.active > 0 && (txn.waiting > 0 || self.commit_due()) {
+            txn = self.idle.wait(txn).unwrap();
+        }
+        if txn.active == 0 {
+            txn.saved = Some(self.savepoint());
+            txn.ops = 0;
+        }
+        txn.active += 1;
+        txn.ops += 1;
     }
 
-    fn flush_sb(&mut self) -> DkResult<()> {
-        self.dev.write_at(&self.sb, SUPER_BLOCK_PTR)
+    /// Finishes an operation. Writes of finished operations are
+    /// committed together once enough of them are pending and
+    /// no operation is in progress.
+    ///
+    /// A failed operation is rolled back if no other operation
+    /// has run alongside it and nothing has been committed since it began.
+    /// Otherwise its writes cannot be told apart from those of the others.
+    fn end_op(&self, failed: bool) -> DkResult<()> {
+        let depth = OP_DEPTH.with(|d| {
+            d.set(d.get() - 1);
+            d.get()
+        });
+        if depth > 0 {
+            return Ok(());
+        }
+        let mut txn = self.txn.lock().unwrap();
+        txn.active -= 1;
+        let rolled_back = match txn.saved.take() {
+            Some(saved) if failed && txn.ops == 1 => self.rollback(saved),
+            _ => Ok(()),
+        };
+        if txn.active > 0 {
+            return rolled_back;
+        }
+        let res = rolled_back.and_then(|_| {
+            if self.commit_due() {
+                // Released files and directories are flushed first
+                // so that the transaction does not end in the middle of an operation.
+                self.close_dirs_in_list()
+                    .and_then(|_| self.close_files_in_list())
+                    .and_then(|_| self.commit())
+            } else {
+                Ok(())
+            }
+        });
+        self.idle.notify_all();
+        res
     }
 
-    fn close_files_in_list(&mut self) -> DkResult<()> {
+    fn savepoint(&self) -> Savepoint {
+        let mut files = HashMap::new();
+        for (&ino, f) in self.opened_files.lock().unwrap().iter() {
+            let f = f.lock().unwrap();
+            if f.dirty {
+                files.insert(ino, f.clone());
+            }
+        }
+        let alloc = self.alloc();
+        self.dev().savepoint();
+        Savepoint {
+            sb: alloc.sb.clone(),
+            sb_dirty: alloc.sb_dirty,
+            files,
+        }
+    }
+
+    /// Restores the state of `saved`. Files and directories opened since then
+    /// are dropped or read again.
+    fn rollback(&self, mut saved: Savepoint) -> DkResult<()> {
+        if !self.dev().rollback() {
+            // Something was committed in the meantime
+            return Ok(());
+        }
+        self.opened_dirs
+            .lock()
+            .unwrap()
+            .retain(|_, d| Arc::strong_count(d) > 1);
+        let mut opened = self.opened_files.lock().unwrap();
+        let inos: Vec<u64> = opened.keys().cloned().collect();
+        for ino in inos {
+            if let Some(f) = saved.files.remove(&ino) {
+                *opened[&ino].lock().unwrap() = f;
+            } else if Arc::strong_count(&opened[&ino]) == 1 {
+                opened.remove(&ino);
+            } else {
+                *opened[&ino].lock().unwrap() = self.load_file(ino)?;
+            }
+        }
+        // Closed by the failed operation, but their changes are gone
+        for (ino, f) in saved.files {
+            opened.insert(ino, Arc::new(Mutex::new(f)));
+            self.close_file_list.lock().unwrap().push(ino);
+        }
+        let mut alloc = self.alloc();
+        let mut dev = self.dev();
+        alloc.sb = saved.sb;
+        alloc.sb_dirty = saved.sb_dirty;
+        alloc.inode_bitmap.reload(&mut *dev)?;
+        alloc.db_bitmap.reload(&mut *dev)?;
+        Ok(())
+    }
+
+    fn commit_due(&self) -> bool {
+        let dev = self.dev();
+        dev.pending_len() * 4 >= dev.capacity()
+            || self.last_commit.lock().unwrap().elapsed() >= COMMIT_INTERVAL
+    }
+
+    fn commit(&self) -> DkResult<()> {
+        let mut alloc = self.alloc();
+        let mut dev = self.dev();
+        if alloc.sb_dirty {
+            alloc.flush_sb(&mut dev)?;
+        }
+        dev.commit()?;
+        alloc.db_bitmap.release_reserved();
+        *self.last_commit.lock().unwrap() = Instant::now();
+        Ok(())
+    }
+
+    /// Runs `f` after operations in progress finish.
+    /// No operation starts before it returns.
+    fn exclusive<T, F: FnOnce() -> DkResult<T>>(&self, f: F) -> DkResult<T> {
+        let mut txn = self.txn.lock().unwrap();
+        txn.waiting += 1;
+        while txn.active > 0 {
+            txn = self.idle.wait(txn).unwrap();
+        }
+        txn.waiting -= 1;
+        let res = f();
+        self.idle.notify_all();
+        res
+    }
+
+    /// Commits and makes sure everything written reaches the storage.
+    /// Operations in progress are waited for.
+    fn sync(&self) -> DkResult<()> {
+        self.exclusive(|| self.commit().and_then(|_| self.dev().sync()))
+    }
+
+    /// Extends the data area to fill the first `new_size` bytes of the device,
+    /// and the inode table in proportion as far as it has room.
+    ///
+    /// The data block bitmap grows in place if there is room after it.
+    /// Otherwise it moves to the start of the new part of the data area.
+    /// New bitmap bytes are written directly, as nothing refers to them
+    /// until the new super block is committed.
+    fn grow(&self, new_size: u64) -> DkResult<()> {
+        self.exclusive(|| {
+            // The journal must not know the bytes written directly
+            self.commit()?;
+            let mut alloc = self.alloc();
+            let alloc = &mut *alloc;
+            let mut dev = self.dev();
+            dev.checkpoint()?;
+
+            let dev_size = dev.refresh_size()?;
+            if new_size > dev_size {
+                return Err(Invalid(format!("The device has only {} bytes", dev_size)));
+            }
+            let bs = alloc.sb.block_size;
+            let first_db = alloc.sb.first_db_ptr / bs;
+            let old_blocks = first_db + alloc.sb.db_count;
+            let new_blocks = new_size / bs;
+            if new_blocks < old_blocks {
+                return Err(Invalid(
+                    "A file system cannot shrink while in use".to_string(),
+                ));
+            }
+            if new_blocks == old_blocks {
+                return Ok(());
+            }
+
+            let mut sb = alloc.sb.clone();
+            sb.db_count = new_blocks - first_db;
+            let scale = |count: u64| (count as f64 * new_blocks as f64 / old_blocks as f64) as u64;
+            sb.inode_count = max(sb.inode_count, min(sb.max_inode_count(), scale(sb.inode_count)));
+            sb.reserved_db_count = scale(sb.reserved_db_count);
+            let index = |ptr: u64| (ptr - sb.first_db_ptr) / bs;
+
+            // Blocks to take and to free in the data area
+            let old_backups = backup_ptrs(&alloc.sb);
+            let new_backups = backup_ptrs(&sb);
+            let mut taken: Vec<u64> = new_backups
+                .iter()
+                .filter(|p| !old_backups.contains(p))
+                .map(|&p| index(p))
+                .collect();
+            let mut freed: Vec<u64> = old_backups
+                .iter()
+                .filter(|p| !new_backups.contains(p))
+                .map(|&p| index(p))
+                .collect();
+            let bitmap_bytes = Bitmap::bytes_len(sb.db_count);
+            let moved = bitmap_bytes > db_bitmap_room(&alloc.sb);
+            if moved {
+                let len = (bitmap_bytes + bs - 1) / bs;
+                let backup_blocks: Vec<u64> = new_backups.iter().map(|&p| index(p)).collect();
+                let mut start = alloc.sb.db_count;
+                while let Some(&b) = backup_blocks
+                    .iter()
+                    .find(|&&b| b >= start && b < start + len)
+                {
+                    start = b + 1;
+                }
+                if start + len > sb.db_count {
+                    return Err(Invalid(format!(
+                        "{} more blocks are needed to grow the file system",
+                        start + len - sb.db_count
+                    )));
+                }
+                if alloc.sb.db_bitmap_ptr >= alloc.sb.first_db_ptr {
+                    let old_len = (Bitmap::bytes_len(alloc.sb.db_count) + bs - 1) / bs;
+                    let old_start = index(alloc.sb.db_bitmap_ptr);
+                    freed.extend(old_start..old_start + old_len);
+                }
+                taken.extend(start..start + len);
+                sb.db_bitmap_ptr = sb.first_db_ptr + start * bs;
+            }
+
+            let db_bitmap = &mut alloc.db_bitmap;
+            let old_bytes = Bitmap::bytes_len(db_bitmap.len);
+            db_bitmap.grow(sb.db_count);
+            for &i in &taken {
+                db_bitmap.set(i);
+            }
+            for &i in &freed {
+                // Reused only after the new super block is committed
+                db_bitmap.clear_reserved(i);
+            }
+            if moved {
+                db_bitmap.ptr = sb.db_bitmap_ptr;
+                db_bitmap.write_unjournaled(&mut dev, 0)?;
+            } else {
+                db_bitmap.write_unjournaled(&mut dev, old_bytes)?;
+                for &i in taken.iter().chain(&freed).filter(|&&i| i / 8 < old_bytes) {
+                    db_bitmap.flush(&mut *dev, i, 1)?;
+                }
+            }
+            let inode_bitmap = &mut alloc.inode_bitmap;
+            let old_bytes = Bitmap::bytes_len(inode_bitmap.len);
+            inode_bitmap.grow(sb.inode_count);
+            inode_bitmap.write_unjournaled(&mut dev, old_bytes)?;
+            dev.sync()?;
+
+            sb.used_db_count = db_bitmap.count();
+            sb.used_inode_count = inode_bitmap.count();
+            sb.generation += 1;
+            alloc.sb = sb;
+            alloc.flush_sb(&mut dev)?;
+            for ptr in new_backups {
+                dev.write_at(&alloc.sb, ptr)?;
+            }
+            dev.commit()?;
+            alloc.db_bitmap.release_reserved();
+            dev.sync()
+        })
+    }
+
+    /// Copies the super block to the backups. This is only done on unmount,
+    /// so the backups lag behind while the file system is mounted.
+    fn write_backups(&self) -> DkResult<()> {
+        let mut alloc = self.alloc();
+        let mut dev = self.dev();
+        alloc.sb.generation += 1;
+        alloc.flush_sb(&mut dev)?;
+        for ptr in backup_ptrs(&alloc.sb) {
+            dev.write_at(&alloc.sb, ptr)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the counters in the super block agree with the bitmaps.
+    /// The counters are written lazily, so they may be stale
+    /// if the file system was not unmounted cleanly.
+    fn reconcile_counts(&self) {
+        let mut alloc = self.alloc();
+        let used_inode_count = alloc.inode_bitmap.count();
+        let used_db_count = alloc.db_bitmap.count();
+        if (used_inode_count, used_db_count) != (alloc.sb.used_inode_count, alloc.sb.used_db_count)
+        {
+            alloc.sb.used_inode_count = used_inode_count;
+            alloc.sb.used_db_count = used_db_count;
+            alloc.sb_dirty = true;
+        }
+    }
+
+    fn close_files_in_list(&self) -> DkResult<()> {
+        let mut opened = self.opened_files.lock().unwrap();
         loop {
-            let ino = self.close_file_list.borrow_mut().pop();
+            let ino = self.close_file_list.lock().unwrap().pop();
             match ino {
                 Some(ino) => {
-                    let drop = self.opened_files.get(&ino).and_then(|rc| {
-                        if Rc::strong_count(rc) == 1 {
-                            // The only rc is in the HashMap
-                            Some(rc.clone())
+                    let drop = opened.get(&ino).and_then(|f| {
+                        if Arc::strong_count(f) == 1 {
+                            // The only reference is in the HashMap,
+                            // so nobody else can lock the file.
+                            Some(f.clone())
                         } else {
                             None
                         }
                     });
-                    if let Some(rc) = drop {
-                        rc.borrow_mut().flush(self)?;
-                        if rc.borrow().inode.nlink == 0 {
-                            rc.borrow_mut().destroy(self)?;
+                    if let Some(f) = drop {
+                        let mut f = f.lock().unwrap();
+                        f.flush(self)?;
+                        if f.inode.nlink == 0 {
+                            f.destroy(self)?;
                         }
-                        self.opened_files.remove(&ino);
+                        opened.remove(&ino);
                     }
                 }
                 None => return Ok(()),
@@ -241,21 +970,22 @@ impl<'a> Donkey<'a> {
         }
     }
 
-    fn close_dirs_in_list(&mut self) -> DkResult<()> {
+    fn close_dirs_in_list(&self) -> DkResult<()> {
+        let mut opened = self.opened_dirs.lock().unwrap();
         loop {
-            let ino = self.close_dir_list.borrow_mut().pop();
+            let ino = self.close_dir_list.lock().unwrap().pop();
             match ino {
                 Some(ino) => {
-                    let drop = self.opened_dirs.get(&ino).and_then(|rc| {
-                        if Rc::strong_count(rc) == 1 {
-                            Some(rc.clone())
+                    let drop = opened.get(&ino).and_then(|d| {
+                        if Arc::strong_count(d) == 1 {
+                            Some(d.clone())
                         } else {
                             None
                         }
                     });
-                    if let Some(rc) = drop {
-                        rc.borrow_mut().flush(self)?;
-                        self.opened_dirs.remove(&ino);
+                    if let Some(d) = drop {
+                        d.lock().unwrap().flush(self)?;
+                        opened.remove(&ino);
                     }
                 }
                 None => return Ok(()),
@@ -263,78 +993,199 @@ impl<'a> Donkey<'a> {
         }
     }
 
-    /// Allocated a block of size `size` from `FreeList` at `ptr`.
-    /// Returns the pointer of the allocated block and the pointer
-    /// of the new `FreeList`.
-    fn allocate_from_free(&mut self, ptr: u64, size: u64) -> DkResult<(u64, u64)> {
-        let fl: FreeList = self.read(ptr)?;
-        if fl.size >= size {
-            let new_ptr = if fl.size - size >= std::mem::size_of::<FreeList>() as u64 {
-                // Split this free list
-                let new_fl = FreeList {
-                    size: fl.size - size,
-                    ..fl
-                };
-                let new_ptr = ptr + size;
-                self.write(new_ptr, &new_fl)?;
-                new_ptr
-            } else {
-                fl.next_ptr
-            };
-            Ok((ptr, new_ptr))
-        } else {
-            self.allocate_from_free(fl.next_ptr, size)
+    /// Returns the inode number of the allocated inode.
+    /// The inode table is used up before any inode chunk.
+    fn allocate_inode(&self) -> DkResult<u64> {
+        loop {
+            let mut alloc = self.alloc();
+            if let Some(i) = alloc.inode_bitmap.find_free() {
+                alloc.inode_bitmap.set_range(i, 1);
+                alloc.inode_bitmap.flush(&mut *self.dev(), i, 1)?;
+                alloc.sb.used_inode_count += 1;
+                alloc.sb_dirty = true;
+                return Ok(ROOT_INODE + i);
+            }
+            if !alloc.sb.feature_incompat.contains(FeatureIncompat::INODE_CHUNKS) {
+                return Err(Exhausted);
+            }
+            let ptr = alloc.sb.free_inode_chunk_ptr;
+            if ptr != 0 {
+                let mut chunk: InodeChunk = self.read(ptr)?;
+                let per_chunk = inodes_per_chunk(self.bs);
+                let slot = chunk.find_free(per_chunk).ok_or_else(|| {
+                    Corrupted(format!("Full inode chunk at {} is in the free list", ptr))
+                })?;
+                chunk.set_used(slot, true);
+                if chunk.find_free(per_chunk).is_none() {
+                    alloc.sb.free_inode_chunk_ptr = chunk.next_free;
+                    chunk.next_free = 0;
+                }
+                self.write(ptr, &chunk)?;
+                alloc.sb.used_chunk_inode_count += 1;
+                alloc.sb_dirty = true;
+                return Ok(self.chunk_ino(ptr, slot));
+            }
+            drop(alloc);
+            self.add_inode_chunk()?;
         }
     }
 
-    /// Returns the inode number of the allocated inode
-    fn allocate_inode(&mut self) -> DkResult<u64> {
-        if self.sb.used_inode_count < self.sb.inode_count {
-            let fl_ptr = self.sb.inode_fl_ptr;
-            let (fi_ptr, new_fl_ptr) = self.allocate_from_free(fl_ptr, INODE_SIZE)?;
-            self.sb.inode_fl_ptr = new_fl_ptr;
-            self.sb.used_inode_count += 1;
-            self.flush_sb()?;
-            Ok(Inode::ino(fi_ptr))
-        } else {
-            Err(Exhausted)
+    /// Makes a data block an inode chunk with all slots free.
+    /// Chunks are never freed, but their slots are reused.
+    fn add_inode_chunk(&self) -> DkResult<()> {
+        let ptr = self.allocate_db()?;
+        let mut alloc = self.alloc();
+        let chunk = InodeChunk::new(alloc.sb.inode_chunk_ptr, alloc.sb.free_inode_chunk_ptr);
+        let mut block = vec![0; self.bs as usize];
+        block[..INODE_SIZE as usize].copy_from_slice(&chunk.as_bytes()?);
+        self.write(ptr, &Data(block))?;
+        alloc.sb.inode_chunk_ptr = ptr;
+        alloc.sb.free_inode_chunk_ptr = ptr;
+        alloc.sb.inode_chunk_count += 1;
+        alloc.sb_dirty = true;
+        Ok(())
+    }
+
+    /// The pointer of inode `ino`, which is in the inode table
+    /// or else in the inode chunk told by its number
+    fn inode_ptr(&self, ino: u64) -> DkResult<u64> {
+        if ino < FIRST_CHUNK_INODE {
+            return Ok(Inode::ptr(ino));
+        }
+        let (ptr, slot) = self.chunk_slot(ino)?;
+        Ok(ptr + slot * INODE_SIZE)
+    }
+
+    /// The pointer of the chunk holding inode `ino` and the slot of the inode
+    fn chunk_slot(&self, ino: u64) -> DkResult<(u64, u64)> {
+        let n = ino - FIRST_CHUNK_INODE;
+        let slot = n % CHUNK_INODE_STRIDE;
+        if slot == 0 || slot > inodes_per_chunk(self.bs) {
+            return Err(Invalid(format!("No inode can be numbered {}", ino)));
+        }
+        Ok((self.first_db_ptr + n / CHUNK_INODE_STRIDE * self.bs, slot))
+    }
+
+    fn chunk_ino(&self, ptr: u64, slot: u64) -> u64 {
+        FIRST_CHUNK_INODE + (ptr - self.first_db_ptr) / self.bs * CHUNK_INODE_STRIDE + slot
+    }
+
+    /// All inode chunks and their heads in the order of the list
+    fn inode_chunks(&self) -> DkResult<Vec<(u64, InodeChunk)>> {
+        let (mut ptr, count) = {
+            let alloc = self.alloc();
+            (alloc.sb.inode_chunk_ptr, alloc.sb.inode_chunk_count)
+        };
+        let mut chunks = Vec::new();
+        while ptr != 0 && (chunks.len() as u64) < count {
+            let chunk: InodeChunk = self.read(ptr)?;
+            let next = chunk.next;
+            chunks.push((ptr, chunk));
+            ptr = next;
+        }
+        if ptr != 0 || chunks.len() as u64 != count {
+            return Err(Corrupted(format!(
+                "The list of {} inode chunks is broken after {} chunks",
+                count,
+                chunks.len()
+            )));
+        }
+        Ok(chunks)
+    }
+
+    /// Numbers of all inodes in use
+    fn inodes_in_use(&self) -> DkResult<Vec<u64>> {
+        let mut inos: Vec<u64> = {
+            let alloc = self.alloc();
+            (0..alloc.inode_bitmap.len)
+                .filter(|&i| alloc.inode_bitmap.get(i))
+                .map(|i| ROOT_INODE + i)
+                .collect()
+        };
+        let per_chunk = inodes_per_chunk(self.bs);
+        for (ptr, chunk) in self.inode_chunks()? {
+            let slots = (1..=per_chunk).filter(|&slot| chunk.is_used(slot));
+            inos.extend(slots.map(|slot| self.chunk_ino(ptr, slot)));
         }
+        Ok(inos)
     }
 
-    fn read_inode(&mut self, ino: u64) -> DkResult<Inode> {
-        self.read(Inode::ptr(ino))
+    fn read_inode(&self, ino: u64) -> DkResult<Inode> {
+        let inode: Inode = self.read(self.inode_ptr(ino)?)?;
+        if inode.ino != ino {
+            return Err(Corrupted(format!("Inode {} is numbered {}", ino, inode.ino)));
+        }
+        Ok(inode)
     }
 
-    fn write_inode(&mut self, inode: &Inode) -> DkResult<()> {
-        self.write(Inode::ptr(inode.ino), inode)
+    fn write_inode(&self, inode: &Inode) -> DkResult<()> {
+        self.write(self.inode_ptr(inode.ino)?, inode)
     }
 
     /// Returns the pointer of the allocated data block
-    fn allocate_db(&mut self) -> DkResult<u64> {
-        if self.sb.used_db_count < self.sb.db_count {
-            let fl_ptr = self.sb.db_fl_ptr;
-            let bs = self.block_size();
-            let (fd_ptr, new_fl_ptr) = self.allocate_from_free(fl_ptr, bs)?;
-            self.sb.db_fl_ptr = new_fl_ptr;
-            self.sb.used_db_count += 1;
-            self.flush_sb()?;
-            Ok(fd_ptr)
+    fn allocate_db(&self) -> DkResult<u64> {
+        self.allocate_dbs(1).map(|(ptr, _)| ptr)
+    }
+
+    /// Allocates at most `count` contiguous data blocks.
+    /// Returns the pointer of the first block and the number
+    /// of blocks actually allocated.
+    /// Unprivileged operations do not take the reserved blocks.
+    fn allocate_dbs(&self, count: u64) -> DkResult<(u64, u64)> {
+        let mut alloc = self.alloc();
+        let count = if PRIVILEGED.with(|p| p.get()) {
+            count
         } else {
-            Err(Exhausted)
-        }
+            let sb = &alloc.sb;
+            let free = sb.db_count - sb.used_db_count;
+            match free.saturating_sub(sb.reserved_db_count) {
+                0 => return Err(Exhausted),
+                available => min(count, available),
+            }
+        };
+        let run = alloc.db_bitmap.find_free_run(count);
+        let (i, n) = match run {
+            Some(run) => run,
+            None if alloc.db_bitmap.has_reserved() => {
+                // Blocks freed by the current transaction
+                // become available after committing it.
+                drop(alloc);
+                self.commit()?;
+                return self.allocate_dbs(count);
+            }
+            None => return Err(Exhausted),
+        };
+        alloc.db_bitmap.set_range(i, n);
+        alloc.db_bitmap.flush(&mut *self.dev(), i, n)?;
+        alloc.sb.used_db_count += n;
+        alloc.sb_dirty = true;
+        Ok((alloc.sb.first_db_ptr + i * self.block_size(), n))
     }
 
     /// Returns the inode number of the new node.
+    /// It inherits the ACLs of `default_acl` before it is written,
+    /// so that it never shows up without them.
     fn mknod(
-        &mut self,
+        &self,
         mode: FileMode,
         uid: u32,
         gid: u32,
         nlink: u64,
         rdev: Option<u64>,
+        default_acl: Option<&[u8]>,
     ) -> DkResult<u64> {
         let ino = self.allocate_inode()?;
         let time = SystemTime::now().into();
+        let features = self.alloc().sb.feature_incompat;
+        let inline = features.contains(FeatureIncompat::INLINE_DATA)
+            && (mode.is_regular_file() || mode.is_symbolic_link());
+        let (flags, ptrs) = if inline {
+            (InodeFlags::INLINE_DATA, Default::default())
+        } else if features.contains(FeatureIncompat::EXTENTS) {
+            (InodeFlags::EXTENTS, ExtentMap::empty_root())
+        } else {
+            (InodeFlags::empty(), Default::default())
+        };
         let inode = Inode {
             ino,
             mode,
@@ -349,18 +1200,31 @@ impl<'a> Donkey<'a> {
             blocks: 0,
             device: rdev.unwrap_or(0),
             xattr_ptr: 0,
-            ptrs: Default::default(),
+            flags,
+            ptrs,
         };
-        self.write_inode(&inode)?;
+        let mut f = DkFile::new(inode);
+        if let Some(default) = default_acl {
+            acl::inherit(&mut f, default)?;
+            f.write_xattr(self)?;
+        }
+        self.write_inode(&f.inode)?;
         Ok(ino)
     }
 
     /// Returns the inode number of the new directory.
     /// This method **DOES NOT** link the new directory to
     /// the parent directory!
-    fn mkdir(&mut self, parent_ino: u64, mode: FileMode, uid: u32, gid: u32) -> DkResult<u64> {
+    fn mkdir(
+        &self,
+        parent_ino: u64,
+        mode: FileMode,
+        uid: u32,
+        gid: u32,
+        default_acl: Option<&[u8]>,
+    ) -> DkResult<u64> {
         let mode = FileMode::DIRECTORY | mode;
-        let ino = self.mknod(mode, uid, gid, 0, None)?;
+        let ino = self.mknod(mode, uid, gid, 0, None, default_acl)?;
 
         // Create `.` and `..` entry
         let dir = self.open_dir(ino)?;
@@ -370,86 +1234,319 @@ impl<'a> Donkey<'a> {
         Ok(ino)
     }
 
-    fn link(&mut self, ino: u64, parent: DkDirHandle, name: &OsStr) -> DkResult<()> {
-        parent.add_entry(name, ino)?;
+    /// The default ACL of directory `parent`, inherited by new files in it
+    fn default_acl(&self, parent: u64) -> DkResult<Option<Vec<u8>>> {
+        let fh = self.open(parent, Flags::READ_ONLY)?;
+        let f = fh.lock();
+        Ok(f.xattr.get(OsStr::new(acl::DEFAULT_XATTR)).cloned())
+    }
+
+    fn link(&self, ino: u64, parent: DkDirHandle, name: &OsStr) -> DkResult<()> {
         let file = self.open(ino, Flags::READ_ONLY)?;
-        file.inner.borrow_mut().inode.nlink += 1;
-        file.inner.borrow_mut().inode.ctime = SystemTime::now().into();
-        file.inner.borrow_mut().dirty = true;
+        let mode = file.lock().inode.mode;
+        parent.add_entry(self, name, ino, mode)?;
+        let mut f = file.lock();
+        f.inode.nlink += 1;
+        f.inode.ctime = SystemTime::now().into();
+        f.dirty = true;
         Ok(())
     }
 
-    fn unlink(&mut self, parent: DkDirHandle, name: &OsStr) -> DkResult<()> {
-        if let Some(ino) = parent.remove_entry(name)? {
-            let fh = self.open(ino, Flags::READ_ONLY)?;
-            fh.inner.borrow_mut().inode.nlink -= 1;
-            fh.inner.borrow_mut().inode.ctime = SystemTime::now().into();
-            fh.inner.borrow_mut().dirty = true;
+    fn unlink(&self, parent: DkDirHandle, name: &OsStr) -> DkResult<()> {
+        if let Some(ino) = parent.remove_entry(self, name)? {
+            self.drop_link(ino)?;
         }
         Ok(())
     }
 
-    fn open(&mut self, ino: u64, flags: Flags) -> DkResult<DkFileHandle> {
-        self.close_files_in_list()?;
+    /// Decreases the link count of `ino` whose entry has been removed.
+    fn drop_link(&self, ino: u64) -> DkResult<()> {
+        let fh = self.open(ino, Flags::READ_ONLY)?;
+        let mut f = fh.lock();
+        f.inode.nlink -= 1;
+        f.inode.ctime = SystemTime::now().into();
+        f.dirty = true;
+        Ok(())
+    }
+
+    /// Removes the empty directory `name` in `parent`.
+    fn rmdir(&self, parent: DkDirHandle, name: &OsStr) -> DkResult<()> {
+        let ino = parent.lookup(self, name)?.ok_or(NotFound)?;
+        let dir = self.open_dir(ino)?;
+        for ino in dir.remove_dots(self)? {
+            self.drop_link(ino)?;
+        }
+        self.unlink(parent, name)
+    }
+
+    /// Moves entry `name` in `old_parent` to `new_name` in `new_parent`.
+    /// All checks are done before anything is modified.
+    /// Renames after `check` passes. It runs under the rename lock,
+    /// so that no entry it looks at can change before the rename.
+    fn rename<F: FnOnce() -> DkResult<()>>(
+        &self,
+        old_parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: RenameFlags,
+        check: F,
+    ) -> DkResult<()> {
+        // Keeps the checks below valid until the end
+        let _rename = self.rename_lock.write().unwrap();
+        check()?;
+        let src_dir = self.open_dir(old_parent)?;
+        let dst_dir = self.open_dir(new_parent)?;
+        let ino = src_dir.lookup(self, name)?.ok_or(NotFound)?;
+        let target = dst_dir.lookup(self, new_name)?;
+        if target == Some(ino) {
+            // Both names refer to the same file
+            return Ok(());
+        }
+        let mode = self.open(ino, Flags::READ_ONLY)?.lock().inode.mode;
+        let is_dir = mode.is_directory();
+        if is_dir && self.is_ancestor(ino, new_parent)? {
+            return Err(RenameLoop);
+        }
+
+        if flags.contains(RenameFlags::EXCHANGE) {
+            let target = target.ok_or(NotFound)?;
+            let target_mode = self.open(target, Flags::READ_ONLY)?.lock().inode.mode;
+            let target_is_dir = target_mode.is_directory();
+            if target_is_dir && self.is_ancestor(target, old_parent)? {
+                return Err(RenameLoop);
+            }
+            src_dir.remove_entry(self, name)?;
+            dst_dir.remove_entry(self, new_name)?;
+            dst_dir.add_entry(self, new_name, ino, mode)?;
+            src_dir.add_entry(self, name, target, target_mode)?;
+            self.touch(target)?;
+            if target_is_dir {
+                self.set_parent(target, new_parent, old_parent)?;
+            }
+        } else {
+            if let Some(target) = target {
+                if flags.contains(RenameFlags::NOREPLACE) {
+                    return Err(AlreadyExists);
+                }
+                let target_is_dir = self
+                    .open(target, Flags::READ_ONLY)?
+                    .lock()
+                    .inode
+                    .mode
+                    .is_directory();
+                match (is_dir, target_is_dir) {
+                    (true, false) => return Err(NotDirectory),
+                    (false, true) => return Err(IsDirectory),
+                    (true, true) => {
+                        let dir = self.open_dir(target)?;
+                        for ino in dir.remove_dots(self)? {
+                            self.drop_link(ino)?;
+                        }
+                    }
+                    (false, false) => {}
+                }
+                self.unlink(dst_dir.clone(), new_name)?;
+            }
+            src_dir.remove_entry(self, name)?;
+            dst_dir.add_entry(self, new_name, ino, mode)?;
+        }
+        self.touch(ino)?;
+        if is_dir {
+            self.set_parent(ino, old_parent, new_parent)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `ino` is `dir` or one of its descendants
+    fn is_ancestor(&self, dir: u64, mut ino: u64) -> DkResult<bool> {
+        // The depth of the tree is bounded by the number of inodes.
+        // The allocator is not kept locked while walking up the tree.
+        let max = self.alloc().sb.used_inodes();
+        for _ in 0..max {
+            if ino == dir {
+                return Ok(true);
+            }
+            if ino == ROOT_INODE {
+                return Ok(false);
+            }
+            let parent = self.open_dir(ino)?.lookup(self, OsStr::new(".."))?;
+            ino = parent.ok_or_else(|| Corrupted(format!("Directory {} has no parent", ino)))?;
+        }
+        Err(Corrupted(format!("Directory {} is in a loop", ino)))
+    }
+
+    /// Points `..` of directory `ino` to `new_parent` instead of `old_parent`.
+    fn set_parent(&self, ino: u64, old_parent: u64, new_parent: u64) -> DkResult<()> {
+        if old_parent != new_parent {
+            let dir = self.open_dir(ino)?;
+            self.unlink(dir.clone(), OsStr::new(".."))?;
+            self.link(new_parent, dir, OsStr::new(".."))?;
+        }
+        Ok(())
+    }
+
+    /// Updates the ctime of `ino`.
+    fn touch(&self, ino: u64) -> DkResult<()> {
+        let fh = self.open(ino, Flags::READ_ONLY)?;
+        let mut f = fh.lock();
+        f.inode.ctime = SystemTime::now().into();
+        f.dirty = true;
+        Ok(())
+    }
+
+    /// Whether the current thread is in an operation, so that
+    /// writes it makes can be rolled back
+    fn in_op(&self) -> bool {
+        OP_DEPTH.with(|d| d.get() > 0)
+    }
+
+    /// Runs `f`, which may take the data blocks reserved
+    /// for the super user only if `privileged`.
+    fn privileged<T, F: FnOnce() -> T>(&self, privileged: bool, f: F) -> T {
+        let outer = PRIVILEGED.with(|p| p.replace(privileged));
+        let res = f();
+        PRIVILEGED.with(|p| p.set(outer));
+        res
+    }
+
+    fn open(&self, ino: u64, flags: Flags) -> DkResult<DkFileHandle> {
+        if self.in_op() {
+            self.close_files_in_list()?;
+        }
         if flags == Flags::INVALID {
             return Err(Invalid("Open with invalid flags.".to_string()));
         }
-        // We do not use entry API here to prevent `self` being borrowed twice
-        let inner = if let Some(fh) = self.opened_files.get(&ino).cloned() {
-            fh
+        // The map stays locked while loading the file,
+        // so that it is never loaded twice.
+        let mut opened = self.opened_files.lock().unwrap();
+        let inner = if let Some(f) = opened.get(&ino).cloned() {
+            f
         } else {
-            let inode = self.read_inode(ino)?;
-            let mut f = DkFile::new(inode, self.close_file_list.clone());
-            f.read_xattr(self)?;
-            let rc = Rc::new(RefCell::new(f));
-            self.opened_files.insert(ino, rc.clone());
-            rc
+            let f = Arc::new(Mutex::new(self.load_file(ino)?));
+            opened.insert(ino, f.clone());
+            f
         };
-        let df = DkFileHandle { inner, flags };
+        Ok(DkFileHandle {
+            inner,
+            ino,
+            close_file_list: self.close_file_list.clone(),
+            flags,
+            privileged: true,
+        })
+    }
 
-        Ok(df)
+    fn load_file(&self, ino: u64) -> DkResult<DkFile> {
+        let inode = self.read_inode(ino)?;
+        let mut f = DkFile::new(inode);
+        f.read_xattr(self)?;
+        f.read_extents(self)?;
+        Ok(f)
     }
 
-    fn open_dir(&mut self, ino: u64) -> DkResult<DkDirHandle> {
-        self.close_dirs_in_list()?;
-        // We do not use entry API here to prevent `self` being borrowed twice
-        let inner = if let Some(dh) = self.opened_dirs.get(&ino).cloned() {
-            dh
+    fn open_dir(&self, ino: u64) -> DkResult<DkDirHandle> {
+        if self.in_op() {
+            self.close_dirs_in_list()?;
+        }
+        let mut opened = self.opened_dirs.lock().unwrap();
+        let inner = if let Some(d) = opened.get(&ino).cloned() {
+            d
         } else {
             let fh = self.open(ino, Flags::READ_WRITE)?;
-            let mut dir = DkDir::from_file(fh, self.close_dir_list.clone())?;
-            dir.read_fully(self)?;
-            let rc = Rc::new(RefCell::new(dir));
-            self.opened_dirs.insert(ino, rc.clone());
-            rc
+            let d = Arc::new(Mutex::new(DkDir::from_file(fh)?));
+            opened.insert(ino, d.clone());
+            d
         };
-        let entries = inner.borrow().entries.clone();
-        let dd = DkDirHandle { inner, entries };
+        Ok(DkDirHandle {
+            inner,
+            ino,
+            close_dir_list: self.close_dir_list.clone(),
+        })
+    }
 
-        Ok(dd)
+    fn free_inode(&self, ino: u64) -> DkResult<()> {
+        if ino >= FIRST_CHUNK_INODE {
+            return self.free_chunk_inode(ino);
+        }
+        let mut alloc = self.alloc();
+        let i = ino - ROOT_INODE;
+        if i >= alloc.sb.inode_count || !alloc.inode_bitmap.get(i) {
+            return Err(Corrupted(format!("Freeing inode {} which is not in use", ino)));
+        }
+        alloc.inode_bitmap.clear(i);
+        alloc.inode_bitmap.flush(&mut *self.dev(), i, 1)?;
+        alloc.sb.used_inode_count -= 1;
+        alloc.sb_dirty = true;
+        Ok(())
     }
 
-    fn free_inode(&mut self, ino: u64) -> DkResult<()> {
-        let new_fl = FreeList {
-            size: INODE_SIZE,
-            next_ptr: self.sb.inode_fl_ptr,
-        };
-        let ptr = Inode::ptr(ino);
-        self.sb.inode_fl_ptr = ptr;
-        self.write(ptr, &new_fl)?;
-        self.sb.used_inode_count -= 1;
-        self.flush_sb()
+    /// Frees the slot of `ino` in its chunk, which is put back
+    /// into the free list if it was full. The chunk stays even if
+    /// all of its slots are free, so its data block is never given back;
+    /// only shrinking the file system drops chunks.
+    fn free_chunk_inode(&self, ino: u64) -> DkResult<()> {
+        let (ptr, slot) = self.chunk_slot(ino)?;
+        let mut alloc = self.alloc();
+        let mut chunk: InodeChunk = self.read(ptr)?;
+        if !chunk.is_used(slot) {
+            return Err(Corrupted(format!("Freeing inode {} which is not in use", ino)));
+        }
+        if chunk.find_free(inodes_per_chunk(self.bs)).is_none() {
+            chunk.next_free = alloc.sb.free_inode_chunk_ptr;
+            alloc.sb.free_inode_chunk_ptr = ptr;
+        }
+        chunk.set_used(slot, false);
+        self.write(ptr, &chunk)?;
+        alloc.sb.used_chunk_inode_count -= 1;
+        alloc.sb_dirty = true;
+        Ok(())
     }
 
-    fn free_db(&mut self, ptr: u64) -> DkResult<()> {
-        let new_fl = FreeList {
-            size: self.block_size(),
-            next_ptr: self.sb.db_fl_ptr,
-        };
-        self.sb.db_fl_ptr = ptr;
-        self.write(ptr, &new_fl)?;
-        self.sb.used_db_count -= 1;
-        self.flush_sb()
+    fn free_db(&self, ptr: u64) -> DkResult<()> {
+        self.free_dbs(ptr, 1)
+    }
+
+    /// Frees `count` contiguous data blocks starting at `ptr`.
+    fn free_dbs(&self, ptr: u64, count: u64) -> DkResult<()> {
+        let mut alloc = self.alloc();
+        let (bs, first_db_ptr) = (self.block_size(), alloc.sb.first_db_ptr);
+        if ptr < first_db_ptr || (ptr - first_db_ptr) % bs != 0 {
+            return Err(Corrupted(format!("Invalid data block pointer {}", ptr)));
+        }
+        let start = (ptr - first_db_ptr) / bs;
+        for i in start..start + count {
+            if i >= alloc.sb.db_count || !alloc.db_bitmap.get(i) {
+                return Err(Corrupted(format!(
+                    "Freeing data block {} which is not in use",
+                    first_db_ptr + i * bs
+                )));
+            }
+            alloc.db_bitmap.clear_reserved(i);
+        }
+        alloc.db_bitmap.flush(&mut *self.dev(), start, count)?;
+        alloc.sb.used_db_count -= count;
+        alloc.sb_dirty = true;
+        Ok(())
+    }
+
+    /// Copies the data block at `from` to `to`, through the journal
+    /// if `journaled`.
+    fn copy_db(&self, from: u64, to: u64, journaled: bool) -> DkResult<()> {
+        let data: ByteData = self.read_block(from)?;
+        if journaled {
+            self.write(to, &data)
+        } else {
+            self.write_data(to, &data)
+        }
+    }
+
+    /// Moves the data block at `ptr` to a newly allocated one.
+    /// Returns the new pointer.
+    fn move_db(&self, ptr: u64, journaled: bool) -> DkResult<u64> {
+        let new_ptr = self.allocate_db()?;
+        self.copy_db(ptr, new_ptr, journaled)?;
+        self.free_db(ptr)?;
+        Ok(new_ptr)
     }
 }
 
@@ -467,30 +1564,187 @@ impl<'a> Drop for Donkey<'a> {
                 e
             );
         }
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.write_backups() {
+            eprintln!("Failed to write the backup super blocks: {}", e);
+        }
+        if let Err(e) = self.commit().and_then(|_| self.dev().checkpoint()) {
+            eprintln!("Failed to commit the journal: {}. Recent changes may be lost!", e);
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct FormatOptions {
-    bytes_per_inode: u64,
+    bytes_per_inode: Option<u64>,
+    extents: bool,
+    inode_chunks: bool,
+    inline_data: bool,
+    large_xattrs: bool,
+    journal_blocks: Option<u64>,
+    block_size: Option<u64>,
+    inode_count: Option<u64>,
+    max_inode_count: Option<u64>,
+    label: String,
+    uuid: Option<[u8; 16]>,
+    reserved_percent: f64,
 }
 
 impl Default for FormatOptions {
     fn default() -> Self {
         FormatOptions {
-            bytes_per_inode: DEFAULT_BYTES_PER_INODE,
+            bytes_per_inode: None,
+            extents: true,
+            inode_chunks: true,
+            inline_data: true,
+            large_xattrs: true,
+            journal_blocks: None,
+            block_size: None,
+            inode_count: None,
+            max_inode_count: None,
+            label: String::new(),
+            uuid: None,
+            reserved_percent: 0.0,
         }
     }
 }
 
 impl FormatOptions {
+    /// Sizes the inode table by the bytes/inode ratio. By default, the ratio is
+    /// `DEFAULT_BYTES_PER_INODE`, but the table holds no more than
+    /// `DEFAULT_CHUNKED_INODE_COUNT` inodes if inode chunks are used.
     pub fn bytes_per_inode(mut self, bytes_per_inode: u64) -> Self {
-        self.bytes_per_inode = bytes_per_inode;
+        self.bytes_per_inode = Some(bytes_per_inode);
+        self
+    }
+
+    /// Whether new files map their blocks with extents.
+    /// Otherwise, the indirect pointers in `InodePtrs` are used.
+    pub fn extents(mut self, extents: bool) -> Self {
+        self.extents = extents;
+        self
+    }
+
+    /// Whether inodes are allocated in chunks from the data area
+    /// once the inode table is full. Otherwise, no more files can be
+    /// made at that point.
+    pub fn inode_chunks(mut self, inode_chunks: bool) -> Self {
+        self.inode_chunks = inode_chunks;
+        self
+    }
+
+    /// Whether small regular files and symbolic links keep their data
+    /// in the inode. They move to data blocks when they grow.
+    pub fn inline_data(mut self, inline_data: bool) -> Self {
+        self.inline_data = inline_data;
+        self
+    }
+
+    /// Whether the extended attributes of an inode may take up more than
+    /// one block, up to `MAX_XATTRS_LEN` bytes or half of what the journal holds.
+    pub fn large_xattrs(mut self, large_xattrs: bool) -> Self {
+        self.large_xattrs = large_xattrs;
+        self
+    }
+
+    /// Size of the journal in blocks.
+    /// By default, 1/64 of the device is used, but no more than 32 MiB.
+    pub fn journal_blocks(mut self, journal_blocks: u64) -> Self {
+        self.journal_blocks = Some(journal_blocks);
+        self
+    }
+
+    /// A power of 2 from 1 KiB to 64 KiB.
+    /// The block size of the device is used by default.
+    pub fn block_size(mut self, block_size: u64) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Overrides the bytes/inode ratio, which sizes the inode table.
+    pub fn inode_count(mut self, inode_count: u64) -> Self {
+        self.inode_count = Some(inode_count);
+        self
+    }
+
+    /// Leaves room for the inode table to grow to `max_inode_count` inodes
+    /// when the file system is grown. No room is left by default.
+    pub fn max_inode_count(mut self, max_inode_count: u64) -> Self {
+        self.max_inode_count = Some(max_inode_count);
+        self
+    }
+
+    /// At most `MAX_LABEL_LEN` bytes
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+
+    /// A random UUID is generated by default.
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// Percentage of data blocks only available to the super user
+    pub fn reserved_percent(mut self, reserved_percent: f64) -> Self {
+        self.reserved_percent = reserved_percent;
         self
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+/// Options for `open_with`
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    backup: Option<u64>,
+}
+
+impl OpenOptions {
+    /// Reads the super block from the backup at byte `ptr`
+    /// instead of the primary one, which is then rebuilt from it.
+    pub fn backup(mut self, ptr: u64) -> Self {
+        self.backup = Some(ptr);
+        self
+    }
+}
+
+/// Attributes changed by `setattr`. Those left `None` are kept.
+#[derive(Debug, Clone, Default)]
+pub struct SetAttr {
+    pub mode: Option<FileMode>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<DkTimespec>,
+    pub mtime: Option<DkTimespec>,
+    /// Set to the current time instead if others change and this is `None`
+    pub ctime: Option<DkTimespec>,
+    pub crtime: Option<DkTimespec>,
+}
+
+/// Positions and sizes of the structures of a new file system.
+/// Pointers are in bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub block_size: u64,
+    pub block_count: u64,
+    pub inode_count: u64,
+    /// Inodes the inode table has room for
+    pub max_inode_count: u64,
+    pub inode_bitmap_ptr: u64,
+    pub db_bitmap_ptr: u64,
+    pub journal_ptr: u64,
+    pub journal_blocks: u64,
+    pub first_db_ptr: u64,
+    pub db_count: u64,
+    pub reserved_db_count: u64,
+    /// Backup super blocks, whose data blocks are marked as used
+    pub backup_ptrs: Vec<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct DkTimespec {
     pub sec: i64,
     pub nsec: u32,
@@ -508,7 +1762,6 @@ impl From<SystemTime> for DkTimespec {
 }
 
 bitflags! {
-    #[derive(Serialize, Deserialize)]
     pub struct FileMode: u16 {
         const FILE_TYPE_MASK   = 0b1111_0000_0000_0000;
         const SOCKET           = 0b1100_0000_0000_0000;
@@ -577,7 +1830,73 @@ impl FileMode {
 }
 
 bitflags! {
-    #[derive(Serialize, Deserialize)]
+    pub struct InodeFlags: u32 {
+        /// Data blocks are mapped by extents instead of `InodePtrs`
+        const EXTENTS          = 0b0000_0000_0000_0001;
+        /// The data is kept in `InodePtrs`, and no data blocks are used
+        const INLINE_DATA      = 0b0000_0000_0000_0010;
+    }
+}
+
+bitflags! {
+    /// Same values as `R_OK`, `W_OK` and `X_OK` in Linux
+    pub struct AccessMode: u32 {
+        const READ             = 0b0000_0000_0000_0100;
+        const WRITE            = 0b0000_0000_0000_0010;
+        const EXECUTE          = 0b0000_0000_0000_0001;
+    }
+}
+
+/// The user on whose behalf an operation runs.
+/// Its permissions on the files involved are checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Credentials {
+            uid,
+            gid,
+            groups: Vec::new(),
+        }
+    }
+
+    /// The super user, who bypasses most checks
+    pub fn root() -> Self {
+        Credentials::new(0, 0)
+    }
+
+    pub fn groups(mut self, groups: Vec<u32>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether `gid` is the primary or a supplementary group
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+bitflags! {
+    /// Same values as `RENAME_*` in Linux
+    pub struct RenameFlags: u32 {
+        /// Fails if the new name exists
+        const NOREPLACE        = 0b0000_0000_0000_0001;
+        /// Atomically exchanges the old and the new name
+        const EXCHANGE         = 0b0000_0000_0000_0010;
+    }
+}
+
+bitflags! {
     pub struct Flags: u32 {
         const ACCESS_MODE_MASK = 0b0000_0000_0000_0011;
         const INVALID          = 0b0000_0000_0000_0011;
@@ -587,11 +1906,35 @@ bitflags! {
     }
 }
 
+/// Formats an image of 32 MiB in memory with the default options,
+/// like `prepare!` in the integration tests.
+#[cfg(test)]
+macro_rules! prepare {
+    ($mem: ident, $handle: ident) => {
+        let mut $mem = vec![0; 1 << 25];
+        let $handle = format(
+            Box::new(::device::Memory::new(&mut $mem[..])),
+            Default::default(),
+        )?;
+    };
+}
+
+pub mod acl;
+mod alloc;
+pub mod aio;
 pub mod block;
+mod cache;
+pub mod check;
+mod checksum;
 pub mod device;
+mod dir;
+mod extent;
+mod journal;
 pub mod file;
 pub mod ops;
+pub mod pool;
 pub mod replies;
+pub mod resize;
 
 #[cfg(test)]
 mod tests {
@@ -608,4 +1951,226 @@ mod tests {
             _ => unreachable!(),
         }
     }
+
+    #[test]
+    fn reconcile_counts() -> DkResult<()> {
+        use device::Memory;
+
+        let cred = Credentials::root();
+        prepare!(mem, handle);
+        let statfs = {
+            let madoka = OsStr::new("Madoka");
+            let stat = handle.mknod(&cred, ROOT_INODE, madoka, FileMode::REGULAR_FILE, None)?;
+            let fh = handle.open(&cred, stat.ino, Flags::WRITE_ONLY)?;
+            handle.write(fh, 0, &[42; 100_000])?;
+            let statfs = handle.statfs()?;
+            // Pretend the counters were not written back before a crash
+            let dk = &handle.inner;
+            dk.commit()?;
+            let mut alloc = dk.alloc();
+            alloc.sb.used_inode_count = 1;
+            alloc.sb.used_db_count = 1;
+            alloc.flush_sb(&mut dk.dev())?;
+            statfs
+        };
+        drop(handle);
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        assert_eq!(handle.statfs()?, statfs);
+        Ok(())
+    }
+
+    /// Formats a file system and patches the super block with `f`
+    /// at the level of bytes, so that unknown bits can be set.
+    fn patched_image<F: FnOnce(&mut [u8])>(f: F) -> DkResult<Vec<u8>> {
+        use byteorder::{ByteOrder, LE};
+
+        prepare!(mem, handle);
+        drop(handle);
+        let ptr = SUPER_BLOCK_PTR as usize;
+        let bytes = &mut mem[ptr..ptr + SUPER_BLOCK_SIZE as usize];
+        f(bytes);
+        let sum = checksum::crc32c(&bytes[..1020]);
+        LE::write_u32(&mut bytes[1020..], sum);
+        Ok(mem)
+    }
+
+    #[test]
+    fn refuse_unknown_features() -> DkResult<()> {
+        use device::Memory;
+
+        // The incompatible features follow the magic number, the versions
+        // and the other features
+        let mut mem = patched_image(|sb| sb[39] |= 0x80)?;
+        match open(Box::new(Memory::new(&mut mem[..]))) {
+            Err(Incompatible(_)) => {}
+            r => panic!("Expected incompatible features, got {:?}", r.map(|_| ())),
+        }
+        let mut mem = patched_image(|sb| sb[8] = 2)?;
+        match open(Box::new(Memory::new(&mut mem[..]))) {
+            Err(Incompatible(_)) => {}
+            r => panic!("Expected an incompatible version, got {:?}", r.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_with_unknown_ro_compat_features() -> DkResult<()> {
+        use device::Memory;
+
+        let cred = Credentials::root();
+        let mut mem = patched_image(|sb| sb[31] |= 0x80)?;
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        assert!(handle.read_only());
+        assert_eq!(handle.getattr(ROOT_INODE)?.ino, ROOT_INODE);
+        let madoka = OsStr::new("Madoka");
+        match handle.mkdir(&cred, ROOT_INODE, madoka, FileMode::USER_RWX) {
+            Err(ReadOnly) => {}
+            r => panic!("Expected a read-only file system, got {:?}", r),
+        }
+        match handle.open(&cred, ROOT_INODE, Flags::READ_WRITE) {
+            Err(ReadOnly) => {}
+            r => panic!("Expected a read-only file system, got {:?}", r.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn open_from_backup_super_block() -> DkResult<()> {
+        use device::Memory;
+
+        let cred = Credentials::root();
+        prepare!(mem, handle);
+        handle.mkdir(&cred, ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+        drop(handle);
+        for b in &mut mem[SUPER_BLOCK_PTR as usize..FIRST_INODE_PTR as usize] {
+            *b = 0;
+        }
+        assert!(open(Box::new(Memory::new(&mut mem[..]))).is_err());
+        match open_with(
+            Box::new(Memory::new(&mut mem[..])),
+            OpenOptions::default().backup(5 << 20),
+        ) {
+            Err(Invalid(_)) => {}
+            r => panic!("Expected an invalid backup, got {:?}", r.map(|_| ())),
+        }
+        {
+            let opts = OpenOptions::default().backup(16 << 20);
+            let handle = open_with(Box::new(Memory::new(&mut mem[..])), opts)?;
+            handle.lookup(&cred, ROOT_INODE, OsStr::new("Madoka"))?;
+        }
+        // The primary super block is rebuilt
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        handle.lookup(&cred, ROOT_INODE, OsStr::new("Madoka"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn recover_from_newest_backup() -> DkResult<()> {
+        use device::Memory;
+
+        let cred = Credentials::root();
+        let last = (1 << 25) - SUPER_BLOCK_SIZE;
+        assert_eq!(
+            backup_super_block_ptrs(1 << 25),
+            vec![1 << 20, 4 << 20, 16 << 20, last]
+        );
+        prepare!(mem, handle);
+        drop(handle);
+        let old = mem[16 << 20..(16 << 20) + SUPER_BLOCK_SIZE as usize].to_vec();
+        {
+            let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+            handle.mkdir(&cred, ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+        }
+        // An older backup loses to a newer one
+        mem[16 << 20..(16 << 20) + SUPER_BLOCK_SIZE as usize].copy_from_slice(&old);
+        for b in &mut mem[SUPER_BLOCK_PTR as usize..FIRST_INODE_PTR as usize] {
+            *b = 0;
+        }
+        assert_eq!(recover_super_block(&mut Memory::new(&mut mem[..]))?, 1 << 20);
+
+        // Damaged backups are skipped
+        mem[1 << 20] ^= 1;
+        mem[4 << 20] ^= 1;
+        for b in &mut mem[SUPER_BLOCK_PTR as usize..FIRST_INODE_PTR as usize] {
+            *b = 0;
+        }
+        assert_eq!(recover_super_block(&mut Memory::new(&mut mem[..]))?, last);
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        handle.lookup(&cred, ROOT_INODE, OsStr::new("Madoka"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn backups_stay_on_larger_device() -> DkResult<()> {
+        use device::Memory;
+
+        let cred = Credentials::root();
+        let mut mem = vec![0; 12 << 20];
+        {
+            let handle = format(Box::new(Memory::new(&mut mem[..8 << 20])), Default::default())?;
+            let madoka = OsStr::new("Madoka");
+            let stat = handle.mknod(&cred, ROOT_INODE, madoka, FileMode::REGULAR_FILE, None)?;
+            let fh = handle.open(&cred, stat.ino, Flags::WRITE_ONLY)?;
+            handle.write(fh, 0, &[1; 6 << 20])?;
+        }
+        // The device is enlarged behind the file system's back
+        drop(open(Box::new(Memory::new(&mut mem[..])))?);
+        assert!(mem[8 << 20..].iter().all(|&b| b == 0));
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        let stat = handle.lookup(&cred, ROOT_INODE, OsStr::new("Madoka"))?;
+        let fh = handle.open(&cred, stat.ino, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, 6 << 20)?, vec![1; 6 << 20]);
+        Ok(())
+    }
+
+    /// `tests/golden.img` pins the on-disk format. It is a 64 KiB image
+    /// of 1 KiB blocks holding a directory `Madoka` with a file `Homura`
+    /// and its xattr, a file `Sayaka` of 3000 bytes, and a symbolic link
+    /// `Kyubey`. Every change breaking it needs a new format version.
+    #[test]
+    fn golden_image() -> DkResult<()> {
+        use device::Memory;
+
+        let golden = include_bytes!("../tests/golden.img");
+        let cred = Credentials::root();
+        let mut mem = golden.to_vec();
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+
+        // Decoding and encoding again gives the same bytes
+        let reencoded = |ptr: u64, len: u64, bytes: Box<Writable>| -> DkResult<()> {
+            let range = ptr as usize..(ptr + len) as usize;
+            assert_eq!(&golden[range], &bytes.as_bytes()?[..]);
+            Ok(())
+        };
+        let sb = SuperBlock::from_bytes(&golden[SUPER_BLOCK_PTR as usize..])?;
+        reencoded(SUPER_BLOCK_PTR, SUPER_BLOCK_SIZE, Box::new(sb.clone()))?;
+        assert_eq!((sb.block_size, sb.inode_count), (1024, 16));
+        for i in 0..sb.inode_count {
+            if handle.inner.alloc().inode_bitmap.get(i) {
+                let ptr = Inode::ptr(i + ROOT_INODE);
+                let inode = Inode::from_bytes(&golden[ptr as usize..])?;
+                reencoded(ptr, INODE_SIZE, Box::new(inode))?;
+            }
+        }
+
+        let volume = handle.volume()?;
+        assert_eq!(volume.label, "golden");
+        assert_eq!(volume.uuid[..4], [0x01, 0x23, 0x45, 0x67]);
+        let madoka = handle.lookup(&cred, ROOT_INODE, OsStr::new("Madoka"))?;
+        assert_eq!(madoka.mode, FileMode::DIRECTORY | FileMode::USER_RWX | FileMode::GROUP_READ);
+        assert_eq!((madoka.uid, madoka.gid), (1000, 1000));
+        let homura = handle.lookup(&cred, madoka.ino, OsStr::new("Homura"))?.ino;
+        let fh = handle.open(&cred, homura, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, 100)?, b"Akemi Homura\n");
+        let soul_gem = handle.getxattr(&cred, homura, OsStr::new("user.soul_gem"))?;
+        assert_eq!(soul_gem, Some(b"purple".to_vec()));
+        let sayaka = handle.lookup(&cred, ROOT_INODE, OsStr::new("Sayaka"))?.ino;
+        let fh = handle.open(&cred, sayaka, Flags::READ_ONLY)?;
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(handle.read(fh, 0, 10000)?, data);
+        let kyubey = handle.lookup(&cred, ROOT_INODE, OsStr::new("Kyubey"))?;
+        let fh = handle.open(&cred, kyubey.ino, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, kyubey.size)?, b"Madoka/Homura");
+        Ok(())
+    }
 }
@@ -1,177 +1,399 @@
 use file::*;
+use dir::cookies;
 use replies::*;
-use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::ffi::{OsStr, OsString};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::Arc;
 use *;
 
+/// A handle to a mounted file system. It can be cloned and
+/// shared among threads, which may operate on it in parallel.
 #[derive(Debug, Clone)]
 pub struct Handle<'a> {
-    pub(crate) inner: Rc<RefCell<Donkey<'a>>>,
+    pub(crate) inner: Arc<Donkey<'a>>,
 }
 
 impl<'a> Handle<'a> {
     pub(crate) fn new(dk: Donkey<'a>) -> Self {
         Handle {
-            inner: Rc::new(RefCell::new(dk)),
+            inner: Arc::new(dk),
+        }
+    }
+
+    /// Runs `f` as one operation. Its writes are committed
+    /// to the journal together.
+    fn op<T, F: FnOnce() -> DkResult<T>>(&self, f: F) -> DkResult<T> {
+        self.inner.begin_op();
+        let res = f();
+        let end = self.inner.end_op(res.is_err());
+        res.and_then(|v| end.map(|_| v))
+    }
+
+    /// Like `op`, but it may take the data blocks reserved
+    /// for the super user only if `privileged`.
+    fn op_as<T, F: FnOnce() -> DkResult<T>>(&self, privileged: bool, f: F) -> DkResult<T> {
+        self.inner.privileged(privileged, || self.op(f))
+    }
+
+    /// Whether the file system refuses changes, because it uses
+    /// read-only compatible features unknown to this version
+    pub fn read_only(&self) -> bool {
+        self.inner.read_only
+    }
+
+    fn writable(&self) -> DkResult<()> {
+        if self.inner.read_only {
+            Err(ReadOnly)
+        } else {
+            Ok(())
         }
     }
 
     pub fn statfs(&self) -> DkResult<Statvfs> {
-        let sb = &self.inner.borrow().sb;
+        let alloc = self.inner.alloc();
+        let sb = &alloc.sb;
+        let bavail = (sb.db_count - sb.used_db_count).saturating_sub(sb.reserved_db_count);
+        let mut files = sb.inode_count;
+        if sb.feature_incompat.contains(FeatureIncompat::INODE_CHUNKS) {
+            // Every free block may become an inode chunk
+            files += (sb.inode_chunk_count + bavail) * inodes_per_chunk(sb.block_size);
+        }
         let stat = Statvfs {
             blocks: sb.db_count,
             bfree: sb.db_count - sb.used_db_count,
-            bavail: sb.db_count - sb.used_db_count,
-            files: sb.inode_count,
-            ffree: sb.inode_count - sb.used_inode_count,
+            bavail,
+            files,
+            ffree: files - sb.used_inodes(),
             bsize: sb.block_size,
             namelen: MAX_NAMELEN,
         };
         Ok(stat)
     }
 
+    /// Bytes of the device taken up by the file system
+    pub fn size(&self) -> u64 {
+        self.inner.alloc().sb.db_end()
+    }
+
+    /// Grows the file system to `new_size` bytes after the device
+    /// has been enlarged. It may stay in use meanwhile.
+    pub fn grow(&self, new_size: u64) -> DkResult<()> {
+        self.writable()?;
+        self.inner.grow(new_size)
+    }
+
+    pub fn volume(&self) -> DkResult<Volume> {
+        let alloc = self.inner.alloc();
+        let label = &alloc.sb.label;
+        let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+        Ok(Volume {
+            label: String::from_utf8_lossy(&label[..len]).into_owned(),
+            uuid: alloc.sb.uuid,
+        })
+    }
+
     pub fn getattr(&self, ino: u64) -> DkResult<Stat> {
-        let f = self.inner.borrow_mut().open(ino, Flags::READ_ONLY)?;
-        let statfs = self.statfs()?;
-        let inode = &f.inner.borrow_mut().inode;
-        let stat = Stat {
-            ino,
-            mode: inode.mode,
-            size: inode.size,
-            blksize: statfs.bsize as u32,
-            blocks: inode.blocks * (statfs.bsize / 512),
-            atime: inode.atime,
-            mtime: inode.mtime,
-            ctime: inode.ctime,
-            crtime: inode.crtime,
-            nlink: inode.nlink,
-            uid: inode.uid,
-            gid: inode.gid,
-            rdev: inode.device,
-        };
-        Ok(stat)
+        let fh = self.inner.open(ino, Flags::READ_ONLY)?;
+        let f = fh.lock();
+        Ok(stat(&f.inode, self.inner.block_size()))
     }
 
-    pub fn opendir(&self, ino: u64) -> DkResult<DkDirHandle> {
-        self.inner.borrow_mut().open_dir(ino)
+    /// Like `getattr`, but only reads the inode if the file is not open.
+    fn peek_attr(&self, ino: u64) -> DkResult<Stat> {
+        let dk = &self.inner;
+        let bs = dk.block_size();
+        let opened = dk.opened_files.lock().unwrap().get(&ino).cloned();
+        match opened {
+            Some(f) => Ok(stat(&f.lock().unwrap().inode, bs)),
+            None => Ok(stat(&dk.read_inode(ino)?, bs)),
+        }
+    }
+
+    /// Checks whether `cred` is granted `mode` on `ino` by its access ACL
+    /// or permission bits. The super user may read and write anything,
+    /// and execute directories and files with any execute bit set.
+    pub fn access(&self, cred: &Credentials, ino: u64, mode: AccessMode) -> DkResult<()> {
+        let fh = self.inner.open(ino, Flags::READ_ONLY)?;
+        let f = fh.lock();
+        check_access(&f, cred, mode)
+    }
+
+    /// The inode of the entry `name` in `parent` if any
+    fn entry(&self, parent: u64, name: &OsStr) -> DkResult<Option<u64>> {
+        self.inner.open_dir(parent)?.lookup(&self.inner, name)
+    }
+
+    /// Checks whether `cred` may remove the entry `name` from `parent`,
+    /// which needs writing and searching it. In a sticky directory,
+    /// only the owners of the directory and of the entry may remove it.
+    fn check_removal(&self, cred: &Credentials, parent: u64, name: &OsStr) -> DkResult<()> {
+        self.access(cred, parent, AccessMode::WRITE | AccessMode::EXECUTE)?;
+        let dir = self.peek_attr(parent)?;
+        if !dir.mode.contains(FileMode::STICKY) || cred.is_root() || dir.uid == cred.uid {
+            return Ok(());
+        }
+        match self.entry(parent, name)? {
+            Some(ino) if self.peek_attr(ino)?.uid != cred.uid => Err(NotPermitted),
+            _ => Ok(()),
+        }
+    }
+
+    /// The group and the mode of a file made by `cred` in `parent`.
+    /// Files made in a directory with the set-group-ID bit take its group,
+    /// and directories also take the bit.
+    fn new_group(
+        &self,
+        cred: &Credentials,
+        parent: u64,
+        mut mode: FileMode,
+    ) -> DkResult<(u32, FileMode)> {
+        let dir = self.peek_attr(parent)?;
+        if !dir.mode.contains(FileMode::SET_GROUP_ID) {
+            return Ok((cred.gid, mode));
+        }
+        if mode.is_directory() {
+            mode.insert(FileMode::SET_GROUP_ID);
+        } else if !cred.is_root() && !cred.in_group(dir.gid) {
+            mode.remove(FileMode::SET_GROUP_ID);
+        }
+        Ok((dir.gid, mode))
+    }
+
+    pub fn opendir(&self, cred: &Credentials, ino: u64) -> DkResult<DkDirHandle> {
+        let dh = self.inner.open_dir(ino)?;
+        self.access(cred, ino, AccessMode::READ)?;
+        Ok(dh)
     }
 
     pub fn apply_releases(&self) -> DkResult<()> {
-        self.inner.borrow_mut().close_dirs_in_list()?;
-        self.inner.borrow_mut().close_files_in_list()?;
-        Ok(())
+        self.op(|| {
+            self.inner.close_dirs_in_list()?;
+            self.inner.close_files_in_list()
+        })
     }
 
-    pub fn lookup(&self, parent: u64, name: &OsStr) -> DkResult<Stat> {
+    pub fn lookup(&self, cred: &Credentials, parent: u64, name: &OsStr) -> DkResult<Stat> {
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let dir = self.opendir(parent)?;
-        match dir.entries.get(name) {
-            Some(ino) => self.getattr(*ino),
+        let dir = self.inner.open_dir(parent)?;
+        self.access(cred, parent, AccessMode::EXECUTE)?;
+        let ino = dir.lookup(&self.inner, name)?;
+        match ino {
+            Some(ino) => self.getattr(ino),
             None => Err(NotFound),
         }
     }
 
-    pub fn readdir(
-        &self,
-        dir: DkDirHandle,
-        offset: usize,
-    ) -> impl Iterator<Item = (OsString, u64)> {
-        dir.entries.skip(offset).skip(offset).into_iter()
+    /// Streams the entries of `dir`, starting from `cookie`.
+    /// The cookie of the first entry is 0. Continuing from
+    /// the cookie of a yielded entry never skips an entry that
+    /// exists all the time, even if the directory is modified.
+    /// Use `readdir_plus` if the attributes of the entries are needed.
+    pub fn readdir(&self, dir: DkDirHandle, cookie: u64) -> ReadDir<'a> {
+        ReadDir {
+            handle: self.clone(),
+            dir,
+            next_leaf: None,
+            started: false,
+            buf: VecDeque::new(),
+            cookie,
+        }
+    }
+
+    /// Like `readdir`, but also yields the attributes of each entry.
+    /// This avoids loading extents and xattrs of the entries.
+    pub fn readdir_plus(&self, dir: DkDirHandle, cookie: u64) -> ReadDirPlus<'a> {
+        ReadDirPlus {
+            inner: self.readdir(dir, cookie),
+        }
     }
 
     pub fn mknod(
         &self,
-        uid: u32,
-        gid: u32,
+        cred: &Credentials,
         parent: u64,
         name: &OsStr,
         mode: FileMode,
         rdev: Option<u64>,
     ) -> DkResult<Stat> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let parent = self.opendir(parent)?;
-        let ino = self.inner.borrow_mut().mknod(mode, uid, gid, 0, rdev)?;
-        self.inner.borrow_mut().link(ino, parent, name)?;
-        self.getattr(ino)
+        self.op_as(cred.is_root(), || {
+            let _names = self.inner.rename_lock.read().unwrap();
+            let dir = self.inner.open_dir(parent)?;
+            self.access(cred, parent, AccessMode::WRITE | AccessMode::EXECUTE)?;
+            let (gid, mode) = self.new_group(cred, parent, mode)?;
+            let default = self.inner.default_acl(parent)?;
+            let default = default.as_ref().map(|d| &d[..]);
+            let ino = self.inner.mknod(mode, cred.uid, gid, 0, rdev, default)?;
+            self.inner.link(ino, dir, name)?;
+            self.getattr(ino)
+        })
     }
 
-    pub fn link(&self, ino: u64, parent: u64, name: &OsStr) -> DkResult<Stat> {
+    /// Makes a file like `mknod` and opens it with `flags`. It is opened
+    /// even if its mode does not grant the access, like `open` with `O_CREAT`.
+    pub fn create(
+        &self,
+        cred: &Credentials,
+        parent: u64,
+        name: &OsStr,
+        mode: FileMode,
+        flags: Flags,
+    ) -> DkResult<(Stat, DkFileHandle)> {
+        let stat = self.mknod(cred, parent, name, mode, None)?;
+        let mut fh = self.inner.open(stat.ino, flags)?;
+        fh.privileged = cred.is_root();
+        Ok((stat, fh))
+    }
+
+    pub fn link(&self, cred: &Credentials, ino: u64, parent: u64, name: &OsStr) -> DkResult<Stat> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let parent = self.opendir(parent)?;
-        self.inner.borrow_mut().link(ino, parent, name)?;
-        self.getattr(ino)
+        self.op_as(cred.is_root(), || {
+            let _names = self.inner.rename_lock.read().unwrap();
+            let dir = self.inner.open_dir(parent)?;
+            self.access(cred, parent, AccessMode::WRITE | AccessMode::EXECUTE)?;
+            self.inner.link(ino, dir, name)?;
+            self.getattr(ino)
+        })
     }
 
-    pub fn open(&self, ino: u64, flags: Flags) -> DkResult<DkFileHandle> {
-        self.inner.borrow_mut().open(ino, flags)
+    pub fn open(&self, cred: &Credentials, ino: u64, flags: Flags) -> DkResult<DkFileHandle> {
+        let want = match flags & Flags::ACCESS_MODE_MASK {
+            Flags::READ_ONLY => AccessMode::READ,
+            Flags::WRITE_ONLY => AccessMode::WRITE,
+            _ => AccessMode::READ | AccessMode::WRITE,
+        };
+        if want.contains(AccessMode::WRITE) {
+            self.writable()?;
+        }
+        let mut fh = self.inner.open(ino, flags)?;
+        check_access(&fh.lock(), cred, want)?;
+        fh.privileged = cred.is_root();
+        Ok(fh)
     }
 
     pub fn flush(&self, fh: DkFileHandle) -> DkResult<()> {
-        let dk = &mut *self.inner.borrow_mut();
-        fh.inner.borrow_mut().flush(dk)
+        self.op(|| fh.lock().flush(&self.inner))
     }
 
+    /// Only the owner may change the mode, the group and the change and
+    /// creation times of a file, and only the super user may change the owner.
+    /// Changing the access and modification times also needs write permission
+    /// if not done by the owner, and truncating needs it if `fh` is not opened
+    /// for writing.
     pub fn setattr(
         &self,
+        cred: &Credentials,
         ino: u64,
         fh: Option<DkFileHandle>,
-        mode: Option<FileMode>,
-        uid: Option<u32>,
-        gid: Option<u32>,
-        size: Option<u64>,
-        atime: Option<DkTimespec>,
-        mtime: Option<DkTimespec>,
-        mut ctime: Option<DkTimespec>,
-        crtime: Option<DkTimespec>,
+        attr: SetAttr,
     ) -> DkResult<Stat> {
-        let fh = match fh {
-            Some(fh) => fh,
-            None => self.open(ino, Flags::READ_ONLY)?,
-        };
-        let mut modified = false;
-        fh.borrow_mut().dirty = true;
-        macro_rules! setattrs {
-            ($($i:ident),*) => {
-                $(
-                if let Some(v) = $i {
-                    fh.borrow_mut().inode.$i = v;
-                    modified = true;
-                })*
+        self.writable()?;
+        let SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            mut ctime,
+            crtime,
+        } = attr;
+        self.op_as(cred.is_root(), || {
+            let fh = match fh {
+                Some(fh) => fh,
+                None => self.inner.open(ino, Flags::READ_ONLY)?,
             };
-        }
-        setattrs![mode, uid, gid, atime, mtime, crtime];
-        if let Some(size) = size {
-            let dk = &mut *self.inner.borrow_mut();
-            fh.borrow_mut().update_size(dk, size)?;
-            modified = true;
-        }
+            let mut modified = false;
+            {
+                let mut f = fh.lock();
+                if !cred.is_root() {
+                    let owner = f.inode.uid == cred.uid;
+                    if !owner && (mode.is_some() || ctime.is_some() || crtime.is_some()) {
+                        return Err(NotPermitted);
+                    }
+                    if uid.is_some() && uid != Some(f.inode.uid) {
+                        return Err(NotPermitted);
+                    }
+                    if let Some(gid) = gid {
+                        if gid != f.inode.gid && !(owner && cred.in_group(gid)) {
+                            return Err(NotPermitted);
+                        }
+                    }
+                    if !owner && (atime.is_some() || mtime.is_some()) {
+                        check_access(&f, cred, AccessMode::WRITE)?;
+                    }
+                }
+                if size.is_some() && fh.flags & Flags::ACCESS_MODE_MASK == Flags::READ_ONLY {
+                    check_access(&f, cred, AccessMode::WRITE)?;
+                }
+                let group = gid.unwrap_or(f.inode.gid);
+                let mode = match mode {
+                    Some(mode) if !cred.is_root() && !cred.in_group(group) => {
+                        Some(mode - FileMode::SET_GROUP_ID)
+                    }
+                    mode => mode,
+                };
+                if (uid.is_some() || gid.is_some())
+                    && mode.is_none()
+                    && !f.inode.mode.is_directory()
+                {
+                    // Changing the owner drops the set-user-ID bit, and
+                    // the set-group-ID bit if the group may execute the file
+                    f.inode.mode.remove(FileMode::SET_USER_ID);
+                    if f.inode.mode.contains(FileMode::GROUP_EXECUTE) {
+                        f.inode.mode.remove(FileMode::SET_GROUP_ID);
+                    }
+                }
+                f.dirty = true;
+                macro_rules! setattrs {
+                    ($($i:ident),*) => {
+                        $(
+                        if let Some(v) = $i {
+                            f.inode.$i = v;
+                            modified = true;
+                        })*
+                    };
+                }
+                setattrs![mode, uid, gid, atime, mtime, crtime];
+                if mode.is_some() {
+                    acl::chmod(&mut f)?;
+                }
+            }
+            if let Some(size) = size {
+                fh.lock().update_size(&self.inner, size)?;
+                modified = true;
+            }
 
-        // Update ctime
-        if modified && ctime.is_none() {
-            ctime = Some(SystemTime::now().into());
-        }
-        if let Some(ctime) = ctime {
-            fh.borrow_mut().inode.ctime = ctime;
-        }
+            // Update ctime
+            if modified && ctime.is_none() {
+                ctime = Some(SystemTime::now().into());
+            }
+            if let Some(ctime) = ctime {
+                fh.lock().inode.ctime = ctime;
+            }
 
-        self.getattr(ino)
+            self.getattr(ino)
+        })
     }
 
     pub fn read(&self, fh: DkFileHandle, offset: u64, size: u64) -> DkResult<Vec<u8>> {
-        let dk = &mut *self.inner.borrow_mut();
-        fh.inner.borrow_mut().seek(SeekFrom::Start(offset))?;
-        let file = &mut *fh.inner.borrow_mut();
-        let io = DkFileIO { dk, file };
+        let mut file = fh.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        let io = DkFileIO {
+            dk: &self.inner,
+            file: &mut *file,
+        };
         let mut v = Vec::new();
         let len = io.take(size).read_to_end(&mut v)?;
         v.truncate(len);
@@ -179,165 +401,455 @@ impl<'a> Handle<'a> {
     }
 
     pub fn write(&self, fh: DkFileHandle, offset: u64, data: &[u8]) -> DkResult<usize> {
-        let dk = &mut *self.inner.borrow_mut();
-        fh.inner.borrow_mut().seek(SeekFrom::Start(offset))?;
-        let file = &mut *fh.inner.borrow_mut();
-        let mut io = DkFileIO { dk, file };
-        io.write_all(data)?;
-        Ok(data.len())
+        self.writable()?;
+        self.op_as(fh.privileged, || {
+            let mut file = fh.lock();
+            file.seek(SeekFrom::Start(offset))?;
+            let mut io = DkFileIO {
+                dk: &self.inner,
+                file: &mut *file,
+            };
+            io.write_all(data)?;
+            Ok(data.len())
+        })
+    }
+
+    /// Frees the blocks within `len` bytes at `offset`, leaving a hole
+    /// which reads as zeros. The file size does not change.
+    pub fn punch_hole(&self, fh: DkFileHandle, offset: u64, len: u64) -> DkResult<()> {
+        self.writable()?;
+        self.op_as(fh.privileged, || {
+            let mut file = fh.lock();
+            if file.inode.mode.is_directory() {
+                return Err(IsDirectory);
+            }
+            file.punch_hole(&self.inner, offset, len)
+        })
+    }
+
+    /// Allocates zeroed blocks for the holes within `len` bytes at `offset`,
+    /// so that writing there later does not run out of space.
+    /// The file grows to cover the range unless `keep_size` is set.
+    pub fn fallocate(
+        &self,
+        fh: DkFileHandle,
+        offset: u64,
+        len: u64,
+        keep_size: bool,
+    ) -> DkResult<()> {
+        self.writable()?;
+        self.op_as(fh.privileged, || {
+            let mut file = fh.lock();
+            if file.inode.mode.is_directory() {
+                return Err(IsDirectory);
+            }
+            file.allocate(&self.inner, offset, len, keep_size)
+        })
+    }
+
+    /// The offset of the first data at or after `offset`, like `SEEK_DATA`.
+    /// Returns `None` if there is no data before the end of the file.
+    pub fn seek_data(&self, fh: DkFileHandle, offset: u64) -> DkResult<Option<u64>> {
+        let mut file = fh.lock();
+        let size = file.inode.size;
+        if offset >= size {
+            return Ok(None);
+        }
+        let bs = self.inner.block_size();
+        Ok(file
+            .next_data(&self.inner, offset / bs)?
+            .map(|bi| max(offset, bi * bs))
+            .filter(|&pos| pos < size))
+    }
+
+    /// The offset of the first hole at or after `offset`, like `SEEK_HOLE`.
+    /// The end of the file counts as a hole.
+    /// Returns `None` if `offset` is beyond the end of the file.
+    pub fn seek_hole(&self, fh: DkFileHandle, offset: u64) -> DkResult<Option<u64>> {
+        let mut file = fh.lock();
+        let size = file.inode.size;
+        if offset >= size {
+            return Ok(None);
+        }
+        let bs = self.inner.block_size();
+        let bi = file.next_hole(&self.inner, offset / bs)?;
+        Ok(Some(min(size, max(offset, bi * bs))))
     }
 
     pub fn mkdir(
         &self,
+        cred: &Credentials,
         parent: u64,
-        uid: u32,
-        gid: u32,
         name: &OsStr,
         mode: FileMode,
     ) -> DkResult<Stat> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let ino = self.inner.borrow_mut().mkdir(parent, mode, uid, gid)?;
-        let parent = self.opendir(parent)?;
-        self.inner.borrow_mut().link(ino, parent, name)?;
-        self.getattr(ino)
+        self.op_as(cred.is_root(), || {
+            let _names = self.inner.rename_lock.read().unwrap();
+            let dir = self.inner.open_dir(parent)?;
+            self.access(cred, parent, AccessMode::WRITE | AccessMode::EXECUTE)?;
+            let (gid, mode) = self.new_group(cred, parent, FileMode::DIRECTORY | mode)?;
+            let default = self.inner.default_acl(parent)?;
+            let default = default.as_ref().map(|d| &d[..]);
+            let ino = self.inner.mkdir(parent, mode, cred.uid, gid, default)?;
+            self.inner.link(ino, dir, name)?;
+            self.getattr(ino)
+        })
     }
 
-    pub fn getxattr(&self, ino: u64, name: &OsStr) -> DkResult<Option<Vec<u8>>> {
+    pub fn getxattr(
+        &self,
+        cred: &Credentials,
+        ino: u64,
+        name: &OsStr,
+    ) -> DkResult<Option<Vec<u8>>> {
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let fh = self.open(ino, Flags::READ_ONLY)?;
-        let fh = fh.borrow();
-        Ok(fh.xattr.get(name).cloned())
+        let fh = self.inner.open(ino, Flags::READ_ONLY)?;
+        let f = fh.lock();
+        check_xattr(&f, cred, name, AccessMode::READ)?;
+        Ok(f.xattr.get(name).cloned())
     }
 
-    pub fn listxattr(&self, ino: u64) -> DkResult<Vec<OsString>> {
-        let fh = self.open(ino, Flags::READ_ONLY)?;
-        let v = fh.borrow().xattr.keys().map(|key| key.to_owned()).collect();
+    /// Trusted xattrs are only listed for the super user.
+    pub fn listxattr(&self, cred: &Credentials, ino: u64) -> DkResult<Vec<OsString>> {
+        let fh = self.inner.open(ino, Flags::READ_ONLY)?;
+        let v = fh
+            .lock()
+            .xattr
+            .keys()
+            .filter(|key| cred.is_root() || !key.as_bytes().starts_with(b"trusted."))
+            .map(|key| key.to_owned())
+            .collect();
         Ok(v)
     }
 
-    pub fn setxattr(&self, ino: u64, name: &OsStr, value: &[u8]) -> DkResult<()> {
+    pub fn setxattr(
+        &self,
+        cred: &Credentials,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+    ) -> DkResult<()> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let fh = self.open(ino, Flags::READ_ONLY)?;
-        fh.borrow_mut().dirty = true;
-        fh.borrow_mut()
-            .xattr
-            .insert(name.to_owned(), Vec::from(value));
-        Ok(())
+        if value.len() > MAX_XATTR_VALUE_LEN {
+            return Err(TooLarge);
+        }
+        self.op_as(cred.is_root(), || {
+            let fh = self.inner.open(ino, Flags::READ_ONLY)?;
+            let mut f = fh.lock();
+            check_xattr(&f, cred, name, AccessMode::WRITE)?;
+            let (mode, ctime) = (f.inode.mode, f.inode.ctime);
+            let value = acl::set(&mut f, name, value)?;
+            if name == acl::ACCESS_XATTR && !cred.is_root() && !cred.in_group(f.inode.gid) {
+                // Like `chmod`
+                f.inode.mode.remove(FileMode::SET_GROUP_ID);
+            }
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    // The permission bits say it all
+                    f.xattr.remove(name);
+                    f.dirty = true;
+                    return Ok(());
+                }
+            };
+            let old = f.xattr.insert(name.to_owned(), value);
+            if f.xattr_len() > DkFile::xattr_capacity(&self.inner) {
+                match old {
+                    Some(old) => f.xattr.insert(name.to_owned(), old),
+                    None => f.xattr.remove(name),
+                };
+                f.inode.mode = mode;
+                f.inode.ctime = ctime;
+                return Err(XattrsFull);
+            }
+            f.dirty = true;
+            Ok(())
+        })
     }
 
-    pub fn removexattr(&self, ino: u64, name: &OsStr) -> DkResult<()> {
+    pub fn removexattr(&self, cred: &Credentials, ino: u64, name: &OsStr) -> DkResult<()> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let fh = self.open(ino, Flags::READ_ONLY)?;
-        fh.borrow_mut().dirty = true;
-        fh.borrow_mut().xattr.remove(name);
-        Ok(())
+        self.op(|| {
+            let fh = self.inner.open(ino, Flags::READ_ONLY)?;
+            let mut f = fh.lock();
+            check_xattr(&f, cred, name, AccessMode::WRITE)?;
+            f.dirty = true;
+            f.xattr.remove(name);
+            Ok(())
+        })
     }
 
     pub fn fsync(&self, fh: DkFileHandle, datasync: bool) -> DkResult<()> {
-        // Now we do not support data cache, so only metadata needs synchronizing
         if !datasync {
             self.flush(fh)?;
         }
-        Ok(())
+        self.inner.sync()
     }
 
     pub fn fsyncdir(&self, dh: DkDirHandle, datasync: bool) -> DkResult<()> {
-        let dk = &mut *self.inner.borrow_mut();
-        dh.borrow_mut().flush(dk)?;
-        self.fsync(dh.borrow().fh.clone(), datasync)
+        self.op(|| dh.lock().flush(&self.inner))?;
+        let fh = dh.lock().fh.clone();
+        self.fsync(fh, datasync)
     }
 
-    pub fn unlink(&self, parent: u64, name: &OsStr) -> DkResult<()> {
+    pub fn unlink(&self, cred: &Credentials, parent: u64, name: &OsStr) -> DkResult<()> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let dh = self.opendir(parent)?;
-        self.inner.borrow_mut().unlink(dh, name)
+        self.op(|| {
+            let _names = self.inner.rename_lock.read().unwrap();
+            let dh = self.inner.open_dir(parent)?;
+            self.check_removal(cred, parent, name)?;
+            self.inner.unlink(dh, name)
+        })
     }
 
     pub fn rename(
         &self,
+        cred: &Credentials,
         old_parent: u64,
         name: &OsStr,
         new_parent: u64,
         new_name: &OsStr,
+        flags: RenameFlags,
     ) -> DkResult<()> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize || new_name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let ino = self.lookup(old_parent, name)?.ino;
-        if let Ok(stat) = self.lookup(new_parent, new_name) {
-            if stat.mode.is_directory() {
-                self.rmdir(new_parent, new_name)?;
-            } else {
-                self.unlink(new_parent, new_name)?;
-            }
+        if flags.contains(RenameFlags::NOREPLACE | RenameFlags::EXCHANGE) {
+            return Err(Invalid(
+                "NOREPLACE and EXCHANGE cannot be used together".to_string(),
+            ));
         }
-        let new_parent = self.opendir(new_parent)?;
-        self.inner.borrow_mut().link(ino, new_parent, new_name)?;
-        self.unlink(old_parent, name)?;
-        Ok(())
+        let dots = [OsStr::new("."), OsStr::new("..")];
+        if dots.contains(&name) || dots.contains(&new_name) {
+            return Err(Invalid("Cannot rename . or ..".to_string()));
+        }
+        // A directory moved to another parent needs writing its `..` entry
+        let moved_dir = |parent, name| -> DkResult<()> {
+            match self.entry(parent, name)? {
+                Some(ino) if self.peek_attr(ino)?.mode.is_directory() => {
+                    self.access(cred, ino, AccessMode::WRITE)
+                }
+                _ => Ok(()),
+            }
+        };
+        let check = || -> DkResult<()> {
+            self.check_removal(cred, old_parent, name)?;
+            self.check_removal(cred, new_parent, new_name)?;
+            if old_parent != new_parent {
+                moved_dir(old_parent, name)?;
+                if flags.contains(RenameFlags::EXCHANGE) {
+                    moved_dir(new_parent, new_name)?;
+                }
+            }
+            Ok(())
+        };
+        self.op_as(cred.is_root(), || {
+            self.inner
+                .rename(old_parent, name, new_parent, new_name, flags, check)
+        })
     }
 
-    pub fn rmdir(&self, parent: u64, name: &OsStr) -> DkResult<()> {
-        let dir = self.lookup(parent, name)?;
-        let ino = dir.ino;
-        let dir = self.opendir(ino)?;
-        if dir.entries.len() == 2 {
-            // dir only contains . and ..
-            self.unlink(ino, OsStr::new("."))?;
-            self.unlink(ino, OsStr::new(".."))?;
-            self.unlink(parent, name)
-        } else {
-            Err(NotEmpty)
+    pub fn rmdir(&self, cred: &Credentials, parent: u64, name: &OsStr) -> DkResult<()> {
+        self.writable()?;
+        if name.len() > MAX_NAMELEN as usize {
+            return Err(NameTooLong);
         }
+        self.op(|| {
+            let _names = self.inner.rename_lock.read().unwrap();
+            let dh = self.inner.open_dir(parent)?;
+            self.check_removal(cred, parent, name)?;
+            self.inner.rmdir(dh, name)
+        })
     }
 
     pub fn symlink(
         &self,
-        uid: u32,
-        gid: u32,
+        cred: &Credentials,
         parent: u64,
         name: &OsStr,
         link: &Path,
     ) -> DkResult<Stat> {
+        self.writable()?;
         if name.len() > MAX_NAMELEN as usize {
             return Err(NameTooLong);
         }
-        let stat = self.mknod(
-            uid,
-            gid,
-            parent,
-            name,
-            FileMode::SYMBOLIC_LINK
-                | FileMode::USER_RWX
-                | FileMode::GROUP_RWX
-                | FileMode::OTHERS_RWX,
-            None,
-        )?;
-        let fh = self.open(stat.ino, Flags::WRITE_ONLY)?;
-        let bytes = link.as_os_str().as_bytes();
-        let mut offset = 0;
-        while offset < bytes.len() {
-            offset += self.write(fh.clone(), offset as u64, &bytes[offset..])?;
-        }
-        self.getattr(stat.ino)
+        self.op_as(cred.is_root(), || {
+            let stat = self.mknod(
+                cred,
+                parent,
+                name,
+                FileMode::SYMBOLIC_LINK
+                    | FileMode::USER_RWX
+                    | FileMode::GROUP_RWX
+                    | FileMode::OTHERS_RWX,
+                None,
+            )?;
+            let mut fh = self.inner.open(stat.ino, Flags::WRITE_ONLY)?;
+            fh.privileged = cred.is_root();
+            let bytes = link.as_os_str().as_bytes();
+            let mut offset = 0;
+            while offset < bytes.len() {
+                offset += self.write(fh.clone(), offset as u64, &bytes[offset..])?;
+            }
+            self.getattr(stat.ino)
+        })
     }
 
     pub fn clear_set_bits(&self, fh: DkFileHandle) -> DkResult<DkFileHandle> {
-        let mut mode = fh.borrow().inode.mode;
-        mode.remove(FileMode::SET_USER_ID);
-        mode.remove(FileMode::SET_GROUP_ID);
-        fh.borrow_mut().inode.mode = mode;
-        fh.borrow_mut().dirty = true;
+        self.writable()?;
+        self.op(|| {
+            let mut f = fh.lock();
+            f.inode.mode.remove(FileMode::SET_USER_ID);
+            f.inode.mode.remove(FileMode::SET_GROUP_ID);
+            f.dirty = true;
+            Ok(())
+        })?;
         Ok(fh)
     }
 }
+
+/// Iterator returned by `Handle::readdir`.
+/// Only one leaf of the directory is kept in memory at a time.
+#[derive(Debug)]
+pub struct ReadDir<'a> {
+    handle: Handle<'a>,
+    dir: DkDirHandle,
+    next_leaf: Option<u64>,
+    started: bool,
+    buf: VecDeque<DirEntry>,
+    cookie: u64,
+}
+
+impl<'a> ReadDir<'a> {
+    /// Reads leaves until some entries are buffered or all are read.
+    fn fill(&mut self) -> DkResult<()> {
+        let dk = &self.handle.inner;
+        let mut dir = self.dir.lock();
+        if !self.started {
+            self.started = true;
+            self.next_leaf = dir.find_leaf(dk, self.cookie)?;
+        }
+        while self.buf.is_empty() {
+            let lblk = match self.next_leaf {
+                Some(lblk) => lblk,
+                None => return Ok(()),
+            };
+            let (entries, next) = dir.read_leaf(dk, lblk)?;
+            self.next_leaf = next;
+            let cookies = cookies(&entries);
+            let cookie = self.cookie;
+            self.buf.extend(
+                entries
+                    .into_iter()
+                    .zip(cookies)
+                    .filter(|(e, _)| e.hash >= cookie)
+                    .map(|(e, cookie)| DirEntry {
+                        name: e.name,
+                        ino: e.ino,
+                        file_type: e.file_type,
+                        cookie,
+                    }),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ReadDir<'a> {
+    type Item = DkResult<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            // Stop after reporting the error
+            self.next_leaf = None;
+            self.buf.clear();
+            return Some(Err(e));
+        }
+        self.buf.pop_front().map(Ok)
+    }
+}
+
+/// Iterator returned by `Handle::readdir_plus`
+#[derive(Debug)]
+pub struct ReadDirPlus<'a> {
+    inner: ReadDir<'a>,
+}
+
+impl<'a> Iterator for ReadDirPlus<'a> {
+    type Item = DkResult<(DirEntry, Stat)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let e = self.inner.next()?;
+        let handle = &self.inner.handle;
+        Some(e.and_then(|e| handle.peek_attr(e.ino).map(|stat| (e, stat))))
+    }
+}
+
+/// Like `Handle::access` on an opened file
+fn check_access(f: &DkFile, cred: &Credentials, mode: AccessMode) -> DkResult<()> {
+    let granted = if cred.is_root() {
+        let executable =
+            FileMode::USER_EXECUTE | FileMode::GROUP_EXECUTE | FileMode::OTHERS_EXECUTE;
+        !mode.contains(AccessMode::EXECUTE)
+            || f.inode.mode.is_directory()
+            || f.inode.mode.intersects(executable)
+    } else {
+        acl::permits(f, cred, mode)?
+    };
+    if granted {
+        Ok(())
+    } else {
+        Err(PermissionDenied)
+    }
+}
+
+/// Checks whether `cred` may read (`want` is `READ`) or change (`WRITE`)
+/// the xattr `name` of the file. User xattrs follow the permissions of
+/// the file, and ACLs may only be changed by the owner. Other xattrs may
+/// be read by anyone except trusted ones, and only changed by the super user.
+fn check_xattr(f: &DkFile, cred: &Credentials, name: &OsStr, want: AccessMode) -> DkResult<()> {
+    let bytes = name.as_bytes();
+    let readable = want == AccessMode::READ && !bytes.starts_with(b"trusted.");
+    let is_acl = name == acl::ACCESS_XATTR || name == acl::DEFAULT_XATTR;
+    if bytes.starts_with(b"user.") {
+        check_access(f, cred, want)
+    } else if cred.is_root() || readable || (is_acl && f.inode.uid == cred.uid) {
+        Ok(())
+    } else {
+        Err(NotPermitted)
+    }
+}
+
+fn stat(inode: &Inode, bsize: u64) -> Stat {
+    Stat {
+        ino: inode.ino,
+        mode: inode.mode,
+        size: inode.size,
+        blksize: bsize as u32,
+        blocks: inode.blocks * (bsize / 512),
+        atime: inode.atime,
+        mtime: inode.mtime,
+        ctime: inode.ctime,
+        crtime: inode.crtime,
+        nlink: inode.nlink,
+        uid: inode.uid,
+        gid: inode.gid,
+        rdev: inode.device,
+    }
+}
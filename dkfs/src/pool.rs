@@ -0,0 +1,73 @@
+use std::cell::Cell;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// `Box<FnOnce()>` cannot be called directly
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+thread_local! {
+    /// Whether the current thread is a worker of some pool
+    static WORKER: Cell<bool> = Cell::new(false);
+}
+
+/// Whether the current thread is a worker of some pool.
+/// A job waiting on another job may run it in place instead,
+/// as the pool may have no other worker free.
+pub fn is_worker() -> bool {
+    WORKER.with(|w| w.get())
+}
+
+/// A fixed number of threads running the jobs sent to them in turn.
+pub struct Pool {
+    sender: Option<Sender<Box<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = channel::<Box<Job>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || {
+                    WORKER.with(|w| w.set(true));
+                    loop {
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job.run(),
+                            // The pool is dropped
+                            Err(_) => return,
+                        }
+                    }
+                })
+            }).collect();
+        Pool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for Pool {
+    /// Waits for the jobs already sent.
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
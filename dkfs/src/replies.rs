@@ -1,3 +1,4 @@
+use std::ffi::OsString;
 use *;
 
 #[derive(Debug, PartialEq)]
@@ -11,6 +12,12 @@ pub struct Statvfs {
     pub namelen: u32,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct Volume {
+    pub label: String,
+    pub uuid: [u8; 16],
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Stat {
     pub ino: u64,
@@ -27,3 +34,13 @@ pub struct Stat {
     pub gid: u32,
     pub rdev: u64,
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub ino: u64,
+    /// Only the file type bits of the mode are set
+    pub file_type: FileMode,
+    /// Pass to `Handle::readdir` to continue after this entry
+    pub cookie: u64,
+}
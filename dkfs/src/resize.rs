@@ -0,0 +1,293 @@
+//! Offline shrinking of a donkey file system.
+//!
+//! Growing needs no moving, so it is done online by `Handle::grow`.
+
+use alloc::Bitmap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use *;
+
+/// Shrinks the file system on `dev` to the first `new_size` bytes,
+/// so that the rest of the device can be cut off.
+///
+/// Blocks and inodes beyond the new end are moved into the part kept,
+/// and directory entries are renumbered after the moved inodes.
+/// Inode chunks beyond the new end are dropped after their inodes move.
+/// The inode table shrinks in proportion to the data area.
+/// Nothing is moved if the files do not fit.
+pub fn shrink<'a>(dev: Box<Device + 'a>, new_size: u64) -> DkResult<Handle<'a>> {
+    let dk = load(dev, None, false)?;
+    if dk.read_only {
+        return Err(ReadOnly);
+    }
+    dk.reconcile_counts();
+    let handle = Handle::new(dk);
+    Shrinker::plan(&handle.inner, new_size)?.run()?;
+    Ok(handle)
+}
+
+struct Shrinker<'a, 'b: 'a> {
+    dk: &'a Donkey<'b>,
+    /// The super block after shrinking
+    sb: SuperBlock,
+    /// Data blocks from this index on are moved. It is the end of the new
+    /// data area, or the block of the last backup super block if any.
+    limit: u64,
+    /// Data blocks taken by the data block bitmap at its new place
+    taken: Range<u64>,
+    /// Data blocks of the old data block bitmap which are not needed anymore
+    freed: Vec<u64>,
+}
+
+impl<'a, 'b: 'a> Shrinker<'a, 'b> {
+    /// Works out the new layout and checks that the files fit in it.
+    fn plan(dk: &'a Donkey<'b>, new_size: u64) -> DkResult<Self> {
+        let alloc = dk.alloc();
+        let old = &alloc.sb;
+        let bs = old.block_size;
+        let first_db = old.first_db_ptr / bs;
+        let old_blocks = first_db + old.db_count;
+        let new_blocks = new_size / bs;
+        if new_blocks > old_blocks {
+            return Err(Invalid(format!(
+                "The file system takes up only {} bytes",
+                old_blocks * bs
+            )));
+        }
+        if new_blocks <= first_db {
+            return Err(Invalid(format!(
+                "The file system needs more than {} bytes",
+                first_db * bs
+            )));
+        }
+
+        let mut sb = old.clone();
+        sb.db_count = new_blocks - first_db;
+        let scale = |count: u64| (count as f64 * new_blocks as f64 / old_blocks as f64) as u64;
+        // The inode table may grow back later
+        sb.max_inode_count = old.max_inode_count();
+        sb.inode_count = max(old.used_inode_count, scale(old.inode_count));
+        sb.reserved_db_count = scale(old.reserved_db_count);
+        let first_db_ptr = old.first_db_ptr;
+        let index = |ptr: u64| (ptr - first_db_ptr) / bs;
+        let limit = match backup_ptrs(&sb).last() {
+            Some(&ptr) => index(ptr),
+            None => sb.db_count,
+        };
+
+        let mut taken = 0..0;
+        let mut freed = Vec::new();
+        if old.db_bitmap_ptr >= old.first_db_ptr {
+            // Moved into the data area by growing the file system
+            let bitmap_blocks = |count: u64| (Bitmap::bytes_len(count) + bs - 1) / bs;
+            let old_start = index(old.db_bitmap_ptr);
+            let old_run = old_start..old_start + bitmap_blocks(old.db_count);
+            let len = bitmap_blocks(sb.db_count);
+            let front = front_db_bitmap_ptr(old);
+            let kept = if Bitmap::bytes_len(sb.db_count) <= room_before_data_area(old, front) {
+                sb.db_bitmap_ptr = front;
+                0..0
+            } else if old_start + len <= limit {
+                old_start..old_start + len
+            } else {
+                let start = alloc
+                    .db_bitmap
+                    .find_run_before(len, limit)
+                    .ok_or_else(|| Invalid("No room is left for the block bitmap".to_string()))?;
+                sb.db_bitmap_ptr = first_db_ptr + start * bs;
+                taken = start..start + len;
+                taken.clone()
+            };
+            freed = old_run
+                .filter(|&i| i < limit && !kept.contains(&i))
+                .collect();
+        }
+
+        let metadata: HashSet<u64> = metadata_in_data_area(old).into_iter().map(index).collect();
+        let db_bitmap = &alloc.db_bitmap;
+        let to_move = (limit..old.db_count)
+            .filter(|&i| db_bitmap.get(i) && !metadata.contains(&i))
+            .count() as u64;
+        let free = (0..limit).filter(|&i| !db_bitmap.get(i)).count() as u64;
+        let free = free - (taken.end - taken.start);
+        if to_move > free {
+            return Err(Invalid(format!(
+                "{} more blocks are needed to hold the files",
+                to_move - free
+            )));
+        }
+
+        Ok(Shrinker {
+            dk,
+            sb,
+            limit,
+            taken,
+            freed,
+        })
+    }
+
+    fn run(&mut self) -> DkResult<()> {
+        {
+            let mut alloc = self.dk.alloc();
+            let alloc = &mut *alloc;
+            let (start, len) = (self.taken.start, self.taken.end - self.taken.start);
+            alloc.db_bitmap.set_range(start, len);
+            alloc.db_bitmap.flush(&mut *self.dk.dev(), start, len)?;
+            alloc.sb.used_db_count += len;
+            alloc.sb_dirty = true;
+            // Nothing is allocated beyond the new ends from now on.
+            // The bits there are kept until the bitmaps are truncated.
+            alloc.db_bitmap.len = self.limit;
+            alloc.inode_bitmap.len = self.sb.inode_count;
+        }
+        let moved = self.move_inodes()?;
+        self.move_blocks(&moved)?;
+        self.finish()
+    }
+
+    /// Moves the inodes beyond the new end of the inode table
+    /// and those in the inode chunks beyond the limit.
+    /// Returns the new numbers of the moved inodes.
+    fn move_inodes(&mut self) -> DkResult<HashMap<u64, u64>> {
+        let dk = self.dk;
+        let in_chunks = self.drop_chunks()?;
+        let in_table: Vec<u64> = {
+            let alloc = dk.alloc();
+            (self.sb.inode_count..alloc.sb.inode_count)
+                .filter(|&i| alloc.inode_bitmap.get(i))
+                .map(|i| ROOT_INODE + i)
+                .collect()
+        };
+        let mut moved = HashMap::new();
+        for &ino in in_table.iter().chain(&in_chunks) {
+            let mut inode = dk.read_inode(ino)?;
+            inode.ino = dk.allocate_inode()?;
+            dk.write_inode(&inode)?;
+            moved.insert(ino, inode.ino);
+            // A transaction cannot outgrow the journal
+            if dk.commit_due() {
+                dk.commit()?;
+            }
+        }
+        for ino in in_table {
+            dk.free_inode(ino)?;
+        }
+        Ok(moved)
+    }
+
+    /// Takes the inode chunks beyond the limit out of both lists,
+    /// so that no inode is allocated in them.
+    /// Returns the inodes in use in them, which are no longer counted.
+    fn drop_chunks(&mut self) -> DkResult<Vec<u64>> {
+        let dk = self.dk;
+        let limit = self.sb.first_db_ptr + self.limit * self.sb.block_size;
+        let (kept, dropped): (Vec<_>, Vec<_>) = dk
+            .inode_chunks()?
+            .into_iter()
+            .partition(|&(ptr, _)| ptr < limit);
+        if dropped.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let per_chunk = inodes_per_chunk(self.sb.block_size);
+        let kept_count = kept.len() as u64;
+        let (mut next, mut next_free) = (0, 0);
+        for (ptr, mut chunk) in kept.into_iter().rev() {
+            let old = (chunk.next, chunk.next_free);
+            chunk.next = next;
+            if chunk.find_free(per_chunk).is_some() {
+                chunk.next_free = next_free;
+                next_free = ptr;
+            }
+            if (chunk.next, chunk.next_free) != old {
+                dk.write(ptr, &chunk)?;
+            }
+            next = ptr;
+        }
+        let mut inos = Vec::new();
+        for (ptr, chunk) in dropped {
+            let slots = (1..=per_chunk).filter(|&slot| chunk.is_used(slot));
+            inos.extend(slots.map(|slot| dk.chunk_ino(ptr, slot)));
+        }
+
+        let mut alloc = dk.alloc();
+        alloc.sb.inode_chunk_ptr = next;
+        alloc.sb.free_inode_chunk_ptr = next_free;
+        alloc.sb.inode_chunk_count = kept_count;
+        alloc.sb.used_chunk_inode_count -= inos.len() as u64;
+        alloc.sb_dirty = true;
+        Ok(inos)
+    }
+
+    /// Moves the blocks beyond the limit used by every file,
+    /// and renumbers the entries referring to moved inodes.
+    fn move_blocks(&mut self, moved: &HashMap<u64, u64>) -> DkResult<()> {
+        let dk = self.dk;
+        let limit = self.sb.first_db_ptr + self.limit * self.sb.block_size;
+        for ino in dk.inodes_in_use()? {
+            let is_dir = {
+                let fh = dk.open(ino, Flags::READ_ONLY)?;
+                let mut f = fh.lock();
+                f.relocate(dk, limit)?;
+                f.inode.mode.is_directory()
+            };
+            if is_dir && !moved.is_empty() {
+                dk.open_dir(ino)?.lock().renumber(dk, moved)?;
+            }
+            dk.close_dirs_in_list()?;
+            dk.close_files_in_list()?;
+            if dk.commit_due() {
+                dk.commit()?;
+            }
+        }
+        dk.commit()
+    }
+
+    /// Truncates the bitmaps, moves the data block bitmap if needed
+    /// and writes the new super block.
+    fn finish(&mut self) -> DkResult<()> {
+        let mut alloc = self.dk.alloc();
+        let alloc = &mut *alloc;
+        let mut dev = self.dk.dev();
+        // The journal must not know the bytes written directly
+        dev.checkpoint()?;
+
+        let sb = &self.sb;
+        let db_bitmap = &mut alloc.db_bitmap;
+        for &i in &self.freed {
+            db_bitmap.clear(i);
+        }
+        if self.limit < sb.db_count {
+            // The block of the last backup super block
+            db_bitmap.set(self.limit);
+        }
+        db_bitmap.truncate(sb.db_count);
+        if db_bitmap.ptr != sb.db_bitmap_ptr {
+            // Nothing refers to the new place until the new super block is committed
+            db_bitmap.ptr = sb.db_bitmap_ptr;
+            db_bitmap.write_unjournaled(&mut dev, 0)?;
+            dev.sync()?;
+        } else {
+            for &i in &self.freed {
+                db_bitmap.flush(&mut *dev, i, 1)?;
+            }
+            // The last byte, which holds the block of the last backup
+            db_bitmap.flush(&mut *dev, sb.db_count - 1, 1)?;
+        }
+        alloc.inode_bitmap.truncate(sb.inode_count);
+        alloc.inode_bitmap.flush(&mut *dev, sb.inode_count - 1, 1)?;
+
+        let mut sb = sb.clone();
+        sb.used_db_count = alloc.db_bitmap.count();
+        sb.used_inode_count = alloc.inode_bitmap.count();
+        sb.inode_chunk_ptr = alloc.sb.inode_chunk_ptr;
+        sb.free_inode_chunk_ptr = alloc.sb.free_inode_chunk_ptr;
+        sb.inode_chunk_count = alloc.sb.inode_chunk_count;
+        sb.used_chunk_inode_count = alloc.sb.used_chunk_inode_count;
+        alloc.sb = sb;
+        alloc.flush_sb(&mut dev)?;
+        dev.commit()?;
+        alloc.db_bitmap.release_reserved();
+        dev.sync()
+    }
+}
@@ -1,5 +1,6 @@
 extern crate dkfs;
 extern crate rand;
+extern crate tokio;
 
 use dkfs::device::Memory;
 use dkfs::replies::*;
@@ -11,25 +12,34 @@ use std::collections::{BTreeMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::thread;
 
 macro_rules! prepare {
     ($i: ident) => {
+        prepare!($i, FormatOptions::default());
+    };
+    ($i: ident, $opts: expr) => {
         let mut mem = vec![0; 33554432]; // 32MB
         let mem = Box::new(Memory::new(&mut mem[..]));
-        let $i = format(mem, FormatOptions::default())?;
+        let $i = format(mem, $opts)?;
     };
 }
+
+fn root() -> Credentials {
+    Credentials::root()
+}
 #[test]
 fn statfs() -> DkResult<()> {
     prepare!(handle);
     assert_eq!(
         handle.statfs()?,
         Statvfs {
-            blocks: 8063,
-            bfree: 8062,
-            bavail: 8062,
-            files: 2048,
-            ffree: 2047,
+            blocks: 7999,
+            bfree: 7994,
+            bavail: 7994,
+            // Each free block may become a chunk of 15 inodes
+            files: 1024 + 7994 * 15,
+            ffree: 1023 + 7994 * 15,
             bsize: 4096,
             namelen: 256,
         }
@@ -53,8 +63,7 @@ fn get_root_attr() -> DkResult<()> {
 fn mknod_in_root() -> DkResult<()> {
     prepare!(handle);
     let stat = handle.mknod(
-        0,
-        0,
+        &root(),
         ROOT_INODE,
         OsStr::new("Homura"),
         FileMode::REGULAR_FILE | FileMode::USER_RWX,
@@ -66,25 +75,24 @@ fn mknod_in_root() -> DkResult<()> {
     assert!(stat.mode.is_regular_file());
     assert!(stat.mode.contains(FileMode::USER_RWX));
     assert_eq!(handle.getattr(stat.ino)?, stat);
-    assert_eq!(handle.lookup(ROOT_INODE, OsStr::new("Homura"))?, stat);
+    assert_eq!(handle.lookup(&root(), ROOT_INODE, OsStr::new("Homura"))?, stat);
     Ok(())
 }
 
 #[test]
 fn mknod_in_newdir() -> DkResult<()> {
     prepare!(handle);
-    let stat = handle.mkdir(ROOT_INODE, 0, 0, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+    let stat = handle.mkdir(&root(), ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
     assert!(stat.mode.is_directory());
     let dir_ino = stat.ino;
     let homura = OsStr::new("Homura");
-    let stat = handle.mknod(0, 0, dir_ino, homura, FileMode::REGULAR_FILE, None)?;
-    assert_eq!(handle.lookup(dir_ino, homura)?, stat);
-    let dir = handle.opendir(dir_ino)?;
-    assert!(
-        handle
-            .readdir(dir, 0)
-            .any(|(name, ino)| name == homura && ino == stat.ino)
-    );
+    let stat = handle.mknod(&root(), dir_ino, homura, FileMode::REGULAR_FILE, None)?;
+    assert_eq!(handle.lookup(&root(), dir_ino, homura)?, stat);
+    let dir = handle.opendir(&root(), dir_ino)?;
+    let entries = handle.readdir(dir, 0).collect::<DkResult<Vec<_>>>()?;
+    assert!(entries.iter().any(|e| e.name == homura
+        && e.ino == stat.ino
+        && e.file_type == FileMode::REGULAR_FILE));
     Ok(())
 }
 
@@ -92,8 +100,7 @@ fn mknod_in_newdir() -> DkResult<()> {
 fn set_attrs_except_size() -> DkResult<()> {
     prepare!(handle);
     let stat = handle.mknod(
-        0,
-        0,
+        &root(),
         ROOT_INODE,
         OsStr::new("Homura"),
         FileMode::REGULAR_FILE | FileMode::USER_RWX,
@@ -101,28 +108,31 @@ fn set_attrs_except_size() -> DkResult<()> {
     )?;
     let ino = stat.ino;
     let stat = handle.setattr(
+        &root(),
         ino,
         None,
-        Some(FileMode::REGULAR_FILE | FileMode::USER_READ),
-        Some(1000),
-        Some(1000),
-        None,
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 3,
-        }),
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 2,
-        }),
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 1,
-        }),
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 0,
-        }),
+        SetAttr {
+            mode: Some(FileMode::REGULAR_FILE | FileMode::USER_READ),
+            uid: Some(1000),
+            gid: Some(1000),
+            atime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 3,
+            }),
+            mtime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 2,
+            }),
+            ctime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 1,
+            }),
+            crtime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 0,
+            }),
+            ..Default::default()
+        },
     )?;
     assert_eq!(stat.ino, ino);
     assert_eq!(stat.mode, FileMode::REGULAR_FILE | FileMode::USER_READ);
@@ -164,38 +174,40 @@ fn set_attrs_except_size() -> DkResult<()> {
 fn set_fh_attrs_except_size() -> DkResult<()> {
     prepare!(handle);
     let stat = handle.mknod(
-        0,
-        0,
+        &root(),
         ROOT_INODE,
         OsStr::new("Homura"),
         FileMode::REGULAR_FILE | FileMode::USER_RWX,
         None,
     )?;
     let ino = stat.ino;
-    let fh = handle.open(ino, Flags::READ_ONLY)?;
+    let fh = handle.open(&root(), ino, Flags::READ_ONLY)?;
     let stat = handle.setattr(
+        &root(),
         ino,
         Some(fh),
-        Some(FileMode::REGULAR_FILE | FileMode::USER_READ),
-        Some(1000),
-        Some(1000),
-        None,
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 3,
-        }),
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 2,
-        }),
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 1,
-        }),
-        Some(DkTimespec {
-            sec: 612921600,
-            nsec: 0,
-        }),
+        SetAttr {
+            mode: Some(FileMode::REGULAR_FILE | FileMode::USER_READ),
+            uid: Some(1000),
+            gid: Some(1000),
+            atime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 3,
+            }),
+            mtime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 2,
+            }),
+            ctime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 1,
+            }),
+            crtime: Some(DkTimespec {
+                sec: 612921600,
+                nsec: 0,
+            }),
+            ..Default::default()
+        },
     )?;
     assert_eq!(stat.ino, ino);
     assert_eq!(stat.mode, FileMode::REGULAR_FILE | FileMode::USER_READ);
@@ -247,12 +259,15 @@ fn traverse_dir() -> DkResult<()> {
         .map(|name| name.to_string().into())
         .collect();
     for name in &names {
-        handle.mknod(0, 0, ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+        handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
     }
     names.insert(".".to_string().into());
     names.insert("..".to_string().into());
-    let dir = handle.opendir(ROOT_INODE)?;
-    let names_read: HashSet<OsString> = handle.readdir(dir, 0).map(|(name, _)| name).collect();
+    let dir = handle.opendir(&root(), ROOT_INODE)?;
+    let names_read = handle
+        .readdir(dir, 0)
+        .map(|entry| entry.map(|e| e.name))
+        .collect::<DkResult<HashSet<_>>>()?;
     assert_eq!(names, names_read);
     Ok(())
 }
@@ -269,19 +284,116 @@ fn traverse_big_dir() -> DkResult<()> {
                 .into()
         }).collect();
     for name in &names {
-        handle.mknod(0, 0, ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+        handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
     }
     names.insert(".".to_string().into());
     names.insert("..".to_string().into());
-    let dir = handle.opendir(ROOT_INODE)?;
-    let names_read: HashSet<OsString> = handle.readdir(dir, 0).map(|(name, _)| name).collect();
+    let dir = handle.opendir(&root(), ROOT_INODE)?;
+    let names_read = handle
+        .readdir(dir, 0)
+        .map(|entry| entry.map(|e| e.name))
+        .collect::<DkResult<HashSet<_>>>()?;
     assert_eq!(names, names_read);
     Ok(())
 }
 
+#[test]
+fn unlink_in_big_dir() -> DkResult<()> {
+    prepare!(handle);
+    let names: Vec<OsString> = (0..1500)
+        .map(|i| format!("{:060}", i).into())
+        .collect();
+    for name in &names {
+        handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+    }
+    for name in names.iter().step_by(2) {
+        handle.unlink(&root(), ROOT_INODE, name)?;
+    }
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(handle.lookup(&root(), ROOT_INODE, name).is_ok(), i % 2 == 1);
+    }
+    let dir = handle.opendir(&root(), ROOT_INODE)?;
+    let all = handle
+        .readdir(dir.clone(), 0)
+        .collect::<DkResult<Vec<_>>>()?;
+    assert_eq!(all.len(), 752);
+    let rest = handle
+        .readdir(dir, all[499].cookie)
+        .collect::<DkResult<Vec<_>>>()?;
+    assert_eq!(&all[500..], &rest[..]);
+    Ok(())
+}
+
+#[test]
+fn readdir_while_modifying() -> DkResult<()> {
+    prepare!(handle);
+    let names: Vec<OsString> = (0..600).map(|i| format!("{:040}", i).into()).collect();
+    for name in &names[..300] {
+        handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+    }
+    let dir = handle.opendir(&root(), ROOT_INODE)?;
+    let mut read: Vec<OsString> = Vec::new();
+    let mut cookie = 0;
+    for (i, entry) in handle.readdir(dir.clone(), 0).take(150).enumerate() {
+        let entry = entry?;
+        read.push(entry.name);
+        if i == 149 {
+            cookie = entry.cookie;
+        }
+    }
+    // Add and remove entries between two reads
+    for name in &names[300..] {
+        handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+    }
+    for name in names[..300].iter().step_by(3) {
+        handle.unlink(&root(), ROOT_INODE, name)?;
+    }
+    for entry in handle.readdir(dir, cookie) {
+        read.push(entry?.name);
+    }
+    // Entries existing all the time are read exactly once
+    for name in names[..300].iter().skip(1).step_by(3) {
+        assert_eq!(read.iter().filter(|n| *n == name).count(), 1);
+    }
+    let unique: HashSet<&OsString> = read.iter().collect();
+    assert_eq!(unique.len(), read.len());
+    Ok(())
+}
+
+#[test]
+fn readdir_plus() -> DkResult<()> {
+    prepare!(handle);
+    let dir = handle.mkdir(&root(), ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+    let homura = OsStr::new("Homura");
+    handle.symlink(&root(), dir.ino, homura, Path::new("Madoka"))?;
+    let dh = handle.opendir(&root(), dir.ino)?;
+    for entry in handle.readdir_plus(dh, 0) {
+        let (entry, stat) = entry?;
+        assert_eq!(entry.ino, stat.ino);
+        assert_eq!(entry.file_type, stat.mode & FileMode::FILE_TYPE_MASK);
+        assert_eq!(stat, handle.getattr(entry.ino)?);
+        if entry.name == homura {
+            assert_eq!(entry.file_type, FileMode::SYMBOLIC_LINK);
+        } else {
+            assert_eq!(entry.file_type, FileMode::DIRECTORY);
+        }
+    }
+    Ok(())
+}
+
 #[test]
 fn read_write() -> DkResult<()> {
     prepare!(handle);
+    read_write_files(&handle)
+}
+
+#[test]
+fn read_write_indirect() -> DkResult<()> {
+    prepare!(handle, FormatOptions::default().extents(false));
+    read_write_files(&handle)
+}
+
+fn read_write_files(handle: &Handle) -> DkResult<()> {
     let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
     let files: BTreeMap<OsString, Vec<u8>> = (0..=16)
         .map(|i| {
@@ -294,17 +406,18 @@ fn read_write() -> DkResult<()> {
             (name, data)
         }).collect();
     for (name, data) in &files {
-        let stat = handle.mknod(0, 0, ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
-        let fh = handle.open(stat.ino, Flags::WRITE_ONLY)?;
+        let stat = handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
         let len = handle.write(fh, 0, data)?;
         assert_eq!(len, data.len());
     }
     // A rough estimate
-    assert!(handle.statfs()?.bfree < 2078);
+    let stat = handle.statfs()?;
+    assert!(stat.bfree * stat.bsize <= 2078 * 4096);
     for (name, data) in &files {
-        let stat = handle.lookup(ROOT_INODE, name)?;
+        let stat = handle.lookup(&root(), ROOT_INODE, name)?;
         assert!(stat.blocks >= stat.size / 512);
-        let fh = handle.open(stat.ino, Flags::READ_ONLY)?;
+        let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
 
         // Read once
         let read = handle.read(fh.clone(), 0, data.len() as u64)?;
@@ -326,23 +439,432 @@ fn read_write() -> DkResult<()> {
     Ok(())
 }
 
+#[test]
+fn read_write_block_sizes() -> DkResult<()> {
+    for &bs in &[1024, 2048, 65536] {
+        prepare!(handle, FormatOptions::default().block_size(bs));
+        assert_eq!(handle.statfs()?.bsize, bs);
+        read_write_files(&handle)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn format_options() -> DkResult<()> {
+    let uuid = [0x5a; 16];
+    let opts = FormatOptions::default()
+        .inode_count(100)
+        .inode_chunks(false)
+        .label("Mitakihara")
+        .uuid(uuid)
+        .reserved_percent(10.0);
+    prepare!(handle, opts);
+    let stat = handle.statfs()?;
+    assert_eq!(stat.files, 100);
+    assert_eq!(stat.bavail, stat.bfree - stat.blocks / 10);
+    assert_eq!(
+        handle.volume()?,
+        Volume {
+            label: "Mitakihara".to_string(),
+            uuid,
+        }
+    );
+
+    let mut mem = vec![0; 1 << 25];
+    let layout = layout(&Memory::new(&mut mem), &FormatOptions::default().block_size(1024))?;
+    assert_eq!(layout.block_count, 32768);
+    assert_eq!(layout.first_db_ptr % 1024, 0);
+    assert_eq!(layout.first_db_ptr / 1024 + layout.db_count, layout.block_count);
+    // The inode table is small when more inodes can be made in chunks
+    assert_eq!(layout.inode_count, DEFAULT_CHUNKED_INODE_COUNT);
+    let opts = FormatOptions::default().inode_chunks(false);
+    let fixed = dkfs::layout(&Memory::new(&mut mem), &opts)?;
+    assert_eq!(fixed.inode_count, (1 << 25) / DEFAULT_BYTES_PER_INODE);
+    let opts = FormatOptions::default().bytes_per_inode(8192);
+    let by_ratio = dkfs::layout(&Memory::new(&mut mem), &opts)?;
+    assert_eq!(by_ratio.inode_count, (1 << 25) / 8192);
+    for opts in vec![
+        FormatOptions::default().block_size(3072),
+        FormatOptions::default().block_size(131072),
+        FormatOptions::default().label("Puella Magi Madoka Magica"),
+        FormatOptions::default().reserved_percent(60.0),
+        FormatOptions::default().inode_count(0),
+    ] {
+        match format(Box::new(Memory::new(&mut mem)), opts) {
+            Err(DkError::Invalid(_)) => {}
+            r => panic!("Expected invalid options, got {:?}", r.map(|_| ())),
+        }
+    }
+    Ok(())
+}
+
 #[test]
 fn xattrs() -> DkResult<()> {
     prepare!(handle);
     let madoka = OsStr::new("Madoka");
     let homura = OsStr::new("Homura");
-    let stat = handle.mknod(0, 0, ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
-    assert!(handle.listxattr(stat.ino)?.is_empty());
-    assert_eq!(handle.getxattr(stat.ino, madoka)?, None);
+    let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+    assert!(handle.listxattr(&root(), stat.ino)?.is_empty());
+    assert_eq!(handle.getxattr(&root(), stat.ino, madoka)?, None);
 
-    handle.setxattr(stat.ino, madoka, "鹿目まどか".as_bytes())?;
-    handle.setxattr(stat.ino, homura, "暁美ほむら".as_bytes())?;
-    let v = handle.listxattr(stat.ino)?;
+    handle.setxattr(&root(), stat.ino, madoka, "鹿目まどか".as_bytes())?;
+    handle.setxattr(&root(), stat.ino, homura, "暁美ほむら".as_bytes())?;
+    let v = handle.listxattr(&root(), stat.ino)?;
     let set: HashSet<_> = v.iter().map(|s| s.as_os_str()).collect();
     assert_eq!(set, [madoka, homura].iter().map(|s| *s).collect());
     Ok(())
 }
 
+#[test]
+fn failed_op_rolled_back() -> DkResult<()> {
+    use dkfs::check::check;
+
+    let mut mem = vec![0; 4 << 20];
+    // The pointer blocks of a long file do not fit in the journal
+    let opts = FormatOptions::default()
+        .block_size(1024)
+        .extents(false)
+        .journal_blocks(16);
+    let homura = OsStr::new("Homura");
+    {
+        let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+        let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+        let before = handle.statfs()?;
+        let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+        match handle.write(fh.clone(), 0, &[7; 2 << 20]) {
+            Err(DkError::JournalFull) => {}
+            r => panic!("Expected the journal to be full, got {:?}", r),
+        }
+        assert_eq!(handle.getattr(stat.ino)?.size, 0);
+        assert_eq!(handle.statfs()?.bfree, before.bfree);
+        handle.write(fh, 0, b"Madoka")?;
+    }
+
+    let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+    assert!(report.is_clean(), "{:?}", report);
+    let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+    let ino = handle.lookup(&root(), ROOT_INODE, homura)?.ino;
+    let fh = handle.open(&root(), ino, Flags::READ_ONLY)?;
+    assert_eq!(handle.read(fh, 0, 100)?, b"Madoka");
+    Ok(())
+}
+
+#[test]
+fn large_xattrs() -> DkResult<()> {
+    use dkfs::check::check;
+
+    let mut mem = vec![0; 32 << 20];
+    // Large enough to rewrite the longest list at once
+    let opts = FormatOptions::default().block_size(1024).journal_blocks(4096);
+    let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+    let statfs = handle.statfs()?;
+    let homura = OsStr::new("Homura");
+    let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+    let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
+    let value: Vec<u8> = rng
+        .sample_iter(&Standard)
+        .take(MAX_XATTR_VALUE_LEN)
+        .collect();
+    match handle.setxattr(&root(), stat.ino, homura, &[0; MAX_XATTR_VALUE_LEN + 1]) {
+        Err(DkError::TooLarge) => {}
+        r => panic!("Expected the value to be too large, got {:?}", r),
+    }
+
+    // Fills up the inode
+    let mut names = Vec::new();
+    loop {
+        let name = OsString::from(format!("user.{}", names.len()));
+        match handle.setxattr(&root(), stat.ino, &name, &value) {
+            Ok(()) => names.push(name),
+            Err(DkError::XattrsFull) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    assert_eq!(names.len(), MAX_XATTRS_LEN / (MAX_XATTR_VALUE_LEN + 12));
+    let small = OsStr::new("user.small");
+    handle.setxattr(&root(), stat.ino, small, b"Madoka")?;
+    handle.apply_releases()?;
+    assert!(handle.getattr(stat.ino)?.blocks * 512 > MAX_XATTRS_LEN as u64 * 15 / 16);
+    drop(handle);
+
+    let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+    assert!(report.is_clean(), "{:?}", report);
+    let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+    for name in &names {
+        assert_eq!(handle.getxattr(&root(), stat.ino, name)?.as_ref(), Some(&value));
+    }
+    assert_eq!(handle.getxattr(&root(), stat.ino, small)?, Some(b"Madoka".to_vec()));
+
+    // Back to a single block, and then none
+    for name in &names {
+        handle.removexattr(&root(), stat.ino, name)?;
+    }
+    handle.apply_releases()?;
+    assert_eq!(handle.getattr(stat.ino)?.blocks, 2);
+    handle.removexattr(&root(), stat.ino, small)?;
+    handle.apply_releases()?;
+    assert_eq!(handle.getattr(stat.ino)?.blocks, 0);
+    handle.unlink(&root(), ROOT_INODE, homura)?;
+    handle.apply_releases()?;
+    assert_eq!(handle.statfs()?, statfs);
+    drop(handle);
+
+    // Only one block without the feature
+    let opts = FormatOptions::default()
+        .block_size(1024)
+        .large_xattrs(false);
+    let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+    let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+    handle.setxattr(&root(), stat.ino, homura, &value[..1000])?;
+    match handle.setxattr(&root(), stat.ino, small, &value[..1000]) {
+        Err(DkError::XattrsFull) => {}
+        r => panic!("Expected no room for the xattr, got {:?}", r),
+    }
+    Ok(())
+}
+
+#[test]
+fn large_xattrs_small_journal() -> DkResult<()> {
+    use dkfs::check::check;
+
+    let mut mem = vec![0; 4 << 20];
+    let opts = FormatOptions::default().block_size(1024);
+    let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+    let homura = OsStr::new("Homura");
+    let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+    let value = vec![7; 4000];
+    let mut names = Vec::new();
+    loop {
+        let name = OsString::from(format!("user.{}", names.len()));
+        match handle.setxattr(&root(), stat.ino, &name, &value) {
+            Ok(()) => names.push(name),
+            Err(DkError::XattrsFull) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    // Limited by the journal of 64 blocks rather than `MAX_XATTRS_LEN`
+    assert!(names.len() > 1);
+    assert!(names.len() * value.len() < 64 << 10);
+    handle.apply_releases()?;
+    drop(handle);
+
+    let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+    assert!(report.is_clean(), "{:?}", report);
+    let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+    for name in &names {
+        assert_eq!(handle.getxattr(&root(), stat.ino, name)?.as_ref(), Some(&value));
+    }
+    Ok(())
+}
+
+#[test]
+fn posix_acls() -> DkResult<()> {
+    use dkfs::acl::*;
+
+    prepare!(handle);
+    let mode = |bits| FileMode::from_bits_truncate(bits);
+    let entry = |tag, perm| AclEntry { tag, perm };
+    let everyone = Some(FileMode::DIRECTORY | mode(0o777));
+    handle.setattr(&root(), ROOT_INODE, None, SetAttr { mode: everyone, ..Default::default() })?;
+    let owner = Credentials::new(1, 10);
+    let project = handle.mkdir(&owner, ROOT_INODE, OsStr::new("Madoka"), mode(0o770))?;
+    let acl = Acl {
+        entries: vec![
+            entry(AclTag::UserObj, 7),
+            entry(AclTag::User(1000), 7),
+            entry(AclTag::GroupObj, 5),
+            entry(AclTag::Mask, 7),
+            entry(AclTag::Other, 0),
+        ],
+    };
+    let access = OsStr::new(ACCESS_XATTR);
+    let default = OsStr::new(DEFAULT_XATTR);
+    handle.setxattr(&owner, project.ino, access, &acl.encode())?;
+    handle.setxattr(&owner, project.ino, default, &acl.encode())?;
+    // The group bits show the mask
+    assert_eq!(
+        handle.getattr(project.ino)?.mode,
+        FileMode::DIRECTORY | mode(0o770)
+    );
+    let named = Credentials::new(1000, 1000);
+    let member = Credentials::new(1001, 10);
+    let other = Credentials::new(1001, 1001);
+    let write = AccessMode::WRITE | AccessMode::EXECUTE;
+    handle.access(&named, project.ino, write)?;
+    match handle.access(&member, project.ino, write) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected the group to be denied, got {:?}", r),
+    }
+    handle.access(&member, project.ino, AccessMode::READ)?;
+    match handle.access(&other, project.ino, AccessMode::READ) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected others to be denied, got {:?}", r),
+    }
+    // Only the owner may change ACLs
+    match handle.setxattr(&named, project.ino, access, &acl.encode()) {
+        Err(DkError::NotPermitted) => {}
+        r => panic!("Expected only the owner to set ACLs, got {:?}", r),
+    }
+
+    // chmod limits the named user through the mask
+    let m = Some(FileMode::DIRECTORY | mode(0o750));
+    handle.setattr(&owner, project.ino, None, SetAttr { mode: m, ..Default::default() })?;
+    assert!(handle.access(&named, project.ino, AccessMode::WRITE).is_err());
+    handle.access(&named, project.ino, AccessMode::READ)?;
+    let stored = Acl::decode(&handle.getxattr(&named, project.ino, access)?.unwrap())?;
+    assert_eq!(stored.entries[3], entry(AclTag::Mask, 5));
+
+    // New files inherit the default ACL, limited by their mode
+    let homura = OsStr::new("Homura");
+    let file = handle.mknod(
+        &owner,
+        project.ino,
+        homura,
+        FileMode::REGULAR_FILE | mode(0o640),
+        None,
+    )?;
+    assert_eq!(file.mode, FileMode::REGULAR_FILE | mode(0o640));
+    assert_eq!(handle.getxattr(&owner, file.ino, default)?, None);
+    let inherited = Acl::decode(&handle.getxattr(&owner, file.ino, access)?.unwrap())?;
+    assert_eq!(inherited.entries[1], entry(AclTag::User(1000), 7));
+    assert_eq!(inherited.entries[3], entry(AclTag::Mask, 4));
+    handle.access(&named, file.ino, AccessMode::READ)?;
+    assert!(handle.access(&named, file.ino, AccessMode::WRITE).is_err());
+    let dir = handle.mkdir(&owner, project.ino, OsStr::new("Kyoko"), mode(0o777))?;
+    assert_eq!(dir.mode, FileMode::DIRECTORY | mode(0o770));
+    assert_eq!(handle.getxattr(&owner, dir.ino, default)?, Some(acl.encode()));
+
+    // A minimal ACL only sets the permission bits
+    let minimal = Acl::from_mode(mode(0o604));
+    handle.setxattr(&owner, file.ino, access, &minimal.encode())?;
+    assert_eq!(handle.getxattr(&owner, file.ino, access)?, None);
+    assert_eq!(
+        handle.getattr(file.ino)?.mode,
+        FileMode::REGULAR_FILE | mode(0o604)
+    );
+    handle.access(&other, file.ino, AccessMode::READ)?;
+
+    match handle.setxattr(&owner, file.ino, default, &acl.encode()) {
+        Err(DkError::Invalid(_)) => {}
+        r => panic!("Expected no default ACL on a file, got {:?}", r),
+    }
+    match handle.setxattr(&owner, file.ino, access, &[2, 0, 0, 0, 1, 0]) {
+        Err(DkError::Invalid(_)) => {}
+        r => panic!("Expected a malformed ACL to be rejected, got {:?}", r),
+    }
+    // The super user may execute nothing without an execute bit
+    handle.access(&root(), file.ino, AccessMode::READ | AccessMode::WRITE)?;
+    assert!(handle.access(&root(), file.ino, AccessMode::EXECUTE).is_err());
+    Ok(())
+}
+
+#[test]
+fn permissions() -> DkResult<()> {
+    prepare!(handle);
+    let mode = |bits| FileMode::from_bits_truncate(bits);
+    let madoka = Credentials::new(1000, 1000);
+    let homura = Credentials::new(1001, 1001).groups(vec![1000]);
+    let kyubey = Credentials::new(2000, 2000);
+    let name = OsStr::new("Madoka");
+    match handle.mkdir(&madoka, ROOT_INODE, name, mode(0o755)) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected users to be denied, got {:?}", r),
+    }
+
+    // Anyone may make files in a sticky directory, but only remove their own
+    let tmp = handle.mkdir(&root(), ROOT_INODE, OsStr::new("tmp"), mode(0o1777))?;
+    let file = handle.mknod(
+        &madoka,
+        tmp.ino,
+        name,
+        FileMode::REGULAR_FILE | mode(0o640),
+        None,
+    )?;
+    assert_eq!((file.uid, file.gid), (1000, 1000));
+    handle.open(&homura, file.ino, Flags::READ_ONLY)?;
+    match handle.open(&homura, file.ino, Flags::WRITE_ONLY) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected the group to be denied, got {:?}", r.map(|_| ())),
+    }
+    match handle.open(&kyubey, file.ino, Flags::READ_ONLY) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected others to be denied, got {:?}", r.map(|_| ())),
+    }
+    match handle.unlink(&homura, tmp.ino, name) {
+        Err(DkError::NotPermitted) => {}
+        r => panic!("Expected the file to be kept, got {:?}", r),
+    }
+    let renamed = OsStr::new("Kyubey");
+    match handle.rename(
+        &kyubey,
+        tmp.ino,
+        name,
+        tmp.ino,
+        renamed,
+        RenameFlags::empty(),
+    ) {
+        Err(DkError::NotPermitted) => {}
+        r => panic!("Expected the file to be kept, got {:?}", r),
+    }
+    handle.unlink(&madoka, tmp.ino, name)?;
+
+    // Searching and listing a directory need permissions on it
+    let private = handle.mkdir(&madoka, tmp.ino, name, mode(0o700))?;
+    match handle.lookup(&kyubey, private.ino, OsStr::new(".")) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected searching to be denied, got {:?}", r),
+    }
+    match handle.opendir(&kyubey, private.ino) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected listing to be denied, got {:?}", r.map(|_| ())),
+    }
+
+    // Files in a set-group-ID directory take its group
+    let shared = handle.mkdir(&madoka, tmp.ino, OsStr::new("shared"), mode(0o2775))?;
+    let dir = handle.mkdir(&homura, shared.ino, name, mode(0o755))?;
+    assert_eq!((dir.uid, dir.gid), (1001, 1000));
+    assert_eq!(dir.mode, FileMode::DIRECTORY | mode(0o2755));
+    let file = handle.mknod(
+        &homura,
+        dir.ino,
+        name,
+        FileMode::REGULAR_FILE | mode(0o2754),
+        None,
+    )?;
+    assert_eq!((file.uid, file.gid), (1001, 1000));
+    let file = file.ino;
+
+    // Only the super user may give files away, and owners may only
+    // change the group to their own groups
+    let chown = |cred: &Credentials, uid, gid| {
+        handle.setattr(cred, file, None, SetAttr { uid, gid, ..Default::default() })
+    };
+    match chown(&homura, Some(1000), None) {
+        Err(DkError::NotPermitted) => {}
+        r => panic!("Expected giving the file away to fail, got {:?}", r),
+    }
+    match chown(&homura, None, Some(2000)) {
+        Err(DkError::NotPermitted) => {}
+        r => panic!("Expected a group of others to be refused, got {:?}", r),
+    }
+    // The set-group-ID bit is dropped with the group
+    let stat = chown(&homura, None, Some(1001))?;
+    assert_eq!(stat.mode, FileMode::REGULAR_FILE | mode(0o754));
+    let stat = chown(&root(), Some(2000), Some(2000))?;
+    assert_eq!((stat.uid, stat.gid), (2000, 2000));
+    let mode = Some(FileMode::REGULAR_FILE | mode(0o777));
+    let size = Some(0);
+    match handle.setattr(&homura, file, None, SetAttr { mode, ..Default::default() }) {
+        Err(DkError::NotPermitted) => {}
+        r => panic!("Expected only the owner to chmod, got {:?}", r),
+    }
+    match handle.setattr(&homura, file, None, SetAttr { size, ..Default::default() }) {
+        Err(DkError::PermissionDenied) => {}
+        r => panic!("Expected truncating to need write permission, got {:?}", r),
+    }
+    Ok(())
+}
+
 #[test]
 fn unlink() -> DkResult<()> {
     prepare!(handle);
@@ -357,15 +879,18 @@ fn unlink() -> DkResult<()> {
         .map(|name| name.to_string().into())
         .collect();
     for name in &names {
-        handle.mknod(0, 0, ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+        handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
     }
     let mami = OsStr::new("巴マミ");
-    handle.unlink(ROOT_INODE, mami)?;
+    handle.unlink(&root(), ROOT_INODE, mami)?;
     names.remove(mami);
     names.insert(".".to_string().into());
     names.insert("..".to_string().into());
-    let dir = handle.opendir(ROOT_INODE)?;
-    let names_read: HashSet<OsString> = handle.readdir(dir, 0).map(|(name, _)| name).collect();
+    let dir = handle.opendir(&root(), ROOT_INODE)?;
+    let names_read = handle
+        .readdir(dir, 0)
+        .map(|entry| entry.map(|e| e.name))
+        .collect::<DkResult<HashSet<_>>>()?;
     assert_eq!(names, names_read);
     Ok(())
 }
@@ -377,32 +902,22 @@ fn shrink_size() -> DkResult<()> {
     let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
     let homura = OsStr::new("Homura");
     let data: Vec<u8> = rng.sample_iter(&Standard).take(1 << 24).collect(); // 16 MB
-    let stat = handle.mknod(0, 0, ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+    let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
     let statfs = handle.statfs()?;
 
-    let fh = handle.open(stat.ino, Flags::WRITE_ONLY)?;
+    let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
     let len = handle.write(fh.clone(), 0, &data)?;
     assert_eq!(len, data.len());
     assert_ne!(handle.statfs()?, statfs);
     drop(fh);
     assert_ne!(handle.statfs()?, statfs);
 
-    let fh = handle.open(stat.ino, Flags::READ_ONLY)?;
+    let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
     let read = handle.read(fh, 0, data.len() as u64)?;
     assert_eq!(data.len(), read.len());
 
-    handle.setattr(
-        stat.ino,
-        None,
-        None,
-        None,
-        None,
-        Some(0),
-        None,
-        None,
-        None,
-        None,
-    )?; // Set size to 0
+    // Set size to 0
+    handle.setattr(&root(), stat.ino, None, SetAttr { size: Some(0), ..Default::default() })?;
     assert_eq!(handle.getattr(stat.ino)?.size, 0);
     assert_eq!(handle.getattr(stat.ino)?.blocks, 0);
     assert_eq!(handle.statfs()?, statfs);
@@ -416,13 +931,76 @@ fn rename() -> DkResult<()> {
 
     let homura = OsStr::new("Homura");
     let madoka = OsStr::new("Madoka");
-    let stat = handle.mknod(0, 0, ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+    let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
     let new_dir = handle
-        .mkdir(ROOT_INODE, 0, 0, OsStr::new("newdir"), FileMode::USER_RWX)?
+        .mkdir(&root(), ROOT_INODE, OsStr::new("newdir"), FileMode::USER_RWX)?
         .ino;
-    handle.rename(ROOT_INODE, homura, new_dir, madoka)?;
-    assert!(handle.lookup(ROOT_INODE, homura).is_err());
-    assert_eq!(stat.blocks, handle.lookup(new_dir, madoka)?.blocks);
+    handle.rename(&root(), ROOT_INODE, homura, new_dir, madoka, RenameFlags::empty())?;
+    assert!(handle.lookup(&root(), ROOT_INODE, homura).is_err());
+    assert_eq!(stat.blocks, handle.lookup(&root(), new_dir, madoka)?.blocks);
+    Ok(())
+}
+
+#[test]
+fn rename_dir() -> DkResult<()> {
+    prepare!(handle);
+
+    let a = handle.mkdir(&root(), ROOT_INODE, OsStr::new("a"), FileMode::USER_RWX)?.ino;
+    let b = handle.mkdir(&root(), ROOT_INODE, OsStr::new("b"), FileMode::USER_RWX)?.ino;
+    let c = handle.mkdir(&root(), a, OsStr::new("c"), FileMode::USER_RWX)?.ino;
+    assert_eq!(handle.getattr(a)?.nlink, 3);
+
+    handle.rename(&root(), a, OsStr::new("c"), b, OsStr::new("d"), RenameFlags::empty())?;
+    assert_eq!(handle.lookup(&root(), c, OsStr::new(".."))?.ino, b);
+    assert_eq!(handle.getattr(a)?.nlink, 2);
+    assert_eq!(handle.getattr(b)?.nlink, 3);
+    assert_eq!(handle.getattr(c)?.nlink, 2);
+
+    // Moving a directory into itself or its descendant
+    let flags = RenameFlags::empty();
+    match handle.rename(&root(), ROOT_INODE, OsStr::new("b"), c, OsStr::new("e"), flags) {
+        Err(DkError::RenameLoop) => {}
+        r => panic!("Expected RenameLoop, got {:?}", r),
+    }
+    match handle.rename(&root(), b, OsStr::new("d"), c, OsStr::new("e"), RenameFlags::empty()) {
+        Err(DkError::RenameLoop) => {}
+        r => panic!("Expected RenameLoop, got {:?}", r),
+    }
+
+    // Replacing an empty directory
+    handle.rename(&root(), b, OsStr::new("d"), ROOT_INODE, OsStr::new("a"), RenameFlags::empty())?;
+    assert_eq!(handle.lookup(&root(), ROOT_INODE, OsStr::new("a"))?.ino, c);
+    assert_eq!(handle.lookup(&root(), c, OsStr::new(".."))?.ino, ROOT_INODE);
+    assert_eq!(handle.getattr(ROOT_INODE)?.nlink, 4);
+    assert_eq!(handle.getattr(b)?.nlink, 2);
+    Ok(())
+}
+
+#[test]
+fn rename_flags() -> DkResult<()> {
+    prepare!(handle);
+
+    let homura = OsStr::new("Homura");
+    let madoka = OsStr::new("Madoka");
+    let file = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?.ino;
+    let dir = handle.mkdir(&root(), ROOT_INODE, madoka, FileMode::USER_RWX)?.ino;
+    match handle.rename(&root(), ROOT_INODE, homura, ROOT_INODE, madoka, RenameFlags::NOREPLACE) {
+        Err(DkError::AlreadyExists) => {}
+        r => panic!("Expected AlreadyExists, got {:?}", r),
+    }
+    match handle.rename(&root(), ROOT_INODE, homura, ROOT_INODE, madoka, RenameFlags::empty()) {
+        Err(DkError::IsDirectory) => {}
+        r => panic!("Expected IsDirectory, got {:?}", r),
+    }
+    assert_eq!(handle.lookup(&root(), ROOT_INODE, homura)?.ino, file);
+
+    let sub = handle.mkdir(&root(), dir, OsStr::new("sub"), FileMode::USER_RWX)?.ino;
+    handle.rename(&root(), ROOT_INODE, homura, dir, OsStr::new("sub"), RenameFlags::EXCHANGE)?;
+    assert_eq!(handle.lookup(&root(), ROOT_INODE, homura)?.ino, sub);
+    assert_eq!(handle.lookup(&root(), dir, OsStr::new("sub"))?.ino, file);
+    assert_eq!(handle.lookup(&root(), sub, OsStr::new(".."))?.ino, ROOT_INODE);
+    assert_eq!(handle.getattr(dir)?.nlink, 2);
+    assert_eq!(handle.getattr(ROOT_INODE)?.nlink, 4);
     Ok(())
 }
 
@@ -433,13 +1011,13 @@ fn rmdir() -> DkResult<()> {
     let homura = OsStr::new("Homura");
     let madoka = OsStr::new("Madoka");
     let statfs = handle.statfs()?;
-    let new_dir = handle.mkdir(ROOT_INODE, 0, 0, homura, FileMode::USER_RWX)?;
-    handle.mknod(0, 0, new_dir.ino, madoka, FileMode::REGULAR_FILE, None)?;
+    let new_dir = handle.mkdir(&root(), ROOT_INODE, homura, FileMode::USER_RWX)?;
+    handle.mknod(&root(), new_dir.ino, madoka, FileMode::REGULAR_FILE, None)?;
     assert_eq!(handle.getattr(ROOT_INODE)?.nlink, 3);
-    assert!(handle.rmdir(ROOT_INODE, homura).is_err());
-    handle.unlink(new_dir.ino, madoka)?;
-    handle.rmdir(ROOT_INODE, homura)?;
-    assert!(handle.lookup(ROOT_INODE, homura).is_err());
+    assert!(handle.rmdir(&root(), ROOT_INODE, homura).is_err());
+    handle.unlink(&root(), new_dir.ino, madoka)?;
+    handle.rmdir(&root(), ROOT_INODE, homura)?;
+    assert!(handle.lookup(&root(), ROOT_INODE, homura).is_err());
     handle.apply_releases()?;
     assert_eq!(handle.getattr(ROOT_INODE)?.nlink, 2);
     assert_eq!(statfs, handle.statfs()?);
@@ -452,12 +1030,12 @@ fn symlink() -> DkResult<()> {
     let homura = OsStr::new("Homura");
     let homura_link = OsStr::new("/暁美ほむら");
     let path = Path::new(homura_link);
-    let link = handle.symlink(0, 0, ROOT_INODE, homura, path)?;
+    let link = handle.symlink(&root(), ROOT_INODE, homura, path)?;
     assert!(link.mode.contains(
         FileMode::SYMBOLIC_LINK | FileMode::USER_RWX | FileMode::GROUP_RWX | FileMode::OTHERS_RWX
     ));
 
-    let fh = handle.open(link.ino, Flags::READ_ONLY)?;
+    let fh = handle.open(&root(), link.ino, Flags::READ_ONLY)?;
     let read = handle.read(fh, 0, 4096)?;
     assert_eq!(homura_link.as_bytes(), read.as_slice());
     Ok(())
@@ -465,7 +1043,7 @@ fn symlink() -> DkResult<()> {
 
 #[test]
 fn exhaust_inodes() -> DkResult<()> {
-    prepare!(handle);
+    prepare!(handle, FormatOptions::default().inode_chunks(false));
     let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
     let mut names: HashSet<OsString> = HashSet::new();
     let statfs = handle.statfs()?;
@@ -481,7 +1059,7 @@ fn exhaust_inodes() -> DkResult<()> {
     for names in names.iter().take(100) {
         dirs.push(
             handle
-                .mkdir(ROOT_INODE, 0, 0, names, FileMode::empty())?
+                .mkdir(&root(), ROOT_INODE, names, FileMode::empty())?
                 .ino,
         );
     }
@@ -499,21 +1077,708 @@ fn exhaust_inodes() -> DkResult<()> {
             rng.shuffle(used.as_mut());
         } else if r < 0.4 {
             if let Some((ino, name)) = used.pop() {
-                handle.unlink(ino, name)?;
+                handle.unlink(&root(), ino, name)?;
                 unused.push(name);
             }
         } else {
             if let Some(name) = unused.pop() {
                 let ino = dirs[i % dirs.len()];
-                handle.mknod(0, 0, ino, name, FileMode::REGULAR_FILE, None)?;
+                handle.mknod(&root(), ino, name, FileMode::REGULAR_FILE, None)?;
                 used.push((ino, name));
             }
         }
     }
     while let Some((ino, name)) = used.pop() {
-        handle.unlink(ino, name)?;
+        handle.unlink(&root(), ino, name)?;
     }
     handle.apply_releases()?;
     assert_eq!(statfs, handle.statfs()?);
     Ok(())
 }
+
+#[test]
+fn reuse_freed_blocks() -> DkResult<()> {
+    prepare!(handle);
+    let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
+    let homura = OsStr::new("Homura");
+    let data: Vec<u8> = rng.sample_iter(&Standard).take(1 << 24).collect(); // 16 MB
+    let statfs = handle.statfs()?;
+    for _ in 0..3 {
+        let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+        assert_eq!(handle.write(fh, 0, &data)?, data.len());
+        handle.unlink(&root(), ROOT_INODE, homura)?;
+        handle.apply_releases()?;
+        assert_eq!(handle.statfs()?, statfs);
+    }
+    Ok(())
+}
+
+#[test]
+fn contiguous_extents() -> DkResult<()> {
+    prepare!(handle);
+    let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
+    let data: Vec<u8> = rng.sample_iter(&Standard).take(1 << 24).collect(); // 16 MB
+    let homura = OsStr::new("Homura");
+    let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+    let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+    handle.write(fh.clone(), 0, &data)?;
+    handle.flush(fh)?;
+    // No pointer blocks are needed
+    assert_eq!(handle.getattr(stat.ino)?.blocks, data.len() as u64 / 512);
+    Ok(())
+}
+
+#[test]
+fn fragmented_extents() -> DkResult<()> {
+    prepare!(handle);
+    let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
+    let statfs = handle.statfs()?;
+    let names = [OsStr::new("Madoka"), OsStr::new("Homura")];
+    let mut files = Vec::new();
+    for name in &names {
+        let stat = handle.mknod(&root(), ROOT_INODE, name, FileMode::REGULAR_FILE, None)?;
+        let data: Vec<u8> = rng.sample_iter(&Standard).take(2000 * 4096).collect();
+        files.push((stat.ino, data));
+    }
+    // Interleave block-sized writes so that every block becomes an extent
+    for off in (0..2000 * 4096).step_by(4096) {
+        for (ino, data) in &files {
+            let fh = handle.open(&root(), *ino, Flags::WRITE_ONLY)?;
+            handle.write(fh, off as u64, &data[off..off + 4096])?;
+        }
+    }
+    handle.apply_releases()?;
+    for (ino, data) in &files {
+        let fh = handle.open(&root(), *ino, Flags::READ_ONLY)?;
+        assert_eq!(&handle.read(fh, 0, data.len() as u64)?, data);
+        // Data blocks plus leaf and index nodes of the extent tree
+        assert!(handle.getattr(*ino)?.blocks > data.len() as u64 / 512);
+    }
+    for name in &names {
+        handle.unlink(&root(), ROOT_INODE, name)?;
+    }
+    handle.apply_releases()?;
+    assert_eq!(handle.statfs()?, statfs);
+    Ok(())
+}
+
+#[test]
+fn sparse_files() -> DkResult<()> {
+    use dkfs::check::check;
+
+    for &extents in &[true, false] {
+        let mut mem = vec![0; 32 << 20];
+        let mut rng = XorShiftRng::from_seed([1, 1, 4, 5, 1, 4, 1, 9, 1, 9, 8, 1, 0, 8, 9, 3]);
+        let mut data: Vec<u8> = rng.sample_iter(&Standard).take(16 << 20).collect();
+        let opts = FormatOptions::default().extents(extents);
+        let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+        let statfs = handle.statfs()?;
+        let homura = OsStr::new("Homura");
+        let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::READ_WRITE)?;
+        handle.write(fh.clone(), 0, &data)?;
+        let blocks = handle.getattr(stat.ino)?.blocks;
+
+        // Only the blocks 2 and 3 are freed, and the rest is zeroed
+        handle.punch_hole(fh.clone(), 5000, 3 * 4096 + 100)?;
+        assert_eq!(handle.getattr(stat.ino)?.blocks, blocks - 2 * 8);
+        zero(&mut data[5000..5000 + 3 * 4096 + 100]);
+        // Across the end of the indirect blocks
+        handle.punch_hole(fh.clone(), 1 << 20, 8 << 20)?;
+        zero(&mut data[1 << 20..9 << 20]);
+        assert!(handle.getattr(stat.ino)?.blocks < blocks - (8 << 20) / 512);
+        assert_eq!(handle.getattr(stat.ino)?.size, 16 << 20);
+        assert_eq!(handle.read(fh.clone(), 0, 32 << 20)?, data);
+
+        assert_eq!(handle.seek_hole(fh.clone(), 0)?, Some(8192));
+        assert_eq!(handle.seek_data(fh.clone(), 8192)?, Some(16384));
+        assert_eq!(handle.seek_data(fh.clone(), 20000)?, Some(20000));
+        assert_eq!(handle.seek_hole(fh.clone(), 20000)?, Some(1 << 20));
+        assert_eq!(handle.seek_data(fh.clone(), 1 << 20)?, Some(9 << 20));
+        assert_eq!(handle.seek_hole(fh.clone(), 9 << 20)?, Some(16 << 20));
+        assert_eq!(handle.seek_data(fh.clone(), 16 << 20)?, None);
+
+        // Allocated blocks read as zeros
+        let blocks = handle.getattr(stat.ino)?.blocks;
+        handle.fallocate(fh.clone(), 2 << 20, 3 * 4096, true)?;
+        assert!(handle.getattr(stat.ino)?.blocks >= blocks + 3 * 8);
+        assert_eq!(handle.seek_data(fh.clone(), 1 << 20)?, Some(2 << 20));
+        let end = (2 << 20) + 3 * 4096;
+        assert_eq!(handle.seek_hole(fh.clone(), 2 << 20)?, Some(end));
+        assert_eq!(handle.getattr(stat.ino)?.size, 16 << 20);
+        handle.fallocate(fh.clone(), 16 << 20, 100, false)?;
+        data.extend(&[0; 100]);
+        assert_eq!(handle.getattr(stat.ino)?.size, (16 << 20) + 100);
+        assert_eq!(handle.read(fh.clone(), 0, 32 << 20)?, data);
+        handle.flush(fh)?;
+
+        // Truncating within a hole
+        let size = Some(5 << 20);
+        handle.setattr(&root(), stat.ino, None, SetAttr { size, ..Default::default() })?;
+        data.truncate(5 << 20);
+        let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, 32 << 20)?, data);
+        handle.apply_releases()?;
+        drop(handle);
+
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        assert!(report.is_clean(), "{:?}", report);
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        handle.unlink(&root(), ROOT_INODE, homura)?;
+        handle.apply_releases()?;
+        assert_eq!(handle.statfs()?, statfs);
+    }
+    Ok(())
+}
+
+fn zero(buf: &mut [u8]) {
+    for b in buf {
+        *b = 0;
+    }
+}
+
+#[test]
+fn inline_data() -> DkResult<()> {
+    use dkfs::check::check;
+
+    for &extents in &[true, false] {
+        let mut mem = vec![0; 32 << 20];
+        let opts = FormatOptions::default().extents(extents);
+        let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+        let bfree = handle.statfs()?.bfree;
+        let madoka = OsStr::new("Madoka");
+        let stat = handle.mknod(&root(), ROOT_INODE, madoka, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::READ_WRITE)?;
+        let mut data = vec![42; 100];
+        handle.write(fh.clone(), 0, &data)?;
+        data.extend_from_slice(&[7; 28]);
+        handle.write(fh.clone(), 100, &data[100..])?;
+        assert_eq!(handle.getattr(stat.ino)?.blocks, 0);
+        assert_eq!(handle.read(fh.clone(), 0, 4096)?, data);
+        assert_eq!(handle.statfs()?.bfree, bfree);
+
+        // Bytes cut off read as zeros when the file grows back
+        let size = Some(20);
+        handle.setattr(&root(), stat.ino, None, SetAttr { size, ..Default::default() })?;
+        let size = Some(64);
+        handle.setattr(&root(), stat.ino, None, SetAttr { size, ..Default::default() })?;
+        data.truncate(20);
+        data.resize(64, 0);
+        assert_eq!(handle.read(fh.clone(), 0, 4096)?, data);
+
+        // Moves to a data block
+        data.resize(5000, 9);
+        handle.write(fh.clone(), 64, &data[64..])?;
+        assert_eq!(handle.getattr(stat.ino)?.blocks, 16);
+        assert_eq!(handle.read(fh.clone(), 0, 8192)?, data);
+        handle.flush(fh)?;
+
+        let homura = OsStr::new("Homura");
+        let short = Path::new("/dev/null");
+        let stat = handle.symlink(&root(), ROOT_INODE, homura, short)?;
+        assert_eq!(stat.blocks, 0);
+        let target: String = (0..20).map(|_| "kyubey/").collect();
+        let kyubey = OsStr::new("Kyubey");
+        let stat = handle.symlink(&root(), ROOT_INODE, kyubey, Path::new(&target))?;
+        assert_eq!(stat.blocks, 8);
+        handle.apply_releases()?;
+        drop(handle);
+
+        let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+        assert!(report.is_clean(), "{:?}", report);
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        let stat = handle.lookup(&root(), ROOT_INODE, madoka)?;
+        let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, 8192)?, data);
+        let stat = handle.lookup(&root(), ROOT_INODE, homura)?;
+        let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, 4096)?, b"/dev/null");
+        let stat = handle.lookup(&root(), ROOT_INODE, kyubey)?;
+        let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, 4096)?, target.as_bytes());
+    }
+
+    // Every file takes a block without inline data
+    prepare!(handle, FormatOptions::default().inline_data(false));
+    let mami = OsStr::new("Mami");
+    let stat = handle.mknod(&root(), ROOT_INODE, mami, FileMode::REGULAR_FILE, None)?;
+    let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+    handle.write(fh, 0, b"tiro finale")?;
+    assert_eq!(handle.getattr(stat.ino)?.blocks, 8);
+    Ok(())
+}
+
+#[test]
+fn reopen() -> DkResult<()> {
+    let mut mem = vec![0; 33554432]; // 32MB
+    let ino = {
+        let handle = format(Box::new(Memory::new(&mut mem[..])), FormatOptions::default())?;
+        let stat = handle.mkdir(&root(), ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+        handle.mknod(&root(), stat.ino, OsStr::new("Homura"), FileMode::REGULAR_FILE, None)?;
+        stat.ino
+    };
+    let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+    assert_eq!(handle.lookup(&root(), ROOT_INODE, OsStr::new("Madoka"))?.ino, ino);
+    assert!(handle.lookup(&root(), ino, OsStr::new("Homura")).is_ok());
+    let stat = handle.statfs()?;
+    assert_eq!(stat.files - stat.ffree, 3);
+    Ok(())
+}
+
+#[test]
+fn cached_device() -> DkResult<()> {
+    let mut mem = vec![0; 1 << 25];
+    let ino = {
+        // A tiny cache keeps evicting blocks
+        let dev = Box::new(Cache::new(Box::new(Memory::new(&mut mem[..])), 8));
+        let handle = format(dev, FormatOptions::default())?;
+        read_write_files(&handle)?;
+        let madoka = OsStr::new("Madoka");
+        let stat = handle.mknod(&root(), ROOT_INODE, madoka, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+        handle.write(fh.clone(), 0, &[42; 10000])?;
+        handle.fsync(fh, false)?;
+        stat.ino
+    };
+    let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+    let fh = handle.open(&root(), ino, Flags::READ_ONLY)?;
+    assert_eq!(handle.read(fh, 0, 20000)?, vec![42; 10000]);
+    Ok(())
+}
+
+#[test]
+fn parallel_operations() -> DkResult<()> {
+    // Other threads need a device that lives forever
+    let mem = Box::leak(vec![0; 1 << 25].into_boxed_slice());
+    let handle = format(Box::new(Memory::new(mem)), FormatOptions::default())?;
+    // The shared directory is deep down, so that checking its ancestors
+    // takes a while when a directory is moved into it
+    let mut ancestors = vec![ROOT_INODE];
+    for depth in 0..8 {
+        let name = OsString::from(format!("Walpurgisnacht-{}", depth));
+        let parent = *ancestors.last().unwrap();
+        ancestors.push(handle.mkdir(&root(), parent, &name, FileMode::USER_RWX)?.ino);
+    }
+    let shared = *ancestors.last().unwrap();
+    let threads: Vec<_> = (0..8u8)
+        .map(|t| {
+            let handle = handle.clone();
+            let ancestors = ancestors.clone();
+            thread::spawn(move || -> DkResult<()> {
+                let name = OsString::from(format!("{}", t));
+                let dir = handle.mkdir(&root(), ROOT_INODE, &name, FileMode::USER_RWX)?.ino;
+                for i in 0..20 {
+                    let name = OsString::from(format!("{}", i));
+                    let stat = handle.mknod(&root(), dir, &name, FileMode::REGULAR_FILE, None)?;
+                    let fh = handle.open(&root(), stat.ino, Flags::READ_WRITE)?;
+                    handle.write(fh.clone(), 0, &[t; 5000])?;
+                    assert_eq!(handle.read(fh, 0, 10000)?, vec![t; 5000]);
+                    // Every other file is moved into the shared directory
+                    if i % 2 == 0 {
+                        let new_name = OsString::from(format!("{}-{}", t, i));
+                        let flags = RenameFlags::empty();
+                        handle.rename(&root(), dir, &name, shared, &new_name, flags)?;
+                    }
+                    // Moving directories checks the ancestors of the target,
+                    // while others change the xattrs of the same directories
+                    let xattr = OsString::from(format!("user.{}", t));
+                    for &ino in &ancestors {
+                        handle.setxattr(&root(), ino, &xattr, &[i])?;
+                    }
+                    if i % 5 == 0 {
+                        let sub = OsString::from(format!("d{}", i));
+                        handle.mkdir(&root(), dir, &sub, FileMode::USER_RWX)?;
+                        let new_name = OsString::from(format!("{}-d{}", t, i));
+                        let flags = RenameFlags::empty();
+                        handle.rename(&root(), dir, &sub, shared, &new_name, flags)?;
+                    }
+                }
+                Ok(())
+            })
+        }).collect();
+    for t in threads {
+        t.join().unwrap()?;
+    }
+
+    let count = |ino| -> DkResult<usize> {
+        Ok(handle.readdir(handle.opendir(&root(), ino)?, 0).count())
+    };
+    assert_eq!(count(shared)?, 2 + 8 * 10 + 8 * 4);
+    let moved = handle.lookup(&root(), shared, OsStr::new("7-d15"))?.ino;
+    assert_eq!(handle.lookup(&root(), moved, OsStr::new(".."))?.ino, shared);
+    for t in 0..8 {
+        let dir = handle.lookup(&root(), ROOT_INODE, OsStr::new(&format!("{}", t)))?.ino;
+        assert_eq!(count(dir)?, 2 + 10);
+    }
+    let stat = handle.lookup(&root(), shared, OsStr::new("7-18"))?;
+    let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
+    assert_eq!(handle.read(fh, 0, 10000)?, vec![7; 5000]);
+    Ok(())
+}
+
+#[test]
+fn async_handle() -> DkResult<()> {
+    use dkfs::aio::*;
+
+    let path = std::env::temp_dir().join(format!("dkfs-async-{}", std::process::id()));
+    std::fs::File::create(&path)?.set_len(1 << 25)?;
+    {
+        let dev = SyncDevice::new(AsyncFile::open(&path)?);
+        let handle = AsyncHandle::from(format(Box::new(dev), FormatOptions::default())?);
+        let madoka = OsString::from("Madoka");
+        let dir = block_on(handle.mkdir(root(), ROOT_INODE, madoka.clone(), FileMode::USER_RWX))?;
+        let homura = OsString::from("Homura");
+        let stat = block_on(handle.mknod(root(), dir.ino, homura, FileMode::REGULAR_FILE, None))?;
+        let fh = block_on(handle.open(root(), stat.ino, Flags::READ_WRITE))?;
+        // Issue both writes before awaiting either
+        let first = handle.write(fh.clone(), 0, vec![1; 5000]);
+        let second = handle.write(fh.clone(), 5000, vec![2; 5000]);
+        assert_eq!(block_on(first)? + block_on(second)?, 10000);
+        block_on(handle.fsync(fh, false))?;
+
+        assert_eq!(block_on(handle.lookup(root(), ROOT_INODE, madoka))?.ino, dir.ino);
+        let dh = block_on(handle.opendir(root(), dir.ino))?;
+        let mut entries = block_on(handle.readdir(dh.clone(), 0, 2))?;
+        assert_eq!(entries.len(), 2);
+        let cookie = entries[1].cookie;
+        entries.extend(block_on(handle.readdir(dh, cookie, 10))?);
+        let names: HashSet<_> = entries.into_iter().map(|e| e.name).collect();
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(OsStr::new("Homura")));
+    }
+
+    // Read it back synchronously
+    let handle = open(dev(&path)?)?;
+    let dir = handle.lookup(&root(), ROOT_INODE, OsStr::new("Madoka"))?.ino;
+    let ino = handle.lookup(&root(), dir, OsStr::new("Homura"))?.ino;
+    let data = handle.read(handle.open(&root(), ino, Flags::READ_ONLY)?, 0, 20000)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(data[..5000], [1; 5000][..]);
+    assert_eq!(data[5000..], [2; 5000][..]);
+    Ok(())
+}
+
+#[test]
+fn async_handle_on_tokio() -> DkResult<()> {
+    use dkfs::aio::*;
+    use tokio::runtime::Builder;
+
+    let runtime = Builder::new_multi_thread().worker_threads(2).build()?;
+    let path = std::env::temp_dir().join(format!("dkfs-tokio-{}", std::process::id()));
+    std::fs::File::create(&path)?.set_len(1 << 25)?;
+    let dev = SyncDevice::new(AsyncFile::open(&path)?);
+    let handle = AsyncHandle::from(format(Box::new(dev), FormatOptions::default())?);
+    let madoka = OsString::from("Madoka");
+    let mknod = handle.mknod(root(), ROOT_INODE, madoka, FileMode::REGULAR_FILE, None);
+    let stat = runtime.block_on(mknod)?;
+    let fh = runtime.block_on(handle.open(root(), stat.ino, Flags::READ_WRITE))?;
+    // Far more operations in flight than workers to run them
+    let writes: Vec<_> = (0..64u64)
+        .map(|i| runtime.spawn(handle.write(fh.clone(), i * 4096, vec![i as u8; 4096])))
+        .collect();
+    for write in writes {
+        assert_eq!(runtime.block_on(write).expect("The write panicked")?, 4096);
+    }
+    let data = runtime.block_on(handle.read(fh, 0, 64 * 4096))?;
+    drop(handle);
+    std::fs::remove_file(&path)?;
+    for (i, block) in data.chunks(4096).enumerate() {
+        assert!(block.iter().all(|&b| b == i as u8));
+    }
+    Ok(())
+}
+
+#[test]
+fn detect_corrupted_inode() -> DkResult<()> {
+    let mut mem = vec![0; 33554432]; // 32MB
+    let ino = {
+        let handle = format(Box::new(Memory::new(&mut mem[..])), FormatOptions::default())?;
+        handle.mkdir(&root(), ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?.ino
+    };
+    // Flip a bit of the uid of the new directory
+    mem[2048 + (ino - ROOT_INODE) as usize * 256 + 12] ^= 1;
+    let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+    match handle.getattr(ino) {
+        Err(DkError::Corrupted(msg)) => assert!(msg.contains("inode")),
+        r => panic!("Expected a checksum mismatch, got {:?}", r),
+    }
+    Ok(())
+}
+
+#[test]
+fn grow() -> DkResult<()> {
+    use dkfs::check::check;
+
+    let mut mem = vec![0; 64 << 20];
+    let opts = FormatOptions::default()
+        .block_size(1024)
+        .inode_count(256)
+        .max_inode_count(1024)
+        .inode_chunks(false);
+    {
+        let handle = format(Box::new(Memory::new(&mut mem[..4 << 20])), opts)?;
+        let madoka = OsStr::new("Madoka");
+        let stat = handle.mknod(&root(), ROOT_INODE, madoka, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+        handle.write(fh, 0, &[1; 100_000])?;
+    }
+    {
+        // The file system only takes the first 4 MiB of the device
+        let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+        let before = handle.statfs()?;
+        match handle.grow(128 << 20) {
+            Err(DkError::Invalid(_)) => {}
+            r => panic!("Expected a too large size, got {:?}", r),
+        }
+        match handle.grow(2 << 20) {
+            Err(DkError::Invalid(_)) => {}
+            r => panic!("Expected a smaller size, got {:?}", r),
+        }
+        // The data block bitmap outgrows its place
+        for &size in &[16 << 20, 64 << 20] {
+            handle.grow(size)?;
+            let stat = handle.statfs()?;
+            assert_eq!(stat.blocks, before.blocks + (size - (4 << 20)) / 1024);
+            assert_eq!(stat.files, 1024);
+        }
+        let homura = OsStr::new("Homura");
+        let stat = handle.mknod(&root(), ROOT_INODE, homura, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+        handle.write(fh, 0, &[2; 40 << 20])?;
+    }
+    let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+    assert!(report.is_clean(), "{:?}", report);
+
+    let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+    let madoka = handle.lookup(&root(), ROOT_INODE, OsStr::new("Madoka"))?;
+    let fh = handle.open(&root(), madoka.ino, Flags::READ_ONLY)?;
+    assert_eq!(handle.read(fh, 0, 200_000)?, vec![1; 100_000]);
+    let homura = handle.lookup(&root(), ROOT_INODE, OsStr::new("Homura"))?;
+    let fh = handle.open(&root(), homura.ino, Flags::READ_ONLY)?;
+    assert_eq!(handle.read(fh, (40 << 20) - 10, 100)?, vec![2; 10]);
+    Ok(())
+}
+
+#[test]
+fn grow_image_in_use() -> DkResult<()> {
+    let path = std::env::temp_dir().join(format!("dkfs-grow-{}", std::process::id()));
+    std::fs::File::create(&path)?.set_len(8 << 20)?;
+    {
+        let handle = format(dev(&path)?, FormatOptions::default())?;
+        let writer = {
+            let handle = handle.clone();
+            thread::spawn(move || -> DkResult<()> {
+                for i in 0..100 {
+                    let name = format!("{}", i);
+                    let mode = FileMode::REGULAR_FILE;
+                    let stat = handle.mknod(&root(), ROOT_INODE, OsStr::new(&name), mode, None)?;
+                    let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+                    handle.write(fh, 0, &[i as u8; 10000])?;
+                }
+                Ok(())
+            })
+        };
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(32 << 20)?;
+        let blocks = handle.statfs()?.blocks;
+        handle.grow(32 << 20)?;
+        assert_eq!(handle.statfs()?.blocks, blocks + (24 << 20) / 4096);
+        writer.join().unwrap()?;
+    }
+    let report = dkfs::check::check(dev(&path)?, false)?;
+    let handle = open(dev(&path)?)?;
+    let stat = handle.lookup(&root(), ROOT_INODE, OsStr::new("99"))?;
+    let data = handle.read(handle.open(&root(), stat.ino, Flags::READ_ONLY)?, 0, 20000)?;
+    std::fs::remove_file(&path)?;
+    assert!(report.is_clean(), "{:?}", report);
+    assert_eq!(data, vec![99; 10000]);
+    Ok(())
+}
+
+#[test]
+fn shrink() -> DkResult<()> {
+    use dkfs::check::check;
+    use dkfs::resize::shrink;
+
+    for &extents in &[true, false] {
+        let mut mem = vec![0; 16 << 20];
+        let opts = FormatOptions::default()
+            .block_size(1024)
+            .inode_count(512)
+            .inode_chunks(false)
+            .extents(extents);
+        let mut inos = BTreeMap::new();
+        {
+            let handle = format(Box::new(Memory::new(&mut mem[..4 << 20])), opts)?;
+            let dir = handle.mkdir(&root(), ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?;
+            for i in 0..400 {
+                let name = format!("{}", i);
+                let mode = FileMode::REGULAR_FILE;
+                let stat = handle.mknod(&root(), dir.ino, OsStr::new(&name), mode, None)?;
+                let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+                handle.write(fh, 0, &vec![i as u8; 5000])?;
+                inos.insert(i, stat.ino);
+            }
+            // Frees the low inodes and blocks, and keeps the high ones
+            for i in 0..300 {
+                handle.unlink(&root(), dir.ino, OsStr::new(&format!("{}", i)))?;
+                inos.remove(&i);
+            }
+            handle.setxattr(&root(), inos[&399], OsStr::new("user.soul_gem"), b"pink")?;
+            let homura = OsStr::new("Homura");
+            let homura = handle.mknod(&root(), dir.ino, homura, FileMode::REGULAR_FILE, None)?;
+            let fh = handle.open(&root(), homura.ino, Flags::WRITE_ONLY)?;
+            handle.write(fh, 0, &[42; 300_000])?;
+        }
+        {
+            let handle = open(Box::new(Memory::new(&mut mem[..])))?;
+            handle.grow(16 << 20)?;
+        }
+
+        // Too small for the files
+        match shrink(Box::new(Memory::new(&mut mem[..])), 512 << 10) {
+            Err(DkError::Invalid(_)) => {}
+            r => panic!("Expected the files not to fit, got {:?}", r.map(|_| ())),
+        }
+        let handle = shrink(Box::new(Memory::new(&mut mem[..])), 2 << 20)?;
+        assert_eq!(handle.size(), 2 << 20);
+        // Not fewer than the 103 inodes in use
+        assert_eq!(handle.statfs()?.files, 103);
+        drop(handle);
+
+        let report = check(Box::new(Memory::new(&mut mem[..2 << 20])), false)?;
+        assert!(report.is_clean(), "{:?}", report);
+        let handle = open(Box::new(Memory::new(&mut mem[..2 << 20])))?;
+        let dir = handle.lookup(&root(), ROOT_INODE, OsStr::new("Madoka"))?.ino;
+        for i in 300..400 {
+            let stat = handle.lookup(&root(), dir, OsStr::new(&format!("{}", i)))?;
+            assert!(stat.ino < ROOT_INODE + 103);
+            let fh = handle.open(&root(), stat.ino, Flags::READ_ONLY)?;
+            assert_eq!(handle.read(fh, 0, 10000)?, vec![i as u8; 5000]);
+        }
+        let kyoko = handle.lookup(&root(), dir, OsStr::new("399"))?.ino;
+        let soul_gem = handle.getxattr(&root(), kyoko, OsStr::new("user.soul_gem"))?;
+        assert_eq!(soul_gem, Some(b"pink".to_vec()));
+        assert_eq!(handle.lookup(&root(), dir, OsStr::new("."))?.ino, dir);
+        let homura = handle.lookup(&root(), dir, OsStr::new("Homura"))?;
+        let fh = handle.open(&root(), homura.ino, Flags::READ_ONLY)?;
+        assert_eq!(handle.read(fh, 0, 400_000)?, vec![42; 300_000]);
+    }
+    Ok(())
+}
+
+#[test]
+fn reserved_blocks() -> DkResult<()> {
+    prepare!(handle, FormatOptions::default().reserved_percent(50.0));
+    let everyone = Some(FileMode::DIRECTORY | FileMode::from_bits_truncate(0o777));
+    handle.setattr(&root(), ROOT_INODE, None, SetAttr { mode: everyone, ..Default::default() })?;
+    let madoka = Credentials::new(1000, 1000);
+    let mode = FileMode::REGULAR_FILE | FileMode::USER_RWX;
+    let stat = handle.mknod(&madoka, ROOT_INODE, OsStr::new("Madoka"), mode, None)?;
+    let fh = handle.open(&madoka, stat.ino, Flags::WRITE_ONLY)?;
+    let chunk = vec![42; 1 << 20];
+    let mut offset = 0;
+    loop {
+        match handle.write(fh.clone(), offset, &chunk) {
+            Ok(len) => offset += len as u64,
+            Err(DkError::Exhausted) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    // Users stop short of the reserved blocks
+    let statfs = handle.statfs()?;
+    assert!(statfs.bavail < 256, "{:?}", statfs);
+    assert!(statfs.bfree >= statfs.blocks / 2, "{:?}", statfs);
+
+    // which are left to the super user
+    let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+    assert_eq!(handle.write(fh, offset, &chunk)?, chunk.len());
+    Ok(())
+}
+
+#[test]
+fn inode_chunks() -> DkResult<()> {
+    use dkfs::check::check;
+    use dkfs::resize::shrink;
+
+    let mut mem = vec![0; 4 << 20];
+    let opts = FormatOptions::default().block_size(1024).inode_count(16);
+    {
+        let handle = format(Box::new(Memory::new(&mut mem[..])), opts.clone().inode_chunks(false))?;
+        for i in 0..15 {
+            let name = format!("{}", i);
+            handle.mknod(&root(), ROOT_INODE, OsStr::new(&name), FileMode::REGULAR_FILE, None)?;
+        }
+        match handle.mknod(&root(), ROOT_INODE, OsStr::new("15"), FileMode::REGULAR_FILE, None) {
+            Err(DkError::Exhausted) => {}
+            r => panic!("Expected inodes to be exhausted, got {:?}", r),
+        }
+    }
+
+    let read_all = |handle: &Handle, dir: u64| -> DkResult<BTreeMap<OsString, Vec<u8>>> {
+        let mut files = BTreeMap::new();
+        for entry in handle.readdir(handle.opendir(&root(), dir)?, 0) {
+            let entry = entry?;
+            if entry.name != "." && entry.name != ".." {
+                let fh = handle.open(&root(), entry.ino, Flags::READ_ONLY)?;
+                files.insert(entry.name, handle.read(fh, 0, 1000)?);
+            }
+        }
+        Ok(files)
+    };
+    let files = {
+        let handle = format(Box::new(Memory::new(&mut mem[..])), opts)?;
+        // Takes the blocks at the front, so that chunks are made at the back
+        let kyoko = OsStr::new("Kyoko");
+        let stat = handle.mknod(&root(), ROOT_INODE, kyoko, FileMode::REGULAR_FILE, None)?;
+        let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+        handle.write(fh, 0, &vec![7; 2 << 20])?;
+
+        let dir = handle.mkdir(&root(), ROOT_INODE, OsStr::new("Madoka"), FileMode::USER_RWX)?.ino;
+        let mut inos = BTreeMap::new();
+        for i in 0..300 {
+            let name = format!("{}", i);
+            let stat = handle.mknod(&root(), dir, OsStr::new(&name), FileMode::REGULAR_FILE, None)?;
+            let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+            handle.write(fh, 0, name.as_bytes())?;
+            inos.insert(i, stat.ino);
+        }
+        let mut freed = HashSet::new();
+        for i in (0..300).step_by(2) {
+            handle.unlink(&root(), dir, OsStr::new(&format!("{}", i)))?;
+            freed.insert(inos[&i]);
+        }
+        handle.apply_releases()?;
+        // Free slots in the chunks are used before new chunks are made
+        for i in 0..100 {
+            let name = format!("Homura{}", i);
+            let stat = handle.mknod(&root(), dir, OsStr::new(&name), FileMode::REGULAR_FILE, None)?;
+            assert!(freed.contains(&stat.ino));
+            let fh = handle.open(&root(), stat.ino, Flags::WRITE_ONLY)?;
+            handle.write(fh, 0, name.as_bytes())?;
+        }
+        handle.unlink(&root(), ROOT_INODE, kyoko)?;
+        handle.apply_releases()?;
+        let stat = handle.statfs()?;
+        // The root, Madoka and the files in it
+        assert_eq!(stat.files - stat.ffree, 252);
+        read_all(&handle, dir)?
+    };
+    assert_eq!(files.len(), 250);
+    let report = check(Box::new(Memory::new(&mut mem[..])), false)?;
+    assert!(report.is_clean(), "{:?}", report);
+
+    // The chunks at the back are dropped and their inodes moved
+    drop(shrink(Box::new(Memory::new(&mut mem[..])), 1 << 20)?);
+    let report = check(Box::new(Memory::new(&mut mem[..1 << 20])), false)?;
+    assert!(report.is_clean(), "{:?}", report);
+    let handle = open(Box::new(Memory::new(&mut mem[..1 << 20])))?;
+    let dir = handle.lookup(&root(), ROOT_INODE, OsStr::new("Madoka"))?.ino;
+    assert_eq!(read_all(&handle, dir)?, files);
+    let stat = handle.statfs()?;
+    assert_eq!(stat.files - stat.ffree, 252);
+    Ok(())
+}
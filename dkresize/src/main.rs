@@ -0,0 +1,67 @@
+extern crate clap;
+extern crate dkfs;
+
+use dkfs::*;
+
+fn main() -> DkResult<()> {
+    use clap::*;
+
+    let matches = App::new("dkresize")
+        .version("0.1.0")
+        .author("Yilin Chen <sticnarf@gmail.com>")
+        .about("Grow or shrink an unmounted donkey file system")
+        .arg(
+            Arg::with_name("device")
+                .help("Path to the device holding the file system")
+                .required(true),
+        ).arg(
+            Arg::with_name("size")
+                .help("New size in bytes, or with a K, M, G or T suffix [default: device size]"),
+        ).get_matches();
+
+    let dev_path = matches.value_of("device").unwrap();
+    let dev = dev(dev_path)?;
+    let dev_size = dev.size();
+    let size = match matches.value_of("size") {
+        Some(size) => match parse_size(size) {
+            Some(size) => size,
+            None => Error::value_validation_auto("Invalid size".to_string()).exit(),
+        },
+        None => dev_size,
+    };
+
+    let handle = open(dev)?;
+    let before = handle.statfs()?;
+    let handle = if size < handle.size() {
+        drop(handle);
+        resize::shrink(dkfs::dev(dev_path)?, size)?
+    } else {
+        handle.grow(size)?;
+        handle
+    };
+    let after = handle.statfs()?;
+    drop(handle);
+    if size < dev_size && std::fs::metadata(dev_path)?.is_file() {
+        // Cut off the rest of an image file
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(dev_path)?
+            .set_len(size)?;
+    }
+    println!("Blocks: {} -> {}", before.blocks, after.blocks);
+    println!("Inodes: {} -> {}", before.files, after.files);
+    Ok(())
+}
+
+/// Parses a number of bytes like `4096`, `512M` or `2G`
+fn parse_size(s: &str) -> Option<u64> {
+    let (num, shift) = match s.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&s[..i], 10),
+        (i, 'M') | (i, 'm') => (&s[..i], 20),
+        (i, 'G') | (i, 'g') => (&s[..i], 30),
+        (i, 'T') | (i, 't') => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let num: u64 = num.parse().ok()?;
+    num.checked_mul(1 << shift)
+}
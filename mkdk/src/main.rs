@@ -7,7 +7,10 @@ use dkfs::*;
 fn main() -> DkResult<()> {
     use clap::*;
 
-    let bpi = format!("{}", DEFAULT_BYTES_PER_INODE);
+    let bpi_help = format!(
+        "Specify the bytes/inode ratio [default: {}, with at most {} inodes unless -F]",
+        DEFAULT_BYTES_PER_INODE, DEFAULT_CHUNKED_INODE_COUNT
+    );
     let matches = App::new("mkdk")
         .version("0.1.1")
         .author("Yilin Chen <sticnarf@gmail.com>")
@@ -18,17 +21,142 @@ fn main() -> DkResult<()> {
                 .required(true),
         ).arg(
             Arg::with_name("bytes-per-inode")
-                .help("Specify the bytes/inode ratio")
+                .help(&bpi_help)
                 .short("i")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("block-size")
+                .help("Block size in bytes, a power of 2 from 1024 to 65536")
+                .short("b")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("inode-count")
+                .help("Number of inodes, overriding the bytes/inode ratio")
+                .short("N")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("max-inodes")
+                .help("Number of inodes the inode table can grow to on resize")
+                .short("I")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("label")
+                .help("Volume label of at most 16 bytes")
+                .short("L")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("uuid")
+                .help("UUID of the file system [default: random]")
+                .short("U")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("reserved-percent")
+                .help("Percentage of blocks reserved for the super user")
+                .short("m")
                 .takes_value(true)
-                .default_value(&bpi),
+                .default_value("0"),
+        ).arg(
+            Arg::with_name("fixed-inodes")
+                .help("Keep all inodes in the inode table, without inode chunks")
+                .short("F"),
+        ).arg(
+            Arg::with_name("dry-run")
+                .help("Print the layout without writing anything")
+                .short("n"),
         ).get_matches();
 
     let dev_path = matches.value_of("device").unwrap();
-    let bytes_per_inode =
-        value_t!(matches.value_of("bytes-per-inode"), u64).unwrap_or_else(|e| e.exit());
+    let reserved_percent =
+        value_t!(matches.value_of("reserved-percent"), f64).unwrap_or_else(|e| e.exit());
 
-    let opt = FormatOptions::default().bytes_per_inode(bytes_per_inode);
-    let _ = format(dev(dev_path)?, opt)?;
+    let mut opt = FormatOptions::default()
+        .reserved_percent(reserved_percent)
+        .inode_chunks(!matches.is_present("fixed-inodes"));
+    if matches.is_present("bytes-per-inode") {
+        let ratio = value_t!(matches, "bytes-per-inode", u64).unwrap_or_else(|e| e.exit());
+        opt = opt.bytes_per_inode(ratio);
+    }
+    if matches.is_present("block-size") {
+        opt = opt.block_size(value_t!(matches, "block-size", u64).unwrap_or_else(|e| e.exit()));
+    }
+    if matches.is_present("inode-count") {
+        opt = opt.inode_count(value_t!(matches, "inode-count", u64).unwrap_or_else(|e| e.exit()));
+    }
+    if matches.is_present("max-inodes") {
+        let max = value_t!(matches, "max-inodes", u64).unwrap_or_else(|e| e.exit());
+        opt = opt.max_inode_count(max);
+    }
+    if let Some(label) = matches.value_of("label") {
+        opt = opt.label(label);
+    }
+    if let Some(uuid) = matches.value_of("uuid") {
+        match parse_uuid(uuid) {
+            Some(uuid) => opt = opt.uuid(uuid),
+            None => Error::value_validation_auto("Invalid UUID".to_string()).exit(),
+        }
+    }
+
+    let dev = dev(dev_path)?;
+    let layout = layout(&*dev, &opt)?;
+    print_layout(&layout);
+    if matches.is_present("dry-run") {
+        return Ok(());
+    }
+    let handle = format(dev, opt)?;
+    println!("UUID:            {}", format_uuid(&handle.volume()?.uuid));
     Ok(())
 }
+
+fn print_layout(layout: &Layout) {
+    let bs = layout.block_size;
+    println!("Block size:      {}", bs);
+    println!("Blocks:          {}", layout.block_count);
+    println!("Inodes:          {}", layout.inode_count);
+    println!("Max inodes:      {}", layout.max_inode_count);
+    println!("Inode bitmap:    byte {}", layout.inode_bitmap_ptr);
+    println!("Block bitmap:    byte {}", layout.db_bitmap_ptr);
+    println!(
+        "Journal:         blocks {}-{}",
+        layout.journal_ptr / bs,
+        layout.journal_ptr / bs + layout.journal_blocks - 1
+    );
+    println!(
+        "Data blocks:     {} from block {}",
+        layout.db_count,
+        layout.first_db_ptr / bs
+    );
+    println!("Reserved blocks: {}", layout.reserved_db_count);
+    if layout.backup_ptrs.is_empty() {
+        println!("Super backups:   none");
+    } else {
+        let backups: Vec<String> = layout.backup_ptrs.iter().map(|p| p.to_string()).collect();
+        println!("Super backups:   bytes {}", backups.join(", "));
+    }
+}
+
+/// Parses the `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` form
+fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = s.split('-').collect();
+    let lens: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+    if lens != [8, 4, 4, 4, 12] {
+        return None;
+    }
+    let hex = groups.concat();
+    let mut uuid = [0; 16];
+    for (i, b) in uuid.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: Vec<String> = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..].concat()
+    )
+}
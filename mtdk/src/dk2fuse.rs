@@ -70,5 +70,14 @@ pub fn errno(error: &DkError) -> c_int {
         AlreadyExists => EEXIST,
         Invalid(_) => EINVAL,
         NameTooLong => ENAMETOOLONG,
+        TooLarge => E2BIG,
+        XattrsFull => ENOSPC,
+        JournalFull => ENOSPC,
+        IsDirectory => EISDIR,
+        RenameLoop => EINVAL,
+        Incompatible(_) => EINVAL,
+        ReadOnly => EROFS,
+        PermissionDenied => EACCES,
+        NotPermitted => EPERM,
     }
 }
@@ -1,5 +1,7 @@
 use dkfs::*;
+use fuse::Request;
 use libc::*;
+use std::fs;
 use time::Timespec;
 
 pub fn file_mode(mode: u32) -> FileMode {
@@ -81,3 +83,43 @@ pub fn timespec(t: Timespec) -> DkTimespec {
         nsec: t.nsec as u32,
     }
 }
+
+/// The process making a request. Its credentials can be resolved
+/// after the request has been dispatched.
+pub struct Caller {
+    pid: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl Caller {
+    pub fn new(req: &Request) -> Self {
+        Caller {
+            pid: req.pid(),
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+
+    /// The credentials of the process. Its supplementary groups
+    /// are read from `/proc`, and left out if the process has exited.
+    pub fn credentials(&self) -> Credentials {
+        let status = fs::read_to_string(format!("/proc/{}/status", self.pid)).unwrap_or_default();
+        let groups = status
+            .lines()
+            .find(|line| line.starts_with("Groups:"))
+            .map(|line| {
+                line["Groups:".len()..]
+                    .split_whitespace()
+                    .filter_map(|gid| gid.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Credentials::new(self.uid, self.gid).groups(groups)
+    }
+}
+
+/// The credentials of the process making `req`
+pub fn credentials(req: &Request) -> Credentials {
+    Caller::new(req).credentials()
+}
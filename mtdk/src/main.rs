@@ -9,18 +9,24 @@ extern crate nix;
 extern crate slog_term;
 extern crate time;
 
+use dkfs::pool::Pool;
 use dkfs::*;
 use fuse::*;
 use libc::*;
 use slog::{Drain, Logger};
+use nix::sys::signal::{SigSet, Signal};
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 fn main() -> DkResult<()> {
     use clap::*;
 
+    let cache = format!("{}", DEFAULT_CACHE_BLOCKS);
+    let threads = format!("{}", DEFAULT_THREADS);
     let matches = App::new("mtdk")
         .version("0.1.2")
         .author("Yilin Chen <sticnarf@gmail.com>")
@@ -34,32 +40,73 @@ fn main() -> DkResult<()> {
                 .help("Path of the mount point")
                 .required(true),
         ).arg(Arg::with_name("daemon").short("d").help("Run as a daemon"))
-        .get_matches();
+        .arg(
+            Arg::with_name("cache-blocks")
+                .help("Number of blocks kept in the buffer cache")
+                .short("c")
+                .takes_value(true)
+                .default_value(&cache),
+        ).arg(
+            Arg::with_name("threads")
+                .help("Number of threads serving requests")
+                .short("t")
+                .takes_value(true)
+                .default_value(&threads),
+        ).arg(
+            Arg::with_name("backup")
+                .help("Rebuild the super block from the backup at the given byte")
+                .short("b")
+                .takes_value(true),
+        ).get_matches();
 
     let log = logger();
     let dev_path = matches.value_of("device").unwrap();
     let mount_point = matches.value_of("dir").unwrap();
     let daemon = matches.is_present("daemon");
-    let options = [
+    let cache_blocks = match matches.value_of("cache-blocks").unwrap().parse() {
+        Ok(blocks) => blocks,
+        Err(_) => {
+            clap::Error::value_validation_auto("Invalid number of cache blocks".to_string()).exit()
+        }
+    };
+    let threads = match matches.value_of("threads").unwrap().parse() {
+        Ok(threads) if threads > 0 => threads,
+        _ => clap::Error::value_validation_auto("Invalid number of threads".to_string()).exit(),
+    };
+    let mut open_opts = OpenOptions::default();
+    if let Some(backup) = matches.value_of("backup") {
+        match backup.parse() {
+            Ok(backup) => open_opts = open_opts.backup(backup),
+            Err(_) => clap::Error::value_validation_auto("Invalid backup".to_string()).exit(),
+        }
+    }
+    let dk = dkfs::open_with(Box::new(Cache::new(dev(dev_path)?, cache_blocks)), open_opts)?;
+    let mut options = vec![
         "-o",
         "fsname=donkey",
         "-o",
         "allow_other",
         "-o",
-        "default_permissions",
-        "-o",
         "auto_unmount",
-    ]
+    ];
+    if dk.read_only() {
+        warn!(log, "Unknown read-only compatible features. Mounting read-only.");
+        options.extend(&["-o", "ro"]);
+    }
+    let options = options
         .iter()
         .map(|o| OsStr::new(o))
         .collect::<Vec<&OsStr>>();
 
-    let dk = dkfs::open(dev(dev_path)?)?;
     let fuse = DonkeyFuse {
         dk,
+        dev_path: dev_path.to_string(),
+        growing: Arc::new(Mutex::new(None)),
         log: log.clone(),
         dir_fh: HashMap::new(),
         file_fh: HashMap::new(),
+        threads,
+        pool: None,
     };
     if daemon {
         mount_as_daemon(fuse, &mount_point, &options, log);
@@ -89,13 +136,62 @@ fn logger() -> Logger {
     Logger::root(drain, o!())
 }
 
-const TTL: time::Timespec = time::Timespec { sec: 1, nsec: 0 };
+/// Grows the file system to fill the device each time SIGUSR1 arrives.
+fn grow_on_signal(
+    signals: &SigSet,
+    growing: &Mutex<Option<Handle<'static>>>,
+    dev_path: &str,
+    log: &Logger,
+) {
+    while signals.wait().is_ok() {
+        let dk = match *growing.lock().unwrap() {
+            Some(ref dk) => dk.clone(),
+            None => return,
+        };
+        match dev(dev_path).and_then(|d| dk.grow(d.size())) {
+            Ok(()) => info!(log, "Grown to fill {}", dev_path),
+            Err(e) => error!(log, "Failed to grow: {}", e),
+        }
+    }
+}
 
-struct DonkeyFuse<'a> {
-    dk: Handle<'a>,
+const TTL: time::Timespec = time::Timespec { sec: 1, nsec: 0 };
+const DEFAULT_THREADS: usize = 4;
+
+struct DonkeyFuse {
+    dk: Handle<'static>,
+    dev_path: String,
+    /// Shared with the thread growing the file system on SIGUSR1.
+    /// Emptied in `destroy`, so that the file system can be closed.
+    growing: Arc<Mutex<Option<Handle<'static>>>>,
     log: Logger,
     dir_fh: HashMap<u64, DkDirHandle>,
     file_fh: HashMap<u64, DkFileHandle>,
+    threads: usize,
+    /// Started in `init`, because threads do not survive `fork`
+    pool: Option<Pool>,
+}
+
+impl DonkeyFuse {
+    /// Serves a request on a worker thread.
+    /// Requests opening or releasing handles are served in place.
+    fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce(&Handle<'static>, &Logger) + Send + 'static,
+    {
+        let (dk, log) = (self.dk.clone(), self.log.clone());
+        self.pool.as_ref().unwrap().spawn(move || f(&dk, &log));
+    }
+
+    /// Like `spawn`, but also passes the credentials of the process
+    /// making `req`. They are resolved on the worker thread.
+    fn spawn_as<F>(&self, req: &Request, f: F)
+    where
+        F: FnOnce(&Handle<'static>, &Logger, &Credentials) + Send + 'static,
+    {
+        let caller = fuse2dk::Caller::new(req);
+        self.spawn(move |dk, log| f(dk, log, &caller.credentials()));
+    }
 }
 
 macro_rules! construct_fmt {
@@ -140,29 +236,48 @@ macro_rules! ino {
     };
 }
 
-impl<'a> Filesystem for DonkeyFuse<'a> {
+impl Filesystem for DonkeyFuse {
     fn init(&mut self, req: &Request) -> std::result::Result<(), c_int> {
         debug_params!(self.log; init; req);
+        // Only the growing thread takes SIGUSR1. Threads spawned
+        // from now on inherit the blocked signal.
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGUSR1);
+        match signals.thread_block() {
+            Ok(()) => {
+                *self.growing.lock().unwrap() = Some(self.dk.clone());
+                let (growing, log) = (self.growing.clone(), self.log.clone());
+                let dev_path = self.dev_path.clone();
+                thread::spawn(move || grow_on_signal(&signals, &growing, &dev_path, &log));
+            }
+            Err(e) => warn!(self.log, "Growing on SIGUSR1 is disabled: {}", e),
+        }
+        self.pool = Some(Pool::new(self.threads));
         Ok(())
     }
 
     fn destroy(&mut self, req: &Request) {
         debug_params!(self.log; destroy; req);
+        self.growing.lock().unwrap().take();
+        // Wait for the requests in progress
+        self.pool.take();
     }
 
     fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         ino![parent];
         debug_params!(self.log; lookup; req, parent, name);
-        match self.dk.lookup(parent, name) {
-            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), req.unique()),
+        let name = name.to_os_string();
+        let unique = req.unique();
+        self.spawn_as(req, move |dk, log, cred| match dk.lookup(cred, parent, &name) {
+            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), unique),
             Err(e) => {
                 match &e {
                     DkError::NotFound => {}
-                    _ => error!(self.log, "{}", e),
+                    _ => error!(log, "{}", e),
                 }
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
@@ -174,15 +289,15 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         ino![ino];
         debug_params!(self.log; getattr; req, ino);
-        match self.dk.getattr(ino) {
+        self.spawn(move |dk, log| match dk.getattr(ino) {
             Ok(stat) => {
                 reply.attr(&TTL, &dk2fuse::file_attr(&stat));
             }
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn setattr(
@@ -206,42 +321,44 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         debug_params!(self.log; setattr;
             req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags);
         let fh = fh.and_then(|fh| self.file_fh.get(&fh)).cloned();
-        match self.dk.setattr(
-            ino,
-            fh,
-            mode.map(fuse2dk::file_mode),
-            uid,
-            gid,
-            size,
-            atime.map(fuse2dk::timespec),
-            mtime.map(fuse2dk::timespec),
-            chgtime.map(fuse2dk::timespec),
-            crtime.map(fuse2dk::timespec),
-        ) {
-            Ok(stat) => reply.attr(&TTL, &dk2fuse::file_attr(&stat)),
-            Err(e) => {
-                error!(self.log, "{}", e);
-                reply.error(dk2fuse::errno(&e));
+        self.spawn_as(req, move |dk, log, cred| {
+            let attr = SetAttr {
+                mode: mode.map(fuse2dk::file_mode),
+                uid,
+                gid,
+                size,
+                atime: atime.map(fuse2dk::timespec),
+                mtime: mtime.map(fuse2dk::timespec),
+                ctime: chgtime.map(fuse2dk::timespec),
+                crtime: crtime.map(fuse2dk::timespec),
+            };
+            match dk.setattr(cred, ino, fh, attr) {
+                Ok(stat) => reply.attr(&TTL, &dk2fuse::file_attr(&stat)),
+                Err(e) => {
+                    error!(log, "{}", e);
+                    reply.error(dk2fuse::errno(&e));
+                }
             }
-        }
+        });
     }
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         ino![ino];
         debug_params!(self.log; readlink; req, ino);
-        let res = self
-            .dk
-            .getattr(ino)
-            .map(|stat| stat.size)
-            .and_then(|size| self.dk.open(ino, Flags::READ_ONLY).map(|fh| (fh, size)))
-            .and_then(|(fh, size)| self.dk.read(fh, 0, size));
-        match res {
-            Ok(v) => reply.data(&v[..]),
-            Err(e) => {
-                error!(self.log, "{}", e);
-                reply.error(dk2fuse::errno(&e));
+        self.spawn_as(req, move |dk, log, cred| {
+            let res = dk
+                .getattr(ino)
+                .map(|stat| stat.size)
+                .and_then(|size| dk.open(cred, ino, Flags::READ_ONLY).map(|fh| (fh, size)))
+                .and_then(|(fh, size)| dk.read(fh, 0, size));
+            match res {
+                Ok(v) => reply.data(&v[..]),
+                Err(e) => {
+                    error!(log, "{}", e);
+                    reply.error(dk2fuse::errno(&e));
+                }
             }
-        }
+        });
     }
 
     fn mknod(
@@ -261,55 +378,57 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         } else {
             None
         };
-        match self
-            .dk
-            .mknod(req.uid(), req.gid(), parent, name, mode, rdev)
-        {
-            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), req.unique()),
+        let name = name.to_os_string();
+        let unique = req.unique();
+        self.spawn_as(req, move |dk, log, cred| match dk.mknod(cred, parent, &name, mode, rdev) {
+            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), unique),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
         ino![parent];
         debug_params!(self.log; mkdir; req, parent, name, mode);
-        match self
-            .dk
-            .mkdir(parent, req.uid(), req.gid(), name, fuse2dk::file_mode(mode))
-        {
-            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), req.unique()),
-            Err(e) => {
-                error!(self.log, "{}", e);
-                reply.error(dk2fuse::errno(&e));
+        let name = name.to_os_string();
+        let unique = req.unique();
+        self.spawn_as(req, move |dk, log, cred| {
+            match dk.mkdir(cred, parent, &name, fuse2dk::file_mode(mode)) {
+                Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), unique),
+                Err(e) => {
+                    error!(log, "{}", e);
+                    reply.error(dk2fuse::errno(&e));
+                }
             }
-        }
+        });
     }
 
     fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         ino![parent];
         debug_params!(self.log; unlink; req, parent, name);
-        match self.dk.unlink(parent, name) {
+        let name = name.to_os_string();
+        self.spawn_as(req, move |dk, log, cred| match dk.unlink(cred, parent, &name) {
             Ok(_) => reply.ok(),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         ino![parent];
         debug_params!(self.log; rmdir; req, parent, name);
-        match self.dk.rmdir(parent, name) {
+        let name = name.to_os_string();
+        self.spawn_as(req, move |dk, log, cred| match dk.rmdir(cred, parent, &name) {
             Ok(_) => reply.ok(),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn symlink(
@@ -322,13 +441,16 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
     ) {
         ino![parent];
         debug_params!(self.log; symlink; req, parent, name, link);
-        match self.dk.symlink(req.uid(), req.gid(), parent, name, link) {
-            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), req.unique()),
+        let name = name.to_os_string();
+        let link = link.to_path_buf();
+        let unique = req.unique();
+        self.spawn_as(req, move |dk, log, cred| match dk.symlink(cred, parent, &name, &link) {
+            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), unique),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn rename(
@@ -342,13 +464,25 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
     ) {
         ino![parent, newparent];
         debug_params!(self.log; rename; req, parent, name, newparent, newname);
-        match self.dk.rename(parent, name, newparent, newname) {
-            Ok(_) => reply.ok(),
-            Err(e) => {
-                error!(self.log, "{}", e);
-                reply.error(dk2fuse::errno(&e));
+        let name = name.to_os_string();
+        let newname = newname.to_os_string();
+        self.spawn_as(req, move |dk, log, cred| {
+            match dk.rename(
+                cred,
+                parent,
+                &name,
+                newparent,
+                &newname,
+                // Not passed by the `fuse` crate
+                RenameFlags::empty(),
+            ) {
+                Ok(_) => reply.ok(),
+                Err(e) => {
+                    error!(log, "{}", e);
+                    reply.error(dk2fuse::errno(&e));
+                }
             }
-        }
+        });
     }
 
     fn link(
@@ -361,13 +495,15 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
     ) {
         ino![ino, newparent];
         debug_params!(self.log; link; req, ino, newparent, newname);
-        match self.dk.link(ino, newparent, newname) {
-            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), req.unique()),
+        let newname = newname.to_os_string();
+        let unique = req.unique();
+        self.spawn_as(req, move |dk, log, cred| match dk.link(cred, ino, newparent, &newname) {
+            Ok(stat) => reply.entry(&TTL, &dk2fuse::file_attr(&stat), unique),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
@@ -375,7 +511,7 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         debug_params!(self.log; open; req, ino, flags);
         // clear set-user-id and set-group-id bits if uid is not root
         let flags = fuse2dk::flags(flags);
-        let mut res = self.dk.open(ino, flags);
+        let mut res = self.dk.open(&fuse2dk::credentials(req), ino, flags);
         if req.uid() != 0 {
             res = res.and_then(|fh| self.dk.clear_set_bits(fh));
         }
@@ -395,19 +531,19 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         ino![ino];
         debug_params!(self.log; read; req, ino, fh, offset, size);
         let fh = match self.file_fh.get(&fh) {
-            Some(fh) => fh,
+            Some(fh) => fh.clone(),
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        match self.dk.read(fh.clone(), offset as u64, size as u64) {
+        self.spawn(move |dk, log| match dk.read(fh, offset as u64, size as u64) {
             Ok(v) => reply.data(&v[..]),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn write(
@@ -423,38 +559,39 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         ino![ino];
         debug_params!(self.log; write; req, ino, fh, offset, data, flags);
         let fh = match self.file_fh.get(&fh) {
-            Some(fh) => fh,
+            Some(fh) => fh.clone(),
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        match self.dk.write(fh.clone(), offset as u64, data) {
+        let data = data.to_vec();
+        self.spawn(move |dk, log| match dk.write(fh, offset as u64, &data) {
             Ok(size) => reply.written(size as u32),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         ino![ino];
         debug_params!(self.log; flush; req, ino, fh, lock_owner);
         let fh = match self.file_fh.get(&fh) {
-            Some(fh) => fh,
+            Some(fh) => fh.clone(),
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        match self.dk.flush(fh.clone()) {
+        self.spawn(move |dk, log| match dk.flush(fh) {
             Ok(_) => reply.ok(),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn release(
@@ -484,25 +621,25 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         ino![ino];
         debug_params!(self.log; fsync; req, ino, fh, datasync);
         let fh = match self.file_fh.get(&fh) {
-            Some(fh) => fh,
+            Some(fh) => fh.clone(),
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        match self.dk.fsync(fh.clone(), datasync) {
+        self.spawn(move |dk, log| match dk.fsync(fh, datasync) {
             Ok(_) => reply.ok(),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         ino![ino];
         debug_params!(self.log; opendir; req, ino, flags);
-        match self.dk.opendir(ino) {
+        match self.dk.opendir(&fuse2dk::credentials(req), ino) {
             Ok(dh) => {
                 self.dir_fh.insert(req.unique(), dh);
                 reply.opened(req.unique(), flags);
@@ -525,33 +662,35 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         ino![ino];
         debug_params!(self.log; readdir; req, ino, fh, offset);
         let dh = match self.dir_fh.get(&fh) {
-            Some(dh) => dh,
+            Some(dh) => dh.clone(),
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        for (i, (name, ino)) in self.dk.readdir(dh.clone(), offset as usize).enumerate() {
-            match self.dk.getattr(ino) {
-                Ok(stat) => {
-                    if reply.add(
-                        ino,
-                        offset + i as i64 + 1,
-                        dk2fuse::file_type(stat.mode),
-                        name,
-                    ) {
-                        // Full
+        self.spawn(move |dk, log| {
+            for entry in dk.readdir(dh, offset as u64) {
+                match entry {
+                    Ok(e) => {
+                        if reply.add(
+                            e.ino,
+                            e.cookie as i64,
+                            dk2fuse::file_type(e.file_type),
+                            e.name,
+                        ) {
+                            // Full
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        error!(log, "{}", e);
+                        reply.error(dk2fuse::errno(&e));
                         return;
                     }
                 }
-                Err(e) => {
-                    error!(self.log, "{}", e);
-                    reply.error(dk2fuse::errno(&e));
-                    return;
-                }
             }
-        }
-        reply.ok();
+            reply.ok();
+        });
     }
 
     fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
@@ -572,25 +711,25 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
         ino![ino];
         debug_params!(self.log; fsyncdir; req, ino, fh, datasync);
         let dh = match self.dir_fh.get(&fh) {
-            Some(dh) => dh,
+            Some(dh) => dh.clone(),
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        match self.dk.fsyncdir(dh.clone(), datasync) {
+        self.spawn(move |dk, log| match dk.fsyncdir(dh, datasync) {
             Ok(_) => reply.ok(),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         ino![ino];
         debug_params!(self.log; statfs; req, ino);
-        match self.dk.statfs() {
+        self.spawn(move |dk, log| match dk.statfs() {
             Ok(stat) => {
                 reply.statfs(
                     stat.blocks,
@@ -604,10 +743,10 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
                 );
             }
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn setxattr(
@@ -622,19 +761,22 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
     ) {
         ino![ino];
         debug_params!(self.log; setxattr; req, ino, name, value, flags, position);
-        match self.dk.setxattr(ino, name, value) {
+        let name = name.to_os_string();
+        let value = value.to_vec();
+        self.spawn_as(req, move |dk, log, cred| match dk.setxattr(cred, ino, &name, &value) {
             Ok(_) => reply.ok(),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
         ino![ino];
         debug_params!(self.log; getxattr; req, ino, name, size);
-        match self.dk.getxattr(ino, name) {
+        let name = name.to_os_string();
+        self.spawn_as(req, move |dk, log, cred| match dk.getxattr(cred, ino, &name) {
             Ok(Some(v)) => {
                 if size == 0 {
                     reply.size(v.len() as u32);
@@ -648,16 +790,16 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
             }
             Ok(None) => reply.error(ENOATTR),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         ino![ino];
         debug_params!(self.log; listxattr; req, ino, size);
-        match self.dk.listxattr(ino) {
+        self.spawn_as(req, move |dk, log, cred| match dk.listxattr(cred, ino) {
             Ok(v) => {
                 let mut b = Vec::new();
                 for name in &v {
@@ -675,29 +817,39 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
                 }
             }
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
         ino![ino];
         debug_params!(self.log; removexattr; req, ino, name);
-        match self.dk.removexattr(ino, name) {
+        let name = name.to_os_string();
+        self.spawn_as(req, move |dk, log, cred| match dk.removexattr(cred, ino, &name) {
             Ok(_) => reply.ok(),
             Err(e) => {
-                error!(self.log, "{}", e);
+                error!(log, "{}", e);
                 reply.error(dk2fuse::errno(&e));
             }
-        }
+        });
     }
 
     fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
         ino![ino];
         debug_params!(self.log; access; req, ino, mask);
-        // This function should not be called with `default_permissions` mount option.
-        reply.error(ENOSYS);
+        let mode = AccessMode::from_bits_truncate(mask);
+        self.spawn_as(req, move |dk, log, cred| match dk.access(cred, ino, mode) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                match &e {
+                    DkError::PermissionDenied => {}
+                    _ => error!(log, "{}", e),
+                }
+                reply.error(dk2fuse::errno(&e));
+            }
+        });
     }
 
     fn create(
@@ -711,8 +863,19 @@ impl<'a> Filesystem for DonkeyFuse<'a> {
     ) {
         ino![parent];
         debug_params!(self.log; create; req, parent, name, mode, flags);
-        // Returns ENOSYS to let fuse use mknod and open instead.
-        reply.error(ENOSYS);
+        let cred = fuse2dk::credentials(req);
+        let (mode, flags) = (fuse2dk::file_mode(mode), fuse2dk::flags(flags));
+        match self.dk.create(&cred, parent, name, mode, flags) {
+            Ok((stat, fh)) => {
+                let attr = dk2fuse::file_attr(&stat);
+                reply.created(&TTL, &attr, 0, req.unique(), dk2fuse::flags(fh.flags));
+                self.file_fh.insert(req.unique(), fh);
+            }
+            Err(e) => {
+                error!(self.log, "{}", e);
+                reply.error(dk2fuse::errno(&e));
+            }
+        }
     }
 
     fn getlk(